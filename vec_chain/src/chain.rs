@@ -1,145 +1,1401 @@
+use crate::clock::{SystemClock, TimeSource};
+use crate::consensus::{
+    BLOCK_REWARD, COINBASE_MATURITY, GENESIS_PREMINE, MAX_OUTPUTS_PER_TX, MAX_REORG_DEPTH,
+    MAX_TIMESTAMP_DRIFT, MEDIAN_TIME_SPAN, MIN_POW_DIFFICULTY, POW_DIFFICULTY, POW_HASHER,
+    RANGE_PROOF_BITS, TARGET_BLOCK_TIME, TRANSACTION_VERSION,
+};
 use bulletproofs::{BulletproofGens, PedersenGens, RangeProof};
 use curve25519_dalek_ng::ristretto::CompressedRistretto;
+use futures::stream::{self, Stream};
 use merlin::Transcript;
 use prost::Message;
-use vec_crypto::crypto::{verify_blsag, BLSAGSignature, Wallet};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::watch;
+use vec_crypto::crypto::{
+    burned_amount, decompress_non_identity, has_duplicate_members, is_coinbase, range_proof_bits,
+    scan_transactions_for, transaction_signing_hash, verify_blsag, BLSAGSignature, ViewOnlyWallet,
+    Wallet,
+};
 use vec_errors::errors::*;
-use vec_merkle::merkle::MerkleTree;
-use vec_proto::messages::{Block, Transaction};
-use vec_storage::block_db::BlockStorer;
+use vec_merkle::merkle::{verify_proof, MerkleTree};
+use vec_proto::messages::{Block, BlockExport, Header, ScannedOutput, Transaction};
 use vec_storage::{
-    image_db::ImageStorer,
-    lazy_traits::{BLOCK_STORER, IMAGE_STORER, OUTPUT_STORER},
-    output_db::OutputStorer,
+    block_db::{BlockDB, BlockStorer},
+    image_db::{ImageDB, ImageStorer},
+    maintenance::CompactableDb,
+    meta_db::{
+        cumulative_work_key, wallet_birthday_key, MetaDB, MetaStorer, TOTAL_SUPPLY_KEY,
+        VALIDATED_UP_TO_KEY,
+    },
+    output_db::{OutputDB, OutputKind, OutputStorer, OwnedOutput},
 };
 use vec_utils::utils::*;
 
-// Return the "highest" block index in the local chain instance
-pub async fn max_index() -> Result<u32, BlockStorageError> {
-    match BLOCK_STORER.get_highest_index().await {
-        Ok(Some(index)) => Ok(index),
-        Ok(None) => Ok(0),
-        Err(e) => Err(e),
+// Inclusion of a subscribed transaction, its own block counts as the first confirmation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConfirmationEvent {
+    pub block_index: u32,
+    pub confirmations: u32,
+}
+
+// A local chain instance working on the databases it owns, several of them can live in one process
+pub struct Chain {
+    blocks: Arc<BlockDB>,
+    images: Arc<ImageDB>,
+    outputs: Arc<OutputDB>,
+    meta: Arc<MetaDB>,
+    // Index of the highest block, readable without touching the BlockDB and watchable for new blocks
+    tip_index: watch::Sender<u32>,
+    // Time source of block timestamps, the system clock unless replaced with with_clock
+    clock: Arc<dyn TimeSource>,
+    // Additional accounts whose outputs are stored with every committed block, next to the wallet's own
+    accounts: std::sync::RwLock<Vec<Arc<Wallet>>>,
+}
+
+impl Chain {
+    pub fn new(
+        blocks: Arc<BlockDB>,
+        images: Arc<ImageDB>,
+        outputs: Arc<OutputDB>,
+        meta: Arc<MetaDB>,
+    ) -> Chain {
+        Chain {
+            blocks,
+            images,
+            outputs,
+            meta,
+            tip_index: watch::channel(0).0,
+            clock: Arc::new(SystemClock),
+            accounts: std::sync::RwLock::new(Vec::new()),
+        }
+    }
+
+    // Makes and checks block timestamps against the given clock instead of the system one
+    pub fn with_clock(mut self, clock: Arc<dyn TimeSource>) -> Chain {
+        self.clock = clock;
+        self
+    }
+
+    // Seconds since the Unix epoch according to the chain's clock
+    pub fn unix_now(&self) -> u64 {
+        self.clock.unix_now()
+    }
+
+    // Opens the chain's Sled databases in the given directory, creating them if needed
+    pub async fn open(dir: &str) -> Result<Chain, ChainOpsError> {
+        let open = |name: &str| {
+            CompactableDb::open(format!("{}/{}", dir, name))
+                .map_err(|_| ChainOpsError::SledOpenError)
+        };
+        let chain = Chain::new(
+            Arc::new(BlockDB::new(
+                open("blocks_db")?,
+                open("index_db")?,
+                open("tx_db")?,
+            )),
+            Arc::new(ImageDB::new(open("image_db")?)),
+            Arc::new(OutputDB::new(open("output_db")?)),
+            Arc::new(MetaDB::new(open("meta_db")?)),
+        );
+        chain.refresh_tip_index().await?;
+        Ok(chain)
+    }
+
+    pub fn blocks(&self) -> &BlockDB {
+        &self.blocks
+    }
+
+    pub fn images(&self) -> &ImageDB {
+        &self.images
+    }
+
+    pub fn outputs(&self) -> &OutputDB {
+        &self.outputs
+    }
+
+    pub fn meta(&self) -> &MetaDB {
+        &self.meta
+    }
+
+    // Scans the transactions for the wallet's outputs and stores the owned ones in this chain's OutputDB
+    async fn store_owned_outputs(
+        &self,
+        wallet: &Wallet,
+        transactions: &[Transaction],
+        height: u32,
+    ) -> Result<(), ChainOpsError> {
+        for owned_output in wallet.scan_transactions(transactions, height)? {
+            self.outputs
+                .put(&self.with_recorded_kind(owned_output).await?)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // A scan finds every owned output as received, the change the wallet recorded building its transactions
+    // is marked here
+    async fn with_recorded_kind(
+        &self,
+        mut owned_output: OwnedOutput,
+    ) -> Result<OwnedOutput, ChainOpsError> {
+        if self.outputs.is_change(&owned_output.output.stealth).await? {
+            owned_output.kind = OutputKind::Change;
+        }
+        Ok(owned_output)
+    }
+
+    // Stores the block together with the outputs it pays to the wallet and the accounts, the outputs are taken
+    // back if any write fails so a block that couldn't be committed leaves nothing behind
+    async fn commit_block(
+        &self,
+        wallet: &Wallet,
+        block: &Block,
+        index: u32,
+        hash: Vec<u8>,
+    ) -> Result<(), ChainOpsError> {
+        let owned_outputs = wallet.scan_transactions(&block.msg_transactions, index)?;
+        let account_outputs =
+            self.scan_accounts(&self.accounts(), &block.msg_transactions, index)?;
+        let result = self
+            .put_block_with_outputs(&owned_outputs, &account_outputs, block, index, hash)
+            .await;
+        if result.is_err() {
+            for owned_output in &owned_outputs {
+                self.outputs.remove(&owned_output.output.stealth).await?;
+            }
+            for (account, owned_output) in &account_outputs {
+                self.outputs
+                    .remove_for(account, &owned_output.output.stealth)
+                    .await?;
+            }
+        } else if !owned_outputs.is_empty() {
+            self.store_birthday(wallet).await?;
+        }
+        result
+    }
+
+    // Persists the wallet's birthday, the scan that found its first output only recorded it in memory
+    async fn store_birthday(&self, wallet: &Wallet) -> Result<(), ChainOpsError> {
+        let Some(birthday) = wallet.birthday() else {
+            return Ok(());
+        };
+        let key = wallet_birthday_key(wallet.public_spend_key.as_bytes());
+        if self.meta.get(&key).await? != Some(u64::from(birthday)) {
+            self.meta.put(&key, u64::from(birthday)).await?;
+        }
+        Ok(())
+    }
+
+    // Gives the wallet back the birthday stored for it by an earlier run
+    pub async fn load_birthday(&self, wallet: &Wallet) -> Result<(), ChainOpsError> {
+        let key = wallet_birthday_key(wallet.public_spend_key.as_bytes());
+        if let Some(birthday) = self.meta.get(&key).await? {
+            wallet.record_birthday(birthday as u32);
+        }
+        Ok(())
+    }
+
+    async fn put_block_with_outputs(
+        &self,
+        owned_outputs: &[OwnedOutput],
+        account_outputs: &[(Vec<u8>, OwnedOutput)],
+        block: &Block,
+        index: u32,
+        hash: Vec<u8>,
+    ) -> Result<(), ChainOpsError> {
+        for owned_output in owned_outputs {
+            self.outputs
+                .put(&self.with_recorded_kind(owned_output.clone()).await?)
+                .await?;
+        }
+        for (account, owned_output) in account_outputs {
+            self.outputs.put_for(account, owned_output).await?;
+        }
+        self.blocks.put_block(index, hash, block).await?;
+        Ok(())
+    }
+
+    // Scans the transactions for the outputs of several accounts at once, each owned output comes with the
+    // address of the account it pays
+    fn scan_accounts(
+        &self,
+        accounts: &[Arc<Wallet>],
+        transactions: &[Transaction],
+        height: u32,
+    ) -> Result<Vec<(Vec<u8>, OwnedOutput)>, ChainOpsError> {
+        let wallets: Vec<&Wallet> = accounts.iter().map(|wallet| wallet.as_ref()).collect();
+        Ok(scan_transactions_for(&wallets, transactions, height)?
+            .into_iter()
+            .map(|(position, owned_output)| (wallets[position].address.to_vec(), owned_output))
+            .collect())
+    }
+
+    fn accounts(&self) -> Vec<Arc<Wallet>> {
+        self.accounts.read().unwrap().clone()
+    }
+
+    // Serves one more account from this chain: its outputs in the local blocks are scanned now, those of the
+    // blocks committed from then on with the block. Returns the number of blocks scanned
+    pub async fn add_account(&self, wallet: Arc<Wallet>) -> Result<u32, ChainOpsError> {
+        {
+            let mut accounts = self.accounts.write().unwrap();
+            accounts.retain(|account| account.address != wallet.address);
+            accounts.push(Arc::clone(&wallet));
+        }
+        // Blocks committed while the rescan runs store the same outputs again under the same keys
+        self.outputs.clear_for(&wallet.address).await?;
+        let mut scanned = 0;
+        for index in wallet.scan_from_index.max(1)..=self.max_index().await? {
+            let block = self
+                .blocks
+                .get_by_index(index)
+                .await?
+                .ok_or(ChainOpsError::BlockNotFound)?;
+            for (account, owned_output) in
+                self.scan_accounts(&[Arc::clone(&wallet)], &block.msg_transactions, index)?
+            {
+                self.outputs.put_for(&account, &owned_output).await?;
+            }
+            scanned += 1;
+        }
+        Ok(scanned)
+    }
+
+    // Sum of the decrypted outputs stored for the account whose key images the chain hasn't seen spent
+    pub async fn account_balance(&self, wallet: &Wallet) -> Result<u64, ChainOpsError> {
+        let mut balance = 0;
+        for owned_output in self.outputs.get_for(&wallet.address).await? {
+            if !self
+                .images
+                .contains(wallet.key_image(&owned_output)?)
+                .await?
+            {
+                balance += owned_output.decrypted_amount;
+            }
+        }
+        Ok(balance)
+    }
+
+    // Return the "highest" block index in the local chain instance
+    pub async fn max_index(&self) -> Result<u32, BlockStorageError> {
+        match self.blocks.get_highest_index().await {
+            Ok(Some(index)) => Ok(index),
+            Ok(None) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Return the cached "highest" block index, never waiting on the BlockDB
+    pub fn cached_tip_index(&self) -> u32 {
+        *self.tip_index.borrow()
+    }
+
+    // Receiver notified every time the tip index rises
+    pub fn subscribe_tip(&self) -> watch::Receiver<u32> {
+        self.tip_index.subscribe()
+    }
+
+    // Stream emitting once the transaction enters a block and again every time the tip rises above it,
+    // ends if the chain is dropped or its databases fail
+    pub fn subscribe_tx(&self, tx_hash: Vec<u8>) -> impl Stream<Item = ConfirmationEvent> + '_ {
+        stream::unfold((self.subscribe_tip(), true), move |(mut tip, first)| {
+            let tx_hash = tx_hash.clone();
+            async move {
+                let mut first = first;
+                loop {
+                    if !first && tip.changed().await.is_err() {
+                        return None;
+                    }
+                    first = false;
+                    let tip_index = *tip.borrow_and_update();
+                    let block_index = match self.transaction_block_index(tx_hash.clone()).await {
+                        Ok(Some(block_index)) => block_index,
+                        Ok(None) => continue,
+                        Err(_) => return None,
+                    };
+                    let event = ConfirmationEvent {
+                        block_index,
+                        confirmations: tip_index.saturating_sub(block_index) + 1,
+                    };
+                    return Some((event, (tip, false)));
+                }
+            }
+        })
+    }
+
+    // Index of the local block including the transaction with given hash
+    pub async fn transaction_block_index(
+        &self,
+        tx_hash: Vec<u8>,
+    ) -> Result<Option<u32>, ChainOpsError> {
+        let block_hash = match self.blocks.get_block_hash_by_tx(tx_hash).await? {
+            Some(block_hash) => block_hash,
+            None => return Ok(None),
+        };
+        let block = self
+            .blocks
+            .get(block_hash)
+            .await?
+            .ok_or(ChainOpsError::BlockNotFound)?;
+        let header = block
+            .msg_header
+            .as_ref()
+            .ok_or(ChainOpsError::MissingBlockHeader)?;
+        Ok(Some(header.msg_index))
+    }
+
+    fn raise_tip_index(&self, index: u32) {
+        self.tip_index.send_if_modified(|tip| {
+            if index > *tip {
+                *tip = index;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    // Reload the cached tip index from the BlockDB
+    pub async fn refresh_tip_index(&self) -> Result<u32, BlockStorageError> {
+        let index = self.max_index().await?;
+        self.raise_tip_index(index);
+        Ok(self.cached_tip_index())
+    }
+
+    // Add the block to the chain
+    pub async fn add_block(&self, wallet: &Wallet, block: Block) -> Result<(), ChainOpsError> {
+        let header = block
+            .msg_header
+            .as_ref()
+            .ok_or(ChainOpsError::MissingBlockHeader)?;
+        let hash = hash_block(&block)?;
+        let index = header.msg_index;
+        self.check_index_free(index, &hash).await?;
+        self.validate_block(&block).await?;
+        self.commit_block(wallet, &block, index, hash.clone())
+            .await?;
+        // Raised once the transactions are indexed so tip subscribers can look them up
+        self.index_transactions(&block, hash).await?;
+        self.record_key_images(&block).await?;
+        self.raise_tip_index(index);
+        self.update_total_supply(&block).await?;
+        self.record_cumulative_work(index).await?;
+        self.commit_validated(index).await?;
+        Ok(())
+    }
+
+    // Refuses a block whose index already holds a different one before the wallet's outputs are touched,
+    // replacing blocks is left to the reorg path
+    pub async fn check_index_free(&self, index: u32, hash: &[u8]) -> Result<(), ChainOpsError> {
+        match self.blocks.get_hash_by_index(index).await? {
+            Some(existing) if existing != hash => {
+                Err(BlockStorageError::IndexOccupied { index }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Validate the candidate block on top of the local tip
+    pub async fn validate_block(&self, incoming_block: &Block) -> Result<(), ChainOpsError> {
+        let previous_hash = self.get_previous_hash_in_chain().await?;
+        self.validate_block_after(incoming_block, &previous_hash)
+            .await
+    }
+
+    // Every check a block following the block with the given hash has to pass
+    async fn validate_block_after(
+        &self,
+        incoming_block: &Block,
+        previous_hash: &[u8],
+    ) -> Result<(), ChainOpsError> {
+        let header = incoming_block
+            .msg_header
+            .as_ref()
+            .ok_or(ChainOpsError::MissingBlockHeader)?;
+        let difficulty = self.required_difficulty(header.msg_index).await?;
+        if !verify_block_pow(incoming_block, difficulty) {
+            return Err(ChainOpsError::InvalidProofOfWork);
+        }
+        // The block hash only covers the header, the transactions have to be the ones its root hash commits to
+        if !verify_root_hash(incoming_block)? {
+            return Err(ChainOpsError::InvalidRootHash);
+        }
+        if header.msg_previous_hash != previous_hash {
+            return Err(ChainOpsError::InvalidPreviousBlockHash);
+        }
+        self.check_block_timestamp(incoming_block).await?;
+        self.check_stealth_addresses(incoming_block).await?;
+        self.check_transactions_in_block(incoming_block).await?;
+        Ok(())
+    }
+
+    // Owned outputs are keyed by stealth address, so a reused one would overwrite the stored output; checked against
+    // every output in the chain, not just the local wallet's, so all nodes agree on the block
+    pub async fn check_stealth_addresses(&self, block: &Block) -> Result<(), ChainOpsError> {
+        let mut seen = HashSet::new();
+        for output in block.msg_transactions.iter().flat_map(|tx| &tx.msg_outputs) {
+            if !seen.insert(&output.msg_stealth_address)
+                || self
+                    .blocks
+                    .contains_stealth_address(&output.msg_stealth_address)
+                    .await?
+            {
+                return Err(ChainOpsError::DuplicateStealthAddress);
+            }
+        }
+        Ok(())
+    }
+
+    // Function used during the genesis to add the block without actual verifying the transactions
+    pub async fn add_genesis_block(
+        &self,
+        wallet: &Wallet,
+        block: Block,
+    ) -> Result<(), ChainOpsError> {
+        let header = block
+            .msg_header
+            .as_ref()
+            .ok_or(ChainOpsError::MissingBlockHeader)?;
+        let hash = hash_block(&block)?.to_vec();
+        let index = header.msg_index;
+        self.check_index_free(index, &hash).await?;
+        self.commit_block(wallet, &block, index, hash.clone())
+            .await?;
+        self.index_transactions(&block, hash).await?;
+        self.record_key_images(&block).await?;
+        self.raise_tip_index(index);
+        self.update_total_supply(&block).await?;
+        self.record_cumulative_work(index).await?;
+        self.commit_validated(index).await?;
+        Ok(())
+    }
+
+    // Highest index up to which every stored block was validated and fully applied,
+    // None for a chain stored before the index was tracked
+    pub async fn validated_up_to(&self) -> Result<Option<u32>, ChainOpsError> {
+        Ok(self
+            .meta
+            .get(VALIDATED_UP_TO_KEY)
+            .await?
+            .map(|index| index as u32))
+    }
+
+    // Only ever raises the validated index, blocks may finish applying out of order across tasks
+    async fn commit_validated(&self, index: u32) -> Result<(), ChainOpsError> {
+        // None orders below every index
+        if self.validated_up_to().await? < Some(index) {
+            self.meta.put(VALIDATED_UP_TO_KEY, u64::from(index)).await?;
+        }
+        Ok(())
+    }
+
+    // Re-validates the stored blocks above the validated index, left behind by a crash in the middle of applying
+    // them, and redoes their idempotent bookkeeping. From the first block that fails on, the blocks are rolled back
+    // so the chain ends at the last valid one. The total supply is recounted afterwards, since the crash may have
+    // happened before or after it was updated. Returns the number of blocks committed and rolled back
+    pub async fn resume_validation(&self, wallet: &Wallet) -> Result<(u32, u32), ChainOpsError> {
+        let tip = self.max_index().await?;
+        let validated = match self.validated_up_to().await? {
+            Some(validated) => validated,
+            None => {
+                self.commit_validated(tip).await?;
+                return Ok((0, 0));
+            }
+        };
+        if validated >= tip {
+            return Ok((0, 0));
+        }
+        let mut committed = 0;
+        let mut rolled_back = 0;
+        for index in (validated + 1)..=tip {
+            let block = self
+                .blocks
+                .get_by_index(index)
+                .await?
+                .ok_or(ChainOpsError::BlockNotFound)?;
+            if self.revalidate_stored_block(index, &block).await.is_err() {
+                rolled_back = self.rollback_to(wallet, index - 1).await?.len() as u32;
+                break;
+            }
+            self.index_transactions(&block, hash_block(&block)?).await?;
+            self.record_key_images(&block).await?;
+            self.record_cumulative_work(index).await?;
+            self.commit_validated(index).await?;
+            committed += 1;
+        }
+        self.recount_total_supply().await?;
+        Ok((committed, rolled_back))
+    }
+
+    // Checks of validate_block that still hold for a block that is already stored at the given index
+    async fn revalidate_stored_block(
+        &self,
+        index: u32,
+        block: &Block,
+    ) -> Result<(), ChainOpsError> {
+        if index == 1 {
+            return check_genesis_block(block);
+        }
+        let header = block
+            .msg_header
+            .as_ref()
+            .ok_or(ChainOpsError::MissingBlockHeader)?;
+        let previous_hash = self
+            .blocks
+            .get_hash_by_index(index - 1)
+            .await?
+            .ok_or(ChainOpsError::MissingBlockHash)?;
+        if header.msg_index != index || header.msg_previous_hash != previous_hash {
+            return Err(ChainOpsError::InvalidPreviousBlockHash);
+        }
+        self.check_block_timestamp(block).await?;
+        self.check_transactions_in_block(block).await
+    }
+
+    // Rebuilds the OutputDB, accounts included, and the spent key images by rescanning the local BlockDB from the
+    // earliest creation height of the wallet and the accounts, returns the number of blocks scanned
+    pub async fn rebuild_outputs(&self, wallet: &Wallet) -> Result<u32, ChainOpsError> {
+        self.outputs.clear().await?;
+        let accounts = self.accounts();
+        let from_index = accounts
+            .iter()
+            .map(|account| account.scan_from_index)
+            .fold(wallet.scan_from_index, u32::min);
+        let mut scanned = 0;
+        for index in from_index.max(1)..=self.max_index().await? {
+            let block = self
+                .blocks
+                .get_by_index(index)
+                .await?
+                .ok_or(ChainOpsError::BlockNotFound)?;
+            self.store_owned_outputs(wallet, &block.msg_transactions, index)
+                .await?;
+            for (account, owned_output) in
+                self.scan_accounts(&accounts, &block.msg_transactions, index)?
+            {
+                self.outputs.put_for(&account, &owned_output).await?;
+            }
+            self.record_key_images(&block).await?;
+            scanned += 1;
+        }
+        self.store_birthday(wallet).await?;
+        Ok(scanned)
+    }
+
+    // Outputs of the local blocks from the given index on that pay to the watch-only keys, nothing is stored
+    pub async fn scan_blocks_with_view_key(
+        &self,
+        keys: &ViewOnlyWallet,
+        from_index: u32,
+        to_index: u32,
+    ) -> Result<Vec<ScannedOutput>, ChainOpsError> {
+        let mut scanned = Vec::new();
+        for index in from_index.max(1)..=to_index.min(self.max_index().await?) {
+            let block = self
+                .blocks
+                .get_by_index(index)
+                .await?
+                .ok_or(ChainOpsError::BlockNotFound)?;
+            for transaction in block.msg_transactions.iter() {
+                for output in transaction.msg_outputs.iter() {
+                    // Outputs that can't be scanned are someone else's junk, not a reason to fail the whole scan
+                    if let Ok(Some(amount)) = keys.scan_output(output) {
+                        scanned.push(ScannedOutput {
+                            msg_block_index: index,
+                            msg_transaction_hash: hash_transaction(transaction),
+                            msg_output_index: output.msg_index,
+                            msg_amount: amount,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(scanned)
+    }
+
+    // Map every transaction of the stored block to the block's hash and every output's stealth address to its transaction
+    pub async fn index_transactions(
+        &self,
+        block: &Block,
+        block_hash: Vec<u8>,
+    ) -> Result<(), ChainOpsError> {
+        for transaction in block.msg_transactions.iter() {
+            let tx_hash = hash_transaction(transaction);
+            for output in &transaction.msg_outputs {
+                self.blocks
+                    .put_stealth_index(&output.msg_stealth_address, tx_hash.clone())
+                    .await?;
+            }
+            self.blocks
+                .put_tx_index(tx_hash, block_hash.clone())
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Marks the key images of every input in the block as spent
+    async fn record_key_images(&self, block: &Block) -> Result<(), ChainOpsError> {
+        for input in block.msg_transactions.iter().flat_map(|tx| &tx.msg_inputs) {
+            self.images.put(input.msg_key_image.clone()).await?;
+        }
+        Ok(())
+    }
+
+    // Check if an input with the given key image is already included in the local chain
+    pub async fn is_image_spent(&self, image: &[u8]) -> Result<bool, ChainOpsError> {
+        Ok(self.images.contains(image.to_vec()).await?)
+    }
+
+    // Check if the transaction with given hash is already included in the local chain
+    pub async fn is_transaction_confirmed(&self, tx_hash: Vec<u8>) -> Result<bool, ChainOpsError> {
+        Ok(self.blocks.get_block_hash_by_tx(tx_hash).await?.is_some())
+    }
+
+    // Check a light client's Merkle proof of the transaction against the root of the block at given index
+    pub async fn verify_inclusion(
+        &self,
+        transaction: &Transaction,
+        block_index: u32,
+        proof: &[(Vec<u8>, bool)],
+    ) -> Result<bool, ChainOpsError> {
+        let block = self
+            .blocks
+            .get_by_index(block_index)
+            .await?
+            .ok_or(ChainOpsError::BlockNotFound)?;
+        let header = block.msg_header.ok_or(ChainOpsError::MissingBlockHeader)?;
+        Ok(verify_proof(
+            &header.msg_root_hash,
+            &transaction.encode_to_vec(),
+            proof,
+        ))
+    }
+
+    // Returns the block from the BlockDB by its hash
+    pub async fn get_block_by_hash(&self, hash: Vec<u8>) -> Result<Block, ChainOpsError> {
+        match self.blocks.get(hash.clone()).await {
+            Ok(Some(block)) => Ok(block),
+            Ok(None) => Err(ChainOpsError::BlockNotFound),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Seconds elapsed since the timestamp of the highest block in the local chain
+    pub async fn seconds_since_tip(&self) -> Result<u64, ChainOpsError> {
+        match self.blocks.get_by_index(self.max_index().await?).await? {
+            Some(tip) => seconds_since_block(&tip, self.unix_now()),
+            None => Err(ChainOpsError::BlockNotFound),
+        }
+    }
+
+    // Locates the block of the local chain the competing branch builds on and checks the implied reorg against MAX_REORG_DEPTH
+    pub async fn check_competing_branch(&self, branch: &[Block]) -> Result<u32, ChainOpsError> {
+        match self.fork_index(branch).await? {
+            Some(fork_index) => {
+                check_reorg_depth(self.max_index().await?, fork_index, MAX_REORG_DEPTH)
+            }
+            None => Ok(0),
+        }
+    }
+
+    // Fork choice: a competing branch replaces the local blocks after the fork only if it carries more
+    // cumulative work than the local tip, however many blocks either side has. A branch whose headers
+    // don't hold up is refused before its work is counted
+    pub async fn is_heavier_branch(&self, branch: &[Block]) -> Result<bool, ChainOpsError> {
+        let fork_index = match self.fork_index(branch).await? {
+            Some(fork_index) => fork_index,
+            None => return Ok(false),
+        };
+        check_reorg_depth(self.max_index().await?, fork_index, MAX_REORG_DEPTH)?;
+        let branch_work = self
+            .cumulative_work(fork_index)
+            .await?
+            .saturating_add(self.check_branch_headers(fork_index, branch).await?);
+        Ok(branch_work > self.tip_work().await?)
+    }
+
+    // Checks what can be checked of a branch without applying its transactions: every block links to the one
+    // before it, has a timestamp acceptable after those preceding it on the branch, meets the difficulty
+    // they require and carries the transactions its root hash commits to. Returns the work the branch adds on top of the fork
+    async fn check_branch_headers(
+        &self,
+        fork_index: u32,
+        branch: &[Block],
+    ) -> Result<u64, ChainOpsError> {
+        let mut timestamps = self.previous_timestamps(fork_index + 1).await?;
+        let mut previous_hash = self
+            .blocks
+            .get_hash_by_index(fork_index)
+            .await?
+            .ok_or(ChainOpsError::MissingBlockHash)?;
+        let mut work: u64 = 0;
+        for (index, block) in (fork_index + 1..).zip(branch) {
+            let header = block
+                .msg_header
+                .as_ref()
+                .ok_or(ChainOpsError::MissingBlockHeader)?;
+            if header.msg_index != index || header.msg_previous_hash != previous_hash {
+                return Err(ChainOpsError::InvalidPreviousBlockHash);
+            }
+            check_timestamp(
+                header.msg_timestamp,
+                &timestamps,
+                self.unix_now(),
+                MAX_TIMESTAMP_DRIFT,
+            )?;
+            let difficulty = adjusted_difficulty(&timestamps);
+            if !verify_block_pow(block, difficulty) {
+                return Err(ChainOpsError::InvalidProofOfWork);
+            }
+            if !verify_root_hash(block)? {
+                return Err(ChainOpsError::InvalidRootHash);
+            }
+            work = work.saturating_add(difficulty_work(difficulty));
+            timestamps.push(header.msg_timestamp);
+            if timestamps.len() > MEDIAN_TIME_SPAN as usize {
+                timestamps.remove(0);
+            }
+            previous_hash = hash_block(block)?;
+        }
+        Ok(work)
+    }
+
+    // Switches to the competing branch if it's heavier: the local blocks after the fork are rolled back and the
+    // branch's blocks go through add_block, so each of them is fully validated. If one fails, the rolled back
+    // blocks are put back. Returns whether the chain switched
+    pub async fn reorganize(
+        &self,
+        wallet: &Wallet,
+        branch: &[Block],
+    ) -> Result<bool, ChainOpsError> {
+        if !self.is_heavier_branch(branch).await? {
+            return Ok(false);
+        }
+        let fork_index = self
+            .fork_index(branch)
+            .await?
+            .ok_or(ChainOpsError::BlockNotFound)?;
+        let rolled_back = self.rollback_to(wallet, fork_index).await?;
+        for block in branch {
+            if let Err(e) = self.add_block(wallet, block.clone()).await {
+                self.rollback_to(wallet, fork_index).await?;
+                // The branch forks after genesis, so the local blocks go back through the same validation
+                // and application as any other block
+                for block in rolled_back {
+                    self.add_block(wallet, block).await?;
+                }
+                return Err(e);
+            }
+        }
+        Ok(true)
+    }
+
+    // Takes the blocks above the index out of the chain, newest first, and undoes what applying them did.
+    // Blocks above the validated index were never applied, their key images and stealth addresses may be an
+    // earlier block's, so only the blocks themselves are removed. Returns them in chain order
+    pub async fn rollback_to(
+        &self,
+        wallet: &Wallet,
+        index: u32,
+    ) -> Result<Vec<Block>, ChainOpsError> {
+        let validated = self.validated_up_to().await?;
+        let mut rolled_back = Vec::new();
+        for rolled_index in ((index + 1)..=self.max_index().await?).rev() {
+            let block = match self.blocks.remove_block(rolled_index).await? {
+                Some(block) => block,
+                None => continue,
+            };
+            if validated < Some(rolled_index) {
+                rolled_back.push(block);
+                continue;
+            }
+            for transaction in &block.msg_transactions {
+                for output in &transaction.msg_outputs {
+                    self.blocks
+                        .remove_stealth_index(&output.msg_stealth_address)
+                        .await?;
+                }
+                for input in &transaction.msg_inputs {
+                    self.images.remove(input.msg_key_image.clone()).await?;
+                }
+                self.blocks
+                    .remove_tx_index(hash_transaction(transaction))
+                    .await?;
+            }
+            for owned_output in wallet.scan_transactions(&block.msg_transactions, rolled_index)? {
+                self.outputs.remove(&owned_output.output.stealth).await?;
+            }
+            for (account, owned_output) in
+                self.scan_accounts(&self.accounts(), &block.msg_transactions, rolled_index)?
+            {
+                self.outputs
+                    .remove_for(&account, &owned_output.output.stealth)
+                    .await?;
+            }
+            self.revert_total_supply(&block).await?;
+            rolled_back.push(block);
+        }
+        if self.validated_up_to().await? > Some(index) {
+            self.meta.put(VALIDATED_UP_TO_KEY, u64::from(index)).await?;
+        }
+        self.tip_index.send_replace(index);
+        rolled_back.reverse();
+        Ok(rolled_back)
+    }
+
+    // Index of the local block the branch builds on, None for an empty branch
+    async fn fork_index(&self, branch: &[Block]) -> Result<Option<u32>, ChainOpsError> {
+        let first_header = match branch.first() {
+            Some(block) => block
+                .msg_header
+                .as_ref()
+                .ok_or(ChainOpsError::MissingBlockHeader)?,
+            None => return Ok(None),
+        };
+        let ancestor = self
+            .get_block_by_hash(first_header.msg_previous_hash.clone())
+            .await?;
+        let fork_index = ancestor
+            .msg_header
+            .as_ref()
+            .ok_or(ChainOpsError::MissingBlockHeader)?
+            .msg_index;
+        Ok(Some(fork_index))
+    }
+
+    // Work of the local chain from genesis up to the given index, 0 for an empty chain
+    pub async fn cumulative_work(&self, index: u32) -> Result<u64, ChainOpsError> {
+        let mut work: u64 = 0;
+        let mut index = index;
+        // Blocks stored before their work was recorded are summed up from the difficulty required of them
+        while index > 0 {
+            if let Some(stored) = self.meta.get(&cumulative_work_key(index)).await? {
+                return Ok(stored.saturating_add(work));
+            }
+            let difficulty = self.required_difficulty(index).await?;
+            work = work.saturating_add(difficulty_work(difficulty));
+            index -= 1;
+        }
+        Ok(work)
+    }
+
+    // Cumulative work of the highest block, the weight fork choice compares branches by
+    pub async fn tip_work(&self) -> Result<u64, ChainOpsError> {
+        self.cumulative_work(self.max_index().await?).await
+    }
+
+    async fn record_cumulative_work(&self, index: u32) -> Result<u64, ChainOpsError> {
+        let difficulty = self.required_difficulty(index).await?;
+        let work = self
+            .cumulative_work(index.saturating_sub(1))
+            .await?
+            .saturating_add(difficulty_work(difficulty));
+        self.meta.put(&cumulative_work_key(index), work).await?;
+        Ok(work)
+    }
+
+    // Encodes the block at the given index together with its hash, to be shared e.g. in bug reports
+    pub async fn export_block(&self, index: u32) -> Result<Vec<u8>, ChainOpsError> {
+        let block = self
+            .blocks
+            .get_by_index(index)
+            .await?
+            .ok_or(ChainOpsError::BlockNotFound)?;
+        let export = BlockExport {
+            msg_hash: hash_block(&block)?,
+            msg_block: Some(block),
+        };
+        Ok(export.encode_to_vec())
+    }
+
+    // Headers of the blocks from..=to in index order, for light clients that only follow the header chain.
+    // Block and PoW hashes are header hashes, so the headers alone can be checked for linkage and PoW
+    pub async fn header_chain(&self, from: u32, to: u32) -> Result<Vec<Header>, ChainOpsError> {
+        if to > self.max_index().await? {
+            return Err(ChainOpsError::IndexTooHigh);
+        }
+        let mut headers = Vec::new();
+        for index in from.max(1)..=to {
+            let header = self
+                .blocks
+                .get_by_index(index)
+                .await?
+                .ok_or(ChainOpsError::BlockNotFound)?
+                .msg_header
+                .ok_or(ChainOpsError::MissingBlockHeader)?;
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    // Fee rates paid in each of the last count blocks in index order, the history fee estimation draws on
+    pub async fn recent_fee_rates(&self, count: u32) -> Result<Vec<Vec<u64>>, ChainOpsError> {
+        let tip = self.max_index().await?;
+        let mut history = Vec::new();
+        for index in tip.saturating_sub(count).saturating_add(1).max(1)..=tip {
+            let block = self
+                .blocks
+                .get_by_index(index)
+                .await?
+                .ok_or(ChainOpsError::BlockNotFound)?;
+            history.push(block.msg_transactions.iter().map(fee_rate).collect());
+        }
+        Ok(history)
+    }
+
+    // Decodes an exported block and re-runs the validation of validate_block against the local chain at its height,
+    // without committing it
+    pub async fn import_block_for_validation(&self, bytes: &[u8]) -> Result<Block, ChainOpsError> {
+        let export = BlockExport::decode(bytes).map_err(|_| ChainOpsError::DeserializationError)?;
+        let block = export
+            .msg_block
+            .ok_or(ChainOpsError::DeserializationError)?;
+        if hash_block(&block)? != export.msg_hash {
+            return Err(ChainOpsError::ExportHashMismatch);
+        }
+        let header = block
+            .msg_header
+            .as_ref()
+            .ok_or(ChainOpsError::MissingBlockHeader)?;
+        let previous_hash = match header.msg_index {
+            0 | 1 => vec![],
+            index => self
+                .blocks
+                .get_hash_by_index(index - 1)
+                .await?
+                .ok_or(ChainOpsError::MissingBlockHash)?,
+        };
+        self.validate_block_after(&block, &previous_hash).await?;
+        Ok(block)
+    }
+
+    // Returns the cleartext amount of coins in circulation
+    pub async fn total_supply(&self) -> Result<u64, ChainOpsError> {
+        Ok(self.meta.get(TOTAL_SUPPLY_KEY).await?.unwrap_or(0))
+    }
+
+    // Adds the amounts minted by the block and subtracts the fees paid in it (fees are not re-minted)
+    // and the amounts it sends to the burn address
+    pub async fn update_total_supply(&self, block: &Block) -> Result<u64, ChainOpsError> {
+        let (added, removed) = supply_change(block)?;
+        let supply = self
+            .total_supply()
+            .await?
+            .saturating_add(added)
+            .saturating_sub(removed);
+        self.meta.put(TOTAL_SUPPLY_KEY, supply).await?;
+        Ok(supply)
+    }
+
+    // Sets the total supply to what the blocks in the chain add up to
+    async fn recount_total_supply(&self) -> Result<u64, ChainOpsError> {
+        let mut supply: u64 = 0;
+        for index in 1..=self.max_index().await? {
+            let block = self
+                .blocks
+                .get_by_index(index)
+                .await?
+                .ok_or(ChainOpsError::BlockNotFound)?;
+            let (added, removed) = supply_change(&block)?;
+            supply = supply.saturating_add(added).saturating_sub(removed);
+        }
+        self.meta.put(TOTAL_SUPPLY_KEY, supply).await?;
+        Ok(supply)
+    }
+
+    // Undoes update_total_supply for a block that is rolled back
+    async fn revert_total_supply(&self, block: &Block) -> Result<u64, ChainOpsError> {
+        let (added, removed) = supply_change(block)?;
+        let supply = self
+            .total_supply()
+            .await?
+            .saturating_sub(added)
+            .saturating_add(removed);
+        self.meta.put(TOTAL_SUPPLY_KEY, supply).await?;
+        Ok(supply)
+    }
+
+    // Check if the hash of the previous block in DB maches the msg_previous_hash of the candidate block
+    // Checks the header's timestamp against the local clock and the blocks preceding it
+    pub async fn check_block_timestamp(&self, incoming_block: &Block) -> Result<(), ChainOpsError> {
+        let header = incoming_block
+            .msg_header
+            .as_ref()
+            .ok_or(ChainOpsError::MissingBlockHeader)?;
+        let previous_timestamps = self.previous_timestamps(header.msg_index).await?;
+        check_timestamp(
+            header.msg_timestamp,
+            &previous_timestamps,
+            self.unix_now(),
+            MAX_TIMESTAMP_DRIFT,
+        )
+    }
+
+    // Timestamp for a block produced at the given index: now, unless that would not pass the median check
+    pub async fn next_block_timestamp(&self, index: u32) -> Result<u64, ChainOpsError> {
+        let median = median_timestamp(&self.previous_timestamps(index).await?);
+        Ok(match median {
+            Some(median) => self.unix_now().max(median + 1),
+            None => self.unix_now(),
+        })
+    }
+
+    // Difficulty the block at the given index has to meet, set by how fast the blocks preceding it came
+    pub async fn required_difficulty(&self, index: u32) -> Result<usize, ChainOpsError> {
+        Ok(adjusted_difficulty(&self.previous_timestamps(index).await?))
+    }
+
+    // Timestamps of the MEDIAN_TIME_SPAN blocks preceding the given index
+    async fn previous_timestamps(&self, index: u32) -> Result<Vec<u64>, ChainOpsError> {
+        let first_index = index.saturating_sub(MEDIAN_TIME_SPAN).max(1);
+        let mut timestamps = Vec::new();
+        for previous_index in first_index..index {
+            if let Some(block) = self.blocks.get_by_index(previous_index).await? {
+                if let Some(previous) = block.msg_header {
+                    timestamps.push(previous.msg_timestamp);
+                }
+            }
+        }
+        Ok(timestamps)
+    }
+
+    pub async fn get_previous_hash_in_chain(&self) -> Result<Vec<u8>, ChainOpsError> {
+        let previous_index = self.max_index().await?;
+        let previous_hash = match self.blocks.get_hash_by_index(previous_index).await? {
+            Some(hash) => hash,
+            None => return Err(ChainOpsError::MissingBlockHash),
+        };
+        Ok(previous_hash)
+    }
+
+    pub async fn check_transactions_in_block(
+        &self,
+        incoming_block: &Block,
+    ) -> Result<(), ChainOpsError> {
+        check_coinbase(incoming_block)?;
+        let transactions = &incoming_block.msg_transactions;
+        for (position, tx) in transactions.iter().enumerate() {
+            if !self
+                .validate_block_transaction(tx, is_coinbase(transactions, position))
+                .await?
+            {
+                return Err(ChainOpsError::InvalidTransaction);
+            }
+        }
+        Ok(())
+    }
+
+    // A loose transaction, like one entering the mempool, can never be a coinbase
+    pub async fn validate_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<bool, ChainOpsError> {
+        self.validate_block_transaction(transaction, false).await
+    }
+
+    async fn validate_block_transaction(
+        &self,
+        transaction: &Transaction,
+        coinbase: bool,
+    ) -> Result<bool, ChainOpsError> {
+        check_transaction_version(transaction)?;
+        check_output_count(transaction)?;
+        validate_output_indices(transaction)?;
+        check_inputs_and_outputs_present(transaction, coinbase)?;
+        check_contract_hash(transaction)?;
+        let inputs_valid = self.validate_inputs(transaction).await?;
+        let outputs_valid = validate_outputs(transaction)?;
+
+        Ok(inputs_valid && outputs_valid)
+    }
+
+    // Returns the sum of decrypted outputs stored in the OutputDB
+    pub async fn get_balance(&self) -> u64 {
+        let output_set = self.outputs.get().await.unwrap();
+        let mut total_balance = 0;
+        for owned_output in &output_set {
+            let decrypted_amount = owned_output.decrypted_amount;
+            total_balance += decrypted_amount;
+        }
+        total_balance
+    }
+
+    pub async fn get_available_balance(
+        &self,
+        wallet: &Wallet,
+        reserved_images: &HashSet<Vec<u8>>,
+    ) -> Result<u64, ChainOpsError> {
+        let outputs = self.outputs.get().await?;
+        spendable_balance(wallet, &outputs, self.cached_tip_index(), reserved_images)
+    }
+
+    // Deserialize the input and validate bLSAG and image, rings have to consist of distinct members
+    // and every input has to sign the transaction's signing hash
+    pub async fn validate_inputs(&self, transaction: &Transaction) -> Result<bool, ChainOpsError> {
+        let message = transaction_signing_hash(transaction);
+        for input in transaction.msg_inputs.iter() {
+            if has_duplicate_members(&input.msg_ring) {
+                return Err(ChainOpsError::DuplicateRingMember);
+            }
+            let signature = BLSAGSignature::from_vec(&input.msg_blsag)?;
+            // from_slice panics on anything but 32 bytes, and the ring comes straight from the peer
+            if let Some(member) = input.msg_ring.iter().find(|member| member.len() != 32) {
+                return Err(ChainOpsError::InvalidRingMember { len: member.len() });
+            }
+            let compressed_ring: Vec<CompressedRistretto> = input
+                .msg_ring
+                .iter()
+                .map(|inner_vec| CompressedRistretto::from_slice(inner_vec))
+                .collect::<Vec<_>>();
+            let ring: &[CompressedRistretto] = &compressed_ring;
+            let image = input.msg_key_image.clone();
+
+            if input.msg_message != message
+                || self.images.contains(image).await?
+                || !verify_blsag(&signature, ring, &message)?
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 }
 
-// Add the block to the chain
-pub async fn add_block(wallet: &Wallet, block: Block) -> Result<(), ChainOpsError> {
+// Seconds elapsed between the timestamp of the given block and now
+pub fn seconds_since_block(block: &Block, now: u64) -> Result<u64, ChainOpsError> {
     let header = block
         .msg_header
         .as_ref()
         .ok_or(ChainOpsError::MissingBlockHeader)?;
-    validate_block(&block).await?;
-    for transaction in block.msg_transactions.iter() {
-        wallet.process_transaction(transaction).await?;
+    Ok(now.saturating_sub(header.msg_timestamp))
+}
+
+// Returns how many blocks a switch to a branch forking after fork_index would roll back, refusing deeper reorgs
+pub fn check_reorg_depth(
+    tip_index: u32,
+    fork_index: u32,
+    max_depth: u32,
+) -> Result<u32, ChainOpsError> {
+    let depth = tip_index.saturating_sub(fork_index);
+    if depth > max_depth {
+        return Err(ChainOpsError::ReorgTooDeep { depth, max_depth });
     }
-    let hash = hash_block(&block)?;
-    let index = header.msg_index;
-    BLOCK_STORER.put_block(index, hash, &block).await?;
-    Ok(())
+    Ok(depth)
 }
 
-// Validate the candidate block
-pub async fn validate_block(incoming_block: &Block) -> Result<(), ChainOpsError> {
-    check_previous_block_hash(incoming_block).await?;
-    check_transactions_in_block(incoming_block).await?;
-    Ok(())
+fn median_timestamp(timestamps: &[u64]) -> Option<u64> {
+    let mut sorted = timestamps.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied()
 }
 
-// Function used during the genesis to add the block without actual verifying the transactions
-pub async fn add_genesis_block(wallet: &Wallet, block: Block) -> Result<(), ChainOpsError> {
-    let header = block
-        .msg_header
-        .as_ref()
-        .ok_or(ChainOpsError::MissingBlockHeader)?;
-    for transaction in block.msg_transactions.iter() {
-        wallet.process_transaction(transaction).await?;
+// A timestamp may be at most max_drift ahead of now and must be after the median of the previous timestamps
+pub fn check_timestamp(
+    timestamp: u64,
+    previous_timestamps: &[u64],
+    now: u64,
+    max_drift: u64,
+) -> Result<(), ChainOpsError> {
+    if timestamp > now.saturating_add(max_drift) {
+        return Err(ChainOpsError::TimestampTooFarAhead {
+            timestamp,
+            now,
+            max_drift,
+        });
+    }
+    match median_timestamp(previous_timestamps) {
+        Some(median) if timestamp <= median => {
+            Err(ChainOpsError::TimestampNotAfterMedian { timestamp, median })
+        }
+        _ => Ok(()),
     }
-    let hash = hash_block(&block)?.to_vec();
-    let index = header.msg_index;
-    BLOCK_STORER.put_block(index, hash, &block).await?;
-    Ok(())
 }
 
-// Returns the block from the BlockDB by its hash
-pub async fn get_block_by_hash(hash: Vec<u8>) -> Result<Block, ChainOpsError> {
-    match BLOCK_STORER.get(hash.clone()).await {
-        Ok(Some(block)) => Ok(block),
-        Ok(None) => Err(ChainOpsError::BlockNotFound),
-        Err(err) => Err(err.into()),
+// Sum of the owned outputs that can be spent now: coinbase outputs need COINBASE_MATURITY confirmations
+// and outputs whose key images are already used by pending transactions are reserved
+pub fn spendable_balance(
+    wallet: &Wallet,
+    outputs: &[OwnedOutput],
+    tip_index: u32,
+    reserved_images: &HashSet<Vec<u8>>,
+) -> Result<u64, ChainOpsError> {
+    let mut balance = 0;
+    for owned_output in outputs {
+        if owned_output.coinbase
+            && tip_index.saturating_sub(owned_output.height) < COINBASE_MATURITY
+        {
+            continue;
+        }
+        if reserved_images.contains(&wallet.key_image(owned_output)?) {
+            continue;
+        }
+        balance += owned_output.decrypted_amount;
     }
+    Ok(balance)
 }
 
-// Check if the hash of the previous block in DB maches the msg_previous_hash of the candidate block
-pub async fn check_previous_block_hash(incoming_block: &Block) -> Result<bool, ChainOpsError> {
-    let previous_hash = get_previous_hash_in_chain().await?;
-    if let Some(header) = &incoming_block.msg_header {
-        if previous_hash != header.msg_previous_hash {
-            return Err(ChainOpsError::InvalidPreviousBlockHash);
-        }
-    } else {
-        return Err(ChainOpsError::MissingBlockHeader);
+// Transactions from a newer format than this node knows can't be validated correctly
+pub fn check_transaction_version(transaction: &Transaction) -> Result<(), ChainOpsError> {
+    if transaction.msg_version > TRANSACTION_VERSION {
+        return Err(ChainOpsError::UnsupportedTransactionVersion {
+            version: transaction.msg_version,
+            max: TRANSACTION_VERSION,
+        });
     }
-    Ok(true)
+    Ok(())
 }
 
-pub async fn get_previous_hash_in_chain() -> Result<Vec<u8>, ChainOpsError> {
-    let previous_index = max_index().await?;
-    let previous_hash = match BLOCK_STORER.get_hash_by_index(previous_index).await? {
-        Some(hash) => hash,
-        None => return Err(ChainOpsError::MissingBlockHash),
+// The code has to hash to the Keccak256 the transaction carries, which catches code swapped or corrupted
+// on its own. Nothing signs the hash itself, a relayer rewriting both code and hash isn't caught here
+pub fn check_contract_hash(transaction: &Transaction) -> Result<(), ChainOpsError> {
+    let expected = match &transaction.msg_contract {
+        Some(contract) => hash_contract_code(&contract.msg_code),
+        None => vec![],
     };
-    Ok(previous_hash)
+    if transaction.msg_contract_hash != expected {
+        return Err(ChainOpsError::ContractHashMismatch);
+    }
+    Ok(())
 }
 
-pub async fn check_transactions_in_block(incoming_block: &Block) -> Result<(), ChainOpsError> {
-    for tx in &incoming_block.msg_transactions {
-        validate_transaction(tx).await?;
+// A genesis block received from a peer has to be a single transaction minting GENESIS_PREMINE into one output,
+// the amount itself is encrypted to the creator so only the cleartext minted amount can be checked
+pub fn check_genesis_block(block: &Block) -> Result<(), ChainOpsError> {
+    let header = block
+        .msg_header
+        .as_ref()
+        .ok_or(ChainOpsError::MissingBlockHeader)?;
+    if header.msg_index != 1 || !header.msg_previous_hash.is_empty() {
+        return Err(ChainOpsError::InvalidGenesisBlock(
+            "not the first block".to_string(),
+        ));
+    }
+    let transaction = match block.msg_transactions.as_slice() {
+        [transaction] => transaction,
+        transactions => {
+            return Err(ChainOpsError::InvalidGenesisBlock(format!(
+                "{} transactions instead of 1",
+                transactions.len()
+            )))
+        }
+    };
+    if !transaction.msg_inputs.is_empty() || transaction.msg_outputs.len() != 1 {
+        return Err(ChainOpsError::InvalidGenesisBlock(format!(
+            "{} inputs and {} outputs instead of 0 and 1",
+            transaction.msg_inputs.len(),
+            transaction.msg_outputs.len()
+        )));
+    }
+    if transaction.msg_minted != GENESIS_PREMINE || transaction.msg_fee != 0 {
+        return Err(ChainOpsError::InvalidGenesisBlock(format!(
+            "mints {} with fee {}, expected {} without fee",
+            transaction.msg_minted, transaction.msg_fee, GENESIS_PREMINE
+        )));
     }
     Ok(())
 }
 
-pub async fn validate_transaction(transaction: &Transaction) -> Result<bool, ChainOpsError> {
-    let inputs_valid = validate_inputs(transaction).await?;
-    let outputs_valid = validate_outputs(transaction)?;
+// Caps the range proofs a single transaction can make a node verify
+pub fn check_output_count(transaction: &Transaction) -> Result<(), ChainOpsError> {
+    let outputs = transaction.msg_outputs.len();
+    if outputs > MAX_OUTPUTS_PER_TX {
+        return Err(ChainOpsError::TooManyOutputs {
+            outputs,
+            max: MAX_OUTPUTS_PER_TX,
+        });
+    }
+    Ok(())
+}
 
-    Ok(inputs_valid && outputs_valid)
+// A transaction has to pay to some output, and only a coinbase may mint it without spending any input
+pub fn check_inputs_and_outputs_present(
+    transaction: &Transaction,
+    coinbase: bool,
+) -> Result<(), ChainOpsError> {
+    if transaction.msg_outputs.is_empty() || (transaction.msg_inputs.is_empty() && !coinbase) {
+        return Err(ValidationError::MissingInput.into());
+    }
+    Ok(())
 }
 
-// Returns the sum of decrypted outputs stored in the OutputDB
-pub async fn get_balance() -> u64 {
-    let output_set = OUTPUT_STORER.get().await.unwrap();
-    let mut total_balance = 0;
-    for owned_output in &output_set {
-        let decrypted_amount = owned_output.decrypted_amount;
-        total_balance += decrypted_amount;
+// Only the block's coinbase, found by its position rather than by what it claims, may mint and it mints exactly
+// the block reward, every other transaction has to leave msg_minted at zero
+pub fn check_coinbase(block: &Block) -> Result<(), ChainOpsError> {
+    let transactions = &block.msg_transactions;
+    for (position, transaction) in transactions.iter().enumerate() {
+        if is_coinbase(transactions, position) {
+            if transaction.msg_minted != BLOCK_REWARD {
+                return Err(ChainOpsError::InvalidCoinbaseAmount {
+                    minted: transaction.msg_minted,
+                    reward: BLOCK_REWARD,
+                });
+            }
+        } else if transaction.msg_minted != 0 {
+            return Err(ChainOpsError::UnexpectedMint { position });
+        }
     }
-    total_balance
+    Ok(())
 }
 
-// Deserialize the input and validate bLSAG and image
-pub async fn validate_inputs(transaction: &Transaction) -> Result<bool, ChainOpsError> {
-    for input in transaction.msg_inputs.iter() {
-        let signature = BLSAGSignature::from_vec(&input.msg_blsag).unwrap();
-        let vec_ring: &Vec<Vec<u8>> = &input.msg_ring;
-        let compressed_ring: Vec<CompressedRistretto> = vec_ring
-            .iter()
-            .map(|inner_vec| CompressedRistretto::from_slice(inner_vec))
-            .collect::<Vec<_>>();
-        let ring: &[CompressedRistretto] = &compressed_ring;
-        let message = &input.msg_message;
-        let image = input.msg_key_image.clone();
+// Only the genesis block's coinbase may mint, and no more than GENESIS_PREMINE
+fn check_genesis_mint(block: &Block) -> Result<(), ChainOpsError> {
+    let transactions = &block.msg_transactions;
+    for (position, transaction) in transactions.iter().enumerate() {
+        if !is_coinbase(transactions, position) && transaction.msg_minted != 0 {
+            return Err(ChainOpsError::UnexpectedMint { position });
+        }
+        if transaction.msg_minted > GENESIS_PREMINE {
+            return Err(ChainOpsError::InvalidGenesisBlock(format!(
+                "mints {}, more than {}",
+                transaction.msg_minted, GENESIS_PREMINE
+            )));
+        }
+    }
+    Ok(())
+}
 
-        if IMAGE_STORER.contains(image).await? || !verify_blsag(&signature, ring, message) {
-            return Ok(false);
+// Stealth addresses and amounts are derived per output index, so the indices must be exactly 1..=n
+pub fn validate_output_indices(transaction: &Transaction) -> Result<(), ChainOpsError> {
+    let outputs = transaction.msg_outputs.len();
+    let mut seen = vec![false; outputs];
+    for output in transaction.msg_outputs.iter() {
+        let index = output.msg_index;
+        if index == 0 || index as usize > outputs {
+            return Err(ChainOpsError::OutputIndexOutOfRange { index, outputs });
+        }
+        if std::mem::replace(&mut seen[index as usize - 1], true) {
+            return Err(ChainOpsError::DuplicateOutputIndex { index });
         }
     }
-    Ok(true)
+    Ok(())
 }
 
 // Verify Pedersen commitment and range proof
 pub fn validate_outputs(transaction: &Transaction) -> Result<bool, ChainOpsError> {
+    validate_outputs_with_bits(transaction, RANGE_PROOF_BITS)
+}
+
+// A proof made for another bit length is an error rather than a plain verification failure
+pub fn validate_outputs_with_bits(
+    transaction: &Transaction,
+    bits: usize,
+) -> Result<bool, ChainOpsError> {
     for output in transaction.msg_outputs.iter() {
+        if decompress_non_identity(&output.msg_output_key).is_none()
+            || decompress_non_identity(&output.msg_stealth_address).is_none()
+        {
+            return Err(ChainOpsError::InvalidOutputPoint);
+        }
+        // A burn has to open its commitment, so the supply only drops by amounts that were really burnt
+        burned_amount(output)?;
+        let found = range_proof_bits(&output.msg_proof);
+        if found != Some(bits) {
+            return Err(ChainOpsError::RangeProofBitsMismatch {
+                expected: bits,
+                found,
+            });
+        }
         let pc_gens = PedersenGens::default();
         let bp_gens = BulletproofGens::new(64, 1);
         let mut verifier_transcript = Transcript::new(b"Transaction");
@@ -153,7 +1409,7 @@ pub fn validate_outputs(transaction: &Transaction) -> Result<bool, ChainOpsError
                 &pc_gens,
                 &mut verifier_transcript,
                 &committed_value,
-                32,
+                bits,
             )
             .is_err()
         {
@@ -163,21 +1419,1620 @@ pub fn validate_outputs(transaction: &Transaction) -> Result<bool, ChainOpsError
     Ok(true)
 }
 
-pub fn verify_root_hash(block: &Block) -> Result<bool, BlockOpsError> {
-    let transaction_data: Vec<Vec<u8>> = block
-        .msg_transactions
+// Amounts the block adds to the supply and takes out of it
+fn supply_change(block: &Block) -> Result<(u64, u64), ChainOpsError> {
+    let header = block
+        .msg_header
+        .as_ref()
+        .ok_or(ChainOpsError::MissingBlockHeader)?;
+    // Only a minted amount the consensus rules allow counts, whatever a block claims to mint. The genesis block's
+    // coinbase mints the premine instead of the reward
+    if header.msg_index == 1 {
+        check_genesis_mint(block)?;
+    } else {
+        check_coinbase(block)?;
+    }
+    let mut added: u64 = 0;
+    let mut removed: u64 = 0;
+    for transaction in block.msg_transactions.iter() {
+        added = added.saturating_add(transaction.msg_minted);
+        removed = removed.saturating_add(transaction.msg_fee);
+        // An output whose points don't decompress can't pay to the burn address either
+        for output in &transaction.msg_outputs {
+            if let Ok(Some(burnt)) = burned_amount(output) {
+                removed = removed.saturating_add(burnt);
+            }
+        }
+    }
+    Ok((added, removed))
+}
+
+// Check the block's proof of work under the consensus hasher against the difficulty required at its index
+pub fn verify_block_pow(block: &Block, difficulty: usize) -> bool {
+    verify_pow(&POW_HASHER, block, difficulty)
+}
+
+// Difficulty after blocks with the given timestamps: POW_DIFFICULTY while they came about every TARGET_BLOCK_TIME,
+// one hex digit (16 times less work) easier for every factor of 16 they were slower, down to MIN_POW_DIFFICULTY.
+// Only block timestamps go in, so every node computes the same difficulty for a block
+pub fn adjusted_difficulty(previous_timestamps: &[u64]) -> usize {
+    let (first, last) = match previous_timestamps {
+        [first, .., last] => (*first, *last),
+        _ => return POW_DIFFICULTY,
+    };
+    let expected = TARGET_BLOCK_TIME * (previous_timestamps.len() as u64 - 1);
+    let mut slowdown = last.saturating_sub(first) / expected;
+    let mut difficulty = POW_DIFFICULTY;
+    while slowdown >= 16 && difficulty > MIN_POW_DIFFICULTY {
+        slowdown /= 16;
+        difficulty -= 1;
+    }
+    difficulty
+}
+
+// Merkle root over the encoded transactions, the header's msg_root_hash
+pub fn transactions_root(transactions: &[Transaction]) -> Vec<u8> {
+    let transaction_data: Vec<Vec<u8>> = transactions
         .iter()
-        .map(|transaction| {
-            let mut bytes = Vec::new();
-            transaction.encode(&mut bytes).unwrap();
-            bytes
-        })
+        .map(|transaction| transaction.encode_to_vec())
         .collect();
-    let merkle_tree = MerkleTree::from_list(&transaction_data);
+    MerkleTree::from_list(&transaction_data).get_hash()
+}
+
+pub fn verify_root_hash(block: &Block) -> Result<bool, BlockOpsError> {
     if let Some(header) = &block.msg_header {
-        let merkle_root = merkle_tree.get_hash();
-        Ok(header.msg_root_hash == merkle_root)
+        Ok(header.msg_root_hash == transactions_root(&block.msg_transactions))
     } else {
         Err(BlockOpsError::MissingHeader)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::fixtures::{coinbase_block, open_test_chain, spending_transaction};
+    use curve25519_dalek_ng::scalar::Scalar;
+    use vec_crypto::crypto::{
+        burn_address, derive_keys_from_address, encrypt_amount, shared_secret, unsigned_input,
+    };
+    use vec_proto::messages::{Contract, TransactionInput, TransactionOutput};
+
+    // Block at the index with a coinbase minting the amount, if any, and a spending transaction per fee
+    fn make_block(index: u32, minted: u64, fees: &[u64]) -> Block {
+        let mut transactions = vec![];
+        if minted > 0 {
+            transactions.push(Transaction {
+                msg_minted: minted,
+                ..Default::default()
+            });
+        }
+        for fee in fees {
+            transactions.push(Transaction {
+                msg_inputs: vec![TransactionInput::default()],
+                msg_fee: *fee,
+                ..Default::default()
+            });
+        }
+        Block {
+            msg_header: Some(Header {
+                msg_index: index,
+                ..Default::default()
+            }),
+            msg_transactions: transactions,
+        }
+    }
+
+    // Every test opens a chain of its own, so they never see each other's blocks
+    #[tokio::test]
+    async fn test_total_supply() {
+        let chain = open_test_chain("total_supply").await;
+        let initial_supply = chain.total_supply().await.unwrap();
+        chain
+            .update_total_supply(&make_block(1, GENESIS_PREMINE, &[]))
+            .await
+            .unwrap();
+        assert_eq!(
+            chain.total_supply().await.unwrap(),
+            initial_supply + GENESIS_PREMINE
+        );
+
+        chain
+            .update_total_supply(&make_block(2, BLOCK_REWARD, &[10, 20]))
+            .await
+            .unwrap();
+        chain
+            .update_total_supply(&make_block(3, 0, &[5]))
+            .await
+            .unwrap();
+        chain
+            .update_total_supply(&make_block(4, 0, &[]))
+            .await
+            .unwrap();
+        let supply = initial_supply + GENESIS_PREMINE + BLOCK_REWARD - 35;
+        assert_eq!(chain.total_supply().await.unwrap(), supply);
+
+        // What a block claims to mint beyond the rules never reaches the supply
+        assert!(matches!(
+            chain
+                .update_total_supply(&make_block(5, BLOCK_REWARD * 1000, &[]))
+                .await,
+            Err(ChainOpsError::InvalidCoinbaseAmount { .. })
+        ));
+        let mut minting_spend = make_block(5, BLOCK_REWARD, &[1]);
+        minting_spend.msg_transactions[1].msg_minted = BLOCK_REWARD;
+        assert!(matches!(
+            chain.update_total_supply(&minting_spend).await,
+            Err(ChainOpsError::UnexpectedMint { position: 1 })
+        ));
+        assert!(matches!(
+            chain
+                .update_total_supply(&make_block(1, GENESIS_PREMINE + 1, &[]))
+                .await,
+            Err(ChainOpsError::InvalidGenesisBlock(_))
+        ));
+        assert_eq!(chain.total_supply().await.unwrap(), supply);
+    }
+
+    #[test]
+    fn test_seconds_since_block() {
+        let now = 1_700_000_000;
+        let mut block = make_block(1, 0, &[]);
+        block.msg_header = None;
+        assert!(matches!(
+            seconds_since_block(&block, now),
+            Err(ChainOpsError::MissingBlockHeader)
+        ));
+        block.msg_header = Some(Header {
+            msg_timestamp: now - 3600,
+            ..Default::default()
+        });
+        assert_eq!(seconds_since_block(&block, now).unwrap(), 3600);
+        block.msg_header = Some(Header {
+            msg_timestamp: now + 60,
+            ..Default::default()
+        });
+        assert_eq!(seconds_since_block(&block, now).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_drives_block_timestamps() {
+        let start = 1_700_000_000;
+        let clock = Arc::new(MockClock::new(start));
+        let chain = open_test_chain("mock_clock")
+            .await
+            .with_clock(Arc::clone(&clock) as Arc<dyn TimeSource>);
+        let block = |index: u32, msg_timestamp: u64| Block {
+            msg_header: Some(Header {
+                msg_index: index,
+                msg_timestamp,
+                ..Default::default()
+            }),
+            msg_transactions: vec![],
+        };
+        let wallet = Wallet::generate().unwrap();
+        chain
+            .add_genesis_block(&wallet, block(1, start))
+            .await
+            .unwrap();
+        assert_eq!(chain.seconds_since_tip().await.unwrap(), 0);
+
+        // A clock behind the median still yields a timestamp after it
+        clock.set(start - 100);
+        assert_eq!(chain.next_block_timestamp(2).await.unwrap(), start + 1);
+        clock.set(start + 30);
+        assert_eq!(chain.next_block_timestamp(2).await.unwrap(), start + 30);
+        assert_eq!(chain.seconds_since_tip().await.unwrap(), 30);
+
+        let ahead = block(2, start + 30 + MAX_TIMESTAMP_DRIFT + 1);
+        assert!(matches!(
+            chain.check_block_timestamp(&ahead).await,
+            Err(ChainOpsError::TimestampTooFarAhead { now, .. }) if now == start + 30
+        ));
+        clock.advance(1);
+        assert!(chain.check_block_timestamp(&ahead).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_drives_difficulty_adjustment() {
+        let start = 1_700_000_000;
+        let wallet = Wallet::generate().unwrap();
+        // Builds a chain whose blocks are stamped by a mock clock advancing by the given spacing in between
+        let chain_with_spacing = |name: &'static str, spacing: u64| {
+            let wallet = &wallet;
+            async move {
+                let clock = Arc::new(MockClock::new(start));
+                let chain = open_test_chain(name)
+                    .await
+                    .with_clock(Arc::clone(&clock) as Arc<dyn TimeSource>);
+                for index in 1..=MEDIAN_TIME_SPAN {
+                    let block = Block {
+                        msg_header: Some(Header {
+                            msg_index: index,
+                            msg_timestamp: chain.next_block_timestamp(index).await.unwrap(),
+                            ..Default::default()
+                        }),
+                        msg_transactions: vec![],
+                    };
+                    chain.add_genesis_block(wallet, block).await.unwrap();
+                    clock.advance(spacing);
+                }
+                chain
+            }
+        };
+        let next = MEDIAN_TIME_SPAN + 1;
+
+        let on_time = chain_with_spacing("difficulty_on_time", TARGET_BLOCK_TIME).await;
+        assert_eq!(
+            on_time.required_difficulty(next).await.unwrap(),
+            POW_DIFFICULTY
+        );
+        let slow = chain_with_spacing("difficulty_slow", 16 * TARGET_BLOCK_TIME).await;
+        assert_eq!(
+            slow.required_difficulty(next).await.unwrap(),
+            POW_DIFFICULTY - 1
+        );
+        let stalled = chain_with_spacing("difficulty_stalled", 4096 * TARGET_BLOCK_TIME).await;
+        assert_eq!(
+            stalled.required_difficulty(next).await.unwrap(),
+            MIN_POW_DIFFICULTY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_import_block_round_trip() {
+        let chain = open_test_chain("export_import").await;
+        let tip_index = chain.max_index().await.unwrap();
+        let previous_hash = match tip_index {
+            0 => vec![],
+            index => chain
+                .blocks()
+                .get_hash_by_index(index)
+                .await
+                .unwrap()
+                .unwrap(),
+        };
+        let coinbase = Wallet::generate()
+            .unwrap()
+            .prepare_change_output(BLOCK_REWARD, 1)
+            .unwrap();
+        let transactions = vec![Transaction {
+            msg_outputs: vec![coinbase],
+            msg_minted: BLOCK_REWARD,
+            msg_version: TRANSACTION_VERSION,
+            ..Default::default()
+        }];
+        let index = tip_index + 1;
+        let unmined = Block {
+            msg_header: Some(Header {
+                msg_index: index,
+                msg_previous_hash: previous_hash,
+                msg_root_hash: transactions_root(&transactions),
+                msg_timestamp: chain.next_block_timestamp(index).await.unwrap(),
+                ..Default::default()
+            }),
+            msg_transactions: transactions,
+        };
+        let difficulty = chain.required_difficulty(index).await.unwrap();
+        let block = mine_rolling(&POW_HASHER, unmined, difficulty, 0, NONCE_SPACE).unwrap();
+        let hash = hash_block(&block).unwrap();
+        chain
+            .blocks()
+            .put_block(tip_index + 1, hash, &block)
+            .await
+            .unwrap();
+
+        let exported = chain.export_block(tip_index + 1).await.unwrap();
+        let imported = chain.import_block_for_validation(&exported).await.unwrap();
+        assert_eq!(imported, block);
+        assert_eq!(chain.max_index().await.unwrap(), tip_index + 1);
+
+        let mut tampered = BlockExport::decode(exported.as_slice()).unwrap();
+        tampered.msg_hash[0] ^= 1;
+        assert!(matches!(
+            chain
+                .import_block_for_validation(&tampered.encode_to_vec())
+                .await,
+            Err(ChainOpsError::ExportHashMismatch)
+        ));
+
+        // The same checks as for a block extending the chain, a consistent export with a bad nonce fails the PoW
+        let mut bad_nonce = block.clone();
+        while verify_block_pow(&bad_nonce, difficulty) {
+            let header = bad_nonce.msg_header.as_mut().unwrap();
+            header.msg_nonce = header.msg_nonce.wrapping_add(1);
+        }
+        let export = BlockExport {
+            msg_hash: hash_block(&bad_nonce).unwrap(),
+            msg_block: Some(bad_nonce),
+        };
+        assert!(matches!(
+            chain
+                .import_block_for_validation(&export.encode_to_vec())
+                .await,
+            Err(ChainOpsError::InvalidProofOfWork)
+        ));
+    }
+
+    #[test]
+    fn test_reorg_depth_limit() {
+        let tip = 10 * MAX_REORG_DEPTH;
+        assert_eq!(check_reorg_depth(tip, tip - 3, MAX_REORG_DEPTH).unwrap(), 3);
+        assert_eq!(
+            check_reorg_depth(tip, tip - MAX_REORG_DEPTH, MAX_REORG_DEPTH).unwrap(),
+            MAX_REORG_DEPTH
+        );
+        assert!(matches!(
+            check_reorg_depth(tip, tip - MAX_REORG_DEPTH - 1, MAX_REORG_DEPTH),
+            Err(ChainOpsError::ReorgTooDeep { depth, .. }) if depth == MAX_REORG_DEPTH + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_competing_branch_with_unknown_ancestor() {
+        let chain = open_test_chain("competing_branch").await;
+        let branch = vec![Block {
+            msg_header: Some(Header {
+                msg_previous_hash: vec![0xab; 32],
+                ..Default::default()
+            }),
+            msg_transactions: vec![],
+        }];
+        assert!(matches!(
+            chain.check_competing_branch(&branch).await,
+            Err(ChainOpsError::BlockNotFound)
+        ));
+        assert_eq!(chain.check_competing_branch(&[]).await.unwrap(), 0);
+    }
+
+    // Block on top of the previous one, mined to the given difficulty
+    fn mined_block(
+        previous: &Block,
+        msg_timestamp: u64,
+        difficulty: usize,
+        msg_transactions: Vec<Transaction>,
+    ) -> Block {
+        let block = Block {
+            msg_header: Some(Header {
+                msg_index: previous.msg_header.as_ref().unwrap().msg_index + 1,
+                msg_previous_hash: hash_block(previous).unwrap(),
+                msg_root_hash: transactions_root(&msg_transactions),
+                msg_timestamp,
+                ..Default::default()
+            }),
+            msg_transactions,
+        };
+        mine_rolling(&POW_HASHER, block, difficulty, 0, NONCE_SPACE).unwrap()
+    }
+
+    // Chain whose mock clock is well past every timestamp the branches below use
+    async fn open_branch_test_chain(name: &str, start: u64) -> Chain {
+        open_test_chain(name)
+            .await
+            .with_clock(Arc::new(MockClock::new(start + 1_000_000)) as Arc<dyn TimeSource>)
+    }
+
+    // Genesis, a light branch of three blocks so slow that the last two only need MIN_POW_DIFFICULTY, and a heavy
+    // branch of two blocks coming on time, both building on genesis
+    fn competing_branches(
+        start: u64,
+        light_transactions: Vec<Transaction>,
+    ) -> (Block, Vec<Block>, Vec<Block>) {
+        let genesis = Block {
+            msg_header: Some(Header {
+                msg_index: 1,
+                msg_timestamp: start,
+                ..Default::default()
+            }),
+            msg_transactions: vec![],
+        };
+        let stall = 4096 * TARGET_BLOCK_TIME;
+        let mut light = vec![mined_block(
+            &genesis,
+            start + stall,
+            POW_DIFFICULTY,
+            light_transactions,
+        )];
+        for step in 2..=3 {
+            let previous = light.last().unwrap();
+            light.push(mined_block(
+                previous,
+                start + step * stall,
+                MIN_POW_DIFFICULTY,
+                vec![],
+            ));
+        }
+        let mut heavy = vec![mined_block(
+            &genesis,
+            start + TARGET_BLOCK_TIME,
+            POW_DIFFICULTY,
+            vec![],
+        )];
+        heavy.push(mined_block(
+            &heavy[0],
+            start + 2 * TARGET_BLOCK_TIME,
+            POW_DIFFICULTY,
+            vec![],
+        ));
+        (genesis, light, heavy)
+    }
+
+    #[tokio::test]
+    async fn test_heavier_branch_wins_over_longer_one() {
+        let start = 1_700_000_000;
+        let wallet = Wallet::generate().unwrap();
+        let (genesis, light, heavy) = competing_branches(start, vec![]);
+        let on_time = difficulty_work(POW_DIFFICULTY);
+        let stalled = difficulty_work(MIN_POW_DIFFICULTY);
+
+        let light_chain = open_branch_test_chain("light_tip", start).await;
+        light_chain
+            .add_genesis_block(&wallet, genesis.clone())
+            .await
+            .unwrap();
+        for block in &light {
+            light_chain.add_block(&wallet, block.clone()).await.unwrap();
+        }
+        assert_eq!(
+            light_chain.tip_work().await.unwrap(),
+            2 * on_time + 2 * stalled
+        );
+        assert!(light_chain.is_heavier_branch(&heavy).await.unwrap());
+
+        let heavy_chain = open_branch_test_chain("heavy_tip", start).await;
+        heavy_chain
+            .add_genesis_block(&wallet, genesis.clone())
+            .await
+            .unwrap();
+        for block in &heavy {
+            heavy_chain.add_block(&wallet, block.clone()).await.unwrap();
+        }
+        assert_eq!(heavy_chain.tip_work().await.unwrap(), 3 * on_time);
+        assert!(!heavy_chain.is_heavier_branch(&light).await.unwrap());
+        assert!(!heavy_chain.is_heavier_branch(&[]).await.unwrap());
+
+        // A branch's work counts only if its blocks really meet the difficulty required of them
+        let mut forged = heavy.clone();
+        while verify_block_pow(&forged[1], POW_DIFFICULTY) {
+            let header = forged[1].msg_header.as_mut().unwrap();
+            header.msg_nonce = header.msg_nonce.wrapping_add(1);
+        }
+        assert!(matches!(
+            light_chain.is_heavier_branch(&forged).await,
+            Err(ChainOpsError::InvalidProofOfWork)
+        ));
+        let unlinked = vec![heavy[1].clone()];
+        assert!(light_chain.is_heavier_branch(&unlinked).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reorganize_switches_to_heavier_branch() {
+        let start = 1_700_000_000;
+        let wallet = Wallet::generate().unwrap();
+        let coinbase = Transaction {
+            msg_outputs: vec![wallet.prepare_change_output(BLOCK_REWARD, 1).unwrap()],
+            msg_minted: BLOCK_REWARD,
+            msg_version: TRANSACTION_VERSION,
+            ..Default::default()
+        };
+        let (genesis, light, heavy) = competing_branches(start, vec![coinbase]);
+        let chain = open_branch_test_chain("reorganize", start).await;
+        chain
+            .add_genesis_block(&wallet, genesis.clone())
+            .await
+            .unwrap();
+        let supply = chain.total_supply().await.unwrap();
+        for block in &light {
+            chain.add_block(&wallet, block.clone()).await.unwrap();
+        }
+        assert_eq!(chain.get_balance().await, BLOCK_REWARD);
+        assert_eq!(chain.total_supply().await.unwrap(), supply + BLOCK_REWARD);
+
+        // The lighter branch is refused, the heavier one replaces the local blocks after genesis
+        assert!(!chain.reorganize(&wallet, &light[..1]).await.unwrap());
+        assert!(chain.reorganize(&wallet, &heavy).await.unwrap());
+        assert_eq!(chain.max_index().await.unwrap(), 3);
+        assert_eq!(chain.cached_tip_index(), 3);
+        assert_eq!(chain.validated_up_to().await.unwrap(), Some(3));
+        assert_eq!(
+            chain.get_previous_hash_in_chain().await.unwrap(),
+            hash_block(&heavy[1]).unwrap()
+        );
+        assert_eq!(chain.get_balance().await, 0);
+        assert_eq!(chain.total_supply().await.unwrap(), supply);
+        let coinbase_hash = hash_transaction(&light[0].msg_transactions[0]);
+        assert!(!chain.is_transaction_confirmed(coinbase_hash).await.unwrap());
+
+        // A heavier branch with an invalid block leaves the local blocks in place
+        let invalid_coinbase = Transaction {
+            msg_outputs: vec![wallet.prepare_change_output(BLOCK_REWARD + 1, 1).unwrap()],
+            msg_minted: BLOCK_REWARD + 1,
+            msg_version: TRANSACTION_VERSION,
+            ..Default::default()
+        };
+        let first = mined_block(
+            &genesis,
+            start + TARGET_BLOCK_TIME + 1,
+            POW_DIFFICULTY,
+            vec![],
+        );
+        let second = mined_block(
+            &first,
+            start + 2 * TARGET_BLOCK_TIME,
+            POW_DIFFICULTY,
+            vec![invalid_coinbase],
+        );
+        let third = mined_block(
+            &second,
+            start + 3 * TARGET_BLOCK_TIME,
+            POW_DIFFICULTY,
+            vec![],
+        );
+        assert!(matches!(
+            chain.reorganize(&wallet, &[first, second, third]).await,
+            Err(ChainOpsError::InvalidCoinbaseAmount { .. })
+        ));
+        assert_eq!(chain.max_index().await.unwrap(), 3);
+        assert_eq!(
+            chain.get_previous_hash_in_chain().await.unwrap(),
+            hash_block(&heavy[1]).unwrap()
+        );
+        assert_eq!(
+            chain.tip_work().await.unwrap(),
+            3 * difficulty_work(POW_DIFFICULTY)
+        );
+        assert_eq!(chain.validated_up_to().await.unwrap(), Some(3));
+        assert_eq!(chain.total_supply().await.unwrap(), supply);
+        let restored = chain
+            .get_block_by_hash(hash_block(&heavy[1]).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(restored, heavy[1]);
+    }
+
+    #[tokio::test]
+    async fn test_reused_stealth_address_is_rejected() {
+        let chain = open_test_chain("reused_stealth").await;
+        let wallet = Wallet::generate().unwrap();
+        let other_wallet = Wallet::generate().unwrap();
+        let stored = other_wallet.prepare_change_output(5, 1).unwrap();
+        let fresh = wallet.prepare_change_output(5, 1).unwrap();
+        let block = |outputs: Vec<Vec<TransactionOutput>>| Block {
+            msg_header: Some(Header {
+                msg_index: 1,
+                ..Default::default()
+            }),
+            msg_transactions: outputs
+                .into_iter()
+                .map(|msg_outputs| Transaction {
+                    msg_outputs,
+                    ..Default::default()
+                })
+                .collect(),
+        };
+
+        // The stored output belongs to another wallet, the local one never sees it among its own outputs
+        chain
+            .add_genesis_block(&wallet, block(vec![vec![stored.clone()]]))
+            .await
+            .unwrap();
+        assert!(chain.outputs().get().await.unwrap().is_empty());
+
+        assert!(chain
+            .check_stealth_addresses(&block(vec![vec![fresh.clone()]]))
+            .await
+            .is_ok());
+        assert!(matches!(
+            chain
+                .check_stealth_addresses(&block(vec![vec![fresh.clone()], vec![fresh]]))
+                .await,
+            Err(ChainOpsError::DuplicateStealthAddress)
+        ));
+        assert!(matches!(
+            chain
+                .check_stealth_addresses(&block(vec![vec![stored]]))
+                .await,
+            Err(ChainOpsError::DuplicateStealthAddress)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_block_at_occupied_index_is_rejected() {
+        let chain = open_test_chain("occupied_index").await;
+        let wallet = Wallet::generate().unwrap();
+        let block = |amount: u64| {
+            let msg_transactions = vec![Transaction {
+                msg_outputs: vec![wallet.prepare_change_output(amount, 1).unwrap()],
+                ..Default::default()
+            }];
+            Block {
+                msg_header: Some(Header {
+                    msg_index: 1,
+                    msg_root_hash: transactions_root(&msg_transactions),
+                    ..Default::default()
+                }),
+                msg_transactions,
+            }
+        };
+        let original = block(5);
+        chain
+            .add_genesis_block(&wallet, original.clone())
+            .await
+            .unwrap();
+
+        let conflicting = block(7);
+        for result in [
+            chain.add_genesis_block(&wallet, conflicting.clone()).await,
+            chain.add_block(&wallet, conflicting).await,
+        ] {
+            assert!(matches!(
+                result,
+                Err(ChainOpsError::BlockStorageError(
+                    BlockStorageError::IndexOccupied { index: 1 }
+                ))
+            ));
+        }
+        // Neither the stored block nor the wallet's outputs changed
+        assert_eq!(
+            chain.blocks().get_by_index(1).await.unwrap(),
+            Some(original)
+        );
+        let amounts: Vec<u64> = chain
+            .outputs()
+            .get()
+            .await
+            .unwrap()
+            .iter()
+            .map(|owned| owned.decrypted_amount)
+            .collect();
+        assert_eq!(amounts, vec![5]);
+    }
+
+    #[tokio::test]
+    async fn test_output_indices_validation() {
+        let chain = open_test_chain("output_indices").await;
+        let with_indices = |indices: &[u32]| Transaction {
+            msg_outputs: indices
+                .iter()
+                .map(|index| TransactionOutput {
+                    msg_index: *index,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            chain.validate_transaction(&with_indices(&[1, 1])).await,
+            Err(ChainOpsError::DuplicateOutputIndex { index: 1 })
+        ));
+        assert!(matches!(
+            validate_output_indices(&with_indices(&[1, 3])),
+            Err(ChainOpsError::OutputIndexOutOfRange {
+                index: 3,
+                outputs: 2
+            })
+        ));
+        assert!(matches!(
+            validate_output_indices(&with_indices(&[0])),
+            Err(ChainOpsError::OutputIndexOutOfRange { index: 0, .. })
+        ));
+        assert!(validate_output_indices(&with_indices(&[2, 1])).is_ok());
+        assert!(validate_output_indices(&with_indices(&[])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_empty_transaction_is_rejected() {
+        let chain = open_test_chain("empty_transaction").await;
+        let spending = spending_transaction(b"");
+        assert!(chain.validate_transaction(&spending).await.unwrap());
+
+        let empty = Transaction {
+            msg_version: TRANSACTION_VERSION,
+            ..Default::default()
+        };
+        let without_inputs = Transaction {
+            msg_inputs: vec![],
+            ..spending.clone()
+        };
+        let without_outputs = Transaction {
+            msg_outputs: vec![],
+            ..spending
+        };
+        for transaction in [&empty, &without_inputs, &without_outputs] {
+            assert!(matches!(
+                chain.validate_transaction(transaction).await,
+                Err(ChainOpsError::ValidationError(
+                    ValidationError::MissingInput
+                ))
+            ));
+        }
+
+        // A coinbase mints its outputs without spending anything, but only as the first transaction of a block
+        let coinbase = Transaction {
+            msg_minted: BLOCK_REWARD,
+            ..without_inputs
+        };
+        assert!(chain.validate_transaction(&coinbase).await.is_err());
+        let block = |msg_transactions: Vec<Transaction>| Block {
+            msg_header: None,
+            msg_transactions,
+        };
+        assert!(chain
+            .check_transactions_in_block(&block(vec![coinbase.clone()]))
+            .await
+            .is_ok());
+        assert!(matches!(
+            chain
+                .check_transactions_in_block(&block(vec![coinbase.clone(), coinbase.clone()]))
+                .await,
+            Err(ChainOpsError::UnexpectedMint { position: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_coinbase_is_identified_by_position() {
+        let minting = |msg_minted: u64, spends: bool| Transaction {
+            msg_inputs: match spends {
+                true => spending_transaction(b"").msg_inputs,
+                false => vec![],
+            },
+            msg_minted,
+            ..Default::default()
+        };
+        let block = |msg_transactions: Vec<Transaction>| Block {
+            msg_header: None,
+            msg_transactions,
+        };
+
+        assert!(check_coinbase(&block(vec![])).is_ok());
+        assert!(check_coinbase(&block(vec![minting(0, true)])).is_ok());
+        assert!(
+            check_coinbase(&block(vec![minting(BLOCK_REWARD, false), minting(0, true)])).is_ok()
+        );
+        assert!(matches!(
+            check_coinbase(&block(vec![minting(BLOCK_REWARD + 1, false)])),
+            Err(ChainOpsError::InvalidCoinbaseAmount { minted, reward })
+                if minted == BLOCK_REWARD + 1 && reward == BLOCK_REWARD
+        ));
+        // Claiming a mint anywhere else, or on a first transaction that spends inputs, doesn't make a coinbase
+        assert!(matches!(
+            check_coinbase(&block(vec![minting(0, true), minting(BLOCK_REWARD, false)])),
+            Err(ChainOpsError::UnexpectedMint { position: 1 })
+        ));
+        assert!(matches!(
+            check_coinbase(&block(vec![minting(BLOCK_REWARD, true)])),
+            Err(ChainOpsError::UnexpectedMint { position: 0 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_output_count_limit() {
+        let chain = open_test_chain("output_count").await;
+        let with_outputs = |count: usize| Transaction {
+            msg_outputs: (1..=count as u32)
+                .map(|msg_index| TransactionOutput {
+                    msg_index,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        assert!(check_output_count(&with_outputs(MAX_OUTPUTS_PER_TX)).is_ok());
+        assert!(matches!(
+            chain
+                .validate_transaction(&with_outputs(MAX_OUTPUTS_PER_TX + 1))
+                .await,
+            Err(ChainOpsError::TooManyOutputs { outputs, max })
+                if outputs == MAX_OUTPUTS_PER_TX + 1 && max == MAX_OUTPUTS_PER_TX
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_ring_member_is_rejected() {
+        let chain = open_test_chain("duplicate_ring_member").await;
+        let wallet = Wallet::generate().unwrap();
+        let output = wallet.prepare_change_output(5, 1).unwrap();
+        let owned_output = wallet.scan_outputs(&[&output]).unwrap().remove(0);
+        let mut transaction = wallet
+            .sign_transaction(
+                &[unsigned_input(&owned_output).unwrap()],
+                Transaction::default(),
+            )
+            .unwrap();
+        assert!(!has_duplicate_members(&transaction.msg_inputs[0].msg_ring));
+        assert!(chain.validate_inputs(&transaction).await.unwrap());
+
+        let ring = &mut transaction.msg_inputs[0].msg_ring;
+        ring[1] = ring[0].clone();
+        assert!(matches!(
+            chain.validate_inputs(&transaction).await,
+            Err(ChainOpsError::DuplicateRingMember)
+        ));
+    }
+
+    // The ring is part of the signed message, so each malformed input gets the message re-derived to reach the
+    // signature check
+    #[tokio::test]
+    async fn test_malformed_input_is_rejected_without_panicking() {
+        let chain = open_test_chain("malformed_input").await;
+        let wallet = Wallet::generate().unwrap();
+        let output = wallet.prepare_change_output(5, 1).unwrap();
+        let owned_output = wallet.scan_outputs(&[&output]).unwrap().remove(0);
+        let transaction = wallet
+            .sign_transaction(
+                &[unsigned_input(&owned_output).unwrap()],
+                Transaction::default(),
+            )
+            .unwrap();
+
+        let mut short_member = transaction.clone();
+        short_member.msg_inputs[0].msg_ring[0].truncate(31);
+        assert!(matches!(
+            chain.validate_inputs(&short_member).await,
+            Err(ChainOpsError::InvalidRingMember { len: 31 })
+        ));
+
+        let mut not_a_point = transaction.clone();
+        not_a_point.msg_inputs[0].msg_ring[0] = vec![0xff; 32];
+        not_a_point.msg_inputs[0].msg_message = transaction_signing_hash(&not_a_point);
+        assert!(matches!(
+            chain.validate_inputs(&not_a_point).await,
+            Err(ChainOpsError::CryptoOpsError(
+                CryptoOpsError::DecompressionFailed
+            ))
+        ));
+
+        let mut missing_member = transaction.clone();
+        missing_member.msg_inputs[0].msg_ring.pop();
+        missing_member.msg_inputs[0].msg_message = transaction_signing_hash(&missing_member);
+        assert!(matches!(
+            chain.validate_inputs(&missing_member).await,
+            Err(ChainOpsError::CryptoOpsError(
+                CryptoOpsError::RingSizeMismatch { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_transaction_round_trip() {
+        let chain = open_test_chain("unsigned_round_trip").await;
+        let wallet = Wallet::generate().unwrap();
+        let funding = [
+            wallet.prepare_change_output(5, 1).unwrap(),
+            wallet.prepare_change_output(4, 2).unwrap(),
+        ];
+        let owned_outputs = wallet.scan_outputs(&[&funding[0], &funding[1]]).unwrap();
+        let view_only = ViewOnlyWallet::new(
+            &wallet.public_spend_key_to_vec(),
+            &wallet.secret_view_key_to_vec(),
+        )
+        .unwrap();
+        let recipient = bs58::encode(Wallet::generate().unwrap().address).into_string();
+
+        let unsigned = view_only
+            .build_unsigned(&owned_outputs, &[(&recipient, 6)], 1)
+            .unwrap();
+        assert_eq!(unsigned.inputs.len(), 2);
+        assert_eq!(unsigned.outputs.len(), 2);
+        assert_eq!(
+            view_only.scan_output(&unsigned.outputs[1]).unwrap(),
+            Some(2)
+        );
+        assert!(matches!(
+            view_only.build_unsigned(&owned_outputs, &[(&recipient, 9)], 1),
+            Err(ChainOpsError::InsufficientInputs {
+                available: 9,
+                needed: 10
+            })
+        ));
+        // Amounts that don't fit in a u64 are refused rather than wrapping around
+        assert!(matches!(
+            view_only.build_unsigned(&owned_outputs, &[(&recipient, u64::MAX)], 1),
+            Err(ChainOpsError::AmountOverflow)
+        ));
+        let mut huge_outputs = owned_outputs.clone();
+        huge_outputs[0].decrypted_amount = u64::MAX - 1;
+        assert!(matches!(
+            view_only.build_unsigned(&huge_outputs, &[(&recipient, u64::MAX - 1)], 1),
+            Err(ChainOpsError::AmountOverflow)
+        ));
+
+        // Only the spend key holder can sign the template
+        assert!(Wallet::generate()
+            .unwrap()
+            .sign_unsigned(&unsigned)
+            .is_err());
+        let transaction = wallet.sign_unsigned(&unsigned).unwrap();
+        assert_eq!(transaction.msg_fee, 1);
+        assert!(chain.validate_transaction(&transaction).await.unwrap());
+        assert_eq!(unsigned.change_index, Some(2));
+        assert_eq!(unsigned.change_output(), Some(&unsigned.outputs[1]));
+
+        // Inputs sign the whole transaction, so changing its fee after signing invalidates them
+        let mut altered = transaction.clone();
+        altered.msg_fee = 2;
+        assert!(!chain.validate_inputs(&altered).await.unwrap());
+    }
+
+    #[test]
+    fn test_genesis_block_conformance() {
+        let wallet = Wallet::generate().unwrap();
+        let genesis = |minted: u64, outputs: usize| Block {
+            msg_header: Some(Header {
+                msg_index: 1,
+                ..Default::default()
+            }),
+            msg_transactions: vec![Transaction {
+                msg_outputs: (1..=outputs as u32)
+                    .map(|index| wallet.prepare_change_output(minted, index).unwrap())
+                    .collect(),
+                msg_minted: minted,
+                ..Default::default()
+            }],
+        };
+
+        assert!(check_genesis_block(&genesis(GENESIS_PREMINE, 1)).is_ok());
+        assert!(matches!(
+            check_genesis_block(&genesis(GENESIS_PREMINE + 1, 1)),
+            Err(ChainOpsError::InvalidGenesisBlock(_))
+        ));
+        assert!(matches!(
+            check_genesis_block(&genesis(GENESIS_PREMINE, 2)),
+            Err(ChainOpsError::InvalidGenesisBlock(_))
+        ));
+        let mut second = genesis(GENESIS_PREMINE, 1);
+        second.msg_header.as_mut().unwrap().msg_index = 2;
+        assert!(matches!(
+            check_genesis_block(&second),
+            Err(ChainOpsError::InvalidGenesisBlock(_))
+        ));
+    }
+
+    fn proven_output(bits: usize) -> TransactionOutput {
+        let (proof, commitment) = RangeProof::prove_single(
+            &BulletproofGens::new(64, 1),
+            &PedersenGens::default(),
+            &mut Transcript::new(b"Transaction"),
+            1_000,
+            &curve25519_dalek_ng::scalar::Scalar::from(7u64),
+            bits,
+        )
+        .unwrap();
+        let wallet = Wallet::generate().unwrap();
+        TransactionOutput {
+            msg_proof: proof.to_bytes(),
+            msg_commitment: commitment.to_bytes().to_vec(),
+            msg_index: 1,
+            msg_output_key: wallet.public_view_key_to_vec(),
+            msg_stealth_address: wallet.public_spend_key_to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_range_proof_bit_length() {
+        let with_output = |output: TransactionOutput| Transaction {
+            msg_outputs: vec![output],
+            ..Default::default()
+        };
+
+        assert!(validate_outputs_with_bits(&with_output(proven_output(64)), 64).unwrap());
+        assert!(validate_outputs(&with_output(proven_output(RANGE_PROOF_BITS))).unwrap());
+        assert!(matches!(
+            validate_outputs_with_bits(&with_output(proven_output(32)), 64),
+            Err(ChainOpsError::RangeProofBitsMismatch {
+                expected: 64,
+                found: Some(32)
+            })
+        ));
+        let mut truncated = proven_output(64);
+        truncated.msg_proof.pop();
+        assert!(matches!(
+            validate_outputs_with_bits(&with_output(truncated), 64),
+            Err(ChainOpsError::RangeProofBitsMismatch { found: None, .. })
+        ));
+    }
+
+    #[test]
+    fn test_identity_output_points_are_rejected() {
+        let identity = CompressedRistretto::default().to_bytes().to_vec();
+        let mut output = proven_output(RANGE_PROOF_BITS);
+        output.msg_output_key = identity.clone();
+        let transaction = Transaction {
+            msg_outputs: vec![output],
+            ..Default::default()
+        };
+        assert!(matches!(
+            validate_outputs(&transaction),
+            Err(ChainOpsError::InvalidOutputPoint)
+        ));
+
+        let mut output = proven_output(RANGE_PROOF_BITS);
+        output.msg_stealth_address = identity;
+        let transaction = Transaction {
+            msg_outputs: vec![output],
+            ..Default::default()
+        };
+        assert!(matches!(
+            validate_outputs(&transaction),
+            Err(ChainOpsError::InvalidOutputPoint)
+        ));
+    }
+
+    #[test]
+    fn test_available_balance_excludes_immature_and_reserved() {
+        let wallet = Wallet::generate().unwrap();
+        let owned = |amount: u64, height: u32, coinbase: bool| {
+            let output = wallet.prepare_change_output(amount, 1).unwrap();
+            let mut owned = wallet.scan_outputs(&[&output]).unwrap().remove(0);
+            owned.height = height;
+            owned.coinbase = coinbase;
+            owned
+        };
+        let tip_index = 20;
+        let outputs = vec![
+            owned(100, 1, true),
+            owned(200, tip_index - COINBASE_MATURITY + 1, true),
+            owned(300, 5, false),
+            owned(400, 5, false),
+        ];
+        let total: u64 = outputs.iter().map(|output| output.decrypted_amount).sum();
+        let reserved: HashSet<Vec<u8>> = [wallet.key_image(&outputs[2]).unwrap()].into();
+
+        let available = spendable_balance(&wallet, &outputs, tip_index, &reserved).unwrap();
+        assert_eq!(total, 1000);
+        assert_eq!(available, 500);
+        assert_eq!(
+            spendable_balance(&wallet, &outputs, tip_index + 1, &HashSet::new()).unwrap(),
+            total
+        );
+    }
+
+    #[test]
+    fn test_block_timestamp_bounds() {
+        let now = 1_700_000_000;
+        let previous = [now - 500, now - 400, now - 300, now - 200, now - 100];
+
+        assert!(check_timestamp(now, &previous, now, MAX_TIMESTAMP_DRIFT).is_ok());
+        assert!(check_timestamp(now - 250, &previous, now, MAX_TIMESTAMP_DRIFT).is_ok());
+        assert!(check_timestamp(now + MAX_TIMESTAMP_DRIFT, &[], now, MAX_TIMESTAMP_DRIFT).is_ok());
+        assert!(matches!(
+            check_timestamp(
+                now + MAX_TIMESTAMP_DRIFT + 1,
+                &previous,
+                now,
+                MAX_TIMESTAMP_DRIFT
+            ),
+            Err(ChainOpsError::TimestampTooFarAhead { .. })
+        ));
+        assert!(matches!(
+            check_timestamp(now - 300, &previous, now, MAX_TIMESTAMP_DRIFT),
+            Err(ChainOpsError::TimestampNotAfterMedian { median, .. }) if median == now - 300
+        ));
+        assert!(matches!(
+            check_timestamp(now - 1000, &previous, now, MAX_TIMESTAMP_DRIFT),
+            Err(ChainOpsError::TimestampNotAfterMedian { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_version_validation() {
+        let chain = open_test_chain("transaction_version").await;
+        let spending = spending_transaction(b"");
+        let with_version = |msg_version: u32| Transaction {
+            msg_version,
+            ..spending.clone()
+        };
+
+        assert!(chain
+            .validate_transaction(&with_version(TRANSACTION_VERSION))
+            .await
+            .unwrap());
+        assert!(check_transaction_version(&with_version(0)).is_ok());
+        assert!(matches!(
+            chain.validate_transaction(&with_version(TRANSACTION_VERSION + 1)).await,
+            Err(ChainOpsError::UnsupportedTransactionVersion { version, max })
+                if version == TRANSACTION_VERSION + 1 && max == TRANSACTION_VERSION
+        ));
+        assert_ne!(
+            hash_transaction(&with_version(TRANSACTION_VERSION)),
+            hash_transaction(&with_version(TRANSACTION_VERSION + 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_contract_hash_validation() {
+        let chain = open_test_chain("contract_hash").await;
+        let committed = spending_transaction(b"contract code");
+        assert!(chain.validate_transaction(&committed).await.unwrap());
+
+        let tampered = Transaction {
+            msg_contract: Some(Contract {
+                msg_code: b"other code".to_vec(),
+            }),
+            ..committed.clone()
+        };
+        let uncommitted = Transaction {
+            msg_contract_hash: vec![],
+            ..committed.clone()
+        };
+        let dangling = Transaction {
+            msg_contract: None,
+            ..committed
+        };
+        for transaction in [&tampered, &uncommitted, &dangling] {
+            assert!(matches!(
+                chain.validate_transaction(transaction).await,
+                Err(ChainOpsError::ContractHashMismatch)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_tx_reports_inclusion_and_confirmations() {
+        use futures::StreamExt;
+
+        let chain = open_test_chain("subscribe_tx").await;
+        let wallet = Wallet::generate().unwrap();
+        let block = |index: u32, amount: u64| Block {
+            msg_header: Some(Header {
+                msg_index: index,
+                ..Default::default()
+            }),
+            msg_transactions: vec![Transaction {
+                msg_outputs: vec![wallet.prepare_change_output(amount, 1).unwrap()],
+                msg_minted: amount,
+                ..Default::default()
+            }],
+        };
+        let watched = block(2, BLOCK_REWARD);
+        let watched_hash = hash_transaction(&watched.msg_transactions[0]);
+        let events = chain.subscribe_tx(watched_hash);
+        futures::pin_mut!(events);
+
+        chain
+            .add_genesis_block(&wallet, block(1, 10))
+            .await
+            .unwrap();
+        chain.add_genesis_block(&wallet, watched).await.unwrap();
+        let included = tokio::time::timeout(std::time::Duration::from_secs(5), events.next())
+            .await
+            .unwrap();
+        assert_eq!(
+            included,
+            Some(ConfirmationEvent {
+                block_index: 2,
+                confirmations: 1,
+            })
+        );
+
+        chain
+            .add_genesis_block(&wallet, block(3, BLOCK_REWARD))
+            .await
+            .unwrap();
+        let confirmed = tokio::time::timeout(std::time::Duration::from_secs(5), events.next())
+            .await
+            .unwrap();
+        assert_eq!(
+            confirmed,
+            Some(ConfirmationEvent {
+                block_index: 2,
+                confirmations: 2,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_account_outputs_are_attributed_per_wallet() {
+        let chain = open_test_chain("account_outputs").await;
+        let (wallet, first, second, stranger) = (
+            Wallet::generate().unwrap(),
+            Arc::new(Wallet::generate().unwrap()),
+            Arc::new(Wallet::generate().unwrap()),
+            Wallet::generate().unwrap(),
+        );
+        let pay = |wallet: &Wallet, amounts: &[u64]| Transaction {
+            msg_outputs: amounts
+                .iter()
+                .enumerate()
+                .map(|(i, amount)| wallet.prepare_change_output(*amount, i as u32 + 1).unwrap())
+                .collect(),
+            ..Default::default()
+        };
+        let block = |index: u32, msg_transactions: Vec<Transaction>| Block {
+            msg_header: Some(Header {
+                msg_index: index,
+                ..Default::default()
+            }),
+            msg_transactions,
+        };
+
+        chain.add_account(Arc::clone(&first)).await.unwrap();
+        chain
+            .add_genesis_block(
+                &wallet,
+                block(
+                    1,
+                    vec![
+                        pay(&first, &[10, 15]),
+                        pay(&second, &[7]),
+                        pay(&stranger, &[3]),
+                    ],
+                ),
+            )
+            .await
+            .unwrap();
+        assert_eq!(chain.account_balance(&first).await.unwrap(), 25);
+        // An account added later finds its outputs in the blocks already stored
+        assert_eq!(chain.add_account(Arc::clone(&second)).await.unwrap(), 1);
+        assert_eq!(chain.account_balance(&second).await.unwrap(), 7);
+        assert_eq!(chain.account_balance(&stranger).await.unwrap(), 0);
+        // The node wallet's own outputs are kept apart
+        assert_eq!(chain.get_balance().await, 0);
+
+        // Spending one of the first account's outputs takes it out of the balance
+        let spent = chain.outputs().get_for(&first.address).await.unwrap()[0].clone();
+        let spending = Transaction {
+            msg_inputs: vec![TransactionInput {
+                msg_key_image: first.key_image(&spent).unwrap(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        chain
+            .add_genesis_block(&wallet, block(2, vec![spending]))
+            .await
+            .unwrap();
+        assert_eq!(
+            chain.account_balance(&first).await.unwrap(),
+            25 - spent.decrypted_amount
+        );
+
+        // A rebuild restores the account outputs it cleared
+        chain.rebuild_outputs(&wallet).await.unwrap();
+        assert_eq!(
+            chain.account_balance(&first).await.unwrap(),
+            25 - spent.decrypted_amount
+        );
+        assert_eq!(chain.account_balance(&second).await.unwrap(), 7);
+
+        // Rolling the blocks back takes the spend and then the outputs away
+        chain.rollback_to(&wallet, 1).await.unwrap();
+        assert_eq!(chain.account_balance(&first).await.unwrap(), 25);
+        chain.rollback_to(&wallet, 0).await.unwrap();
+        assert_eq!(chain.account_balance(&first).await.unwrap(), 0);
+        assert_eq!(chain.account_balance(&second).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_resume_validation_after_crash_mid_sync() {
+        let chain = open_test_chain("resume_validation").await;
+        let wallet = Wallet::generate().unwrap();
+        let now = chain.unix_now();
+        let genesis = coinbase_block(&wallet, 1, vec![], now - 10, GENESIS_PREMINE);
+        chain
+            .add_genesis_block(&wallet, genesis.clone())
+            .await
+            .unwrap();
+        assert_eq!(chain.validated_up_to().await.unwrap(), Some(1));
+
+        // The crash hits after sync stored the blocks but before any of them was applied
+        let second = coinbase_block(
+            &wallet,
+            2,
+            hash_block(&genesis).unwrap(),
+            now - 5,
+            BLOCK_REWARD,
+        );
+        // Repeats the second block's output, rolling it back must leave that output's stealth index alone
+        let mut unlinked = coinbase_block(&wallet, 3, vec![0; 32], now - 1, BLOCK_REWARD);
+        unlinked.msg_transactions = second.msg_transactions.clone();
+        for (index, stored) in [(2, &second), (3, &unlinked)] {
+            chain
+                .blocks()
+                .put_block(index, hash_block(stored).unwrap(), stored)
+                .await
+                .unwrap();
+        }
+        let second_tx = hash_transaction(&second.msg_transactions[0]);
+        assert_eq!(chain.max_index().await.unwrap(), 3);
+        assert_eq!(chain.validated_up_to().await.unwrap(), Some(1));
+        assert!(!chain
+            .is_transaction_confirmed(second_tx.clone())
+            .await
+            .unwrap());
+
+        // On restart the valid block is committed and the chain is rolled back to it from the first one that fails
+        assert_eq!(chain.resume_validation(&wallet).await.unwrap(), (1, 1));
+        assert_eq!(chain.validated_up_to().await.unwrap(), Some(2));
+        assert_eq!(chain.max_index().await.unwrap(), 2);
+        assert!(chain.blocks().get_by_index(3).await.unwrap().is_none());
+        assert!(chain.is_transaction_confirmed(second_tx).await.unwrap());
+        assert!(chain
+            .blocks()
+            .contains_stealth_address(
+                &second.msg_transactions[0].msg_outputs[0].msg_stealth_address
+            )
+            .await
+            .unwrap());
+        // The supply counts the blocks left in the chain, whether or not it was updated before the crash
+        assert_eq!(
+            chain.total_supply().await.unwrap(),
+            GENESIS_PREMINE + BLOCK_REWARD
+        );
+        assert_eq!(chain.resume_validation(&wallet).await.unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_block_with_bad_nonce_is_rejected() {
+        let chain = open_test_chain("bad_nonce").await;
+        let wallet = Wallet::generate().unwrap();
+        let now = chain.unix_now();
+        let genesis = coinbase_block(&wallet, 1, vec![], now - 10, GENESIS_PREMINE);
+        chain
+            .add_genesis_block(&wallet, genesis.clone())
+            .await
+            .unwrap();
+
+        let unmined = coinbase_block(
+            &wallet,
+            2,
+            hash_block(&genesis).unwrap(),
+            now - 5,
+            BLOCK_REWARD,
+        );
+        let mined = mine_rolling(&POW_HASHER, unmined, POW_DIFFICULTY, 0, NONCE_SPACE).unwrap();
+        let mut bad_nonce = mined.clone();
+        while verify_block_pow(&bad_nonce, POW_DIFFICULTY) {
+            let header = bad_nonce.msg_header.as_mut().unwrap();
+            header.msg_nonce = header.msg_nonce.wrapping_add(1);
+        }
+        assert!(matches!(
+            chain.add_block(&wallet, bad_nonce).await,
+            Err(ChainOpsError::InvalidProofOfWork)
+        ));
+        assert_eq!(chain.max_index().await.unwrap(), 1);
+        // Swapping the transactions keeps the PoW valid, but not the root hash
+        let mut swapped = mined.clone();
+        swapped.msg_transactions =
+            coinbase_block(&wallet, 2, vec![], 0, BLOCK_REWARD).msg_transactions;
+        assert!(verify_block_pow(&swapped, POW_DIFFICULTY));
+        assert!(matches!(
+            chain.add_block(&wallet, swapped).await,
+            Err(ChainOpsError::InvalidRootHash)
+        ));
+        chain.add_block(&wallet, mined).await.unwrap();
+        assert_eq!(chain.max_index().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_header_chain_export() {
+        let chain = open_test_chain("header_chain").await;
+        let now = chain.unix_now();
+        let mut blocks: Vec<Block> = Vec::new();
+        for index in 1..=3 {
+            let msg_previous_hash = match blocks.last() {
+                Some(previous) => hash_block(previous).unwrap(),
+                None => vec![],
+            };
+            let msg_transactions = vec![Transaction {
+                msg_minted: index as u64,
+                ..Default::default()
+            }];
+            let block = Block {
+                msg_header: Some(Header {
+                    msg_index: index,
+                    msg_previous_hash,
+                    msg_root_hash: transactions_root(&msg_transactions),
+                    msg_timestamp: now - 10 + index as u64,
+                    ..Default::default()
+                }),
+                msg_transactions,
+            };
+            let block = mine_rolling(&POW_HASHER, block, POW_DIFFICULTY, 0, NONCE_SPACE).unwrap();
+            chain
+                .blocks()
+                .put_block(index, hash_block(&block).unwrap(), &block)
+                .await
+                .unwrap();
+            blocks.push(block);
+        }
+
+        let headers = chain.header_chain(0, 3).await.unwrap();
+        assert_eq!(headers.len(), 3);
+        assert!(headers[0].msg_previous_hash.is_empty());
+        // Checked the way a light client would, from the headers alone
+        for (i, header) in headers.iter().enumerate() {
+            assert_eq!(header.msg_index, i as u32 + 1);
+            let header_only = Block {
+                msg_header: Some(header.clone()),
+                msg_transactions: vec![],
+            };
+            assert!(verify_pow(&POW_HASHER, &header_only, POW_DIFFICULTY));
+            if let Some(next) = headers.get(i + 1) {
+                assert_eq!(next.msg_previous_hash, hash_header(header).unwrap());
+            }
+            // The bodies are committed to by the root hash, not the block hash
+            assert_eq!(
+                header.msg_root_hash,
+                transactions_root(&blocks[i].msg_transactions)
+            );
+        }
+
+        assert_eq!(chain.header_chain(2, 3).await.unwrap(), headers[1..]);
+        assert!(chain.header_chain(3, 2).await.unwrap().is_empty());
+        assert!(matches!(
+            chain.header_chain(1, 4).await,
+            Err(ChainOpsError::IndexTooHigh)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_recent_fee_rates_from_stored_blocks() {
+        let chain = open_test_chain("fee_rates").await;
+        assert!(chain.recent_fee_rates(2).await.unwrap().is_empty());
+        let transaction = |fee: u64| Transaction {
+            msg_fee: fee,
+            ..Default::default()
+        };
+        for index in 1..=3u32 {
+            let block = Block {
+                msg_header: Some(Header {
+                    msg_index: index,
+                    ..Default::default()
+                }),
+                msg_transactions: vec![transaction(index as u64 * 1_000), transaction(0)],
+            };
+            chain
+                .blocks()
+                .put_block(index, hash_block(&block).unwrap(), &block)
+                .await
+                .unwrap();
+        }
+
+        let expected = |index: u64| {
+            vec![
+                fee_rate(&transaction(index * 1_000)),
+                fee_rate(&transaction(0)),
+            ]
+        };
+        assert_eq!(
+            chain.recent_fee_rates(2).await.unwrap(),
+            vec![expected(2), expected(3)]
+        );
+        assert_eq!(chain.recent_fee_rates(10).await.unwrap().len(), 3);
+        assert!(chain.recent_fee_rates(0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spent_key_image_is_reported() {
+        let chain = open_test_chain("image_spent").await;
+        let wallet = Wallet::generate().unwrap();
+        let spending = spending_transaction(b"");
+        let spent_image = spending.msg_inputs[0].msg_key_image.clone();
+        let unused_image = spending_transaction(b"").msg_inputs[0]
+            .msg_key_image
+            .clone();
+        assert!(!chain.is_image_spent(&spent_image).await.unwrap());
+
+        let block = Block {
+            msg_header: Some(Header {
+                msg_index: 1,
+                ..Default::default()
+            }),
+            msg_transactions: vec![spending],
+        };
+        chain.add_genesis_block(&wallet, block).await.unwrap();
+        assert!(chain.is_image_spent(&spent_image).await.unwrap());
+        assert!(!chain.is_image_spent(&unused_image).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_burnt_outputs_leave_balance_and_supply() {
+        let chain = open_test_chain("burn").await;
+        let wallet = Wallet::generate().unwrap();
+        let initial_supply = chain.total_supply().await.unwrap();
+        let block = Block {
+            msg_header: Some(Header {
+                msg_index: 1,
+                ..Default::default()
+            }),
+            msg_transactions: vec![Transaction {
+                msg_outputs: vec![
+                    wallet.prepare_change_output(100, 1).unwrap(),
+                    wallet.prepare_output(&burn_address(), 2, 30, None).unwrap(),
+                ],
+                msg_minted: 130,
+                ..Default::default()
+            }],
+        };
+
+        chain.add_genesis_block(&wallet, block).await.unwrap();
+        assert_eq!(chain.get_balance().await, 100);
+        assert_eq!(chain.total_supply().await.unwrap(), initial_supply + 100);
+    }
+
+    #[test]
+    fn test_burn_claiming_more_than_its_commitment_is_rejected() {
+        let wallet = Wallet::generate().unwrap();
+        let r = Scalar::random(&mut rand::thread_rng());
+        let burnt = wallet
+            .prepare_output_with_key(&burn_address(), 2, 30, None, &r)
+            .unwrap();
+        let transaction = Transaction {
+            msg_outputs: vec![burnt.clone()],
+            ..Default::default()
+        };
+        assert!(validate_outputs(&transaction).unwrap());
+
+        // The encrypted amount is readable with the public burn view key, so anyone could rewrite it
+        let (_, burn_view_key) = derive_keys_from_address(&burn_address()).unwrap();
+        let q_bytes = shared_secret(&r, &burn_view_key).unwrap();
+        let mut inflated = burnt;
+        inflated.msg_amount = encrypt_amount(&q_bytes, 2, 1_000_000).unwrap().to_vec();
+        let transaction = Transaction {
+            msg_outputs: vec![inflated],
+            ..Default::default()
+        };
+        assert!(matches!(
+            validate_outputs(&transaction),
+            Err(ChainOpsError::CryptoOpsError(
+                CryptoOpsError::UnopenedBurnCommitment
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_independent_chains() {
+        let (first, second) = tokio::join!(open_test_chain("first"), open_test_chain("second"));
+        let (first_wallet, second_wallet) =
+            (Wallet::generate().unwrap(), Wallet::generate().unwrap());
+        let funded_block = |wallet: &Wallet, index: u32, amount: u64| Block {
+            msg_header: Some(Header {
+                msg_index: index,
+                ..Default::default()
+            }),
+            msg_transactions: vec![Transaction {
+                msg_outputs: vec![wallet.prepare_change_output(amount, 1).unwrap()],
+                msg_minted: amount,
+                ..Default::default()
+            }],
+        };
+        let first_blocks: Vec<Block> = (1..=3)
+            .map(|index| funded_block(&first_wallet, index, 10))
+            .collect();
+        let second_block = funded_block(&second_wallet, 1, 7);
+
+        let (first_added, second_added) = tokio::join!(
+            async {
+                for block in first_blocks.clone() {
+                    first.add_genesis_block(&first_wallet, block).await?;
+                }
+                Ok::<(), ChainOpsError>(())
+            },
+            second.add_genesis_block(&second_wallet, second_block.clone())
+        );
+        first_added.unwrap();
+        second_added.unwrap();
+
+        assert_eq!(first.max_index().await.unwrap(), 3);
+        assert_eq!(second.max_index().await.unwrap(), 1);
+        assert_eq!(first.cached_tip_index(), 3);
+        assert_eq!(second.cached_tip_index(), 1);
+        assert_eq!(first.get_balance().await, 30);
+        assert_eq!(second.get_balance().await, 7);
+        assert_eq!(first.total_supply().await.unwrap(), 30);
+        assert_eq!(second.total_supply().await.unwrap(), 7);
+
+        let first_hash = hash_transaction(&first_blocks[0].msg_transactions[0]);
+        let second_hash = hash_transaction(&second_block.msg_transactions[0]);
+        assert!(first
+            .is_transaction_confirmed(first_hash.clone())
+            .await
+            .unwrap());
+        assert!(!second.is_transaction_confirmed(first_hash).await.unwrap());
+        assert!(!first.is_transaction_confirmed(second_hash).await.unwrap());
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+// Source of the wall clock time block timestamps are made and checked against
+pub trait TimeSource: Send + Sync {
+    // Seconds since the Unix epoch
+    fn unix_now(&self) -> u64;
+}
+
+// The host's clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl TimeSource for SystemClock {
+    fn unix_now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs()
+    }
+}
+
+// Clock standing still until it is set or advanced, so timestamp logic can be tested deterministically
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> MockClock {
+        MockClock {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.now.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl TimeSource for MockClock {
+    fn unix_now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,45 @@
+use vec_crypto::crypto::{
+    RANGE_PROOF_BITS as WALLET_RANGE_PROOF_BITS, TRANSACTION_VERSION as WALLET_TRANSACTION_VERSION,
+};
+use vec_utils::utils::{KeccakPowHasher, DEFAULT_POW_DIFFICULTY};
+
+// Consensus parameters shared by every node of the network
+
+// Deepest rollback from the tip a competing branch is allowed to cause
+pub const MAX_REORG_DEPTH: u32 = 100;
+
+// Hash function used by mining and PoW verification
+pub const POW_HASHER: KeccakPowHasher = KeccakPowHasher;
+
+// Number of leading 'd' hex digits a block's PoW hash needs while blocks come on time
+pub const POW_DIFFICULTY: usize = DEFAULT_POW_DIFFICULTY;
+
+// Easiest difficulty a chain whose blocks come far slower than TARGET_BLOCK_TIME falls back to
+pub const MIN_POW_DIFFICULTY: usize = 2;
+
+// Seconds a block is expected to take on average
+pub const TARGET_BLOCK_TIME: u64 = 60;
+
+// Bit length output range proofs are verified with, the same one wallets prove with
+pub const RANGE_PROOF_BITS: usize = WALLET_RANGE_PROOF_BITS;
+
+// Confirmations a minted output needs before it can be spent
+pub const COINBASE_MATURITY: u32 = 10;
+
+// How far ahead of the local clock a block's timestamp may be
+pub const MAX_TIMESTAMP_DRIFT: u64 = 2 * 60 * 60;
+
+// Number of preceding blocks whose median timestamp a new block has to exceed
+pub const MEDIAN_TIME_SPAN: u32 = 11;
+
+// Newest transaction format, the one wallets build
+pub const TRANSACTION_VERSION: u32 = WALLET_TRANSACTION_VERSION;
+
+// Amount minted by the genesis block's single output
+pub const GENESIS_PREMINE: u64 = 100000;
+
+// Amount minted by the coinbase of every later block
+pub const BLOCK_REWARD: u64 = 10;
+
+// Most outputs a transaction may create, each of them costs a range proof verification
+pub const MAX_OUTPUTS_PER_TX: usize = 16;
@@ -0,0 +1,109 @@
+use crate::chain::{transactions_root, Chain};
+use crate::consensus::{
+    BLOCK_REWARD, GENESIS_PREMINE, POW_DIFFICULTY, POW_HASHER, TRANSACTION_VERSION,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Once};
+use vec_crypto::crypto::{unsigned_input, Wallet};
+use vec_proto::messages::{Block, Contract, Header, Transaction};
+use vec_utils::utils::{hash_block, hash_contract_code, mine_rolling, NONCE_SPACE};
+
+// Chains and transactions the tests of the chain and of the node share, built only for tests or with the
+// test-fixtures feature
+
+static TEST_DATA_DIR: Once = Once::new();
+static NEXT_TEST_CHAIN: AtomicU64 = AtomicU64::new(0);
+
+// Chain over fresh databases in a temp dir of its own, so tests neither share state nor leave it to later runs
+pub async fn open_test_chain(name: &str) -> Chain {
+    let dir =
+        std::env::temp_dir().join(format!("vector-chain-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    Chain::open(dir.to_str().unwrap()).await.unwrap()
+}
+
+// Test chain under a name no other test of the process uses
+pub async fn temp_chain() -> Arc<Chain> {
+    let name = format!("temp-{}", NEXT_TEST_CHAIN.fetch_add(1, Ordering::Relaxed));
+    Arc::new(open_test_chain(&name).await)
+}
+
+// Points the process-wide storers, e.g. the IPDB, at a temp dir instead of the production data directory.
+// Has to run before they are first opened
+pub fn init_test_data_dir() {
+    TEST_DATA_DIR.call_once(|| {
+        let dir = std::env::temp_dir().join(format!("vector-test-data-{}", std::process::id()));
+        std::env::set_var("VECTOR_DATA_DIR", dir);
+    });
+}
+
+// Valid transaction spending an output a fresh wallet paid to itself, committing to the given contract code
+pub fn spending_transaction(code: &[u8]) -> Transaction {
+    let wallet = Wallet::generate().unwrap();
+    let funding = wallet.prepare_change_output(5, 1).unwrap();
+    let owned_output = wallet.scan_outputs(&[&funding]).unwrap().remove(0);
+    let transaction = Transaction {
+        msg_outputs: vec![wallet.prepare_change_output(5, 1).unwrap()],
+        msg_contract: Some(Contract {
+            msg_code: code.to_vec(),
+        }),
+        msg_version: TRANSACTION_VERSION,
+        msg_contract_hash: hash_contract_code(code),
+        ..Default::default()
+    };
+    wallet
+        .sign_transaction(&[unsigned_input(&owned_output).unwrap()], transaction)
+        .unwrap()
+}
+
+// Unmined block minting the amount to the wallet in its only transaction, with the root hash committing to it
+pub fn coinbase_block(
+    wallet: &Wallet,
+    index: u32,
+    msg_previous_hash: Vec<u8>,
+    msg_timestamp: u64,
+    amount: u64,
+) -> Block {
+    let msg_transactions = vec![Transaction {
+        msg_outputs: vec![wallet.prepare_change_output(amount, 1).unwrap()],
+        msg_minted: amount,
+        msg_version: TRANSACTION_VERSION,
+        ..Default::default()
+    }];
+    Block {
+        msg_header: Some(Header {
+            msg_index: index,
+            msg_previous_hash,
+            msg_root_hash: transactions_root(&msg_transactions),
+            msg_timestamp,
+            ..Default::default()
+        }),
+        msg_transactions,
+    }
+}
+
+// Linked and mined blocks 1..=length, the genesis minting the premine to the wallet and every later one the block
+// reward, one second apart and ending at now
+pub fn mined_chain(wallet: &Wallet, length: u32, now: u64) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    for index in 1..=length {
+        let msg_previous_hash = match blocks.last() {
+            Some(previous) => hash_block(previous).unwrap(),
+            None => vec![],
+        };
+        let amount = if index == 1 {
+            GENESIS_PREMINE
+        } else {
+            BLOCK_REWARD
+        };
+        let block = coinbase_block(
+            wallet,
+            index,
+            msg_previous_hash,
+            now - u64::from(length - index),
+            amount,
+        );
+        blocks.push(mine_rolling(&POW_HASHER, block, POW_DIFFICULTY, 0, NONCE_SPACE).unwrap());
+    }
+    blocks
+}
@@ -1 +1,5 @@
 pub mod chain;
+pub mod clock;
+pub mod consensus;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod fixtures;
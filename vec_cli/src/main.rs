@@ -1,9 +1,14 @@
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
+use std::net::{IpAddr, UdpSocket};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use vec_chain::chain::Chain;
 use vec_crypto::crypto::Wallet;
 use vec_errors::errors::*;
 use vec_node::node::*;
+use vec_storage::lazy_traits::{BLOCK_STORER, IMAGE_STORER, META_STORER, OUTPUT_STORER};
+use vec_storage::maintenance::compact_data_dir;
 
 enum Command {
     SendTransaction {
@@ -19,6 +24,17 @@ enum Command {
     GetAddress,
     GetIndex,
     MakeBlock,
+    Status,
+}
+
+// Chain over the databases of the data directory, the one chain the node process works on
+fn local_chain() -> Arc<Chain> {
+    Arc::new(Chain::new(
+        Arc::clone(&BLOCK_STORER),
+        Arc::clone(&IMAGE_STORER),
+        Arc::clone(&OUTPUT_STORER),
+        Arc::clone(&META_STORER),
+    ))
 }
 
 #[tokio::main]
@@ -50,12 +66,16 @@ async fn main() {
             Ok(res) => res,
             Err(e) => {
                 eprintln!("Failed to get IP: {}", e);
-                return;
+                match read_ip(&mut rl) {
+                    Some(ip) => ip,
+                    None => return,
+                }
             }
         }
     };
 
     let address = format!("{}:{}", ip, port);
+    let bind_address = format!("0.0.0.0:{}", port);
 
     let readline = rl.readline("Do you have a secret key? (yes/no): ");
     let has_secret_key = match readline {
@@ -83,16 +103,58 @@ async fn main() {
         println!("Please, save your secret key: {}", secret_spend_key);
     }
 
-    let ans = match new(secret_spend_key, address).await {
+    let readline = rl.readline("Do you want to run as a validator? (yes/no): ");
+    let is_validator = match readline {
+        Ok(line) => line.trim().eq_ignore_ascii_case("yes"),
+        Err(_) => {
+            eprintln!("Failed to read response");
+            return;
+        }
+    };
+
+    let config = match NodeConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid node settings: {}", e);
+            return;
+        }
+    };
+    if let Some(admin_addr) = &config.admin_addr {
+        println!("Admin service will listen on {}", admin_addr);
+    }
+
+    // The databases get compacted every compaction interval while the node runs, and once before the chain opens them
+    match compact_data_dir() {
+        Ok(reclaimed) => println!("Databases compacted, reclaimed {} bytes", reclaimed),
+        Err(e) => eprintln!("Failed to compact databases: {}", e),
+    }
+
+    let ans = match new(
+        local_chain(),
+        secret_spend_key,
+        bind_address,
+        address,
+        is_validator,
+        &config,
+    )
+    .await
+    {
         Ok(ans) => ans,
         Err(e) => {
             eprintln!("Failed to create NodeService: {}", e);
             return;
         }
     };
+    if has_secret_key {
+        match ans.ns.restore_wallet_balance().await {
+            Ok(balance) => println!("Wallet restored, balance: {}", balance),
+            Err(e) => eprintln!("Failed to rescan local blocks: {}", e),
+        }
+    }
     let (tx, mut rx) = tokio::sync::mpsc::channel(100);
     let arc_ns = Arc::clone(&ans.ns);
     tokio::spawn(async move { start(&arc_ns).await });
+    let flush_ns = Arc::clone(&ans.ns);
 
     let server_future = tokio::spawn(async move {
         loop {
@@ -137,6 +199,10 @@ async fn main() {
                     Ok(_) => println!("Successfully connected to {}", ip),
                     Err(e) => eprintln!("Failed to connect: {}", e),
                 },
+                Some(Command::Status) => match render_status(&ans.ns).await {
+                    Ok(status) => println!("{}", status),
+                    Err(e) => eprintln!("Failed to get status: {}", e),
+                },
                 Some(Command::GetAddress) => match ans.ns.get_address().await {
                     Ok(address) => println!("Address: {}", address),
                     Err(e) => eprintln!("Failed to get address: {}", e),
@@ -205,6 +271,9 @@ async fn main() {
                     "address" => {
                         let _ = tx.send(Command::GetAddress).await;
                     }
+                    "status" => {
+                        let _ = tx.send(Command::Status).await;
+                    }
                     _ => {
                         println!("Invalid command");
                     }
@@ -219,6 +288,10 @@ async fn main() {
             }
         }
     }
+    if let Err(e) = flush_ns.flush_all().await {
+        eprintln!("Failed to flush databases: {}", e);
+    }
+    drop(tx);
     match server_future.await {
         Ok(result) => result,
         Err(e) => {
@@ -227,9 +300,176 @@ async fn main() {
     }
 }
 
+// Summary printed by the status command, the node is behind if a peer announced a higher index
+pub async fn render_status(ns: &NodeService) -> Result<String, NodeServiceError> {
+    let height = ns.get_last_index().await?;
+    let peers = ns.get_addr_list().len();
+    let balance = ns.get_balance().await;
+    let mempool = ns.mempool.len();
+    let best_peer_height = ns
+        .peer_info()
+        .iter()
+        .map(|peer| peer.height)
+        .max()
+        .unwrap_or(0);
+    let sync_state = if best_peer_height > height {
+        format!("behind, peers are at {}", best_peer_height)
+    } else if ns.chain_stalled.load(Ordering::Relaxed) {
+        "stalled".to_string()
+    } else {
+        "synced".to_string()
+    };
+
+    Ok(format!(
+        "Height: {}\nPeers: {}\nBalance: {}\nMempool: {} transactions\nSync: {}",
+        height, peers, balance, mempool, sync_state
+    ))
+}
+
+// Public IP lookups tried in order before falling back to the local interfaces
+const IP_RESOLVERS: &[&str] = &[
+    "https://api.ipify.org",
+    "https://ifconfig.me/ip",
+    "https://icanhazip.com",
+];
+
 pub async fn get_ip() -> Result<String, ServerConfigError> {
-    let response = reqwest::get("https://api.ipify.org").await?;
-    let ip = response.text().await?;
-    let ip_port = format!("{}:8080", ip);
-    Ok(ip_port)
+    get_ip_from(IP_RESOLVERS).await
+}
+
+// Returns the first address found, every failed attempt is reported along the way
+pub async fn get_ip_from(resolvers: &[&str]) -> Result<String, ServerConfigError> {
+    for url in resolvers {
+        match resolve_ip(url).await {
+            Ok(ip) => return Ok(ip),
+            Err(e) => eprintln!("IP resolver {} failed: {}", url, e),
+        }
+    }
+    match local_ip() {
+        Ok(ip) => Ok(ip),
+        Err(e) => {
+            eprintln!("Local interface lookup failed: {}", e);
+            Err(ServerConfigError::IpUnresolved)
+        }
+    }
+}
+
+async fn resolve_ip(url: &str) -> Result<String, ServerConfigError> {
+    let response = reqwest::get(url).await?.error_for_status()?;
+    let body = response.text().await?;
+    let ip = body.trim();
+    match ip.parse::<IpAddr>() {
+        Ok(_) => Ok(ip.to_string()),
+        Err(_) => Err(ServerConfigError::InvalidResolvedIp(ip.to_string())),
+    }
+}
+
+// Address of the interface outgoing traffic would leave through, connecting a UDP socket sends nothing
+fn local_ip() -> Result<String, ServerConfigError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| socket.connect("8.8.8.8:80").map(|_| socket))
+        .and_then(|socket| socket.local_addr())
+        .map_err(|e| ServerConfigError::LocalIpUnavailable(e.to_string()))?;
+    let ip = socket.ip();
+    if ip.is_unspecified() || ip.is_loopback() {
+        return Err(ServerConfigError::LocalIpUnavailable(ip.to_string()));
+    }
+    Ok(ip.to_string())
+}
+
+// Asks for the address until a valid one is entered, None if the prompt can't be read
+fn read_ip(rl: &mut DefaultEditor) -> Option<String> {
+    loop {
+        match rl.readline("Please enter your public IP: ") {
+            Ok(line) => match line.trim().parse::<IpAddr>() {
+                Ok(ip) => return Some(ip.to_string()),
+                Err(_) => println!("Invalid IP address: {}", line.trim()),
+            },
+            Err(_) => {
+                eprintln!("Failed to read IP");
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use vec_chain::fixtures::{init_test_data_dir, temp_chain};
+    use vec_proto::messages::{Contract, Transaction};
+
+    // Minimal HTTP server answering every request with the given body
+    async fn spawn_resolver(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_status_summary() {
+        init_test_data_dir();
+        let wallet = Wallet::generate().unwrap();
+        let ns = NodeService::new(
+            temp_chain().await,
+            bs58::encode(wallet.secret_spend_key_to_vec()).into_string(),
+            "127.0.0.1:8080".to_string(),
+            "127.0.0.1:8080".to_string(),
+            false,
+        )
+        .await
+        .unwrap();
+        ns.mempool.try_add(Transaction {
+            msg_contract: Some(Contract {
+                msg_code: b"status".to_vec(),
+            }),
+            ..Default::default()
+        });
+
+        let status = render_status(&ns).await.unwrap();
+        let height = ns.get_last_index().await.unwrap();
+        let balance = ns.get_balance().await;
+        assert!(status.contains(&format!("Height: {}", height)));
+        assert!(status.contains("Peers: 0"));
+        assert!(status.contains(&format!("Balance: {}", balance)));
+        assert!(status.contains("Mempool: 1 transactions"));
+        assert!(status.contains("Sync: synced"));
+
+        ns.chain_stalled.store(true, Ordering::Relaxed);
+        assert!(render_status(&ns).await.unwrap().contains("Sync: stalled"));
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_next_resolver() {
+        // Nothing listens on the first resolver's port anymore
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let invalid = spawn_resolver("not an address").await;
+        let working = spawn_resolver("203.0.113.7\n").await;
+
+        let ip = get_ip_from(&[&unreachable, &invalid, &working])
+            .await
+            .unwrap();
+        assert_eq!(ip, "203.0.113.7");
+        assert!(matches!(
+            resolve_ip(&invalid).await,
+            Err(ServerConfigError::InvalidResolvedIp(_))
+        ));
+    }
 }
@@ -1,19 +1,23 @@
 use bs58;
 use bulletproofs::{BulletproofGens, PedersenGens, RangeProof};
 use curve25519_dalek_ng::{
-    constants, ristretto::CompressedRistretto, ristretto::RistrettoPoint, scalar::Scalar,
-    traits::Identity,
+    constants,
+    ristretto::CompressedRistretto,
+    ristretto::RistrettoPoint,
+    scalar::Scalar,
+    traits::{Identity, IsIdentity},
 };
 use merlin::Transcript;
+use prost::encoding::encoded_len_varint;
 use rand::seq::SliceRandom;
+use rayon::prelude::*;
 use sha3::{Digest, Keccak256};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
 use vec_errors::errors::*;
 use vec_macros::hash;
 use vec_proto::messages::{Transaction, TransactionInput, TransactionOutput};
-use vec_storage::{
-    lazy_traits::OUTPUT_STORER,
-    output_db::{Output, OutputStorer, OwnedOutput},
-};
+use vec_storage::output_db::{Output, OutputDB, OutputKind, OutputStorer, OwnedOutput};
 
 pub type SSK = Scalar;
 pub type SVK = Scalar;
@@ -21,6 +25,97 @@ pub type PSK = CompressedRistretto;
 pub type PVK = CompressedRistretto;
 pub type ADS = [u8; 64];
 
+// Compact wallets of version 1 hold a spend key whose view key is the legacy, undomained derivation
+const COMPACT_WALLET_VERSION: u8 = 2;
+const LEGACY_COMPACT_WALLET_VERSION: u8 = 1;
+const COMPACT_WALLET_LEN: usize = 1 + 32 + 4;
+// Keys and address of Wallet::to_vec, followed by the creation height and the birthday
+const WALLET_VEC_LEN: usize = 4 * 32 + 64;
+
+// Domain of the secret view key derivation Keccak256("view" || secret_spend_key)
+const VIEW_KEY_DOMAIN: &[u8] = b"view";
+
+// Domain of the node identity key derivation Keccak256("identity" || secret_spend_key), the spend key only ever
+// signs the certificate binding this key to the address, challenges are answered with the identity key
+const IDENTITY_KEY_DOMAIN: &[u8] = b"identity";
+const IDENTITY_CERTIFICATE_DOMAIN: &[u8] = b"Vector identity certificate";
+
+// Domains the keys of the burn address are hashed from
+const BURN_SPEND_DOMAIN: &[u8] = b"burn spend";
+const BURN_VIEW_DOMAIN: &[u8] = b"burn view";
+// Domain of the public blinding Keccak256("burn blinding" || output_key || index) burn commitments are opened with
+const BURN_BLINDING_DOMAIN: &[u8] = b"burn blinding";
+
+// Output index convention of wallet-built transactions: the payment goes at 1 and the change at 2
+pub const RECIPIENT_OUTPUT_INDEX: u32 = 1;
+pub const CHANGE_OUTPUT_INDEX: u32 = 2;
+
+// Bit length of the amount range proofs, provers and verifiers must agree on it
+pub const RANGE_PROOF_BITS: usize = 32;
+
+// Number of ring members (the real output plus decoys) of every input
+pub const RING_SIZE: usize = 10;
+
+// Format of the transactions wallets build, 0 is the unversioned format from before the field existed
+pub const TRANSACTION_VERSION: u32 = 1;
+
+// Encrypted amounts are the 8 little endian bytes of the u64 amount xored with the keystream
+pub const ENCRYPTED_AMOUNT_LEN: usize = 8;
+
+// Number of threads used to scan blocks for owned outputs, 0 picks the number of CPUs
+static SCAN_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_scan_threads(threads: usize) {
+    SCAN_THREADS.store(threads, Ordering::Relaxed);
+}
+
+pub fn scan_threads() -> usize {
+    SCAN_THREADS.load(Ordering::Relaxed)
+}
+
+// Thread pool scanning for a wallet's outputs, built by the first scan and then reused by every scan of the wallet
+// and its clones
+#[derive(Debug)]
+pub struct ScanPool {
+    threads: usize,
+    pool: OnceLock<rayon::ThreadPool>,
+}
+
+impl ScanPool {
+    // Pool of `threads` threads, 0 picks the number of CPUs
+    pub fn new(threads: usize) -> ScanPool {
+        ScanPool {
+            threads,
+            pool: OnceLock::new(),
+        }
+    }
+
+    pub fn get(&self) -> Result<&rayon::ThreadPool, CryptoOpsError> {
+        if let Some(pool) = self.pool.get() {
+            return Ok(pool);
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .map_err(|_| CryptoOpsError::ScanPoolError)?;
+        // A scan racing this one may have built its pool first, the one stored wins
+        Ok(self.pool.get_or_init(|| pool))
+    }
+}
+
+// Rounds of decoy generation an input may take to fill its ring with distinct members before giving up
+pub const DEFAULT_DECOY_ATTEMPTS: usize = 16;
+
+static DECOY_ATTEMPTS: AtomicUsize = AtomicUsize::new(DEFAULT_DECOY_ATTEMPTS);
+
+pub fn set_decoy_attempts(attempts: usize) {
+    DECOY_ATTEMPTS.store(attempts, Ordering::Relaxed);
+}
+
+pub fn decoy_attempts() -> usize {
+    DECOY_ATTEMPTS.load(Ordering::Relaxed)
+}
+
 #[derive(Debug, Clone)]
 pub struct Wallet {
     pub secret_spend_key: SSK,
@@ -28,6 +123,46 @@ pub struct Wallet {
     pub public_spend_key: PSK,
     pub public_view_key: PVK,
     pub address: ADS,
+    // Creation height: blocks below it can't hold owned outputs and are never scanned
+    pub scan_from_index: u32,
+    // Height of the first owned output, 0 until one is received. Shared by the clones of the wallet
+    birthday: Arc<AtomicU32>,
+    scan_pool: Arc<ScanPool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputProof {
+    pub r: Scalar,
+    pub recipient_address: String,
+    pub amount: u64,
+}
+
+// Input of a transaction template: the ring is fixed, the BLSAG and key image need the spend key
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsignedInput {
+    pub ring: Vec<Vec<u8>>,
+    pub stealth: Vec<u8>,
+    pub output_key: Vec<u8>,
+    pub output_index: u32,
+}
+
+// Transaction with its outputs and commitments built but none of its inputs signed yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsignedTransaction {
+    pub inputs: Vec<UnsignedInput>,
+    pub outputs: Vec<TransactionOutput>,
+    pub fee: u64,
+    // Index of the output paying the change back, for the signer to record with OutputStorer::mark_change
+    pub change_index: Option<u32>,
+}
+
+impl UnsignedTransaction {
+    pub fn change_output(&self) -> Option<&TransactionOutput> {
+        let change_index = self.change_index?;
+        self.outputs
+            .iter()
+            .find(|output| output.msg_index == change_index)
+    }
 }
 
 #[derive(Clone)]
@@ -42,30 +177,50 @@ impl Wallet {
     pub fn generate() -> Result<Wallet, CryptoOpsError> {
         let mut rng = rand::thread_rng();
         let secret_spend_key: Scalar = Scalar::random(&mut rng);
-        let hashed_key = hash!(secret_spend_key.as_bytes());
-        let secret_view_key = Scalar::from_bytes_mod_order(hashed_key.into());
-        let public_spend_key = &constants::RISTRETTO_BASEPOINT_TABLE * &secret_spend_key;
-        let public_view_key = &constants::RISTRETTO_BASEPOINT_TABLE * &secret_view_key;
-        let data = [
-            public_spend_key.compress().to_bytes().as_slice(),
-            public_view_key.compress().to_bytes().as_slice(),
-        ]
-        .concat();
-        let address = data.as_slice().try_into().unwrap();
 
-        Ok(Wallet {
+        Ok(Wallet::from_secret_keys(
             secret_spend_key,
-            secret_view_key,
-            public_spend_key: public_spend_key.compress(),
-            public_view_key: public_view_key.compress(),
-            address,
-        })
+            derive_view_key(&secret_spend_key),
+        ))
     }
 
     // Recover the keys using secret spend key
     pub fn reconstruct(secret_spend_key: Scalar) -> Result<Wallet, CryptoOpsError> {
-        let hashed_key = hash!(secret_spend_key.as_bytes());
-        let secret_view_key = Scalar::from_bytes_mod_order(hashed_key.into());
+        check_secret_spend_key(&secret_spend_key)?;
+        Ok(Wallet::from_secret_keys(
+            secret_spend_key,
+            derive_view_key(&secret_spend_key),
+        ))
+    }
+
+    // Recover the keys of a wallet created before the view key derivation was domain separated
+    pub fn reconstruct_legacy(secret_spend_key: Scalar) -> Result<Wallet, CryptoOpsError> {
+        check_secret_spend_key(&secret_spend_key)?;
+        Ok(Wallet::from_secret_keys(
+            secret_spend_key,
+            legacy_view_key(&secret_spend_key),
+        ))
+    }
+
+    // Recover a wallet whose view key isn't derived from the spend key, e.g. imported from another implementation
+    pub fn from_keys(
+        secret_spend_key: Scalar,
+        secret_view_key: Scalar,
+    ) -> Result<Wallet, CryptoOpsError> {
+        check_secret_spend_key(&secret_spend_key)?;
+        // A zero view key makes every shared secret the identity, so anyone could scan the wallet
+        if secret_view_key == Scalar::zero() || !secret_view_key.is_canonical() {
+            return Err(CryptoOpsError::InvalidSecretViewKey);
+        }
+        Ok(Wallet::from_secret_keys(secret_spend_key, secret_view_key))
+    }
+
+    // Whether the view key was derived the pre domain separation way
+    pub fn is_legacy(&self) -> bool {
+        self.secret_view_key == legacy_view_key(&self.secret_spend_key)
+    }
+
+    fn from_secret_keys(secret_spend_key: Scalar, secret_view_key: Scalar) -> Wallet {
         let public_spend_key =
             (&constants::RISTRETTO_BASEPOINT_TABLE * &secret_spend_key).compress();
         let public_view_key = (&constants::RISTRETTO_BASEPOINT_TABLE * &secret_view_key).compress();
@@ -76,30 +231,81 @@ impl Wallet {
         .concat();
         let address = data.as_slice().try_into().unwrap();
 
-        Ok(Wallet {
+        Wallet {
             secret_spend_key,
             secret_view_key,
             public_spend_key,
             public_view_key,
             address,
-        })
+            scan_from_index: 0,
+            birthday: Arc::new(AtomicU32::new(0)),
+            scan_pool: Arc::new(ScanPool::new(scan_threads())),
+        }
+    }
+
+    // Sets the creation height, scans skip all blocks below it
+    pub fn with_scan_from_index(mut self, scan_from_index: u32) -> Wallet {
+        self.scan_from_index = scan_from_index;
+        self
+    }
+
+    // Scans on a pool of `threads` threads (0 picks the number of CPUs) instead of the default scan_threads()
+    pub fn with_scan_threads(mut self, threads: usize) -> Wallet {
+        self.scan_pool = Arc::new(ScanPool::new(threads));
+        self
+    }
+
+    // Scans on the pool of the other wallet, e.g. for the accounts a node serves next to its own wallet
+    pub fn with_scan_pool_of(mut self, other: &Wallet) -> Wallet {
+        self.scan_pool = Arc::clone(&other.scan_pool);
+        self
+    }
+
+    pub fn scan_pool(&self) -> Result<&rayon::ThreadPool, CryptoOpsError> {
+        self.scan_pool.get()
+    }
+
+    // Height at which the wallet received its first output, a rescan can start there
+    pub fn birthday(&self) -> Option<u32> {
+        match self.birthday.load(Ordering::Relaxed) {
+            0 => None,
+            height => Some(height),
+        }
+    }
+
+    // Keeps the lowest height an owned output was received at, blocks may be scanned out of order
+    pub fn record_birthday(&self, height: u32) {
+        let _ = self
+            .birthday
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |birthday| {
+                (height > 0 && (birthday == 0 || height < birthday)).then_some(height)
+            });
     }
 
     // Ordinary ECSDA signing function
     pub fn sign(&self, message: &[u8]) -> Result<Signature, CryptoOpsError> {
-        let mut rng = rand::thread_rng();
-        let nonce = Scalar::random(&mut rng);
-        let r_ep = &constants::RISTRETTO_BASEPOINT_TABLE * &nonce;
-        let r = r_ep.compress();
-        let h = hash!(
-            r_ep.compress().as_bytes(),
-            self.public_spend_key.as_bytes(),
-            message
-        );
-        let h_scalar = Scalar::from_bits(h.into());
-        let s = nonce - h_scalar * self.secret_spend_key;
+        Ok(sign_with(
+            &self.secret_spend_key,
+            &self.public_spend_key,
+            message,
+        ))
+    }
 
-        Ok(Signature { r, s })
+    // Public key the node answers identity challenges with
+    pub fn identity_key(&self) -> CompressedRistretto {
+        let secret = derive_identity_key(&self.secret_spend_key);
+        (&constants::RISTRETTO_BASEPOINT_TABLE * &secret).compress()
+    }
+
+    // Spend key signature over the identity key, proving the key belongs to the wallet's address
+    pub fn identity_certificate(&self) -> Result<Signature, CryptoOpsError> {
+        self.sign(&identity_certificate_message(&self.identity_key()))
+    }
+
+    // Signs with the identity key, which unlike the spend key may sign messages chosen by peers
+    pub fn sign_identity(&self, message: &[u8]) -> Signature {
+        let secret = derive_identity_key(&self.secret_spend_key);
+        sign_with(&secret, &self.identity_key(), message)
     }
 
     pub fn check_property(
@@ -124,110 +330,330 @@ impl Wallet {
         Ok(result.compress() == self.public_spend_key)
     }
 
+    // Re-derives the one-time private key x = Hs(q || i) + secret_spend_key of an owned stealth output
+    pub fn output_private_key(
+        &self,
+        output_key: CompressedRistretto,
+        output_index: u32,
+    ) -> Result<Scalar, CryptoOpsError> {
+        let decompressed_output = output_key
+            .decompress()
+            .ok_or(CryptoOpsError::DecompressionFailed)?;
+        let q = self.secret_view_key * decompressed_output;
+        let q_bytes = q.compress().as_bytes().to_vec();
+        let hash = hash!(&q_bytes, output_index.to_le_bytes());
+        let hash_scalar = Scalar::from_bytes_mod_order(hash.into());
+
+        Ok(hash_scalar + self.secret_spend_key)
+    }
+
     pub async fn process_transaction(
         &self,
+        outputs: &OutputDB,
         transaction: &Transaction,
+        height: u32,
     ) -> Result<(), ChainOpsError> {
-        for output in &transaction.msg_outputs {
-            let index = output.msg_index;
-            let key = CompressedRistretto::from_slice(&output.msg_output_key);
-            let stealth = CompressedRistretto::from_slice(&output.msg_stealth_address);
-
-            if self.check_property(key, index, stealth)? {
-                let decrypted_amount = self.decrypt_amount(key, index, &output.msg_amount)?;
-                let owned_output = OwnedOutput {
-                    output: Output {
-                        stealth: output.msg_stealth_address.clone(),
-                        output_key: output.msg_output_key.clone(),
-                        amount: output.msg_amount.clone(),
-                        commitment: output.msg_commitment.clone(),
-                        range_proof: output.msg_proof.clone(),
-                    },
-                    decrypted_amount,
-                };
-                OUTPUT_STORER.put(&owned_output).await?;
-            }
+        self.process_transactions(outputs, std::slice::from_ref(transaction), height)
+            .await
+    }
+
+    // Scans all outputs of the transactions (included at the given height) in parallel and stores the owned ones
+    // in the OutputDB in their original order, outputs of minting transactions are marked as coinbase
+    pub async fn process_transactions(
+        &self,
+        outputs: &OutputDB,
+        transactions: &[Transaction],
+        height: u32,
+    ) -> Result<(), ChainOpsError> {
+        for owned_output in self.scan_transactions(transactions, height)? {
+            outputs.put(&owned_output).await?;
         }
         Ok(())
     }
 
-    // Collects outputs from OutputDB and constructs Inputs for transaction
-    pub async fn prepare_inputs(&self) -> Result<(Vec<TransactionInput>, u64), ChainOpsError> {
-        let output_set = OUTPUT_STORER.get().await.unwrap();
+    // Same scan as process_transactions, returning the owned outputs instead of storing them,
+    // finding any records the birthday
+    pub fn scan_transactions(
+        &self,
+        transactions: &[Transaction],
+        height: u32,
+    ) -> Result<Vec<OwnedOutput>, ChainOpsError> {
+        if height < self.scan_from_index {
+            return Ok(Vec::new());
+        }
+        let (outputs, coinbase): (Vec<&TransactionOutput>, Vec<bool>) = transactions
+            .iter()
+            .enumerate()
+            .flat_map(|(position, transaction)| {
+                let coinbase = is_coinbase(transactions, position);
+                transaction
+                    .msg_outputs
+                    .iter()
+                    .map(move |output| (output, coinbase))
+            })
+            .unzip();
+        let scanned = self.scan_all(&outputs)?;
+        let mut owned_outputs = Vec::new();
+        for (owned_output, coinbase) in scanned.into_iter().zip(coinbase) {
+            if let Some(mut owned_output) = owned_output {
+                owned_output.height = height;
+                owned_output.coinbase = coinbase;
+                owned_outputs.push(owned_output);
+            }
+        }
+        if !owned_outputs.is_empty() {
+            self.record_birthday(height);
+        }
+        Ok(owned_outputs)
+    }
+
+    // Checks the outputs for ownership on the wallet's scan pool, owned outputs are returned in the same order
+    // as the given outputs
+    pub fn scan_outputs(
+        &self,
+        outputs: &[&TransactionOutput],
+    ) -> Result<Vec<OwnedOutput>, CryptoOpsError> {
+        Ok(self.scan_all(outputs)?.into_iter().flatten().collect())
+    }
+
+    fn scan_all(
+        &self,
+        outputs: &[&TransactionOutput],
+    ) -> Result<Vec<Option<OwnedOutput>>, CryptoOpsError> {
+        self.scan_pool()?.install(|| {
+            outputs
+                .par_iter()
+                .map(|output| self.scan_output(output))
+                .collect::<Result<Vec<_>, _>>()
+        })
+    }
+
+    // Key image I = x*Hp(P) an input spending the owned output will reveal
+    pub fn key_image(&self, owned_output: &OwnedOutput) -> Result<Vec<u8>, CryptoOpsError> {
+        let output = &owned_output.output;
+        self.output_key_image(&output.stealth, &output.output_key, output.index)
+    }
+
+    fn output_key_image(
+        &self,
+        stealth: &[u8],
+        output_key: &[u8],
+        output_index: u32,
+    ) -> Result<Vec<u8>, CryptoOpsError> {
+        let stealth = CompressedRistretto::from_slice(stealth);
+        let output_key = CompressedRistretto::from_slice(output_key);
+        let secret_key = self.output_private_key(output_key, output_index)?;
+
+        Ok((secret_key * hash_to_point(&stealth))
+            .compress()
+            .to_bytes()
+            .to_vec())
+    }
+
+    // Reusable scanner with the view key and spend point prepared once for many outputs
+    pub fn scanner(&self) -> OutputScanner<'_> {
+        OutputScanner {
+            wallet: self,
+            view_key: self.secret_view_key,
+            spend_point: self.public_spend_key.decompress(),
+        }
+    }
+
+    fn scan_output(
+        &self,
+        output: &TransactionOutput,
+    ) -> Result<Option<OwnedOutput>, CryptoOpsError> {
+        let Some((key, stealth)) = scannable_points(output) else {
+            return Ok(None);
+        };
+        if !self.check_property(key, output.msg_index, stealth)? {
+            return Ok(None);
+        }
+        self.open_output(output, key).map(Some)
+    }
+
+    // Decrypts an output already known to pay to this wallet
+    fn open_output(
+        &self,
+        output: &TransactionOutput,
+        key: CompressedRistretto,
+    ) -> Result<OwnedOutput, CryptoOpsError> {
+        let index = output.msg_index;
+        let decrypted_amount = self.decrypt_amount(key, index, &output.msg_amount)?;
+        let decrypted_memo = self.decrypt_memo(key, index, &output.msg_memo)?;
+        // Anyone may pay us at any index, only the wallet that built the transaction knows an output is change
+        // and the chain applies that record when storing it
+        let kind = OutputKind::Received;
+
+        Ok(OwnedOutput {
+            output: Output {
+                stealth: output.msg_stealth_address.clone(),
+                output_key: output.msg_output_key.clone(),
+                amount: output.msg_amount.clone(),
+                commitment: output.msg_commitment.clone(),
+                range_proof: output.msg_proof.clone(),
+                index,
+            },
+            decrypted_amount,
+            decrypted_memo,
+            kind,
+            height: 0,
+            coinbase: false,
+        })
+    }
+
+    // Outputs stored in the OutputDB that pay to this wallet, each marked as received or change
+    pub async fn list_owned_outputs(
+        &self,
+        outputs: &OutputDB,
+    ) -> Result<Vec<OwnedOutput>, ChainOpsError> {
+        let mut owned_outputs = Vec::new();
+        for owned_output in outputs.get().await? {
+            let output = &owned_output.output;
+            if self.check_property(
+                CompressedRistretto::from_slice(&output.output_key),
+                output.index,
+                CompressedRistretto::from_slice(&output.stealth),
+            )? {
+                owned_outputs.push(owned_output);
+            }
+        }
+        Ok(owned_outputs)
+    }
+
+    // Collects outputs from OutputDB and constructs Inputs for transaction, signed by sign_transaction once it is built
+    pub async fn prepare_inputs(
+        &self,
+        outputs: &OutputDB,
+    ) -> Result<(Vec<UnsignedInput>, u64), ChainOpsError> {
+        self.prepare_inputs_with(outputs, |_| true).await
+    }
+
+    // Same as prepare_inputs, but only spends the stored outputs the selector accepts (called once per output, in order)
+    pub async fn prepare_inputs_with(
+        &self,
+        outputs: &OutputDB,
+        mut select: impl FnMut(&OwnedOutput) -> bool,
+    ) -> Result<(Vec<UnsignedInput>, u64), ChainOpsError> {
+        let output_set = outputs.get().await?;
         let mut total_input_amount = 0;
         let mut inputs = Vec::new();
         for owned_output in &output_set {
-            let decrypted_amount = owned_output.decrypted_amount;
-            total_input_amount += decrypted_amount;
-            let owned_stealth_addr = &owned_output.output.stealth;
-            let compressed_stealth = CompressedRistretto::from_slice(owned_stealth_addr);
-            let wallets_res: Result<Vec<Wallet>, _> = (0..9).map(|_| Wallet::generate()).collect();
-            let wallets = wallets_res?;
-            let mut s_addrs: Vec<CompressedRistretto> =
-                wallets.iter().map(|w| w.public_spend_key).collect();
-            s_addrs.push(compressed_stealth);
-            s_addrs.shuffle(&mut rand::thread_rng());
-            let s_addrs_vec: Vec<Vec<u8>> =
-                s_addrs.iter().map(|key| key.to_bytes().to_vec()).collect();
-            let m = b"Message example";
-            let blsag = self.gen_blsag(&s_addrs, m, &compressed_stealth)?;
-            let image = blsag.i;
-            let input = TransactionInput {
-                msg_ring: s_addrs_vec,
-                msg_blsag: blsag.to_vec(),
-                msg_message: m.to_vec(),
-                msg_key_image: image.to_bytes().to_vec(),
-            };
-            inputs.push(input);
+            if !select(owned_output) {
+                continue;
+            }
+            total_input_amount += owned_output.decrypted_amount;
+            inputs.push(unsigned_input(owned_output)?);
         }
 
         Ok((inputs, total_input_amount))
     }
 
-    // Constructs Outputs for the transaction by given Recipient address, output index and amount
+    // Key image the signed input will reveal, known before signing so it can seed the output keys
+    pub fn input_key_image(&self, input: &UnsignedInput) -> Result<Vec<u8>, CryptoOpsError> {
+        self.output_key_image(&input.stealth, &input.output_key, input.output_index)
+    }
+
+    // Fills the transaction's inputs with the rings and key images, then signs each of them over transaction_signing_hash
+    pub fn sign_transaction(
+        &self,
+        inputs: &[UnsignedInput],
+        mut transaction: Transaction,
+    ) -> Result<Transaction, ChainOpsError> {
+        transaction.msg_inputs = inputs
+            .iter()
+            .map(|input| {
+                Ok(TransactionInput {
+                    msg_ring: input.ring.clone(),
+                    msg_key_image: self.input_key_image(input)?,
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<Vec<_>, CryptoOpsError>>()?;
+        let message = transaction_signing_hash(&transaction);
+        transaction.msg_inputs = inputs
+            .iter()
+            .map(|input| self.sign_input(input, &message))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(transaction)
+    }
+
+    // Signs the ring of an unsigned input with the one-time private key of the output it spends
+    pub fn sign_input(
+        &self,
+        input: &UnsignedInput,
+        message: &[u8],
+    ) -> Result<TransactionInput, ChainOpsError> {
+        let compressed_stealth = CompressedRistretto::from_slice(&input.stealth);
+        let output_key = CompressedRistretto::from_slice(&input.output_key);
+        let secret_key = self.output_private_key(output_key, input.output_index)?;
+        let s_addrs: Vec<CompressedRistretto> = input
+            .ring
+            .iter()
+            .map(|key| CompressedRistretto::from_slice(key))
+            .collect();
+        let blsag = self.gen_blsag(&s_addrs, message, &compressed_stealth, &secret_key)?;
+        let image = blsag.i;
+
+        Ok(TransactionInput {
+            msg_ring: input.ring.clone(),
+            msg_blsag: blsag.to_vec(),
+            msg_message: message.to_vec(),
+            msg_key_image: image.to_bytes().to_vec(),
+        })
+    }
+
+    // Signs every input of a transaction template built without the spend key, e.g. by a view-only wallet
+    pub fn sign_unsigned(
+        &self,
+        unsigned: &UnsignedTransaction,
+    ) -> Result<Transaction, ChainOpsError> {
+        self.sign_transaction(
+            &unsigned.inputs,
+            Transaction {
+                msg_inputs: vec![],
+                msg_outputs: unsigned.outputs.clone(),
+                msg_contract: None,
+                msg_fee: unsigned.fee,
+                msg_minted: 0,
+                msg_version: TRANSACTION_VERSION,
+                msg_contract_hash: vec![],
+            },
+        )
+    }
+
+    // Constructs Outputs for the transaction by given Recipient address, output index, amount and optional memo
     pub fn prepare_output(
         &self,
         recipient_address: &str,
         output_index: u32,
         amount: u64,
+        memo: Option<&[u8]>,
+    ) -> Result<TransactionOutput, ChainOpsError> {
+        let r = Scalar::random(&mut rand::thread_rng());
+        self.prepare_output_with_key(recipient_address, output_index, amount, memo, &r)
+    }
+
+    // Same as prepare_output with a caller-chosen transaction private key r, which the sender can keep for output proofs
+    pub fn prepare_output_with_key(
+        &self,
+        recipient_address: &str,
+        output_index: u32,
+        amount: u64,
+        memo: Option<&[u8]>,
+        r: &Scalar,
     ) -> Result<TransactionOutput, ChainOpsError> {
         let (recipient_spend_key, recipient_view_key) =
             derive_keys_from_address(recipient_address).unwrap();
-        let mut rng = rand::thread_rng();
-        let r = Scalar::random(&mut rng);
-        let output_key = (&r * &constants::RISTRETTO_BASEPOINT_TABLE).compress();
-        let recipient_view_key_point = recipient_view_key.decompress().unwrap();
-        let q = r * recipient_view_key_point;
-        let q_bytes = q.compress().to_bytes();
-        let hash = hash!(q_bytes, output_index.to_le_bytes());
-        let hash_in_scalar = Scalar::from_bytes_mod_order(hash.into());
-        let hs_times_g = &constants::RISTRETTO_BASEPOINT_TABLE * &hash_in_scalar;
-        let recipient_spend_key_point = recipient_spend_key.decompress().unwrap();
-        let stealth = (hs_times_g + recipient_spend_key_point).compress();
-        let encrypted_amount = self.encrypt_amount(&q_bytes, output_index, amount)?;
-        let pc_gens = PedersenGens::default();
-        let bp_gens = BulletproofGens::new(64, 1);
-        let blinding = Scalar::random(&mut rand::thread_rng());
-        let mut prover_transcript = Transcript::new(b"Transaction");
-        let secret = amount;
-        let (proof, commitment) = RangeProof::prove_single(
-            &bp_gens,
-            &pc_gens,
-            &mut prover_transcript,
-            secret,
-            &blinding,
-            32,
+        build_output(
+            &recipient_spend_key,
+            &recipient_view_key,
+            output_index,
+            amount,
+            memo.unwrap_or_default(),
+            r,
         )
-        .unwrap();
-
-        Ok(TransactionOutput {
-            msg_stealth_address: stealth.to_bytes().to_vec(),
-            msg_output_key: output_key.to_bytes().to_vec(),
-            msg_proof: proof.to_bytes().to_vec(),
-            msg_commitment: commitment.to_bytes().to_vec(),
-            msg_amount: encrypted_amount.to_vec(),
-            msg_index: output_index,
-        })
     }
 
     // Constructs change output in case the sum of inputs exceeds the amount we want to spend
@@ -236,41 +662,59 @@ impl Wallet {
         change: u64,
         output_index: u32,
     ) -> Result<TransactionOutput, ChainOpsError> {
-        let mut rng = rand::thread_rng();
-        let r = Scalar::random(&mut rng);
-        let output_key = (&r * &constants::RISTRETTO_BASEPOINT_TABLE).compress();
-        let view_key_point = self.public_view_key.decompress().unwrap();
-        let q = r * view_key_point;
-        let q_bytes = q.compress().to_bytes();
-        let hash = hash!(q_bytes, output_index.to_le_bytes());
-        let hash_in_scalar = Scalar::from_bytes_mod_order(hash.into());
-        let hs_times_g = &constants::RISTRETTO_BASEPOINT_TABLE * &hash_in_scalar;
-        let spend_key_point = self.public_spend_key.decompress().unwrap();
-        let stealth = (hs_times_g + spend_key_point).compress();
-        let encrypted_amount = self.encrypt_amount(&q_bytes, output_index, change)?;
-        let pc_gens = PedersenGens::default();
-        let bp_gens = BulletproofGens::new(64, 1);
-        let blinding = Scalar::random(&mut rand::thread_rng());
-        let mut prover_transcript = Transcript::new(b"Transaction");
-        let secret = change;
-        let (proof, commitment) = RangeProof::prove_single(
-            &bp_gens,
-            &pc_gens,
-            &mut prover_transcript,
-            secret,
-            &blinding,
-            32,
+        let r = Scalar::random(&mut rand::thread_rng());
+        self.prepare_change_output_with_key(change, output_index, &r)
+    }
+
+    pub fn prepare_change_output_with_key(
+        &self,
+        change: u64,
+        output_index: u32,
+        r: &Scalar,
+    ) -> Result<TransactionOutput, ChainOpsError> {
+        build_output(
+            &self.public_spend_key,
+            &self.public_view_key,
+            output_index,
+            change,
+            &[],
+            r,
         )
-        .unwrap();
+    }
 
-        Ok(TransactionOutput {
-            msg_stealth_address: stealth.to_bytes().to_vec(),
-            msg_output_key: output_key.to_bytes().to_vec(),
-            msg_proof: proof.to_bytes().to_vec(),
-            msg_commitment: commitment.to_bytes().to_vec(),
-            msg_amount: encrypted_amount.to_vec(),
-            msg_index: output_index,
-        })
+    // A change output derived for the wrong index would be invisible to the sender's scan and burn the change,
+    // so it is checked against check_property before the transaction leaves the wallet
+    pub fn verify_change_spendable(
+        &self,
+        change: &TransactionOutput,
+        output_index: u32,
+    ) -> Result<(), CryptoOpsError> {
+        let output_key = CompressedRistretto::from_slice(&change.msg_output_key);
+        let stealth = CompressedRistretto::from_slice(&change.msg_stealth_address);
+        if !self.check_property(output_key, output_index, stealth)? {
+            return Err(CryptoOpsError::UnspendableChange(output_index));
+        }
+        Ok(())
+    }
+
+    // Transaction private key r = Hs(secret_spend_key || tx_nonce || i), unique per output without runtime entropy
+    // as long as tx_nonce is unique per transaction
+    pub fn deterministic_r(&self, tx_nonce: &[u8], output_index: u32) -> Scalar {
+        let hash = hash!(
+            self.secret_spend_key.as_bytes(),
+            tx_nonce,
+            output_index.to_le_bytes()
+        );
+        Scalar::from_bytes_mod_order(hash.into())
+    }
+
+    // Reveals the transaction private key r so a third party can check what an output pays and to whom
+    pub fn output_proof(&self, r: Scalar, recipient_address: &str, amount: u64) -> OutputProof {
+        OutputProof {
+            r,
+            recipient_address: recipient_address.to_string(),
+            amount,
+        }
     }
 
     pub fn encrypt_amount(
@@ -279,17 +723,7 @@ impl Wallet {
         output_index: u32,
         amount: u64,
     ) -> Result<[u8; 8], CryptoOpsError> {
-        let hash_qi = hash!(q_bytes, output_index.to_le_bytes());
-        let hash = hash!(b"amount", hash_qi);
-        let hash_8: [u8; 8] = hash[0..8]
-            .try_into()
-            .map_err(|_| CryptoOpsError::TryIntoError)?;
-        let amount_in_scalars = Scalar::from(amount).to_bytes();
-        let amount_in_scalars_8 = amount_in_scalars[0..8]
-            .try_into()
-            .map_err(|_| CryptoOpsError::TryIntoError)?;
-
-        Ok(xor8(amount_in_scalars_8, hash_8))
+        encrypt_amount(q_bytes, output_index, amount)
     }
 
     pub fn decrypt_amount(
@@ -298,6 +732,9 @@ impl Wallet {
         output_index: u32,
         encrypted_amount: &[u8],
     ) -> Result<u64, CryptoOpsError> {
+        if encrypted_amount.len() != ENCRYPTED_AMOUNT_LEN {
+            return Err(CryptoOpsError::TryIntoError);
+        }
         let decompressed_output = output_key
             .decompress()
             .ok_or(CryptoOpsError::DecompressionFailed)?;
@@ -316,12 +753,34 @@ impl Wallet {
         Ok(u64::from_le_bytes(decrypted_amount))
     }
 
-    // Complete Back’s Linkable Spontaneous Anonymous Group signature
+    // Encrypts the memo (payment id) with the keystream derived from the shared secret
+    pub fn encrypt_memo(&self, q_bytes: &[u8], output_index: u32, memo: &[u8]) -> Vec<u8> {
+        encrypt_memo(q_bytes, output_index, memo)
+    }
+
+    pub fn decrypt_memo(
+        &self,
+        output_key: CompressedRistretto,
+        output_index: u32,
+        encrypted_memo: &[u8],
+    ) -> Result<Vec<u8>, CryptoOpsError> {
+        let decompressed_output = output_key
+            .decompress()
+            .ok_or(CryptoOpsError::DecompressionFailed)?;
+        let q = self.secret_view_key * decompressed_output;
+        let q_bytes = q.compress().as_bytes().to_vec();
+        let hash_qi = hash!(q_bytes, output_index.to_le_bytes());
+
+        Ok(xor_keystream(encrypted_memo, &hash_qi))
+    }
+
+    // Complete Back’s Linkable Spontaneous Anonymous Group signature, signed with the stealth output's private key
     pub fn gen_blsag(
         &self,
         p: &[CompressedRistretto],
         m: &[u8],
         stealth: &CompressedRistretto,
+        secret_key: &Scalar,
     ) -> Result<BLSAGSignature, CryptoOpsError> {
         let a = Scalar::random(&mut rand::thread_rng());
         let n = p.len();
@@ -329,14 +788,14 @@ impl Wallet {
         let mut s: Vec<Scalar> = vec![Scalar::zero(); n];
         let mut l: Vec<RistrettoPoint> = vec![RistrettoPoint::identity(); n];
         let mut r: Vec<RistrettoPoint> = vec![RistrettoPoint::identity(); n];
-        let mut j = 0;
-        for (i, rk) in p.iter().enumerate() {
-            if stealth == rk {
-                j = i;
-                break;
-            }
+        if (&constants::RISTRETTO_BASEPOINT_TABLE * secret_key).compress() != *stealth {
+            return Err(CryptoOpsError::KeyNotInRing);
         }
-        let image = (self.secret_spend_key * hash_to_point(&p[j])).compress();
+        let j = p
+            .iter()
+            .position(|rk| rk == stealth)
+            .ok_or(CryptoOpsError::KeyNotInRing)?;
+        let image = (secret_key * hash_to_point(&p[j])).compress();
         for (i, item) in s.iter_mut().enumerate().take(n) {
             if i == j {
                 continue;
@@ -364,61 +823,454 @@ impl Wallet {
             let hash = hash!(m, l[i].compress().to_bytes(), r[i].compress().to_bytes());
             c[ip1] = Scalar::from_bytes_mod_order(hash.into());
         }
-        s[j] = a - c[j] * self.secret_spend_key;
+        s[j] = a - c[j] * secret_key;
 
-        Ok(BLSAGSignature {
-            i: image,
-            c: c[0],
-            s,
-        })
+        Ok(BLSAGSignature::new(image, c[0], s))
     }
 }
 
-pub fn verify_blsag(sig: &BLSAGSignature, p: &[CompressedRistretto], m: &[u8]) -> bool {
-    let n = p.len();
-    let c1 = sig.c;
-    let s = sig.s.clone();
-    let image = sig.i;
-    let mut l: Vec<RistrettoPoint> = vec![RistrettoPoint::identity(); n];
-    let mut r: Vec<RistrettoPoint> = vec![RistrettoPoint::identity(); n];
-    let mut c: Vec<Scalar> = vec![Scalar::zero(); n];
-    c[0] = c1;
-    for j in 0..n {
-        let i = j % n;
-        let ip1 = (j + 1) % n;
-        l[i] = s[i] * constants::RISTRETTO_BASEPOINT_POINT + c[i] * p[i].decompress().unwrap();
-        r[i] = s[i] * hash_to_point(&p[i]) + c[i] * image.decompress().unwrap();
-        let hash = hash!(m, l[i].compress().to_bytes(), r[i].compress().to_bytes());
-        c[ip1] = Scalar::from_bytes_mod_order(hash.into());
-    }
+// Stealth output paying amount to the given public keys, with its range proof and encrypted amount and memo
+pub fn build_output(
+    spend_key: &CompressedRistretto,
+    view_key: &CompressedRistretto,
+    output_index: u32,
+    amount: u64,
+    memo: &[u8],
+    r: &Scalar,
+) -> Result<TransactionOutput, ChainOpsError> {
+    let (stealth, output_key) = compute_stealth(r, spend_key, view_key, output_index)?;
+    let q_bytes = shared_secret(r, view_key)?;
+    let encrypted_amount = encrypt_amount(&q_bytes, output_index, amount)?;
+    let encrypted_memo = encrypt_memo(&q_bytes, output_index, memo);
+    let pc_gens = PedersenGens::default();
+    let bp_gens = BulletproofGens::new(64, 1);
+    // A burn has to show its amount to everyone, so its commitment is blinded with a value anyone can derive
+    let blinding = if *spend_key == burn_keys().public_spend_key {
+        burn_blinding(output_key.as_bytes(), output_index)
+    } else {
+        Scalar::random(&mut rand::thread_rng())
+    };
+    let mut prover_transcript = Transcript::new(b"Transaction");
+    let secret = amount;
+    let (proof, commitment) = RangeProof::prove_single(
+        &bp_gens,
+        &pc_gens,
+        &mut prover_transcript,
+        secret,
+        &blinding,
+        RANGE_PROOF_BITS,
+    )
+    .unwrap();
+
+    Ok(TransactionOutput {
+        msg_stealth_address: stealth.to_bytes().to_vec(),
+        msg_output_key: output_key.to_bytes().to_vec(),
+        msg_proof: proof.to_bytes().to_vec(),
+        msg_commitment: commitment.to_bytes().to_vec(),
+        msg_amount: encrypted_amount.to_vec(),
+        msg_index: output_index,
+        msg_memo: encrypted_memo,
+    })
+}
 
-    if c1 == c[0] {
-        return true;
-    }
-    false
+// Shared secret q = r*V between the sender's transaction key and the recipient's view key
+pub fn shared_secret(
+    r: &Scalar,
+    recipient_view: &CompressedRistretto,
+) -> Result<[u8; 32], CryptoOpsError> {
+    let view_key_point = recipient_view
+        .decompress()
+        .ok_or(CryptoOpsError::DecompressionFailed)?;
+    Ok((r * view_key_point).compress().to_bytes())
 }
 
-impl Wallet {
-    pub fn to_vec(&self) -> Vec<u8> {
-        let mut v = Vec::new();
-        v.extend_from_slice(self.secret_spend_key.as_bytes());
-        v.extend_from_slice(self.secret_view_key.as_bytes());
-        v.extend_from_slice(self.public_spend_key.as_bytes());
-        v.extend_from_slice(self.public_view_key.as_bytes());
-        v.extend(&self.address);
+// Stealth address Hs(r*V || i)*G + S and output key r*G of output i paying the recipient's public keys
+pub fn compute_stealth(
+    r: &Scalar,
+    recipient_spend: &CompressedRistretto,
+    recipient_view: &CompressedRistretto,
+    index: u32,
+) -> Result<(CompressedRistretto, CompressedRistretto), CryptoOpsError> {
+    let q_bytes = shared_secret(r, recipient_view)?;
+    let hash = hash!(q_bytes, index.to_le_bytes());
+    let hash_in_scalar = Scalar::from_bytes_mod_order(hash.into());
+    let spend_key_point = recipient_spend
+        .decompress()
+        .ok_or(CryptoOpsError::DecompressionFailed)?;
+    let stealth =
+        (&constants::RISTRETTO_BASEPOINT_TABLE * &hash_in_scalar + spend_key_point).compress();
+    let output_key = (r * &constants::RISTRETTO_BASEPOINT_TABLE).compress();
+    Ok((stealth, output_key))
+}
 
-        v
-    }
+// Secret view key Keccak256("view" || secret_spend_key), so the two keys aren't the same hash chain
+pub fn derive_view_key(secret_spend_key: &Scalar) -> Scalar {
+    let hashed_key = hash!(VIEW_KEY_DOMAIN, secret_spend_key.as_bytes());
+    Scalar::from_bytes_mod_order(hashed_key.into())
+}
 
-    pub fn from_vec(v: &[u8]) -> Result<Wallet, CryptoOpsError> {
-        if v.len() < 160 {
-            return Err(CryptoOpsError::InvalidVecLength);
-        }
+pub fn derive_identity_key(secret_spend_key: &Scalar) -> Scalar {
+    let hashed_key = hash!(IDENTITY_KEY_DOMAIN, secret_spend_key.as_bytes());
+    Scalar::from_bytes_mod_order(hashed_key.into())
+}
 
-        let secret_spend_key = Scalar::from_canonical_bytes(
-            v[0..32]
-                .try_into()
-                .map_err(|_| CryptoOpsError::TryIntoError)?,
+pub fn identity_certificate_message(identity_key: &CompressedRistretto) -> Vec<u8> {
+    [IDENTITY_CERTIFICATE_DOMAIN, identity_key.as_bytes()].concat()
+}
+
+// Whether the identity key was certified by the spend key of the address
+pub fn verify_identity_key(
+    public_spend_key: &CompressedRistretto,
+    identity_key: &CompressedRistretto,
+    certificate: &Signature,
+) -> bool {
+    verify(
+        public_spend_key,
+        &identity_certificate_message(identity_key),
+        certificate,
+    )
+}
+
+// Keys of the canonical burn address: the spend key is hashed to the curve so nobody knows its discrete log
+// and can spend from it, the view key is public so anyone can recognise burnt outputs and read their amounts
+pub fn burn_keys() -> ViewOnlyWallet {
+    let mut uniform = [0u8; 64];
+    uniform[..32].copy_from_slice(&hash!(BURN_SPEND_DOMAIN, [0u8]));
+    uniform[32..].copy_from_slice(&hash!(BURN_SPEND_DOMAIN, [1u8]));
+    ViewOnlyWallet {
+        public_spend_key: RistrettoPoint::from_uniform_bytes(&uniform).compress(),
+        secret_view_key: Scalar::from_bytes_mod_order(hash!(BURN_VIEW_DOMAIN).into()),
+    }
+}
+
+// Address outputs are paid to in order to provably take their amount out of circulation
+pub fn burn_address() -> String {
+    let keys = burn_keys();
+    let public_view_key = &constants::RISTRETTO_BASEPOINT_TABLE * &keys.secret_view_key;
+    let data = [
+        keys.public_spend_key.to_bytes(),
+        public_view_key.compress().to_bytes(),
+    ]
+    .concat();
+    bs58::encode(data).into_string()
+}
+
+// Blinding of the commitment of a burn output, derived from public parts of the output
+pub fn burn_blinding(output_key: &[u8], output_index: u32) -> Scalar {
+    Scalar::from_bytes_mod_order(
+        hash!(BURN_BLINDING_DOMAIN, output_key, output_index.to_le_bytes()).into(),
+    )
+}
+
+// Amount the output burns if it pays to the burn address. The encrypted amount is only a claim,
+// it counts once the commitment opens to it under the public burn blinding
+pub fn burned_amount(output: &TransactionOutput) -> Result<Option<u64>, CryptoOpsError> {
+    let amount = match burn_keys().scan_output(output)? {
+        Some(amount) => amount,
+        None => return Ok(None),
+    };
+    let blinding = burn_blinding(&output.msg_output_key, output.msg_index);
+    let opened = PedersenGens::default()
+        .commit(Scalar::from(amount), blinding)
+        .compress();
+    if opened.as_bytes()[..] != output.msg_commitment[..] {
+        return Err(CryptoOpsError::UnopenedBurnCommitment);
+    }
+    Ok(Some(amount))
+}
+
+// Secret view key Keccak256(secret_spend_key) of wallets created before derive_view_key
+pub fn legacy_view_key(secret_spend_key: &Scalar) -> Scalar {
+    let hashed_key = hash!(secret_spend_key.as_bytes());
+    Scalar::from_bytes_mod_order(hashed_key.into())
+}
+
+// A zero key makes the public spend key the identity, which anyone can spend from
+fn check_secret_spend_key(secret_spend_key: &Scalar) -> Result<(), CryptoOpsError> {
+    if *secret_spend_key == Scalar::zero() || !secret_spend_key.is_canonical() {
+        return Err(CryptoOpsError::InvalidSecretSpendKey);
+    }
+    Ok(())
+}
+
+pub fn encrypt_amount(
+    q_bytes: &[u8],
+    output_index: u32,
+    amount: u64,
+) -> Result<[u8; 8], CryptoOpsError> {
+    let hash_qi = hash!(q_bytes, output_index.to_le_bytes());
+    let hash = hash!(b"amount", hash_qi);
+    let hash_8: [u8; 8] = hash[0..8]
+        .try_into()
+        .map_err(|_| CryptoOpsError::TryIntoError)?;
+    let amount_in_scalars = Scalar::from(amount).to_bytes();
+    let amount_in_scalars_8 = amount_in_scalars[0..8]
+        .try_into()
+        .map_err(|_| CryptoOpsError::TryIntoError)?;
+
+    Ok(xor8(amount_in_scalars_8, hash_8))
+}
+
+// Encrypts the memo (payment id) with the keystream derived from the shared secret
+pub fn encrypt_memo(q_bytes: &[u8], output_index: u32, memo: &[u8]) -> Vec<u8> {
+    let hash_qi = hash!(q_bytes, output_index.to_le_bytes());
+    xor_keystream(memo, &hash_qi)
+}
+
+// Canonical serialization of a transaction used for hashing and signing, in the layout of the header's:
+// fields in a fixed order, integers big-endian, byte strings and lists prefixed with their u32 length
+// and optional messages prefixed with a presence byte
+pub fn canonical_transaction_bytes(transaction: &Transaction) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    put_len(&mut bytes, transaction.msg_inputs.len());
+    for input in &transaction.msg_inputs {
+        put_len(&mut bytes, input.msg_ring.len());
+        for member in &input.msg_ring {
+            put_bytes(&mut bytes, member);
+        }
+        put_bytes(&mut bytes, &input.msg_blsag);
+        put_bytes(&mut bytes, &input.msg_message);
+        put_bytes(&mut bytes, &input.msg_key_image);
+    }
+    put_len(&mut bytes, transaction.msg_outputs.len());
+    for output in &transaction.msg_outputs {
+        put_bytes(&mut bytes, &output.msg_stealth_address);
+        put_bytes(&mut bytes, &output.msg_output_key);
+        put_bytes(&mut bytes, &output.msg_proof);
+        put_bytes(&mut bytes, &output.msg_commitment);
+        put_bytes(&mut bytes, &output.msg_amount);
+        bytes.extend_from_slice(&output.msg_index.to_be_bytes());
+        put_bytes(&mut bytes, &output.msg_memo);
+    }
+    match &transaction.msg_contract {
+        Some(contract) => {
+            bytes.push(1);
+            put_bytes(&mut bytes, &contract.msg_code);
+        }
+        None => bytes.push(0),
+    }
+    bytes.extend_from_slice(&transaction.msg_fee.to_be_bytes());
+    bytes.extend_from_slice(&transaction.msg_minted.to_be_bytes());
+    bytes.extend_from_slice(&transaction.msg_version.to_be_bytes());
+    // Always written, even empty, so no other trailing field can be mistaken for it
+    put_bytes(&mut bytes, &transaction.msg_contract_hash);
+    bytes
+}
+
+pub fn put_len(bytes: &mut Vec<u8>, len: usize) {
+    bytes.extend_from_slice(&(len as u32).to_be_bytes());
+}
+
+pub fn put_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    put_len(bytes, data.len());
+    bytes.extend_from_slice(data);
+}
+
+// Message every input signs: the hash of the transaction with all BLSAGs and messages left empty,
+// so a signature only verifies for these rings, key images, outputs and fee
+pub fn transaction_signing_hash(transaction: &Transaction) -> Vec<u8> {
+    let mut stripped = transaction.clone();
+    for input in &mut stripped.msg_inputs {
+        input.msg_blsag.clear();
+        input.msg_message.clear();
+    }
+    hash!(canonical_transaction_bytes(&stripped)).to_vec()
+}
+
+// Places the owned output's stealth address in a shuffled ring of decoys, ready to be signed
+pub fn unsigned_input(owned_output: &OwnedOutput) -> Result<UnsignedInput, CryptoOpsError> {
+    let compressed_stealth = CompressedRistretto::from_slice(&owned_output.output.stealth);
+    let mut s_addrs = fill_ring(compressed_stealth, decoy_attempts(), || {
+        (1..RING_SIZE)
+            .map(|_| Wallet::generate().map(|wallet| wallet.public_spend_key))
+            .collect()
+    })?;
+    s_addrs.shuffle(&mut rand::thread_rng());
+
+    Ok(UnsignedInput {
+        ring: s_addrs.iter().map(|key| key.to_bytes().to_vec()).collect(),
+        stealth: owned_output.output.stealth.clone(),
+        output_key: owned_output.output.output_key.clone(),
+        output_index: owned_output.output.index,
+    })
+}
+
+// Decoys are redrawn until none of them collides with the real key or another decoy, for at most
+// max_attempts rounds so a source that can't supply enough distinct keys fails instead of spinning forever
+pub fn fill_ring<F>(
+    real: CompressedRistretto,
+    max_attempts: usize,
+    mut decoys: F,
+) -> Result<Vec<CompressedRistretto>, CryptoOpsError>
+where
+    F: FnMut() -> Result<Vec<CompressedRistretto>, CryptoOpsError>,
+{
+    for _ in 0..max_attempts {
+        let mut ring = decoys()?;
+        ring.push(real);
+        if ring.len() == RING_SIZE && !has_duplicate_members(&ring) {
+            return Ok(ring);
+        }
+    }
+    Err(CryptoOpsError::DecoySelectionFailed {
+        attempts: max_attempts,
+    })
+}
+
+// Scans the transactions (included at the given height) for the outputs of several wallets in one parallel pass
+// on the scan pool of the first wallet, each owned output comes with the position of the wallet whose keys it matched
+pub fn scan_transactions_for(
+    wallets: &[&Wallet],
+    transactions: &[Transaction],
+    height: u32,
+) -> Result<Vec<(usize, OwnedOutput)>, CryptoOpsError> {
+    let outputs: Vec<(&TransactionOutput, bool)> = transactions
+        .iter()
+        .enumerate()
+        .flat_map(|(position, transaction)| {
+            let coinbase = is_coinbase(transactions, position);
+            transaction
+                .msg_outputs
+                .iter()
+                .map(move |output| (output, coinbase))
+        })
+        .collect();
+    let Some(first) = wallets.first() else {
+        return Ok(Vec::new());
+    };
+    let scanned = first.scan_pool()?.install(|| {
+        outputs
+            .par_iter()
+            .map(|(output, coinbase)| {
+                for (position, wallet) in wallets.iter().enumerate() {
+                    if height < wallet.scan_from_index {
+                        continue;
+                    }
+                    if let Some(mut owned_output) = wallet.scan_output(output)? {
+                        owned_output.height = height;
+                        owned_output.coinbase = *coinbase;
+                        return Ok(Some((position, owned_output)));
+                    }
+                }
+                Ok(None)
+            })
+            .collect::<Result<Vec<_>, CryptoOpsError>>()
+    })?;
+    Ok(scanned.into_iter().flatten().collect())
+}
+
+// Output key and stealth of an output a scan can check, None if its amount or points are malformed: such an
+// output can't be decrypted and is skipped instead of failing the whole scan
+fn scannable_points(
+    output: &TransactionOutput,
+) -> Option<(CompressedRistretto, CompressedRistretto)> {
+    if output.msg_amount.len() != ENCRYPTED_AMOUNT_LEN
+        || output.msg_output_key.len() != 32
+        || output.msg_stealth_address.len() != 32
+    {
+        return None;
+    }
+    let key = CompressedRistretto::from_slice(&output.msg_output_key);
+    let stealth = CompressedRistretto::from_slice(&output.msg_stealth_address);
+    if key.decompress().is_none() || stealth.decompress().is_none() {
+        return None;
+    }
+    Some((key, stealth))
+}
+
+// A block's coinbase is its first transaction when that one spends no input, no other transaction may mint
+pub fn is_coinbase(transactions: &[Transaction], position: usize) -> bool {
+    position == 0
+        && transactions
+            .first()
+            .is_some_and(|transaction| transaction.msg_inputs.is_empty())
+}
+
+// A ring listing the same key twice weakens the bLSAG anonymity set and can break its verification
+pub fn has_duplicate_members<T: PartialEq>(ring: &[T]) -> bool {
+    ring.iter()
+        .enumerate()
+        .any(|(i, member)| ring[i + 1..].contains(member))
+}
+
+// Errors on a signature that can't be checked against the ring at all: a response count other than the ring size,
+// or a ring member or key image that isn't a valid point
+pub fn verify_blsag(
+    sig: &BLSAGSignature,
+    p: &[CompressedRistretto],
+    m: &[u8],
+) -> Result<bool, CryptoOpsError> {
+    let n = p.len();
+    if n == 0 || sig.s.len() != n {
+        return Err(CryptoOpsError::RingSizeMismatch {
+            ring: n,
+            responses: sig.s.len(),
+        });
+    }
+    let members = p
+        .iter()
+        .map(|member| member.decompress())
+        .collect::<Option<Vec<RistrettoPoint>>>()
+        .ok_or(CryptoOpsError::DecompressionFailed)?;
+    let image = sig
+        .i
+        .decompress()
+        .ok_or(CryptoOpsError::DecompressionFailed)?;
+    let c1 = sig.c;
+    let s = &sig.s;
+    let mut l: Vec<RistrettoPoint> = vec![RistrettoPoint::identity(); n];
+    let mut r: Vec<RistrettoPoint> = vec![RistrettoPoint::identity(); n];
+    let mut c: Vec<Scalar> = vec![Scalar::zero(); n];
+    c[0] = c1;
+    for j in 0..n {
+        let i = j % n;
+        let ip1 = (j + 1) % n;
+        l[i] = s[i] * constants::RISTRETTO_BASEPOINT_POINT + c[i] * members[i];
+        r[i] = s[i] * hash_to_point(&p[i]) + c[i] * image;
+        let hash = hash!(m, l[i].compress().to_bytes(), r[i].compress().to_bytes());
+        c[ip1] = Scalar::from_bytes_mod_order(hash.into());
+    }
+
+    Ok(c1 == c[0])
+}
+
+impl Wallet {
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.extend_from_slice(self.secret_spend_key.as_bytes());
+        v.extend_from_slice(self.secret_view_key.as_bytes());
+        v.extend_from_slice(self.public_spend_key.as_bytes());
+        v.extend_from_slice(self.public_view_key.as_bytes());
+        v.extend(&self.address);
+        v.extend_from_slice(&self.scan_from_index.to_le_bytes());
+        v.extend_from_slice(&self.birthday.load(Ordering::Relaxed).to_le_bytes());
+
+        v
+    }
+
+    // Accepts the current encoding and the ones from before the creation height and the birthday were stored
+    pub fn from_vec(v: &[u8]) -> Result<Wallet, CryptoOpsError> {
+        let trailing_u32 = |offset: usize| -> Result<u32, CryptoOpsError> {
+            Ok(u32::from_le_bytes(
+                v[offset..offset + 4]
+                    .try_into()
+                    .map_err(|_| CryptoOpsError::TryIntoError)?,
+            ))
+        };
+        let (scan_from_index, birthday) = match v.len() {
+            WALLET_VEC_LEN => (0, 0),
+            len if len == WALLET_VEC_LEN + 4 => (trailing_u32(WALLET_VEC_LEN)?, 0),
+            len if len == WALLET_VEC_LEN + 8 => (
+                trailing_u32(WALLET_VEC_LEN)?,
+                trailing_u32(WALLET_VEC_LEN + 4)?,
+            ),
+            _ => return Err(CryptoOpsError::InvalidVecLength),
+        };
+
+        let secret_spend_key = Scalar::from_canonical_bytes(
+            v[0..32]
+                .try_into()
+                .map_err(|_| CryptoOpsError::TryIntoError)?,
         )
         .ok_or(CryptoOpsError::DecompressionFailed)?;
 
@@ -431,7 +1283,9 @@ impl Wallet {
 
         let public_spend_key = CompressedRistretto::from_slice(&v[64..96]);
         let public_view_key = CompressedRistretto::from_slice(&v[96..128]);
-        let address = v[128..].try_into().unwrap();
+        let address = v[128..WALLET_VEC_LEN]
+            .try_into()
+            .map_err(|_| CryptoOpsError::TryIntoError)?;
 
         Ok(Wallet {
             secret_spend_key,
@@ -439,9 +1293,47 @@ impl Wallet {
             public_spend_key,
             public_view_key,
             address,
+            scan_from_index,
+            birthday: Arc::new(AtomicU32::new(birthday)),
+            scan_pool: Arc::new(ScanPool::new(scan_threads())),
         })
     }
 
+    // Version byte, secret spend key and a 4-byte Keccak checksum, the rest of the wallet is derived from the key
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(COMPACT_WALLET_LEN);
+        v.push(if self.is_legacy() {
+            LEGACY_COMPACT_WALLET_VERSION
+        } else {
+            COMPACT_WALLET_VERSION
+        });
+        v.extend_from_slice(self.secret_spend_key.as_bytes());
+        let checksum = hash!(&v);
+        v.extend_from_slice(&checksum[..4]);
+
+        v
+    }
+
+    pub fn from_compact(v: &[u8]) -> Result<Wallet, CryptoOpsError> {
+        if v.len() != COMPACT_WALLET_LEN {
+            return Err(CryptoOpsError::InvalidVecLength);
+        }
+        if v[0] != COMPACT_WALLET_VERSION && v[0] != LEGACY_COMPACT_WALLET_VERSION {
+            return Err(CryptoOpsError::UnsupportedWalletVersion(v[0]));
+        }
+        let checksum = hash!(&v[..33]);
+        if checksum[..4] != v[33..] {
+            return Err(CryptoOpsError::InvalidChecksum);
+        }
+        let secret_spend_key = Wallet::secret_spend_key_from_vec(&v[1..33])?;
+
+        if v[0] == LEGACY_COMPACT_WALLET_VERSION {
+            Wallet::reconstruct_legacy(secret_spend_key)
+        } else {
+            Wallet::reconstruct(secret_spend_key)
+        }
+    }
+
     pub fn secret_spend_key_to_vec(&self) -> Vec<u8> {
         self.secret_spend_key.as_bytes().to_vec()
     }
@@ -487,6 +1379,8 @@ pub struct SerializableWallet {
     public_spend_key: [u8; 32],
     public_view_key: [u8; 32],
     address: [u8; 64],
+    scan_from_index: u32,
+    birthday: u32,
 }
 
 impl Wallet {
@@ -497,6 +1391,8 @@ impl Wallet {
             public_spend_key: self.public_spend_key.to_bytes(),
             public_view_key: self.public_view_key.to_bytes(),
             address: self.address,
+            scan_from_index: self.scan_from_index,
+            birthday: self.birthday.load(Ordering::Relaxed),
         }
     }
 
@@ -507,11 +1403,20 @@ impl Wallet {
             public_spend_key: CompressedRistretto::from_slice(&s.public_spend_key),
             public_view_key: CompressedRistretto::from_slice(&s.public_view_key),
             address: s.address,
+            scan_from_index: s.scan_from_index,
+            birthday: Arc::new(AtomicU32::new(s.birthday)),
+            scan_pool: Arc::new(ScanPool::new(scan_threads())),
         }
     }
 }
 
 impl BLSAGSignature {
+    // Assembles a signature from its key image, initial challenge and responses, e.g. to feed the verifier
+    // hand-crafted components
+    pub fn new(i: CompressedRistretto, c: Scalar, s: Vec<Scalar>) -> BLSAGSignature {
+        BLSAGSignature { i, c, s }
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
         let mut v = Vec::new();
         v.extend_from_slice(self.i.as_bytes());
@@ -540,6 +1445,13 @@ impl BLSAGSignature {
                 .try_into()
                 .map_err(|_| CryptoOpsError::TryIntoError)?,
         ) as usize;
+        let expected_len = s_len
+            .checked_mul(32)
+            .and_then(|len| len.checked_add(72))
+            .ok_or(CryptoOpsError::InvalidBLSAGLength)?;
+        if v.len() != expected_len {
+            return Err(CryptoOpsError::InvalidBLSAGLength);
+        }
         let mut s = Vec::new();
         for n in 0..s_len {
             let start = 72 + n * 32;
@@ -554,7 +1466,7 @@ impl BLSAGSignature {
             );
         }
 
-        Ok(BLSAGSignature { i, c, s })
+        Ok(BLSAGSignature::new(i, c, s))
     }
 }
 
@@ -569,6 +1481,80 @@ pub fn derive_keys_from_address(
     Ok((public_spend_key, public_view_key))
 }
 
+// Recomputes the output's stealth address and amount from r and the recipient's public keys only
+pub fn verify_output_proof(proof: &OutputProof, output: &TransactionOutput) -> bool {
+    let data = match bs58::decode(&proof.recipient_address).into_vec() {
+        Ok(data) if data.len() == 64 => data,
+        _ => return false,
+    };
+    let spend_key = CompressedRistretto::from_slice(&data[..32]);
+    let view_key = CompressedRistretto::from_slice(&data[32..]);
+    let (stealth, output_key) =
+        match compute_stealth(&proof.r, &spend_key, &view_key, output.msg_index) {
+            Ok(keys) => keys,
+            Err(_) => return false,
+        };
+    if output_key.as_bytes()[..] != output.msg_output_key[..]
+        || stealth.as_bytes()[..] != output.msg_stealth_address[..]
+    {
+        return false;
+    }
+    let q_bytes = match shared_secret(&proof.r, &view_key) {
+        Ok(q_bytes) => q_bytes,
+        Err(_) => return false,
+    };
+    let hash_qi = hash!(q_bytes, output.msg_index.to_le_bytes());
+    let hash = hash!(b"amount", hash_qi);
+    let (key, encrypted) = match (hash[0..8].try_into(), output.msg_amount[..].try_into()) {
+        (Ok(key), Ok(encrypted)) => (key, encrypted),
+        _ => return false,
+    };
+
+    u64::from_le_bytes(xor8(encrypted, key)) == proof.amount
+}
+
+// Serialized length of a wallet-built transaction with the given dimensions (outputs indexed 1..=n),
+// memos, contract and fee are not included
+pub fn estimate_tx_weight(num_inputs: usize, ring_size: usize, num_outputs: usize) -> usize {
+    // One byte tag, length varint and payload of a non-empty bytes or message field
+    let field = |len: usize| 1 + encoded_len_varint(len as u64) + len;
+    let blsag_len = 32 + 32 + 8 + 32 * ring_size;
+    let input_len = ring_size * field(32) + field(blsag_len) + field(32) + field(32);
+    let proof_len = 9 * 32 + 2 * 32 * RANGE_PROOF_BITS.trailing_zeros() as usize;
+    let outputs_len: usize = (1..=num_outputs)
+        .map(|index| {
+            3 * field(32)
+                + field(proof_len)
+                + field(ENCRYPTED_AMOUNT_LEN)
+                + 1
+                + encoded_len_varint(index as u64)
+        })
+        .map(field)
+        .sum();
+
+    num_inputs * field(input_len) + outputs_len
+}
+
+// Bit length a serialized bulletproof range proof was created for, its size is 9*32 + 2*32*log2(bits) bytes
+pub fn range_proof_bits(proof: &[u8]) -> Option<usize> {
+    let rounds_bytes = proof.len().checked_sub(9 * 32)?;
+    if rounds_bytes % 64 != 0 || rounds_bytes / 64 >= usize::BITS as usize {
+        return None;
+    }
+    Some(1 << (rounds_bytes / 64))
+}
+
+// Decompresses a point received from a peer, rejecting bad encodings and the identity,
+// e.g. an identity output key would make the shared secret r*V = v*R public
+pub fn decompress_non_identity(bytes: &[u8]) -> Option<RistrettoPoint> {
+    if bytes.len() != 32 {
+        return None;
+    }
+    CompressedRistretto::from_slice(bytes)
+        .decompress()
+        .filter(|point| !point.is_identity())
+}
+
 pub fn hash_to_point(point: &CompressedRistretto) -> RistrettoPoint {
     let hash = hash!(point.to_bytes());
     let scalar = Scalar::from_bytes_mod_order(hash.into());
@@ -585,6 +1571,20 @@ pub fn xor8(a: [u8; 8], b: [u8; 8]) -> [u8; 8] {
     c
 }
 
+pub fn xor_keystream(data: &[u8], seed: &[u8]) -> Vec<u8> {
+    data.chunks(32)
+        .enumerate()
+        .flat_map(|(i, chunk)| {
+            let key = hash!(b"memo", seed, (i as u32).to_le_bytes());
+            chunk
+                .iter()
+                .zip(key)
+                .map(|(byte, key_byte)| byte ^ key_byte)
+                .collect::<Vec<u8>>()
+        })
+        .collect()
+}
+
 pub fn vec_to_string(v: &Vec<u8>) -> String {
     bs58::encode(&v).into_string()
 }
@@ -593,17 +1593,33 @@ pub fn string_to_vec(string: &str) -> Vec<u8> {
     bs58::decode(string).into_vec().unwrap()
 }
 
+fn sign_with(secret_key: &Scalar, public_key: &CompressedRistretto, message: &[u8]) -> Signature {
+    let mut rng = rand::thread_rng();
+    let nonce = Scalar::random(&mut rng);
+    let r_ep = &constants::RISTRETTO_BASEPOINT_TABLE * &nonce;
+    let r = r_ep.compress();
+    let h = hash!(r.as_bytes(), public_key.as_bytes(), message);
+    let h_scalar = Scalar::from_bits(h.into());
+    let s = nonce - h_scalar * secret_key;
+
+    Signature { r, s }
+}
+
 pub fn verify(
     public_spend_key: &CompressedRistretto,
     message: &[u8],
     signature: &Signature,
 ) -> bool {
-    let r = signature.r.decompress().unwrap();
-    let public_spend_key_point = public_spend_key;
+    // Keys and signatures may come from the network, so undecodable points fail the check instead of panicking
+    let (r, public_spend_key_point) =
+        match (signature.r.decompress(), public_spend_key.decompress()) {
+            (Some(r), Some(point)) => (r, point),
+            _ => return false,
+        };
     let hash = hash!(signature.r.to_bytes(), public_spend_key.to_bytes(), message);
     let h_scalar = Scalar::from_bits(hash.into());
-    let r_prime = &constants::RISTRETTO_BASEPOINT_TABLE * &signature.s
-        + public_spend_key_point.decompress().unwrap() * h_scalar;
+    let r_prime =
+        &constants::RISTRETTO_BASEPOINT_TABLE * &signature.s + public_spend_key_point * h_scalar;
 
     r == r_prime
 }
@@ -633,6 +1649,180 @@ impl Signature {
     }
 }
 
+// Checks stealth addresses against a wallet without recomputing its spend point per output,
+// the candidate stealth Hs(q || i)*G + S is compared compressed so the stealth is never decompressed
+pub struct OutputScanner<'a> {
+    wallet: &'a Wallet,
+    view_key: Scalar,
+    spend_point: Option<RistrettoPoint>,
+}
+
+impl OutputScanner<'_> {
+    pub fn check_property(
+        &self,
+        output_key: CompressedRistretto,
+        output_index: u32,
+        stealth: CompressedRistretto,
+    ) -> Result<bool, CryptoOpsError> {
+        let spend_point = self
+            .spend_point
+            .ok_or(CryptoOpsError::DecompressionFailed)?;
+        let decompressed_output = output_key
+            .decompress()
+            .ok_or(CryptoOpsError::DecompressionFailed)?;
+        let q = self.view_key * decompressed_output;
+        let hash = hash!(q.compress().as_bytes(), output_index.to_le_bytes());
+        let hash_scalar = Scalar::from_bytes_mod_order(hash.into());
+        let expected = &constants::RISTRETTO_BASEPOINT_TABLE * &hash_scalar + spend_point;
+
+        Ok(expected.compress() == stealth)
+    }
+
+    // Same contract as Wallet::scan_outputs, on the same pool
+    pub fn scan_outputs(
+        &self,
+        outputs: &[&TransactionOutput],
+    ) -> Result<Vec<OwnedOutput>, CryptoOpsError> {
+        let scanned = self.wallet.scan_pool()?.install(|| {
+            outputs
+                .par_iter()
+                .map(|output| {
+                    let Some((key, stealth)) = scannable_points(output) else {
+                        return Ok(None);
+                    };
+                    // Ownership is settled here, the wallet only decrypts
+                    if self.check_property(key, output.msg_index, stealth)? {
+                        self.wallet.open_output(output, key).map(Some)
+                    } else {
+                        Ok(None)
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        Ok(scanned.into_iter().flatten().collect())
+    }
+}
+
+// Watch-only keys: detect and decrypt outputs paid to an address without being able to spend them
+pub struct ViewOnlyWallet {
+    pub public_spend_key: CompressedRistretto,
+    secret_view_key: Scalar,
+}
+
+impl ViewOnlyWallet {
+    pub fn new(public_spend_key: &[u8], secret_view_key: &[u8]) -> Result<Self, CryptoOpsError> {
+        if public_spend_key.len() != 32 {
+            return Err(CryptoOpsError::InvalidVecLength);
+        }
+        Ok(ViewOnlyWallet {
+            public_spend_key: CompressedRistretto::from_slice(public_spend_key),
+            secret_view_key: Wallet::secret_view_key_from_vec(secret_view_key)?,
+        })
+    }
+
+    // Decrypted amount of the output if it pays to these keys
+    pub fn scan_output(&self, output: &TransactionOutput) -> Result<Option<u64>, CryptoOpsError> {
+        if output.msg_output_key.len() != 32
+            || output.msg_stealth_address.len() != 32
+            || output.msg_amount.len() != ENCRYPTED_AMOUNT_LEN
+        {
+            return Ok(None);
+        }
+        let output_key = match CompressedRistretto::from_slice(&output.msg_output_key).decompress()
+        {
+            Some(output_key) => output_key,
+            None => return Ok(None),
+        };
+        let spend_point = self
+            .public_spend_key
+            .decompress()
+            .ok_or(CryptoOpsError::DecompressionFailed)?;
+        let q_bytes = (self.secret_view_key * output_key).compress().to_bytes();
+        let hash_qi = hash!(q_bytes, output.msg_index.to_le_bytes());
+        let hash_scalar = Scalar::from_bytes_mod_order(hash_qi.into());
+        let stealth =
+            (&constants::RISTRETTO_BASEPOINT_TABLE * &hash_scalar + spend_point).compress();
+        if stealth.as_bytes()[..] != output.msg_stealth_address[..] {
+            return Ok(None);
+        }
+        let hash = hash!(b"amount", hash_qi);
+        let key: [u8; 8] = hash[0..8]
+            .try_into()
+            .map_err(|_| CryptoOpsError::TryIntoError)?;
+        let encrypted: [u8; 8] = output.msg_amount[..]
+            .try_into()
+            .map_err(|_| CryptoOpsError::TryIntoError)?;
+
+        Ok(Some(u64::from_le_bytes(xor8(encrypted, key))))
+    }
+
+    // Transaction template spending owned outputs in order until the payments and fee are covered,
+    // recipients get outputs 1..=n and the change returns to these keys at n+1, the spend key holder signs it
+    pub fn build_unsigned(
+        &self,
+        owned_outputs: &[OwnedOutput],
+        recipients: &[(&str, u64)],
+        fee: u64,
+    ) -> Result<UnsignedTransaction, ChainOpsError> {
+        let needed = recipients
+            .iter()
+            .try_fold(fee, |total, (_, amount)| total.checked_add(*amount))
+            .ok_or(ChainOpsError::AmountOverflow)?;
+        let mut available: u64 = 0;
+        let mut inputs = Vec::new();
+        for owned_output in owned_outputs {
+            if available >= needed {
+                break;
+            }
+            available = available
+                .checked_add(owned_output.decrypted_amount)
+                .ok_or(ChainOpsError::AmountOverflow)?;
+            inputs.push(unsigned_input(owned_output)?);
+        }
+        if inputs.is_empty() || available < needed {
+            return Err(ChainOpsError::InsufficientInputs { available, needed });
+        }
+        let mut outputs = Vec::new();
+        let mut change_index = None;
+        for (output_index, (address, amount)) in (1..).zip(recipients) {
+            let (spend_key, view_key) = derive_keys_from_address(address)
+                .map_err(|_| CryptoOpsError::InvalidAddressString)?;
+            let r = Scalar::random(&mut rand::thread_rng());
+            outputs.push(build_output(
+                &spend_key,
+                &view_key,
+                output_index,
+                *amount,
+                &[],
+                &r,
+            )?);
+        }
+        if available > needed {
+            let view_key =
+                (&constants::RISTRETTO_BASEPOINT_TABLE * &self.secret_view_key).compress();
+            let r = Scalar::random(&mut rand::thread_rng());
+            let output_index = outputs.len() as u32 + 1;
+            outputs.push(build_output(
+                &self.public_spend_key,
+                &view_key,
+                output_index,
+                available - needed,
+                &[],
+                &r,
+            )?);
+            change_index = Some(output_index);
+        }
+
+        Ok(UnsignedTransaction {
+            inputs,
+            outputs,
+            fee,
+            change_index,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -707,6 +1897,35 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_identity_key_is_certified_by_spend_key() {
+        let wallet = Wallet::generate().unwrap();
+        let identity_key = wallet.identity_key();
+        assert_ne!(identity_key, wallet.public_spend_key);
+        let certificate = wallet.identity_certificate().unwrap();
+        assert!(verify_identity_key(
+            &wallet.public_spend_key,
+            &identity_key,
+            &certificate
+        ));
+        let other = Wallet::generate().unwrap();
+        assert!(!verify_identity_key(
+            &other.public_spend_key,
+            &identity_key,
+            &certificate
+        ));
+        assert!(!verify_identity_key(
+            &wallet.public_spend_key,
+            &other.identity_key(),
+            &certificate
+        ));
+
+        // Challenge signatures verify against the identity key, never the spend key
+        let signature = wallet.sign_identity(b"challenge");
+        assert!(verify(&identity_key, b"challenge", &signature));
+        assert!(!verify(&wallet.public_spend_key, b"challenge", &signature));
+    }
+
     #[test]
     fn test_wallet_reconstruction() {
         let wallet = Wallet::generate().unwrap();
@@ -749,4 +1968,827 @@ mod tests {
             "Decrypted amount does not match the original amount"
         );
     }
+
+    #[test]
+    fn test_encrypted_memo() {
+        let sender = Wallet::generate().unwrap();
+        let recipient = Wallet::generate().unwrap();
+        let outsider = Wallet::generate().unwrap();
+        let recipient_address = bs58::encode(recipient.address).into_string();
+        let memo = b"order #4815162342 / invoice 2023-07";
+        let output = sender
+            .prepare_output(&recipient_address, 1, 250, Some(memo))
+            .unwrap();
+        let output_key = CompressedRistretto::from_slice(&output.msg_output_key);
+
+        assert_ne!(output.msg_memo, memo.to_vec());
+        let decrypted_memo = recipient
+            .decrypt_memo(output_key, 1, &output.msg_memo)
+            .unwrap();
+        assert_eq!(decrypted_memo, memo.to_vec());
+        let foreign_memo = outsider
+            .decrypt_memo(output_key, 1, &output.msg_memo)
+            .unwrap();
+        assert_ne!(foreign_memo, memo.to_vec());
+    }
+
+    fn make_blsag_vec() -> Vec<u8> {
+        let wallet = Wallet::generate().unwrap();
+        let mut ring: Vec<CompressedRistretto> = (0..3)
+            .map(|_| Wallet::generate().unwrap().public_spend_key)
+            .collect();
+        ring.push(wallet.public_spend_key);
+        let blsag = wallet
+            .gen_blsag(
+                &ring,
+                b"message",
+                &wallet.public_spend_key,
+                &wallet.secret_spend_key,
+            )
+            .unwrap();
+        blsag.to_vec()
+    }
+
+    #[test]
+    fn test_blsag_from_vec_valid_length() {
+        let v = make_blsag_vec();
+        let blsag = BLSAGSignature::from_vec(&v).unwrap();
+        assert_eq!(blsag.s.len(), 4);
+        assert_eq!(blsag.to_vec(), v);
+    }
+
+    #[test]
+    fn test_blsag_from_components() {
+        let wallet = Wallet::generate().unwrap();
+        let mut ring: Vec<CompressedRistretto> = (0..3)
+            .map(|_| Wallet::generate().unwrap().public_spend_key)
+            .collect();
+        ring.push(wallet.public_spend_key);
+        let signed = wallet
+            .gen_blsag(
+                &ring,
+                b"message",
+                &wallet.public_spend_key,
+                &wallet.secret_spend_key,
+            )
+            .unwrap();
+
+        let rebuilt = BLSAGSignature::new(signed.i, signed.c, signed.s.clone());
+        assert!(verify_blsag(&rebuilt, &ring, b"message").unwrap());
+        let decoded = BLSAGSignature::from_vec(&rebuilt.to_vec()).unwrap();
+        assert_eq!(decoded.to_vec(), signed.to_vec());
+
+        let tampered = BLSAGSignature::new(signed.i, signed.c + Scalar::one(), signed.s);
+        assert!(!verify_blsag(&tampered, &ring, b"message").unwrap());
+    }
+
+    #[test]
+    fn test_verify_blsag_rejects_malformed_signature() {
+        let wallet = Wallet::generate().unwrap();
+        let mut ring: Vec<CompressedRistretto> = (0..3)
+            .map(|_| Wallet::generate().unwrap().public_spend_key)
+            .collect();
+        ring.push(wallet.public_spend_key);
+        let signed = wallet
+            .gen_blsag(
+                &ring,
+                b"message",
+                &wallet.public_spend_key,
+                &wallet.secret_spend_key,
+            )
+            .unwrap();
+
+        let short = BLSAGSignature::new(signed.i, signed.c, signed.s[1..].to_vec());
+        assert!(matches!(
+            verify_blsag(&short, &ring, b"message"),
+            Err(CryptoOpsError::RingSizeMismatch {
+                ring: 4,
+                responses: 3
+            })
+        ));
+        assert!(matches!(
+            verify_blsag(&signed, &[], b"message"),
+            Err(CryptoOpsError::RingSizeMismatch { ring: 0, .. })
+        ));
+
+        let not_a_point = CompressedRistretto([0xff; 32]);
+        let mut bad_ring = ring.clone();
+        bad_ring[0] = not_a_point;
+        assert!(matches!(
+            verify_blsag(&signed, &bad_ring, b"message"),
+            Err(CryptoOpsError::DecompressionFailed)
+        ));
+        let bad_image = BLSAGSignature::new(not_a_point, signed.c, signed.s.clone());
+        assert!(matches!(
+            verify_blsag(&bad_image, &ring, b"message"),
+            Err(CryptoOpsError::DecompressionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_blsag_from_vec_lying_length() {
+        let v = make_blsag_vec();
+
+        let mut too_large = v.clone();
+        too_large[64..72].copy_from_slice(&5u64.to_le_bytes());
+        assert!(matches!(
+            BLSAGSignature::from_vec(&too_large),
+            Err(CryptoOpsError::InvalidBLSAGLength)
+        ));
+
+        let mut huge = v.clone();
+        huge[64..72].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            BLSAGSignature::from_vec(&huge),
+            Err(CryptoOpsError::InvalidBLSAGLength)
+        ));
+
+        let mut too_small = v;
+        too_small[64..72].copy_from_slice(&3u64.to_le_bytes());
+        assert!(matches!(
+            BLSAGSignature::from_vec(&too_small),
+            Err(CryptoOpsError::InvalidBLSAGLength)
+        ));
+    }
+
+    #[test]
+    fn test_output_private_key_matches_stealth() {
+        let wallet = Wallet::generate().unwrap();
+        let output = wallet.prepare_change_output(100, 3).unwrap();
+        let output_key = CompressedRistretto::from_slice(&output.msg_output_key);
+        let stealth = CompressedRistretto::from_slice(&output.msg_stealth_address);
+        let secret_key = wallet.output_private_key(output_key, 3).unwrap();
+        assert_eq!(
+            (&constants::RISTRETTO_BASEPOINT_TABLE * &secret_key).compress(),
+            stealth
+        );
+
+        let mut ring: Vec<CompressedRistretto> = (0..3)
+            .map(|_| Wallet::generate().unwrap().public_spend_key)
+            .collect();
+        ring.push(stealth);
+        let blsag = wallet
+            .gen_blsag(&ring, b"message", &stealth, &secret_key)
+            .unwrap();
+        assert!(verify_blsag(&blsag, &ring, b"message").unwrap());
+    }
+
+    #[test]
+    fn test_input_signature_binds_transaction() {
+        let wallet = Wallet::generate().unwrap();
+        let output = wallet.prepare_change_output(100, 0).unwrap();
+        let owned_output = OwnedOutput {
+            output: Output {
+                stealth: output.msg_stealth_address.clone(),
+                output_key: output.msg_output_key.clone(),
+                amount: output.msg_amount.clone(),
+                commitment: output.msg_commitment.clone(),
+                range_proof: output.msg_proof.clone(),
+                index: output.msg_index,
+            },
+            decrypted_amount: 100,
+            decrypted_memo: vec![],
+            kind: OutputKind::Change,
+            height: 0,
+            coinbase: false,
+        };
+        let unsigned = unsigned_input(&owned_output).unwrap();
+        let transaction = wallet
+            .sign_transaction(
+                std::slice::from_ref(&unsigned),
+                Transaction {
+                    msg_outputs: vec![wallet.prepare_change_output(99, 1).unwrap()],
+                    msg_fee: 1,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let input = &transaction.msg_inputs[0];
+        let ring: Vec<CompressedRistretto> = input
+            .msg_ring
+            .iter()
+            .map(|key| CompressedRistretto::from_slice(key))
+            .collect();
+        assert!(ring.contains(&CompressedRistretto::from_slice(
+            &output.msg_stealth_address
+        )));
+        let message = transaction_signing_hash(&transaction);
+        assert_eq!(input.msg_message, message);
+        let blsag = BLSAGSignature::from_vec(&input.msg_blsag).unwrap();
+        assert!(verify_blsag(&blsag, &ring, &message).unwrap());
+        assert_eq!(blsag.i.to_bytes().to_vec(), input.msg_key_image);
+        assert_eq!(
+            wallet.input_key_image(&unsigned).unwrap(),
+            input.msg_key_image
+        );
+
+        // The signature doesn't carry over to a transaction paying elsewhere or a different fee
+        let mut redirected = transaction.clone();
+        redirected.msg_outputs[0] = Wallet::generate()
+            .unwrap()
+            .prepare_change_output(99, 1)
+            .unwrap();
+        assert!(!verify_blsag(&blsag, &ring, &transaction_signing_hash(&redirected)).unwrap());
+        let mut refeed = transaction.clone();
+        refeed.msg_fee = 2;
+        assert!(!verify_blsag(&blsag, &ring, &transaction_signing_hash(&refeed)).unwrap());
+    }
+
+    #[test]
+    fn test_gen_blsag_rejects_key_outside_ring() {
+        let wallet = Wallet::generate().unwrap();
+        let ring: Vec<CompressedRistretto> = (0..4)
+            .map(|_| Wallet::generate().unwrap().public_spend_key)
+            .collect();
+        assert!(matches!(
+            wallet.gen_blsag(
+                &ring,
+                b"message",
+                &wallet.public_spend_key,
+                &wallet.secret_spend_key
+            ),
+            Err(CryptoOpsError::KeyNotInRing)
+        ));
+        assert!(matches!(
+            wallet.gen_blsag(&ring, b"message", &ring[0], &wallet.secret_spend_key),
+            Err(CryptoOpsError::KeyNotInRing)
+        ));
+    }
+
+    #[test]
+    fn test_parallel_scan_matches_serial() {
+        let wallet = Wallet::generate().unwrap();
+        let other = Wallet::generate().unwrap();
+        let other_address = bs58::encode(other.address).into_string();
+        let outputs: Vec<TransactionOutput> = (0..48)
+            .map(|i| {
+                if i % 3 == 0 {
+                    wallet.prepare_change_output(i as u64, i).unwrap()
+                } else {
+                    wallet
+                        .prepare_output(&other_address, i, i as u64, None)
+                        .unwrap()
+                }
+            })
+            .collect();
+        let refs: Vec<&TransactionOutput> = outputs.iter().collect();
+
+        let serial = wallet
+            .clone()
+            .with_scan_threads(1)
+            .scan_outputs(&refs)
+            .unwrap();
+        let parallel = wallet
+            .clone()
+            .with_scan_threads(4)
+            .scan_outputs(&refs)
+            .unwrap();
+        assert_eq!(serial.len(), 16);
+        assert_eq!(serial, parallel);
+        let indexes: Vec<u32> = parallel.iter().map(|owned| owned.output.index).collect();
+        assert_eq!(indexes, (0..48).step_by(3).collect::<Vec<u32>>());
+        assert!(parallel
+            .iter()
+            .all(|owned| owned.decrypted_amount == owned.output.index as u64));
+    }
+
+    #[test]
+    fn test_scan_pool_is_built_once() {
+        let wallet = Wallet::generate().unwrap().with_scan_threads(2);
+        let pool = wallet.scan_pool().unwrap() as *const rayon::ThreadPool;
+        wallet.scan_outputs(&[]).unwrap();
+        assert!(std::ptr::eq(pool, wallet.scan_pool().unwrap()));
+        // Clones scan on the same pool
+        assert!(std::ptr::eq(pool, wallet.clone().scan_pool().unwrap()));
+        let account = Wallet::generate().unwrap().with_scan_pool_of(&wallet);
+        assert!(std::ptr::eq(pool, account.scan_pool().unwrap()));
+        assert_eq!(wallet.scan_pool().unwrap().current_num_threads(), 2);
+    }
+
+    #[test]
+    fn test_scanner_matches_wallet_scan() {
+        let wallet = Wallet::generate().unwrap();
+        let mut outputs: Vec<TransactionOutput> = (0..2000)
+            .map(|i| {
+                let key = RistrettoPoint::random(&mut rand::thread_rng()).compress();
+                let stealth = RistrettoPoint::random(&mut rand::thread_rng()).compress();
+                TransactionOutput {
+                    msg_output_key: key.to_bytes().to_vec(),
+                    msg_stealth_address: stealth.to_bytes().to_vec(),
+                    msg_index: i,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        for i in (0..2000).step_by(250) {
+            outputs[i as usize] = wallet.prepare_change_output(i as u64, i).unwrap();
+        }
+        let refs: Vec<&TransactionOutput> = outputs.iter().collect();
+
+        let plain = wallet.scan_outputs(&refs).unwrap();
+        let scanned = wallet.scanner().scan_outputs(&refs).unwrap();
+        assert_eq!(plain.len(), 8);
+        assert_eq!(plain, scanned);
+    }
+
+    #[test]
+    fn test_output_proof() {
+        let sender = Wallet::generate().unwrap();
+        let recipient = Wallet::generate().unwrap();
+        let recipient_address = bs58::encode(recipient.address).into_string();
+        let r = Scalar::random(&mut rand::thread_rng());
+        let output = sender
+            .prepare_output_with_key(&recipient_address, 1, 750, None, &r)
+            .unwrap();
+
+        let proof = sender.output_proof(r, &recipient_address, 750);
+        assert!(verify_output_proof(&proof, &output));
+        let key = CompressedRistretto::from_slice(&output.msg_output_key);
+        assert_eq!(
+            recipient
+                .decrypt_amount(key, 1, &output.msg_amount)
+                .unwrap(),
+            750
+        );
+
+        let wrong_amount = sender.output_proof(r, &recipient_address, 751);
+        assert!(!verify_output_proof(&wrong_amount, &output));
+        let other_address = bs58::encode(sender.address).into_string();
+        let wrong_recipient = sender.output_proof(r, &other_address, 750);
+        assert!(!verify_output_proof(&wrong_recipient, &output));
+        let wrong_key = sender.output_proof(
+            Scalar::random(&mut rand::thread_rng()),
+            &recipient_address,
+            750,
+        );
+        assert!(!verify_output_proof(&wrong_key, &output));
+        let malformed = sender.output_proof(r, "not an address", 750);
+        assert!(!verify_output_proof(&malformed, &output));
+    }
+
+    #[test]
+    fn test_birthday_is_first_funded_height() {
+        let wallet = Wallet::generate().unwrap();
+        let pay = |amount: u64| Transaction {
+            msg_outputs: vec![wallet.prepare_change_output(amount, 1).unwrap()],
+            ..Default::default()
+        };
+        let foreign = Transaction {
+            msg_outputs: vec![Wallet::generate()
+                .unwrap()
+                .prepare_change_output(3, 1)
+                .unwrap()],
+            ..Default::default()
+        };
+        wallet.scan_transactions(&[foreign], 4).unwrap();
+        assert_eq!(wallet.birthday(), None);
+
+        wallet.scan_transactions(&[pay(10)], 7).unwrap();
+        assert_eq!(wallet.birthday(), Some(7));
+        wallet.scan_transactions(&[pay(5)], 9).unwrap();
+        assert_eq!(wallet.birthday(), Some(7));
+
+        let restored = Wallet::from_vec(&wallet.to_vec()).unwrap();
+        assert_eq!(restored.birthday(), Some(7));
+        let restored = Wallet::from_serializable(&wallet.to_serializable());
+        assert_eq!(restored.birthday(), Some(7));
+    }
+
+    #[test]
+    fn test_coinbase_outputs_are_found_by_position() {
+        let wallet = Wallet::generate().unwrap();
+        let pay = |amount: u64, spends: bool, msg_minted: u64| Transaction {
+            msg_inputs: if spends {
+                vec![TransactionInput::default()]
+            } else {
+                vec![]
+            },
+            msg_outputs: vec![wallet.prepare_change_output(amount, 1).unwrap()],
+            msg_minted,
+            ..Default::default()
+        };
+        // A spending transaction claiming to mint and a later input-less one are no coinbase
+        let transactions = [pay(10, false, 0), pay(20, true, 7), pay(30, false, 0)];
+        let coinbase: Vec<(u64, bool)> = wallet
+            .scan_transactions(&transactions, 5)
+            .unwrap()
+            .iter()
+            .map(|owned| (owned.decrypted_amount, owned.coinbase))
+            .collect();
+        assert_eq!(coinbase, vec![(10, true), (20, false), (30, false)]);
+
+        let scanned = scan_transactions_for(&[&wallet], &transactions, 5).unwrap();
+        let coinbase: Vec<bool> = scanned.iter().map(|(_, owned)| owned.coinbase).collect();
+        assert_eq!(coinbase, vec![true, false, false]);
+    }
+
+    #[test]
+    fn test_burn_address_outputs_are_recognised() {
+        let sender = Wallet::generate().unwrap();
+        let burnt = sender
+            .prepare_output(&burn_address(), RECIPIENT_OUTPUT_INDEX, 30, None)
+            .unwrap();
+        let change = sender
+            .prepare_change_output(70, CHANGE_OUTPUT_INDEX)
+            .unwrap();
+
+        assert_eq!(burned_amount(&burnt).unwrap(), Some(30));
+        assert_eq!(burned_amount(&change).unwrap(), None);
+
+        // An amount the commitment doesn't open to is refused, not counted as burnt
+        let mut inflated = burnt.clone();
+        let keys = burn_keys();
+        let output_key = CompressedRistretto::from_slice(&burnt.msg_output_key);
+        let q_bytes = (keys.secret_view_key * output_key.decompress().unwrap())
+            .compress()
+            .to_bytes();
+        inflated.msg_amount = encrypt_amount(&q_bytes, RECIPIENT_OUTPUT_INDEX, 3000)
+            .unwrap()
+            .to_vec();
+        assert!(matches!(
+            burned_amount(&inflated),
+            Err(CryptoOpsError::UnopenedBurnCommitment)
+        ));
+        assert_eq!(burn_address(), burn_address());
+        let (spend_key, _) = derive_keys_from_address(&burn_address()).unwrap();
+        assert_eq!(spend_key, burn_keys().public_spend_key);
+    }
+
+    #[test]
+    fn test_wallet_from_independent_keys() {
+        let mut rng = rand::thread_rng();
+        let (spend, view) = (Scalar::random(&mut rng), Scalar::random(&mut rng));
+        let wallet = Wallet::from_keys(spend, view).unwrap();
+        assert_eq!(wallet.secret_view_key, view);
+        assert_ne!(wallet.secret_view_key, derive_view_key(&spend));
+        assert_eq!(
+            wallet.public_view_key,
+            (&constants::RISTRETTO_BASEPOINT_TABLE * &view).compress()
+        );
+
+        // Outputs paid to its address are found with the supplied view key and can be spent
+        let payer = Wallet::generate().unwrap();
+        let address = bs58::encode(wallet.address).into_string();
+        let output = payer
+            .prepare_output(&address, RECIPIENT_OUTPUT_INDEX, 25, None)
+            .unwrap();
+        let owned = wallet.scan_outputs(&[&output]).unwrap();
+        assert_eq!(owned.len(), 1);
+        assert_eq!(owned[0].decrypted_amount, 25);
+        let transaction = wallet
+            .sign_transaction(
+                &[unsigned_input(&owned[0]).unwrap()],
+                Transaction::default(),
+            )
+            .unwrap();
+        let input = &transaction.msg_inputs[0];
+        let ring: Vec<CompressedRistretto> = input
+            .msg_ring
+            .iter()
+            .map(|key| CompressedRistretto::from_slice(key))
+            .collect();
+        let blsag = BLSAGSignature::from_vec(&input.msg_blsag).unwrap();
+        assert!(verify_blsag(&blsag, &ring, &input.msg_message).unwrap());
+
+        assert!(matches!(
+            Wallet::from_keys(spend, Scalar::zero()),
+            Err(CryptoOpsError::InvalidSecretViewKey)
+        ));
+        assert!(matches!(
+            Wallet::from_keys(Scalar::zero(), view),
+            Err(CryptoOpsError::InvalidSecretSpendKey)
+        ));
+    }
+
+    #[test]
+    fn test_compact_wallet_round_trip() {
+        let wallet = Wallet::generate().unwrap();
+        let compact = wallet.to_compact();
+        assert_eq!(compact.len(), 37);
+        assert!(compact.len() < wallet.to_vec().len());
+
+        let restored = Wallet::from_compact(&compact).unwrap();
+        assert_eq!(restored.secret_spend_key, wallet.secret_spend_key);
+        assert_eq!(restored.secret_view_key, wallet.secret_view_key);
+        assert_eq!(restored.public_spend_key, wallet.public_spend_key);
+        assert_eq!(restored.public_view_key, wallet.public_view_key);
+        assert_eq!(restored.address, wallet.address);
+        assert_eq!(restored.to_compact(), compact);
+    }
+
+    #[test]
+    fn test_view_key_derivation_is_domain_separated() {
+        let wallet = Wallet::generate().unwrap();
+        let legacy = Wallet::reconstruct_legacy(wallet.secret_spend_key).unwrap();
+        assert_ne!(wallet.secret_view_key, legacy.secret_view_key);
+        assert_ne!(wallet.address, legacy.address);
+        assert_eq!(wallet.public_spend_key, legacy.public_spend_key);
+        assert!(!wallet.is_legacy());
+        assert!(legacy.is_legacy());
+
+        let reconstructed = Wallet::reconstruct(wallet.secret_spend_key).unwrap();
+        assert_eq!(reconstructed.secret_view_key, wallet.secret_view_key);
+        assert_eq!(reconstructed.address, wallet.address);
+        // Legacy wallets keep reconstructing to their old address through the compact encoding
+        let restored = Wallet::from_compact(&legacy.to_compact()).unwrap();
+        assert_eq!(restored.secret_view_key, legacy.secret_view_key);
+        assert_eq!(restored.address, legacy.address);
+        assert_eq!(restored.to_compact(), legacy.to_compact());
+    }
+
+    #[test]
+    fn test_compact_wallet_rejects_corruption() {
+        let compact = Wallet::generate().unwrap().to_compact();
+
+        let mut flipped = compact.clone();
+        flipped[10] ^= 1;
+        assert!(matches!(
+            Wallet::from_compact(&flipped),
+            Err(CryptoOpsError::InvalidChecksum)
+        ));
+        let mut versioned = compact.clone();
+        versioned[0] = 3;
+        assert!(matches!(
+            Wallet::from_compact(&versioned),
+            Err(CryptoOpsError::UnsupportedWalletVersion(3))
+        ));
+        assert!(matches!(
+            Wallet::from_compact(&compact[..36]),
+            Err(CryptoOpsError::InvalidVecLength)
+        ));
+    }
+
+    #[test]
+    fn test_change_output_classification() {
+        let sender = Wallet::generate().unwrap();
+        let recipient = Wallet::generate().unwrap();
+        let recipient_address = bs58::encode(recipient.address).into_string();
+        let transaction = Transaction {
+            msg_outputs: vec![
+                sender
+                    .prepare_change_output(40, CHANGE_OUTPUT_INDEX)
+                    .unwrap(),
+                sender
+                    .prepare_output(&recipient_address, RECIPIENT_OUTPUT_INDEX, 60, None)
+                    .unwrap(),
+            ],
+            ..Default::default()
+        };
+        let outputs: Vec<&TransactionOutput> = transaction.msg_outputs.iter().collect();
+
+        // The index says nothing about who built the output, only the builder's record makes it change
+        let sender_owned = sender.scan_outputs(&outputs).unwrap();
+        assert_eq!(sender_owned.len(), 1);
+        assert_eq!(sender_owned[0].kind, OutputKind::Received);
+        assert_eq!(sender_owned[0].decrypted_amount, 40);
+
+        let recipient_owned = recipient.scan_outputs(&outputs).unwrap();
+        assert_eq!(recipient_owned.len(), 1);
+        assert_eq!(recipient_owned[0].kind, OutputKind::Received);
+        assert_eq!(recipient_owned[0].decrypted_amount, 60);
+    }
+
+    #[test]
+    fn test_sender_recognizes_own_change() {
+        let sender = Wallet::generate().unwrap();
+        let recipient = Wallet::generate().unwrap();
+        let recipient_address = bs58::encode(recipient.address).into_string();
+        let change = sender
+            .prepare_change_output(40, CHANGE_OUTPUT_INDEX)
+            .unwrap();
+        let transaction = Transaction {
+            msg_outputs: vec![
+                change.clone(),
+                sender
+                    .prepare_output(&recipient_address, RECIPIENT_OUTPUT_INDEX, 60, None)
+                    .unwrap(),
+            ],
+            ..Default::default()
+        };
+
+        assert!(sender
+            .verify_change_spendable(&change, CHANGE_OUTPUT_INDEX)
+            .is_ok());
+        let owned = sender.scan_transactions(&[transaction], 1).unwrap();
+        assert_eq!(owned.len(), 1);
+        assert_eq!(owned[0].decrypted_amount, 40);
+
+        // Checked against the wrong index, or by anyone else, the change would be lost
+        assert!(matches!(
+            sender.verify_change_spendable(&change, RECIPIENT_OUTPUT_INDEX),
+            Err(CryptoOpsError::UnspendableChange(index)) if index == RECIPIENT_OUTPUT_INDEX
+        ));
+        assert!(recipient
+            .verify_change_spendable(&change, CHANGE_OUTPUT_INDEX)
+            .is_err());
+    }
+
+    #[test]
+    fn test_decoy_selection_gives_up() {
+        let real = Wallet::generate().unwrap().public_spend_key;
+        let decoy = Wallet::generate().unwrap().public_spend_key;
+        let mut rounds = 0;
+        // A source with a single distinct key can never fill the ring
+        let result = fill_ring(real, 5, || {
+            rounds += 1;
+            Ok(vec![decoy; RING_SIZE - 1])
+        });
+        assert!(matches!(
+            result,
+            Err(CryptoOpsError::DecoySelectionFailed { attempts: 5 })
+        ));
+        assert_eq!(rounds, 5);
+
+        let ring = fill_ring(real, 1, || {
+            (1..RING_SIZE)
+                .map(|_| Wallet::generate().map(|wallet| wallet.public_spend_key))
+                .collect()
+        })
+        .unwrap();
+        assert_eq!(ring.len(), RING_SIZE);
+        assert!(ring.contains(&real));
+    }
+
+    #[test]
+    fn test_deterministic_r_is_unique_per_output() {
+        let wallet = Wallet::generate().unwrap();
+        let other = Wallet::generate().unwrap();
+        let r_values = [
+            wallet.deterministic_r(b"tx 1", 1),
+            wallet.deterministic_r(b"tx 1", 2),
+            wallet.deterministic_r(b"tx 2", 1),
+            other.deterministic_r(b"tx 1", 1),
+        ];
+        for (i, a) in r_values.iter().enumerate() {
+            for b in &r_values[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        assert_eq!(wallet.deterministic_r(b"tx 1", 1), r_values[0]);
+
+        let recipient_address = bs58::encode(other.address).into_string();
+        let payment = wallet
+            .prepare_output_with_key(&recipient_address, 1, 10, None, &r_values[0])
+            .unwrap();
+        let change = wallet
+            .prepare_change_output_with_key(5, 2, &r_values[1])
+            .unwrap();
+        assert_ne!(payment.msg_output_key, change.msg_output_key);
+        assert_eq!(
+            payment.msg_output_key,
+            (&r_values[0] * &constants::RISTRETTO_BASEPOINT_TABLE)
+                .compress()
+                .to_bytes()
+                .to_vec()
+        );
+        assert_eq!(wallet.scan_outputs(&[&change]).unwrap().len(), 1);
+        assert_eq!(other.scan_outputs(&[&payment]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_malformed_amount_is_skipped() {
+        let wallet = Wallet::generate().unwrap();
+        let mut malformed = wallet
+            .prepare_change_output(7, CHANGE_OUTPUT_INDEX)
+            .unwrap();
+        malformed.msg_amount.truncate(4);
+        let valid = wallet
+            .prepare_change_output(9, CHANGE_OUTPUT_INDEX)
+            .unwrap();
+
+        let key = CompressedRistretto::from_slice(&malformed.msg_output_key);
+        assert!(matches!(
+            wallet.decrypt_amount(key, CHANGE_OUTPUT_INDEX, &malformed.msg_amount),
+            Err(CryptoOpsError::TryIntoError)
+        ));
+        let owned = wallet.scan_outputs(&[&malformed, &valid]).unwrap();
+        assert_eq!(owned.len(), 1);
+        assert_eq!(owned[0].decrypted_amount, 9);
+        let owned = wallet.scanner().scan_outputs(&[&malformed]).unwrap();
+        assert!(owned.is_empty());
+
+        // So is an output whose keys aren't points
+        let mut undecodable = valid.clone();
+        undecodable.msg_output_key = vec![0xff; 32];
+        let owned = wallet.scan_outputs(&[&undecodable, &valid]).unwrap();
+        assert_eq!(owned.len(), 1);
+        assert!(wallet
+            .scanner()
+            .scan_outputs(&[&undecodable])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_reconstruct_rejects_degenerate_keys() {
+        assert!(matches!(
+            Wallet::reconstruct(Scalar::zero()),
+            Err(CryptoOpsError::InvalidSecretSpendKey)
+        ));
+        let zero_key = Wallet::secret_spend_key_from_vec(&[0u8; 32]).unwrap();
+        assert!(matches!(
+            Wallet::reconstruct(zero_key),
+            Err(CryptoOpsError::InvalidSecretSpendKey)
+        ));
+        assert!(matches!(
+            Wallet::reconstruct(Scalar::from_bits([0xff; 32])),
+            Err(CryptoOpsError::InvalidSecretSpendKey)
+        ));
+        assert!(Wallet::reconstruct(Scalar::one()).is_ok());
+    }
+
+    #[test]
+    fn test_compute_stealth() {
+        let recipient = Wallet::generate().unwrap();
+        let other = Wallet::generate().unwrap();
+        let r = Scalar::random(&mut rand::thread_rng());
+        let (stealth, output_key) = compute_stealth(
+            &r,
+            &recipient.public_spend_key,
+            &recipient.public_view_key,
+            3,
+        )
+        .unwrap();
+
+        assert!(recipient.check_property(output_key, 3, stealth).unwrap());
+        assert!(!recipient.check_property(output_key, 4, stealth).unwrap());
+        assert!(!other.check_property(output_key, 3, stealth).unwrap());
+        // prepare_output derives the same keys from the same r
+        let output = recipient.prepare_change_output_with_key(5, 3, &r).unwrap();
+        assert_eq!(output.msg_stealth_address, stealth.to_bytes().to_vec());
+        assert_eq!(output.msg_output_key, output_key.to_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_decompress_non_identity() {
+        let wallet = Wallet::generate().unwrap();
+        assert!(decompress_non_identity(&wallet.public_spend_key_to_vec()).is_some());
+        assert!(
+            decompress_non_identity(&RistrettoPoint::identity().compress().to_bytes()).is_none()
+        );
+        assert!(decompress_non_identity(&[0xff; 32]).is_none());
+        assert!(decompress_non_identity(&[1; 31]).is_none());
+    }
+
+    #[test]
+    fn test_view_only_wallet_scan() {
+        let wallet = Wallet::generate().unwrap();
+        let recipient = Wallet::generate().unwrap();
+        let recipient_address = bs58::encode(recipient.address).into_string();
+        let paid = wallet
+            .prepare_output(&recipient_address, 1, 42, None)
+            .unwrap();
+        let change = wallet.prepare_change_output(8, 2).unwrap();
+
+        let view_only = ViewOnlyWallet::new(
+            &recipient.public_spend_key_to_vec(),
+            &recipient.secret_view_key_to_vec(),
+        )
+        .unwrap();
+        assert_eq!(view_only.scan_output(&paid).unwrap(), Some(42));
+        assert_eq!(view_only.scan_output(&change).unwrap(), None);
+    }
+
+    #[test]
+    fn test_estimate_tx_weight_matches_encoded_len() {
+        use prost::Message;
+
+        let wallet = Wallet::generate().unwrap();
+        let recipient_address = bs58::encode(Wallet::generate().unwrap().address).into_string();
+        let spent = [
+            wallet.prepare_change_output(3, 1).unwrap(),
+            wallet.prepare_change_output(4, 2).unwrap(),
+        ];
+        let owned = wallet.scan_outputs(&[&spent[0], &spent[1]]).unwrap();
+        let inputs = owned
+            .iter()
+            .map(|owned_output| unsigned_input(owned_output).unwrap())
+            .collect::<Vec<_>>();
+        let transaction = wallet
+            .sign_transaction(
+                &inputs,
+                Transaction {
+                    msg_outputs: vec![
+                        wallet.prepare_change_output(2, 1).unwrap(),
+                        wallet
+                            .prepare_output(&recipient_address, 2, 5, None)
+                            .unwrap(),
+                    ],
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let estimate = estimate_tx_weight(2, RING_SIZE, 2);
+        assert_eq!(estimate, transaction.encoded_len());
+        assert_eq!(
+            estimate_tx_weight(1, RING_SIZE, 1),
+            Transaction {
+                msg_inputs: vec![transaction.msg_inputs[0].clone()],
+                msg_outputs: vec![transaction.msg_outputs[0].clone()],
+                ..Default::default()
+            }
+            .encoded_len()
+        );
+    }
 }
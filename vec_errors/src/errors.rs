@@ -98,6 +98,8 @@ pub enum BlockStorageError {
     DeserializationError,
     #[error("Unable to read from DB")]
     ReadError,
+    #[error("Index {index} already holds a different block")]
+    IndexOccupied { index: u32 },
 }
 
 #[derive(Debug, Error)]
@@ -124,6 +126,16 @@ pub enum IPStorageError {
     NotFound,
 }
 
+#[derive(Debug, Error)]
+pub enum MetaStorageError {
+    #[error("Unable to write to DB")]
+    WriteError,
+    #[error("Unable to deserialize value")]
+    DeserializationError,
+    #[error("Unable to read from DB")]
+    ReadError,
+}
+
 #[derive(Debug, Error)]
 pub enum ContractStorageError {
     #[error("Unable to acquire write lock")]
@@ -176,6 +188,26 @@ pub enum CryptoOpsError {
     InvalidAddressString,
     #[error("Trying to recover Wallet from vec with invalid length")]
     InvalidVecLength,
+    #[error("Signing key does not correspond to any ring member")]
+    KeyNotInRing,
+    #[error("Failed to build the output scan thread pool")]
+    ScanPoolError,
+    #[error("Unsupported compact wallet version: {0}")]
+    UnsupportedWalletVersion(u8),
+    #[error("Compact wallet checksum mismatch")]
+    InvalidChecksum,
+    #[error("Secret spend key is zero or not a canonical scalar")]
+    InvalidSecretSpendKey,
+    #[error("Secret view key is zero or not a canonical scalar")]
+    InvalidSecretViewKey,
+    #[error("Change output at index {0} is not spendable by the sender")]
+    UnspendableChange(u32),
+    #[error("Could not fill the ring with distinct decoys in {attempts} attempts")]
+    DecoySelectionFailed { attempts: usize },
+    #[error("Burn output commitment doesn't open to its amount")]
+    UnopenedBurnCommitment,
+    #[error("Signature has {responses} responses for a ring of {ring} members")]
+    RingSizeMismatch { ring: usize, responses: usize },
 }
 
 #[derive(Debug, Error)]
@@ -202,6 +234,8 @@ pub enum ValidationError {
     PublicKeyMismatch,
     #[error("Provided range proofs are incorrect")]
     IncorrectRangeProofs,
+    #[error("String does not hold a valid encoded transaction")]
+    InvalidTransactionString,
 }
 
 #[derive(Debug, Error)]
@@ -224,6 +258,8 @@ pub enum ChainOpsError {
     NoTransactions,
     #[error("Chain is empty")]
     ChainIsEmpty,
+    #[error("Unable to open Sled DB")]
+    SledOpenError,
     #[error("Given height {height} is out of bounds, max height is: {max_height}")]
     HeightTooHigh { height: usize, max_height: usize },
     #[error(transparent)]
@@ -232,6 +268,55 @@ pub enum ChainOpsError {
     InvalidPublicKey,
     #[error("Invalid previous block's hash")]
     InvalidPreviousBlockHash,
+    #[error("Exported block doesn't match its hash")]
+    ExportHashMismatch,
+    #[error("Competing branch would roll back {depth} blocks, max reorg depth is: {max_depth}")]
+    ReorgTooDeep { depth: u32, max_depth: u32 },
+    #[error("Block timestamp {timestamp} is more than {max_drift}s ahead of local time {now}")]
+    TimestampTooFarAhead {
+        timestamp: u64,
+        now: u64,
+        max_drift: u64,
+    },
+    #[error("Block timestamp {timestamp} is not after the median {median} of the previous blocks")]
+    TimestampNotAfterMedian { timestamp: u64, median: u64 },
+    #[error("Transaction version {version} is not supported, newest known version is: {max}")]
+    UnsupportedTransactionVersion { version: u32, max: u32 },
+    #[error("Contract code does not match the hash the transaction commits to")]
+    ContractHashMismatch,
+    #[error("Transaction has {outputs} outputs, at most {max} are allowed")]
+    TooManyOutputs { outputs: usize, max: usize },
+    #[error("Invalid genesis block: {0}")]
+    InvalidGenesisBlock(String),
+    #[error("Block's proof of work doesn't meet the difficulty")]
+    InvalidProofOfWork,
+    #[error("Coinbase mints {minted}, the block reward is {reward}")]
+    InvalidCoinbaseAmount { minted: u64, reward: u64 },
+    #[error("Transaction {position} of the block mints coins without being its coinbase")]
+    UnexpectedMint { position: usize },
+    #[error("Selected inputs hold {available}, the payments and fee need {needed}")]
+    InsufficientInputs { available: u64, needed: u64 },
+    #[error("Amounts of the transaction add up to more than fits in a u64")]
+    AmountOverflow,
+    #[error("Output stealth address is already used by another output")]
+    DuplicateStealthAddress,
+    #[error("Output key or stealth address is not a valid non-identity point")]
+    InvalidOutputPoint,
+    #[error("Input ring lists the same member more than once")]
+    DuplicateRingMember,
+    #[error("Input ring member is {len} bytes, a compressed point is 32")]
+    InvalidRingMember { len: usize },
+    #[error("Output index {index} is used more than once in the transaction")]
+    DuplicateOutputIndex { index: u32 },
+    #[error("Output index {index} is out of range, transaction has {outputs} outputs")]
+    OutputIndexOutOfRange { index: u32, outputs: usize },
+    #[error("Range proof was created for {found:?} bits, expected {expected}")]
+    RangeProofBitsMismatch {
+        expected: usize,
+        found: Option<usize>,
+    },
+    #[error("Invalid merkle root hash of the block")]
+    InvalidRootHash,
     #[error("Invalid pk key in the transaction's input")]
     InvalidPublicKeyInTransactionInput,
     #[error("Invalid transaction's signature")]
@@ -252,6 +337,20 @@ pub enum ChainOpsError {
     CryptoOpsError(#[from] CryptoOpsError),
     #[error(transparent)]
     UTXOStorageError(#[from] UTXOStorageError),
+    #[error(transparent)]
+    MetaStorageError(#[from] MetaStorageError),
+}
+
+#[derive(Debug, Error)]
+pub enum MiningError {
+    #[error("Block has no header to put the nonce in")]
+    MissingHeader,
+    #[error("No nonce meets the difficulty")]
+    NoValidNonce,
+    #[error("Header timestamp can't be rolled any further")]
+    TimestampOverflow,
+    #[error(transparent)]
+    TaskPanic(#[from] tokio::task::JoinError),
 }
 
 #[derive(Debug, Error)]
@@ -276,8 +375,8 @@ pub enum NodeServiceError {
     BootstrapNetworkError(String),
     #[error("Failed to create transaction: {0}")]
     CreateTransactionError(String),
-    #[error("Failed to parse URI: {0}")]
-    UriParseError(#[from] http::uri::InvalidUri),
+    #[error("Invalid peer address {input:?}: {reason}")]
+    UriParseError { input: String, reason: String },
     #[error("Failed to send shutdown signal")]
     ShutdownError,
     #[error("Failed make node client")]
@@ -292,8 +391,8 @@ pub enum NodeServiceError {
     ChainIsNotEmpty,
     #[error("Peer list is empty, no one to broadcast to")]
     NoRecipient,
-    #[error("Failed to mine block")]
-    MineError,
+    #[error("Failed to mine block: {0}")]
+    MineError(#[from] MiningError),
     #[error("Connection failed")]
     ConnectionFailed,
     #[error("No transactions in block")]
@@ -302,8 +401,14 @@ pub enum NodeServiceError {
     PullFromNonValidatorNode,
     #[error("Total owned outputs amount is less then requested")]
     InsufficientBalance,
+    #[error("Cannot send a zero amount")]
+    ZeroAmount,
+    #[error("Wallet has no spendable outputs")]
+    NoSpendableOutputs,
     #[error("Pull from the leader failed")]
     PullStateError,
+    #[error("Pulled block does not hash to the announced one")]
+    PulledBlockMismatch,
     #[error("Failed to read Contract code from the declared path")]
     ReadContractError,
     #[error("Failed to get local block's index")]
@@ -330,6 +435,36 @@ pub enum NodeServiceError {
     CryptoOpsError(#[from] CryptoOpsError),
     #[error("Unable to open Sled DB")]
     SledOpenError,
+    #[error("Failed to flush databases: {0}")]
+    FlushError(String),
+    #[error("Failed to compact databases: {0}")]
+    CompactionError(String),
+    #[error("Synchronisation was cancelled")]
+    SyncCancelled,
+    #[error("Synchronisation stalled, no progress within the timeout")]
+    SyncStalled,
+    #[error("Synchronisation made no progress, the local index didn't advance")]
+    SyncNoProgress,
+    #[error("Height {0} was not reached within the timeout")]
+    HeightNotReached(u64),
+    #[error("Failed to synchronise with any of the peers")]
+    SyncFailed,
+    #[error("No free peer slot for the connection")]
+    PeerLimitReached,
+    #[error("Too many peers from subnet: {0}")]
+    SubnetLimitReached(String),
+    #[error("Only validator nodes can produce blocks")]
+    NotValidator,
+    #[error("Mempool rejected the transaction: {0}")]
+    MempoolRejected(String),
+    #[error("Peer failed to prove it controls its advertised address")]
+    PeerIdentityUnverified,
+    #[error("Handshake nonce is unknown, expired or already used")]
+    InvalidHandshakeNonce,
+    #[error("Handshake signature doesn't match the advertised address")]
+    InvalidHandshakeSignature,
+    #[error("Admin service must listen on a loopback address, got {0}")]
+    AdminNotLoopback(std::net::SocketAddr),
 }
 
 #[derive(Debug, Error)]
@@ -362,6 +497,14 @@ pub enum ServerConfigError {
     FailedToDeserializeConfig(bincode::Error),
     #[error("HTTP request failed: {0}")]
     HttpRequestFailed(reqwest::Error),
+    #[error("Resolver returned an invalid IP address: {0}")]
+    InvalidResolvedIp(String),
+    #[error("No usable local interface address: {0}")]
+    LocalIpUnavailable(String),
+    #[error("Every IP resolver and the local interface lookup failed")]
+    IpUnresolved,
+    #[error("Invalid value {value:?} for setting {name}")]
+    InvalidSetting { name: String, value: String },
 }
 
 impl From<reqwest::Error> for ServerConfigError {
@@ -1,11 +1,40 @@
 use dashmap::DashMap;
 use slog::{info, o, Drain, Logger};
-use vec_proto::messages::Transaction;
-use vec_utils::utils::hash_transaction;
+use vec_chain::consensus::MAX_OUTPUTS_PER_TX;
+use vec_proto::messages::{Block, Transaction};
+use vec_utils::utils::{fee_rate, hash_transaction, transaction_weight};
+
+// Number of recent blocks whose fee rates the fee estimation draws on
+pub const FEE_HISTORY_BLOCKS: usize = 20;
+
+// Lowest percentile of confirmed rates an estimate matches, however many blocks the sender is willing to wait
+pub const MIN_FEE_PERCENTILE: u64 = 10;
+
+// Maximum number of pending transactions
+pub const MAX_MEMPOOL_TRANSACTIONS: usize = 5000;
+
+// Result of offering a transaction to the mempool
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddOutcome {
+    Added,
+    Duplicate,
+    Full,
+    ImageConflict,
+    TooManyOutputs,
+}
+
+// Totals over the pending transactions, e.g. for a miner deciding whether a block is worth building
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MempoolStats {
+    pub count: usize,
+    pub total_fee: u64,
+    pub total_weight: u64,
+}
 
 #[derive(Debug)]
 pub struct Mempool {
     pub transactions: DashMap<String, Transaction>,
+    pub max_transactions: usize,
     pub logger: Logger,
 }
 
@@ -20,6 +49,7 @@ impl Mempool {
         };
         Mempool {
             transactions: DashMap::new(),
+            max_transactions: MAX_MEMPOOL_TRANSACTIONS,
             logger,
         }
     }
@@ -46,6 +76,20 @@ impl Mempool {
         self.transactions.is_empty()
     }
 
+    // Counts the pending transactions and sums their fees and weights, the fees are set by the senders
+    // so the sums saturate instead of overflowing
+    pub fn aggregate(&self) -> MempoolStats {
+        self.transactions
+            .iter()
+            .fold(MempoolStats::default(), |stats, entry| MempoolStats {
+                count: stats.count + 1,
+                total_fee: stats.total_fee.saturating_add(entry.value().msg_fee),
+                total_weight: stats
+                    .total_weight
+                    .saturating_add(transaction_weight(entry.value())),
+            })
+    }
+
     // Checks if transaction is stored in mempool
     pub fn has(&self, tx: &Transaction) -> bool {
         let bs58_hash = bs58::encode(hash_transaction(tx)).into_string();
@@ -54,13 +98,38 @@ impl Mempool {
 
     // Adds transaction to the mempool
     pub fn add(&self, tx: Transaction) -> bool {
+        self.try_add(tx) == AddOutcome::Added
+    }
+
+    // Adds transaction to the mempool unless it has more than MAX_OUTPUTS_PER_TX outputs, it's already pending,
+    // the mempool is full or one of its key images is already spent by a pending transaction
+    pub fn try_add(&self, tx: Transaction) -> AddOutcome {
+        if tx.msg_outputs.len() > MAX_OUTPUTS_PER_TX {
+            return AddOutcome::TooManyOutputs;
+        }
         if self.has(&tx) {
-            return false;
+            return AddOutcome::Duplicate;
+        }
+        if self.spends_pending_image(&tx) {
+            return AddOutcome::ImageConflict;
+        }
+        if self.transactions.len() >= self.max_transactions {
+            return AddOutcome::Full;
         }
         let bs58_hash = bs58::encode(hash_transaction(&tx)).into_string();
         self.transactions.insert(bs58_hash.clone(), tx);
         info!(self.logger, "\nTransaction added to mempool: {}", bs58_hash);
-        true
+        AddOutcome::Added
+    }
+
+    fn spends_pending_image(&self, tx: &Transaction) -> bool {
+        self.transactions.iter().any(|entry| {
+            entry.value().msg_inputs.iter().any(|pending| {
+                tx.msg_inputs
+                    .iter()
+                    .any(|input| input.msg_key_image == pending.msg_key_image)
+            })
+        })
     }
 
     // Removes the specific transaction
@@ -110,6 +179,48 @@ impl Mempool {
             .get(hash)
             .map(|entry| entry.value().clone())
     }
+
+    // Removes the transactions of an applied block, returning how many were pending
+    pub fn prune_block(&self, block: &Block) -> usize {
+        block
+            .msg_transactions
+            .iter()
+            .filter(|transaction| self.remove(transaction))
+            .count()
+    }
+
+    // Suggests the fee per weight unit to get included within target_blocks blocks, given the fee rates paid in the
+    // chain's last blocks (Chain::recent_fee_rates). Pending transactions only count once they are more than the
+    // next target_blocks blocks take, then the estimate has to outbid that backlog; their fees alone can't move it
+    pub fn estimate_fee(&self, target_blocks: u64, history: &[Vec<u64>]) -> u64 {
+        let mut rates: Vec<u64> = history.iter().flatten().copied().collect();
+        if rates.is_empty() {
+            return 0;
+        }
+        rates.sort_unstable();
+        let target_blocks = target_blocks.max(1);
+        // The sooner the inclusion is wanted, the higher percentile of paid rates has to be matched
+        let percentile = (90 / target_blocks).max(MIN_FEE_PERCENTILE);
+        let position = (rates.len() - 1) as u64 * percentile / 100;
+        let confirmed = rates[position as usize];
+
+        // Blocks took rates.len() / history.len() transactions on average
+        let capacity = (rates.len() as u64)
+            .saturating_mul(target_blocks)
+            .checked_div(history.len() as u64)
+            .unwrap_or(0)
+            .max(1) as usize;
+        let mut pending: Vec<u64> = self
+            .transactions
+            .iter()
+            .map(|entry| fee_rate(entry.value()))
+            .collect();
+        if pending.len() < capacity {
+            return confirmed;
+        }
+        let (_, last_included, _) = pending.select_nth_unstable_by(capacity - 1, |a, b| b.cmp(a));
+        confirmed.max(last_included.saturating_add(1))
+    }
 }
 
 impl Default for Mempool {
@@ -149,6 +260,156 @@ mod tests {
         assert_eq!(mempool.has(&transaction), false);
     }
 
+    #[test]
+    fn test_prune_block() {
+        let mempool = Mempool::new();
+        let transaction = create_test_transaction();
+        assert!(mempool.add(transaction.clone()));
+        let block = Block {
+            msg_header: None,
+            msg_transactions: vec![transaction.clone(), Transaction::default()],
+        };
+        assert_eq!(mempool.prune_block(&block), 1);
+        assert!(!mempool.has(&transaction));
+        assert_eq!(mempool.prune_block(&block), 0);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mempool = Mempool::new();
+        assert_eq!(mempool.aggregate(), MempoolStats::default());
+        let transactions: Vec<Transaction> = [(b"first".to_vec(), 30), (vec![7; 100], 12)]
+            .into_iter()
+            .map(|(code, fee)| Transaction {
+                msg_contract: Some(Contract { msg_code: code }),
+                msg_fee: fee,
+                ..Default::default()
+            })
+            .collect();
+        for transaction in &transactions {
+            assert!(mempool.add(transaction.clone()));
+        }
+        // A contract of n < 128 bytes encodes as 2 + (2 + n) bytes, the fee as 1 + 1
+        assert_eq!(
+            mempool.aggregate(),
+            MempoolStats {
+                count: 2,
+                total_fee: 42,
+                total_weight: (4 + 5 + 2) + (4 + 100 + 2),
+            }
+        );
+
+        // Fees are chosen by the senders, a huge one saturates the total
+        assert!(mempool.add(Transaction {
+            msg_fee: u64::MAX,
+            ..Default::default()
+        }));
+        assert_eq!(mempool.aggregate().total_fee, u64::MAX);
+    }
+
+    // Ten blocks of ten transactions each, paying 10..100 plus the block number
+    fn synthetic_history() -> Vec<Vec<u64>> {
+        (0..10)
+            .map(|block| (1..=10).map(|rate| rate * 10 + block).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_estimate_fee() {
+        let mempool = Mempool::new();
+        assert_eq!(mempool.estimate_fee(1, &[]), 0);
+        let history = synthetic_history();
+        let fast = mempool.estimate_fee(1, &history);
+        let medium = mempool.estimate_fee(3, &history);
+        let slow = mempool.estimate_fee(10, &history);
+        assert!(fast > medium);
+        assert!(medium > slow);
+
+        // Far targets are clamped to the lowest percentile instead of dropping to the cheapest rate ever paid
+        assert_eq!(mempool.estimate_fee(100, &history), slow);
+        assert_eq!(mempool.estimate_fee(u64::MAX, &history), slow);
+        assert!(slow > 10);
+        assert_eq!(mempool.estimate_fee(0, &history), fast);
+
+        // A few pending transactions fit in the next block whatever fee they offer
+        assert!(mempool.add(Transaction {
+            msg_fee: u64::MAX,
+            ..Default::default()
+        }));
+        assert_eq!(mempool.estimate_fee(1, &history), fast);
+    }
+
+    #[test]
+    fn test_estimate_fee_with_pending_backlog() {
+        let mempool = Mempool::new();
+        let history = synthetic_history();
+        let fast = mempool.estimate_fee(1, &history);
+        let slow = mempool.estimate_fee(10, &history);
+        // More well-paying transactions than the next two blocks take
+        for fee in 0..25 {
+            assert!(mempool.add(Transaction {
+                msg_fee: 100_000 + fee,
+                ..Default::default()
+            }));
+        }
+        let congested = mempool.estimate_fee(1, &history);
+        assert!(congested > fast);
+        assert!(congested > mempool.estimate_fee(2, &history));
+        // Blocks further away take the whole backlog
+        assert_eq!(mempool.estimate_fee(10, &history), slow);
+    }
+
+    #[test]
+    fn test_try_add_outcomes() {
+        let mut mempool = Mempool::new();
+        mempool.max_transactions = 2;
+        let spending = |image: u8, code: &[u8]| Transaction {
+            msg_inputs: vec![TransactionInput {
+                msg_key_image: vec![image; 32],
+                ..Default::default()
+            }],
+            msg_contract: Some(Contract {
+                msg_code: code.to_vec(),
+            }),
+            ..Default::default()
+        };
+
+        let first = spending(1, b"first");
+        assert_eq!(mempool.try_add(first.clone()), AddOutcome::Added);
+        assert_eq!(mempool.try_add(first), AddOutcome::Duplicate);
+        assert_eq!(
+            mempool.try_add(spending(1, b"double spend")),
+            AddOutcome::ImageConflict
+        );
+        assert_eq!(mempool.try_add(spending(2, b"second")), AddOutcome::Added);
+        assert_eq!(mempool.try_add(spending(3, b"third")), AddOutcome::Full);
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let mempool = Mempool::new();
+        let with_outputs = |count: usize| Transaction {
+            msg_outputs: (1..=count as u32)
+                .map(|msg_index| TransactionOutput {
+                    msg_index,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            mempool.try_add(with_outputs(MAX_OUTPUTS_PER_TX + 1)),
+            AddOutcome::TooManyOutputs
+        );
+        assert!(mempool.is_empty());
+        assert_eq!(
+            mempool.try_add(with_outputs(MAX_OUTPUTS_PER_TX)),
+            AddOutcome::Added
+        );
+    }
+
     fn create_test_transaction() -> Transaction {
         let contract = Contract::default();
         Transaction {
@@ -165,8 +426,13 @@ mod tests {
                 msg_commitment: vec![],
                 msg_amount: vec![],
                 msg_index: 1,
+                msg_memo: vec![],
             }],
             msg_contract: Some(contract),
+            msg_fee: 0,
+            msg_minted: 0,
+            msg_version: 1,
+            msg_contract_hash: vec![],
         }
     }
 }
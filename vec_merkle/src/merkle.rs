@@ -77,16 +77,21 @@ impl MerkleTree {
 
     // Verify persistance via given proof
     pub fn verify(&self, data: &[u8], proof: &[(Vec<u8>, bool)]) -> bool {
-        let mut current_hash = compute_hash(data);
-        for (proof_hash, is_right_sibling) in proof {
-            current_hash = if *is_right_sibling {
-                combine_hash(&current_hash, proof_hash)
-            } else {
-                combine_hash(proof_hash, &current_hash)
-            };
-        }
-        current_hash == self.get_hash()
+        verify_proof(&self.get_hash(), data, proof)
+    }
+}
+
+// Verify persistance against a bare root hash, when the tree itself isn't available
+pub fn verify_proof(root: &[u8], data: &[u8], proof: &[(Vec<u8>, bool)]) -> bool {
+    let mut current_hash = compute_hash(data);
+    for (proof_hash, is_right_sibling) in proof {
+        current_hash = if *is_right_sibling {
+            combine_hash(&current_hash, proof_hash)
+        } else {
+            combine_hash(proof_hash, &current_hash)
+        };
     }
+    current_hash == root
 }
 
 pub fn compute_hash(data: &[u8]) -> Vec<u8> {
@@ -1,2 +1,4 @@
 pub mod clock;
 pub mod node;
+pub mod transport;
+pub mod validator;
@@ -1,69 +1,382 @@
 use bs58;
-use curve25519_dalek_ng::{constants, scalar::Scalar};
+use curve25519_dalek_ng::{ristretto::CompressedRistretto, scalar::Scalar};
 use dashmap::DashMap;
 use futures::future::try_join_all;
-use prost::Message;
 use sha3::{Digest, Keccak256};
-use slog::{error, info, o, Drain, Logger};
+use slog::{error, info, o, warn, Drain, Logger};
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::fs;
-use std::time::SystemTime;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::{Mutex, RwLock};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{RwLock, Semaphore};
+use tokio_util::sync::CancellationToken;
 use tonic::{
-    transport::{Channel, Server},
+    transport::{Channel, Endpoint, Server},
     Request, Response, Status,
 };
 use vec_chain::chain::*;
-use vec_crypto::crypto::Wallet;
+use vec_chain::consensus::{GENESIS_PREMINE, POW_DIFFICULTY, POW_HASHER, TRANSACTION_VERSION};
+use vec_crypto::crypto::{
+    compute_stealth, shared_secret, verify, verify_identity_key, Signature, UnsignedInput,
+    ViewOnlyWallet, Wallet, CHANGE_OUTPUT_INDEX, RECIPIENT_OUTPUT_INDEX,
+};
 use vec_errors::errors::*;
 use vec_macros::hash;
 use vec_mempool::mempool::*;
-use vec_merkle::merkle::MerkleTree;
 use vec_proto::messages::*;
 use vec_proto::messages::{
+    admin_client::AdminClient,
+    admin_server::{Admin, AdminServer},
     node_client::NodeClient,
     node_server::{Node, NodeServer},
 };
 use vec_storage::block_db::BlockStorer;
+use vec_storage::image_db::ImageStorer;
 use vec_storage::ip_db::IPStorer;
-use vec_storage::lazy_traits::{BLOCK_STORER, IP_STORER};
-use vec_utils::utils::hash_transaction;
-use vec_utils::utils::{hash_block, mine};
+use vec_storage::lazy_traits::IP_STORER;
+use vec_storage::meta_db::{wallet_format_key, MetaStorer};
+use vec_storage::output_db::OutputStorer;
+use vec_utils::utils::{hash_block, hash_contract_code, mine_rolling, NONCE_SPACE};
+
+use crate::transport::PeerTransport;
+use crate::validator::ValidatorService;
+use vec_utils::utils::{hash_transaction, transaction_weight};
 
 const VERSION: u8 = 1;
+const SYNC_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+const CHAIN_STALL_THRESHOLD: Duration = Duration::from_secs(600);
+const CHAIN_STALL_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_PEERS: usize = 20;
+const RESERVED_OUTBOUND_SLOTS: usize = 8;
+pub const MAX_PEERS_PER_SUBNET: usize = 4;
+pub const SYNC_BATCH_MAX: u32 = 500;
+pub const MAX_CONCURRENT_PULLS: usize = 16;
+pub const OUTPUT_RESERVATION_TIMEOUT: Duration = Duration::from_secs(600);
+pub const PEER_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+pub const MAX_BROADCAST_FAILURES: u32 = 3;
+pub const MAX_PROTOCOL_VIOLATIONS: u32 = 3;
+// Most transaction hashes a single inventory batch carries
+pub const MAX_INV_BATCH_HASHES: usize = 500;
+// Length of the Keccak256 block and transaction hashes peers announce and pull
+const HASH_LEN: usize = 32;
+const HTTP2_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const HTTP2_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+const IDENTITY_CHALLENGE_DOMAIN: &[u8] = b"Vector peer identity";
+const HANDSHAKE_DOMAIN: &[u8] = b"Vector handshake";
+pub const HANDSHAKE_NONCE_TTL: Duration = Duration::from_secs(30);
+// Handshake nonce layout: issue time in unix milliseconds, random salt, then the tag over both
+const NONCE_TIME_LEN: usize = 8;
+const NONCE_SALT_LEN: usize = 16;
+const NONCE_LEN: usize = NONCE_TIME_LEN + NONCE_SALT_LEN + HASH_LEN;
+pub const IP_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const IP_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const COMPACTION_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+// Environment variables the node settings are read from, durations are in seconds
+pub const COMPACTION_INTERVAL_VAR: &str = "VECTOR_COMPACTION_INTERVAL";
+// Keepalive of the connections to peers, in seconds
+pub const HTTP2_KEEPALIVE_INTERVAL_VAR: &str = "VECTOR_HTTP2_KEEPALIVE_INTERVAL";
+pub const HTTP2_KEEPALIVE_TIMEOUT_VAR: &str = "VECTOR_HTTP2_KEEPALIVE_TIMEOUT";
+pub const TCP_KEEPALIVE_VAR: &str = "VECTOR_TCP_KEEPALIVE";
+// Loopback address the admin service listens on, it isn't started when the variable is unset
+pub const ADMIN_ADDR_VAR: &str = "VECTOR_ADMIN_ADDR";
+pub const PULL_TIMEOUT: Duration = Duration::from_secs(30);
+// Threads a block is mined on, 0 uses one per CPU
+pub const MINING_THREADS: usize = 0;
+// Blocks one view key scan may cover, bigger ranges have to be split into several calls
+pub const MAX_VIEW_KEY_SCAN_RANGE: u32 = 1000;
+// View key derivations a node wallet can be recorded with in the MetaDB
+pub const LEGACY_WALLET_FORMAT: u64 = 0;
+pub const WALLET_FORMAT: u64 = 1;
+const PULL_PRUNE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Whether the peer dialed us (inbound) or we dialed the peer (outbound)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerDirection {
+    Inbound,
+    Outbound,
+}
+
+// Message a broadcast sends to one peer
+enum PeerMessage {
+    BlockHash(PushBlockRequest),
+    TransactionHash(PushTxRequest),
+    PeerList(PeerList),
+    Inventory(InvBatch),
+}
+
+impl PeerMessage {
+    fn name(&self) -> &'static str {
+        match self {
+            PeerMessage::BlockHash(_) => "block hash",
+            PeerMessage::TransactionHash(_) => "transaction hash",
+            PeerMessage::PeerList(_) => "peer list",
+            PeerMessage::Inventory(_) => "inventory",
+        }
+    }
+
+    async fn send(self, peer: &mut dyn PeerTransport) -> Result<Confirmed, Status> {
+        match self {
+            PeerMessage::BlockHash(request) => peer.handle_block_push(request).await,
+            PeerMessage::TransactionHash(request) => peer.handle_tx_push(request).await,
+            PeerMessage::PeerList(request) => peer.handle_peer_list(request).await,
+            PeerMessage::Inventory(request) => peer.handle_inv_batch(request).await,
+        }
+    }
+}
+
+// What sending to peers from spawned tasks needs from the node
+#[derive(Clone)]
+struct PeerSender {
+    send_timeout: Duration,
+    failures: Arc<DashMap<String, u32>>,
+    log: Arc<Logger>,
+}
+
+impl PeerSender {
+    // Sends the message to the peer on its own task within the send timeout. A failed or timed out send counts
+    // against the peer, one that goes through clears its count
+    fn send_to_peer(&self, addr: String, peer: PeerHandle, message: PeerMessage) {
+        let sender = self.clone();
+        tokio::spawn(async move {
+            let name = message.name();
+            let sent = tokio::time::timeout(sender.send_timeout, async {
+                let mut peer_lock = peer.write().await;
+                message.send(peer_lock.as_mut()).await
+            })
+            .await;
+            match sent {
+                Ok(Ok(_)) => {
+                    sender.failures.remove(&addr);
+                    info!(sender.log, "\nBroadcasted {} to {}", name, addr);
+                }
+                Ok(Err(e)) => {
+                    error!(
+                        sender.log,
+                        "\nFailed to broadcast {} to {}: {:?}", name, addr, e
+                    );
+                    *sender.failures.entry(addr).or_insert(0) += 1;
+                }
+                Err(_) => {
+                    error!(sender.log, "\nBroadcast of {} to {} timed out", name, addr);
+                    *sender.failures.entry(addr).or_insert(0) += 1;
+                }
+            }
+        });
+    }
+}
+
+// Pull of an announced transaction or block running on its own task
+pub struct InFlightPull {
+    pub started_at: Instant,
+    pub abort: tokio::task::AbortHandle,
+}
+
+// Diagnostics view of a connected peer, built from its last received version
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub address: String,
+    pub ip: String,
+    pub version: u32,
+    pub height: u32,
+    pub last_seen: u64,
+}
+
+// Connection to a peer, shared by the tasks sending to it
+pub type PeerHandle = Arc<RwLock<Box<dyn PeerTransport>>>;
 
 #[derive(Clone)]
 pub struct NodeService {
     pub wallet: Arc<Wallet>,
-    pub ip: Arc<String>,
-    pub peers: DashMap<String, Arc<RwLock<NodeClient<Channel>>>>,
+    pub bind_addr: Arc<String>,
+    pub advertised_addr: Arc<String>,
+    pub peers: Arc<DashMap<String, PeerHandle>>,
+    pub peer_directions: DashMap<String, PeerDirection>,
+    pub peer_ips: DashMap<String, String>,
+    // Subnet of the address each peer's connection comes from, what the per-subnet cap counts
+    pub peer_subnets: DashMap<String, String>,
+    pub peer_versions: DashMap<String, (Version, u64)>,
+    pub reserved_outbound_slots: usize,
+    pub max_peers_per_subnet: usize,
+    pub sync_batch_max: u32,
+    pub pull_permits: Arc<Semaphore>,
+    pub pulls: Arc<DashMap<Vec<u8>, InFlightPull>>,
+    pub pull_timeout: Duration,
+    pub deterministic_output_keys: bool,
+    pub output_reservations: DashMap<Vec<u8>, Instant>,
+    pub output_reservation_timeout: Duration,
+    pub peer_send_timeout: Duration,
+    pub broadcast_failures: Arc<DashMap<String, u32>>,
+    pub protocol_violations: DashMap<String, u32>,
+    pub tx_batch_interval: Option<Duration>,
+    pub mining_threads: usize,
+    pub tx_batch: Arc<std::sync::Mutex<HashSet<Vec<u8>>>>,
+    handshake_key: [u8; 32],
+    pub used_handshake_nonces: DashMap<Vec<u8>, u64>,
+    pub handshake_nonce_ttl: Duration,
+    pub ip_retention: Duration,
+    pub compaction_interval: Duration,
+    pub accept_http1: bool,
+    pub admin_addr: Option<String>,
+    pub keepalive: KeepaliveConfig,
+    pub chain: Arc<Chain>,
     pub mempool: Arc<Mempool>,
     pub log: Arc<Logger>,
+    pub sync_token: Arc<std::sync::Mutex<CancellationToken>>,
+    // Height the peer being synchronized with reported, 0 while no synchronisation runs
+    pub sync_target: Arc<AtomicU32>,
+    pub chain_stalled: Arc<AtomicBool>,
+    pub validator: Option<Arc<ValidatorService>>,
 }
 
 pub struct ArcNodeService {
     pub ns: Arc<NodeService>,
 }
 
+// Calls for the node's operator, never exposed to peers
+pub struct AdminService {
+    pub ns: Arc<NodeService>,
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn scan_with_view_key(
+        &self,
+        request: Request<ViewKeyScanRequest>,
+    ) -> Result<Response<ViewKeyScanResult>, Status> {
+        let scan_request = request.into_inner();
+        let (from_index, to_index) = (scan_request.msg_from_index, scan_request.msg_to_index);
+        if to_index < from_index || to_index - from_index >= MAX_VIEW_KEY_SCAN_RANGE {
+            return Err(Status::invalid_argument(format!(
+                "Scan range must hold 1 to {} blocks",
+                MAX_VIEW_KEY_SCAN_RANGE
+            )));
+        }
+        let keys = ViewOnlyWallet::new(
+            &scan_request.msg_public_spend_key,
+            &scan_request.msg_secret_view_key,
+        )
+        .map_err(|e| Status::invalid_argument(format!("Invalid view keys: {:?}", e)))?;
+        let msg_outputs = self
+            .ns
+            .scan_with_view_key(&keys, from_index, to_index)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to scan blocks: {:?}", e)))?;
+        Ok(Response::new(ViewKeyScanResult { msg_outputs }))
+    }
+}
+
+impl ArcNodeService {
+    // Whether a message has the shape the current protocol gives it; one that doesn't, e.g. in an older format,
+    // is counted against the peers at the connection's remote address, never the ip the message claims
+    fn check_shape(&self, remote_addr: Option<SocketAddr>, well_formed: bool) -> bool {
+        if well_formed {
+            return true;
+        }
+        if let Some(remote_addr) = remote_addr {
+            for address in self.ns.peers_at(remote_addr.ip()) {
+                self.ns.record_protocol_violation(&address);
+            }
+        }
+        false
+    }
+
+    // Starts pulling an announced transaction from the sender unless it's pending or confirmed already
+    async fn pull_unknown_tx(
+        &self,
+        sender_ip: String,
+        transaction_hash: Vec<u8>,
+    ) -> Result<(), Status> {
+        let bs58_hash = bs58::encode(&transaction_hash).into_string();
+        let is_confirmed = self
+            .ns
+            .chain
+            .is_transaction_confirmed(transaction_hash.clone())
+            .await
+            .map_err(|e| Status::internal(format!("Failed to check transaction: {:?}", e)))?;
+        if self.ns.mempool.has_hash(&bs58_hash) || is_confirmed {
+            return Ok(());
+        }
+        let ns_arc = Arc::clone(&self.ns);
+        self.ns.spawn_pull(transaction_hash.clone(), async move {
+            match ns_arc
+                .pull_transaction_from(&sender_ip, transaction_hash)
+                .await
+            {
+                Ok(_) => (),
+                Err(e) => {
+                    error!(ns_arc.log, "Failed to make transaction pull: {:?}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
 #[tonic::async_trait]
 impl Node for ArcNodeService {
     async fn handshake(&self, request: Request<Version>) -> Result<Response<Version>, Status> {
+        let connection_addr = request
+            .remote_addr()
+            .map_or(String::new(), |addr| addr.to_string());
         let version = request.into_inner();
+        if let Err(e) = self.ns.verify_handshake(&version).await {
+            return Err(Status::unauthenticated(e.to_string()));
+        }
         let vec_address = version.msg_address.clone();
         let bs58_address = bs58::encode(vec_address.clone()).into_string();
         let remote_ip = version.msg_ip.clone();
+        // A connected peer never goes back to an older protocol, a lower version means a downgrade
+        let known_version = self
+            .ns
+            .peer_versions
+            .get(&bs58_address)
+            .map(|entry| entry.value().0.msg_version);
+        if known_version.is_some_and(|known| version.msg_version < known) {
+            self.ns.record_protocol_violation(&bs58_address);
+            return Err(Status::failed_precondition("Peer downgraded its version"));
+        }
         info!(self.ns.log, "\nReceived version, address: {}", bs58_address);
         let connected_addrs = self.ns.get_addr_list();
-        if !self.ns.contains(&bs58_address, &connected_addrs).await && self.ns.peers.len() < 20 {
+        if !self.ns.contains(&bs58_address, &connected_addrs).await
+            && self.ns.has_peer_slot(PeerDirection::Inbound)
+        {
             let ns_arc = Arc::clone(&self.ns);
             tokio::spawn(async move {
-                match make_node_client(&remote_ip).await {
-                    Ok(c) => {
+                match make_node_client_with(&remote_ip, &ns_arc.keepalive).await {
+                    Ok(mut c) => {
                         info!(ns_arc.log, "\nCreated node client successfully");
-                        match ns_arc.add_peer(c, version.clone()).await {
+                        let own_address = ns_arc.wallet.address;
+                        if let Err(e) = verify_peer_identity(
+                            &mut c,
+                            &version.msg_address,
+                            &own_address,
+                            &remote_ip,
+                        )
+                        .await
+                        {
+                            error!(ns_arc.log, "\nRejected peer {}: {:?}", bs58_address, e);
+                            return;
+                        }
+                        match ns_arc
+                            .add_peer(c, version.clone(), PeerDirection::Inbound, &connection_addr)
+                            .await
+                        {
                             Ok(_) => {
                                 info!(ns_arc.log, "\nNew peer added");
+                                if let Err(e) = ns_arc
+                                    .sync_if_ahead(
+                                        &ns_arc.wallet,
+                                        &bs58_address,
+                                        version.msg_local_index,
+                                    )
+                                    .await
+                                {
+                                    error!(ns_arc.log, "\nSynchronisation failed: {:?}", e);
+                                }
                             }
                             Err(e) => {
                                 error!(ns_arc.log, "Failed to add peer: {:?}", e);
@@ -119,12 +432,17 @@ impl Node for ArcNodeService {
         let requester_index = state.msg_local_index;
         let mut blocks = Vec::new();
 
-        let max_index = max_index()
+        let max_index = self
+            .ns
+            .chain
+            .max_index()
             .await
             .map_err(|e| Status::internal(format!("Failed to get max index: {:?}", e)))?;
 
-        for index in (requester_index + 1)..=max_index {
-            match BLOCK_STORER.get_by_index(index).await {
+        // Bounded to sync_batch_max blocks, the requester keeps asking until it gets an empty batch
+        let last_index = max_index.min(requester_index.saturating_add(self.ns.sync_batch_max));
+        for index in (requester_index + 1)..=last_index {
+            match self.ns.chain.blocks().get_by_index(index).await {
                 Ok(Some(block)) => blocks.push(block),
                 Ok(None) => {
                     return Err(Status::internal(format!("No block at height {}", index)));
@@ -164,58 +482,123 @@ impl Node for ArcNodeService {
         &self,
         request: Request<PushTxRequest>,
     ) -> Result<Response<Confirmed>, Status> {
+        let remote_addr = request.remote_addr();
         let push_request = request.into_inner();
         let sender_ip = push_request.msg_ip.clone();
         let transaction_hash = push_request.msg_transaction_hash;
-        let bs58_hash = bs58::encode(&transaction_hash).into_string();
-
-        if self.ns.mempool.has_hash(&bs58_hash) {
-            Ok(Response::new(Confirmed {}))
-        } else {
-            let ns_arc = Arc::clone(&self.ns);
-            tokio::spawn(async move {
-                match ns_arc
-                    .pull_transaction_from(&sender_ip, transaction_hash)
-                    .await
-                {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!(ns_arc.log, "Failed to make transaction pull: {:?}", e);
-                    }
-                }
-            });
+        if !self.check_shape(remote_addr, transaction_hash.len() == HASH_LEN) {
+            return Err(Status::invalid_argument("Malformed transaction push"));
+        }
+        self.pull_unknown_tx(sender_ip, transaction_hash).await?;
+        Ok(Response::new(Confirmed {}))
+    }
 
-            Ok(Response::new(Confirmed {}))
+    async fn handle_inv_batch(
+        &self,
+        request: Request<InvBatch>,
+    ) -> Result<Response<Confirmed>, Status> {
+        let remote_addr = request.remote_addr();
+        let batch = request.into_inner();
+        info!(
+            self.ns.log,
+            "\nReceived inventory of {} transactions",
+            batch.msg_transaction_hashes.len()
+        );
+        let well_formed = batch.msg_transaction_hashes.len() <= MAX_INV_BATCH_HASHES
+            && batch
+                .msg_transaction_hashes
+                .iter()
+                .all(|hash| hash.len() == HASH_LEN);
+        if !self.check_shape(remote_addr, well_formed) {
+            return Err(Status::invalid_argument("Malformed inventory batch"));
+        }
+        for transaction_hash in batch.msg_transaction_hashes {
+            self.pull_unknown_tx(batch.msg_ip.clone(), transaction_hash)
+                .await?;
         }
+        Ok(Response::new(Confirmed {}))
     }
 
     async fn handle_tx_pull(
         &self,
         request: Request<PullTxRequest>,
     ) -> Result<Response<Transaction>, Status> {
-        let pull_request = request.into_inner();
-        let transaction_hash = pull_request.msg_transaction_hash;
+        let remote_addr = request.remote_addr();
+        let transaction_hash = request.into_inner().msg_transaction_hash;
+        if !self.check_shape(remote_addr, transaction_hash.len() == HASH_LEN) {
+            return Err(Status::invalid_argument("Malformed transaction pull"));
+        }
         let bs58_hash = bs58::encode(transaction_hash).into_string();
-        if !self.ns.mempool.has_hash(&bs58_hash) {
-            if let Some(transaction) = self.ns.mempool.get_by_hash(&bs58_hash) {
-                Ok(Response::new(transaction))
-            } else {
-                Err(Status::internal("Requested transaction not found"))
-            }
-        } else {
-            Err(Status::internal("Requested transaction not found"))
+        match self.ns.mempool.get_by_hash(&bs58_hash) {
+            Some(transaction) => Ok(Response::new(transaction)),
+            None => Err(Status::internal("Requested transaction not found")),
         }
     }
 
+    async fn get_mempool(
+        &self,
+        _: Request<MempoolRequest>,
+    ) -> Result<Response<MempoolSummary>, Status> {
+        Ok(Response::new(self.ns.mempool_summary()))
+    }
+
+    async fn challenge(
+        &self,
+        request: Request<ChallengeRequest>,
+    ) -> Result<Response<ChallengeResponse>, Status> {
+        let challenge = request.into_inner();
+        let certificate = self
+            .ns
+            .wallet
+            .identity_certificate()
+            .map_err(|e| Status::internal(format!("Failed to sign challenge: {:?}", e)))?;
+        let signature = self.ns.wallet.sign_identity(&identity_message(
+            &challenge.msg_nonce,
+            &challenge.msg_challenger,
+            &self.ns.advertised_addr,
+        ));
+        Ok(Response::new(ChallengeResponse {
+            msg_signature: signature.to_vec(),
+            msg_identity_key: self.ns.wallet.identity_key().to_bytes().to_vec(),
+            msg_certificate: certificate.to_vec(),
+        }))
+    }
+
+    async fn handshake_nonce(
+        &self,
+        _: Request<NonceRequest>,
+    ) -> Result<Response<NonceResponse>, Status> {
+        Ok(Response::new(NonceResponse {
+            msg_nonce: self.ns.issue_handshake_nonce(),
+        }))
+    }
+
+    async fn is_image_spent(
+        &self,
+        request: Request<ImageSpentRequest>,
+    ) -> Result<Response<ImageSpentResponse>, Status> {
+        let msg_spent = self
+            .ns
+            .chain
+            .is_image_spent(&request.into_inner().msg_key_image)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to look up key image: {:?}", e)))?;
+        Ok(Response::new(ImageSpentResponse { msg_spent }))
+    }
+
     async fn handle_block_push(
         &self,
         request: Request<PushBlockRequest>,
     ) -> Result<Response<Confirmed>, Status> {
         info!(self.ns.log, "\nReceived push block request");
+        let remote_addr = request.remote_addr();
         let push_request = request.into_inner();
         let sender_ip = push_request.msg_ip;
         let block_hash = push_request.msg_block_hash;
-        match BLOCK_STORER.get(block_hash.clone()).await {
+        if !self.check_shape(remote_addr, block_hash.len() == HASH_LEN) {
+            return Err(Status::invalid_argument("Malformed block push"));
+        }
+        match self.ns.chain.blocks().get(block_hash.clone()).await {
             Ok(Some(_)) => {
                 info!(self.ns.log, "\nOffered block already exists");
                 Ok(Response::new(Confirmed {}))
@@ -225,7 +608,7 @@ impl Node for ArcNodeService {
                 let ns_arc = Arc::clone(&self.ns);
                 let sender_ip_clone = sender_ip.clone();
                 let block_hash_clone = block_hash.clone();
-                tokio::spawn(async move {
+                self.ns.spawn_pull(block_hash.clone(), async move {
                     match ns_arc
                         .pull_block_from(&sender_ip_clone, block_hash_clone)
                         .await
@@ -250,9 +633,12 @@ impl Node for ArcNodeService {
         request: Request<PullBlockRequest>,
     ) -> Result<Response<Block>, Status> {
         info!(self.ns.log, "\nRecieved pull block request");
-        let pull_request = request.into_inner();
-        let block_hash = pull_request.msg_block_hash;
-        match BLOCK_STORER.get(block_hash).await {
+        let remote_addr = request.remote_addr();
+        let block_hash = request.into_inner().msg_block_hash;
+        if !self.check_shape(remote_addr, block_hash.len() == HASH_LEN) {
+            return Err(Status::invalid_argument("Malformed block pull"));
+        }
+        match self.ns.chain.blocks().get(block_hash).await {
             Ok(Some(block)) => {
                 info!(self.ns.log, "\nBlock was successfully sent to requester");
                 Ok(Response::new(block))
@@ -267,7 +653,14 @@ impl Node for ArcNodeService {
 }
 
 impl NodeService {
-    pub async fn new(secret_key: String, _ip: String) -> Result<Self, NodeServiceError> {
+    // The node works on the given chain, processes with several nodes hand each its own or share one
+    pub async fn new(
+        chain: Arc<Chain>,
+        secret_key: String,
+        bind_addr: String,
+        advertised_addr: String,
+        is_validator: bool,
+    ) -> Result<Self, NodeServiceError> {
         let _logger = {
             let decorator = slog_term::TermDecorator::new().build();
             let drain = slog_term::FullFormat::new(decorator).build().fuse();
@@ -275,24 +668,86 @@ impl NodeService {
             Logger::root(drain, o!())
         };
         let log = Arc::new(_logger);
-        let ip = Arc::new(_ip);
+        let bind_addr = Arc::new(bind_addr);
+        let advertised_addr = Arc::new(advertised_addr);
+
+        chain.refresh_tip_index().await?;
 
         let vec_secret = string_to_vec(&secret_key);
         let secret_spend_key = Wallet::secret_spend_key_from_vec(&vec_secret)?;
-        let wallet = Arc::new(Wallet::reconstruct(secret_spend_key)?);
+        let wallet = Arc::new(restore_node_wallet(&chain, secret_spend_key).await?);
+        if wallet.is_legacy() {
+            info!(log, "\nWallet uses the legacy view key derivation");
+        }
+        // Every scan of the node's wallet and accounts runs on this pool, building it fails here rather than mid-sync
+        wallet.scan_pool()?;
 
-        let peers = DashMap::new();
+        let peers = Arc::new(DashMap::new());
 
         let mempool = Arc::new(Mempool::new());
+        // Blocks a crash left stored but not fully applied get validated again before syncing resumes, invalid ones
+        // are rolled back. A node whose chain can't be brought back to a consistent state doesn't start
+        let (committed, rolled_back) = chain.resume_validation(&wallet).await?;
+        if committed > 0 {
+            info!(
+                log,
+                "\nRe-validated {} blocks left over from the last run", committed
+            );
+        }
+        if rolled_back > 0 {
+            warn!(
+                log,
+                "\nRolled back {} invalid blocks left over from the last run", rolled_back
+            );
+        }
+
+        let validator = if is_validator {
+            Some(Arc::new(ValidatorService::new(Arc::clone(&log))))
+        } else {
+            None
+        };
 
         info!(log, "\nNodeService created");
 
         Ok(NodeService {
             wallet,
-            ip,
+            bind_addr,
+            advertised_addr,
             peers,
+            peer_directions: DashMap::new(),
+            peer_ips: DashMap::new(),
+            peer_subnets: DashMap::new(),
+            peer_versions: DashMap::new(),
+            reserved_outbound_slots: RESERVED_OUTBOUND_SLOTS,
+            max_peers_per_subnet: MAX_PEERS_PER_SUBNET,
+            sync_batch_max: SYNC_BATCH_MAX,
+            pull_permits: Arc::new(Semaphore::new(MAX_CONCURRENT_PULLS)),
+            pulls: Arc::new(DashMap::new()),
+            pull_timeout: PULL_TIMEOUT,
+            deterministic_output_keys: false,
+            output_reservations: DashMap::new(),
+            output_reservation_timeout: OUTPUT_RESERVATION_TIMEOUT,
+            peer_send_timeout: PEER_SEND_TIMEOUT,
+            broadcast_failures: Arc::new(DashMap::new()),
+            protocol_violations: DashMap::new(),
+            tx_batch_interval: None,
+            mining_threads: MINING_THREADS,
+            tx_batch: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            handshake_key: rand::random(),
+            used_handshake_nonces: DashMap::new(),
+            handshake_nonce_ttl: HANDSHAKE_NONCE_TTL,
+            ip_retention: IP_RETENTION,
+            compaction_interval: COMPACTION_INTERVAL,
+            accept_http1: false,
+            admin_addr: None,
+            keepalive: KeepaliveConfig::default(),
             log,
+            chain,
             mempool,
+            sync_token: Arc::new(std::sync::Mutex::new(CancellationToken::new())),
+            sync_target: Arc::new(AtomicU32::new(0)),
+            chain_stalled: Arc::new(AtomicBool::new(false)),
+            validator,
         })
     }
 
@@ -313,50 +768,307 @@ impl NodeService {
         &self,
         ip: &str,
     ) -> Result<(NodeClient<Channel>, Version), NodeServiceError> {
-        let local_index = match max_index().await {
+        let local_index = match self.chain.max_index().await {
             Ok(index) => index,
             Err(_) => return Err(NodeServiceError::FailedToGetIndex),
         };
-        let mut c = make_node_client(ip).await?;
+        let mut c = make_node_client_with(ip, &self.keepalive).await?;
         info!(
             self.log,
             "\nNode client {:?} created successfully, requesting version", ip
         );
+        let version = sign_handshake(&mut c, &self.wallet, self.get_version().await).await?;
         let v = c
-            .handshake(Request::new(self.get_version().await))
+            .handshake(Request::new(version))
             .await
             .map_err(NodeServiceError::HandshakeError)?
             .into_inner();
+        verify_peer_identity(&mut c, &v.msg_address, &self.wallet.address, ip).await?;
 
+        // A node ahead of us is synchronized with once it's added as a peer, so a stalling one can be fallen
+        // back from
         match v.msg_local_index.cmp(&local_index) {
-            Ordering::Greater => {
-                self.synchronize_with_client(&self.wallet, &mut c).await?;
-                Ok((c, v))
-            }
             Ordering::Less => Err(NodeServiceError::LaggingNode),
-            Ordering::Equal => {
+            Ordering::Greater | Ordering::Equal => {
                 info!(self.log, "\nDialed remote node: {}", ip);
                 Ok((c, v))
             }
         }
     }
 
-    pub async fn add_peer(
+    pub fn count_peers(&self, direction: PeerDirection) -> usize {
+        self.peer_directions
+            .iter()
+            .filter(|entry| *entry.value() == direction)
+            .count()
+    }
+
+    // Inbound peers may not take the slots reserved for outbound ones
+    pub fn has_peer_slot(&self, direction: PeerDirection) -> bool {
+        match direction {
+            PeerDirection::Inbound => {
+                self.peers.len() < MAX_PEERS
+                    && self.count_peers(PeerDirection::Inbound)
+                        < MAX_PEERS.saturating_sub(self.reserved_outbound_slots)
+            }
+            PeerDirection::Outbound => {
+                self.peers.len() < MAX_PEERS || self.count_peers(PeerDirection::Inbound) > 0
+            }
+        }
+    }
+
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        let mut infos: Vec<PeerInfo> = self
+            .peer_versions
+            .iter()
+            .filter(|entry| self.peers.contains_key(entry.key()))
+            .map(|entry| {
+                let (version, last_seen) = entry.value();
+                PeerInfo {
+                    address: entry.key().clone(),
+                    ip: version.msg_ip.clone(),
+                    version: version.msg_version,
+                    height: version.msg_local_index,
+                    last_seen: *last_seen,
+                }
+            })
+            .collect();
+        infos.sort_by(|a, b| a.address.cmp(&b.address));
+        infos
+    }
+
+    // Runs the pull of the given hash on its own task if a pull permit is free and it isn't pulled already,
+    // otherwise drops it since peers re-announce
+    pub fn spawn_pull<F>(&self, hash: Vec<u8>, pull: F) -> bool
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let entry = match self.pulls.entry(hash.clone()) {
+            dashmap::mapref::entry::Entry::Occupied(_) => return false,
+            dashmap::mapref::entry::Entry::Vacant(entry) => entry,
+        };
+        match Arc::clone(&self.pull_permits).try_acquire_owned() {
+            Ok(permit) => {
+                let pulls = Arc::clone(&self.pulls);
+                let task = tokio::spawn(async move {
+                    pull.await;
+                    pulls.remove(&hash);
+                    drop(permit);
+                });
+                entry.insert(InFlightPull {
+                    started_at: Instant::now(),
+                    abort: task.abort_handle(),
+                });
+                true
+            }
+            Err(_) => {
+                warn!(self.log, "\nPull limit reached, dropping announcement");
+                false
+            }
+        }
+    }
+
+    // Hashes of the running pulls with the time each of them started
+    pub fn in_flight_pulls(&self) -> Vec<(Vec<u8>, Instant)> {
+        let mut pulls: Vec<(Vec<u8>, Instant)> = self
+            .pulls
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().started_at))
+            .collect();
+        pulls.sort_by_key(|(_, started_at)| *started_at);
+        pulls
+    }
+
+    // Aborts the pulls running longer than pull_timeout, freeing their permits, and returns how many
+    pub fn prune_stale_pulls(&self) -> usize {
+        let now = Instant::now();
+        let mut pruned = 0;
+        self.pulls.retain(|_, pull| {
+            if now.duration_since(pull.started_at) < self.pull_timeout {
+                return true;
+            }
+            pull.abort.abort();
+            pruned += 1;
+            false
+        });
+        pruned
+    }
+
+    // Peers are keyed by their address while pushes only carry the sender's ip
+    pub fn get_peer_by_ip(&self, ip: &str) -> Option<PeerHandle> {
+        let address = self.peer_ips.get(ip)?.value().clone();
+        self.peers
+            .get(&address)
+            .map(|entry| Arc::clone(entry.value()))
+    }
+
+    // Addresses of the connected peers listening on the given host
+    pub fn peers_at(&self, host: IpAddr) -> Vec<String> {
+        self.peer_ips
+            .iter()
+            .filter(|entry| {
+                entry
+                    .key()
+                    .parse::<SocketAddr>()
+                    .is_ok_and(|addr| addr.ip() == host)
+            })
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    // Number of connected peers whose connection comes from the subnet
+    pub fn count_subnet_peers(&self, subnet: &str) -> usize {
+        self.peer_subnets
+            .iter()
+            .filter(|entry| entry.value() == subnet)
+            .count()
+    }
+
+    // Makes room for an outbound peer by dropping an inbound one, taken from the most crowded subnet
+    // so an attacker holding one range can't fill the node
+    fn evict_inbound_peer(&self) -> Option<String> {
+        let evicted = self
+            .peer_directions
+            .iter()
+            .filter(|entry| *entry.value() == PeerDirection::Inbound)
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>()
+            .into_iter()
+            .max_by_key(|address| {
+                self.peer_subnets
+                    .get(address)
+                    .map_or(0, |subnet| self.count_subnet_peers(subnet.value()))
+            })?;
+        self.drop_peer(&evicted);
+        info!(self.log, "\nEvicted inbound peer: {}", evicted);
+        Some(evicted)
+    }
+
+    // Forgets every in-memory trace of the peer, dropping its client; returns false if it wasn't connected
+    fn drop_peer(&self, bs58_address: &str) -> bool {
+        self.peer_directions.remove(bs58_address);
+        self.peer_ips.retain(|_, address| address != bs58_address);
+        self.peer_subnets.remove(bs58_address);
+        self.peer_versions.remove(bs58_address);
+        self.broadcast_failures.remove(bs58_address);
+        self.protocol_violations.remove(bs58_address);
+        self.peers.remove(bs58_address).is_some()
+    }
+
+    // Counts a malformed message against a connected peer and drops the peer once it reaches
+    // MAX_PROTOCOL_VIOLATIONS; returns whether it was dropped
+    pub fn record_protocol_violation(&self, bs58_address: &str) -> bool {
+        if !self.peers.contains_key(bs58_address) {
+            return false;
+        }
+        let violations = {
+            let mut violations = self
+                .protocol_violations
+                .entry(bs58_address.to_string())
+                .or_insert(0);
+            *violations += 1;
+            *violations
+        };
+        warn!(
+            self.log,
+            "\nProtocol violation {} from peer: {}", violations, bs58_address
+        );
+        if violations < MAX_PROTOCOL_VIOLATIONS || !self.drop_peer(bs58_address) {
+            return false;
+        }
+        error!(self.log, "\nDropped misbehaving peer: {}", bs58_address);
+        true
+    }
+
+    fn peer_sender(&self) -> PeerSender {
+        PeerSender {
+            send_timeout: self.peer_send_timeout,
+            failures: Arc::clone(&self.broadcast_failures),
+            log: Arc::clone(&self.log),
+        }
+    }
+
+    // Disconnects peers whose last broadcasts all failed or timed out
+    fn drop_dead_peers(&self) {
+        let dead: Vec<String> = self
+            .broadcast_failures
+            .iter()
+            .filter(|entry| *entry.value() >= MAX_BROADCAST_FAILURES)
+            .map(|entry| entry.key().clone())
+            .collect();
+        for bs58_address in dead {
+            if self.drop_peer(&bs58_address) {
+                error!(self.log, "\nDropped unresponsive peer: {}", bs58_address);
+            }
+        }
+    }
+
+    // Disconnects a peer, optionally removing its stored ip so it isn't redialed
+    pub async fn remove_peer(
+        &self,
+        bs58_address: &str,
+        forget_ip: bool,
+    ) -> Result<bool, NodeServiceError> {
+        let removed = self.drop_peer(bs58_address);
+        if forget_ip {
+            let address = bs58::decode(bs58_address)
+                .into_vec()
+                .map_err(|_| CryptoOpsError::InvalidAddressString)?;
+            IP_STORER.remove(&address).await?;
+        }
+        if removed {
+            info!(self.log, "\nRemoved peer: {}", bs58_address);
+        }
+        Ok(removed)
+    }
+
+    // connection_addr is where the connection to the peer goes or comes from, unlike the msg_ip it reports
+    // about itself the peer can't choose it freely, so it decides the subnet the peer counts against
+    pub async fn add_peer<T: PeerTransport + 'static>(
         &self,
-        c: NodeClient<Channel>,
+        c: T,
         v: Version,
+        direction: PeerDirection,
+        connection_addr: &str,
     ) -> Result<(), NodeServiceError> {
         let vec_address = v.msg_address.clone();
         let bs58_address = bs58::encode(vec_address.clone()).into_string();
         let remote_ip = v.msg_ip.clone();
+        let subnet = ip_subnet(connection_addr);
 
         if !self.peers.contains_key(&bs58_address) {
+            if !self.has_peer_slot(direction) {
+                return Err(NodeServiceError::PeerLimitReached);
+            }
+            if let Some(subnet) = &subnet {
+                if self.count_subnet_peers(subnet) >= self.max_peers_per_subnet {
+                    return Err(NodeServiceError::SubnetLimitReached(subnet.clone()));
+                }
+            }
+            if self.peers.len() >= MAX_PEERS {
+                self.evict_inbound_peer();
+            }
             IP_STORER
                 .put(vec_address.clone(), remote_ip.clone())
                 .await?;
-            self.peers.insert(bs58_address.clone(), Arc::new(c.into()));
+            self.peers
+                .insert(bs58_address.clone(), Arc::new(RwLock::new(Box::new(c))));
+            self.peer_directions.insert(bs58_address.clone(), direction);
+            self.peer_ips
+                .insert(remote_ip.clone(), bs58_address.clone());
+            if let Some(subnet) = subnet {
+                self.peer_subnets.insert(bs58_address.clone(), subnet);
+            }
             info!(self.log, "\nNew peer added: {}", bs58_address);
         } else {
+            self.peer_ips.retain(|_, address| *address != bs58_address);
+            self.peer_ips
+                .insert(remote_ip.clone(), bs58_address.clone());
+            if let Some(subnet) = subnet {
+                self.peer_subnets.insert(bs58_address.clone(), subnet);
+            } else {
+                self.peer_subnets.remove(&bs58_address);
+            }
             match IP_STORER.get_by_address(&vec_address).await {
                 Ok(Some(stored_ip)) => {
                     if stored_ip != remote_ip {
@@ -374,13 +1086,18 @@ impl NodeService {
             }
             info!(self.log, "\nPeer already exists: {}", bs58_address);
         }
+        let last_seen = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        self.peer_versions.insert(bs58_address, (v, last_seen));
         Ok(())
     }
 
     pub async fn get_version(&self) -> Version {
-        let ip = &self.ip;
+        let ip = &self.advertised_addr;
         let msg_version = VERSION as u32;
-        let local_index = max_index().await.unwrap();
+        let local_index = self.chain.cached_tip_index();
         let address = &self.wallet.address;
 
         Version {
@@ -388,55 +1105,122 @@ impl NodeService {
             msg_address: address.to_vec(),
             msg_ip: ip.to_string(),
             msg_local_index: local_index,
+            ..Default::default()
+        }
+    }
+
+    // Nonce a connecting node has to sign in its handshake. It's stateless: the issue time and a salt tagged with a
+    // key only this node knows, so anyone can ask for as many as they like without using up the node's memory
+    pub fn issue_handshake_nonce(&self) -> Vec<u8> {
+        let issued = unix_millis().to_be_bytes();
+        let salt: [u8; NONCE_SALT_LEN] = rand::random();
+        let tag = hash!(self.handshake_key, issued, salt);
+        [&issued[..], &salt, &tag].concat()
+    }
+
+    // Issue time of a nonce this node issued, None for anything it didn't
+    fn handshake_nonce_issued_at(&self, nonce: &[u8]) -> Option<u64> {
+        if nonce.len() != NONCE_LEN {
+            return None;
         }
+        let (issued, rest) = nonce.split_at(NONCE_TIME_LEN);
+        let (salt, tag) = rest.split_at(NONCE_SALT_LEN);
+        if hash!(self.handshake_key, issued, salt).as_slice() != tag {
+            return None;
+        }
+        Some(u64::from_be_bytes(issued.try_into().ok()?))
+    }
+
+    // A nonce is accepted once and only while fresh, so a captured handshake can't be replayed. Only nonces of
+    // handshakes that verified are remembered, and only until they expire
+    pub async fn verify_handshake(&self, version: &Version) -> Result<(), NodeServiceError> {
+        let now = unix_millis();
+        let ttl = self.handshake_nonce_ttl.as_millis() as u64;
+        let issued = match self.handshake_nonce_issued_at(&version.msg_nonce) {
+            Some(issued) if issued <= now && now - issued < ttl => issued,
+            _ => return Err(NodeServiceError::InvalidHandshakeNonce),
+        };
+        if version.msg_address.len() != 64 {
+            return Err(NodeServiceError::InvalidHandshakeSignature);
+        }
+        let public_spend_key = CompressedRistretto::from_slice(&version.msg_address[..32]);
+        let message = handshake_message(&version.msg_nonce, &version.msg_ip);
+        match Signature::from_vec(&version.msg_signature) {
+            Some(signature) if verify(&public_spend_key, &message, &signature) => {}
+            _ => return Err(NodeServiceError::InvalidHandshakeSignature),
+        }
+        self.used_handshake_nonces
+            .retain(|_, used| now.saturating_sub(*used) < ttl);
+        if self
+            .used_handshake_nonces
+            .insert(version.msg_nonce.clone(), issued)
+            .is_some()
+        {
+            return Err(NodeServiceError::InvalidHandshakeNonce);
+        }
+        Ok(())
+    }
+
+    // Serves one more account from this node, the chain scans the local blocks for it and stores its outputs
+    // with every block applied from now on. Returns the number of blocks scanned
+    pub async fn add_account(&self, wallet: Wallet) -> Result<u32, NodeServiceError> {
+        let wallet = wallet.with_scan_pool_of(&self.wallet);
+        let scanned = self.chain.add_account(Arc::new(wallet)).await?;
+        Ok(scanned)
+    }
+
+    pub fn is_validator(&self) -> bool {
+        self.validator.is_some()
     }
 
     pub async fn make_block(&self) -> Result<(), NodeServiceError> {
-        let msg_previous_hash = get_previous_hash_in_chain().await?;
-        let local_index = match max_index().await {
+        let validator = self
+            .validator
+            .as_ref()
+            .ok_or(NodeServiceError::NotValidator)?;
+        let msg_previous_hash = self.chain.get_previous_hash_in_chain().await?;
+        let local_index = match self.chain.max_index().await {
             Ok(index) => index,
             Err(_) => return Err(NodeServiceError::FailedToGetIndex),
         };
         let msg_index = local_index + 1;
         let transactions = self.mempool.get_transactions();
-        let transaction_data: Vec<Vec<u8>> = transactions
-            .iter()
-            .map(|transaction| {
-                let mut bytes = Vec::new();
-                transaction.encode(&mut bytes).unwrap();
-                bytes
-            })
-            .collect();
-        let merkle_tree = MerkleTree::from_list(&transaction_data);
-        let merkle_root = merkle_tree.get_hash();
+        let merkle_root = transactions_root(&transactions);
         let header = Header {
             msg_version: 1,
             msg_index,
             msg_previous_hash,
             msg_root_hash: merkle_root,
-            msg_timestamp: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs(),
+            msg_timestamp: self.chain.next_block_timestamp(msg_index).await?,
             msg_nonce: 0,
         };
-        let mut block = Block {
+        let block = Block {
             msg_header: Some(header.clone()),
             msg_transactions: transactions,
         };
-        let nonce = mine(block.clone())?;
-        block.msg_header.as_mut().unwrap().msg_nonce = nonce;
-        add_block(&self.wallet, block.clone()).await?;
-        let bs58_hash = bs58::encode(hash_block(&block)?).into_string();
+        let difficulty = self.chain.required_difficulty(msg_index).await?;
+        let block = mine_block(block, difficulty, self.mining_threads).await?;
+        self.chain.add_block(&self.wallet, block.clone()).await?;
+        // Only drop the transactions once they are in the chain, a failed block leaves them for the next attempt
+        self.mempool.prune_block(&block);
+        self.release_spent_outputs(&block);
+        validator.record_produced_block(msg_index);
+        let block_hash = hash_block(&block)?;
+        let bs58_hash = bs58::encode(&block_hash).into_string();
         info!(
             self.log,
-            "\nGenesis block {:?} with tx successfully created", bs58_hash
+            "\nBlock {:?} with tx successfully created", bs58_hash
         );
+        // A lone validator keeps its block, peers catch up on it through sync once they connect
+        if !self.peers.is_empty() {
+            self.broadcast_block_hash(block_hash).await?;
+        }
 
         Ok(())
     }
 
     pub async fn broadcast_block_hash(&self, hash: Vec<u8>) -> Result<(), NodeServiceError> {
+        self.drop_dead_peers();
         if self.peers.is_empty() {
             return Err(NodeServiceError::NoRecipient);
         }
@@ -451,22 +1235,13 @@ impl NodeService {
             .map(|entry| (entry.key().clone(), Arc::clone(entry.value())))
             .collect::<Vec<_>>();
 
+        let sender = self.peer_sender();
         for (addr, peer_client) in peers_data {
-            let hash_clone = hash.clone();
-            let ip = Arc::clone(&self.ip);
-            let log = Arc::clone(&self.log);
-            tokio::spawn(async move {
-                let mut peer_client_lock = peer_client.write().await;
-                let message = PushBlockRequest {
-                    msg_block_hash: hash_clone,
-                    msg_ip: ip.to_string(),
-                };
-                if let Err(e) = peer_client_lock.handle_block_push(message).await {
-                    error!(log.as_ref(), "\nBroadcast error: {:?}", e);
-                } else {
-                    info!(log.as_ref(), "\nBroadcasted hash to: {:?}", addr);
-                }
-            });
+            let message = PushBlockRequest {
+                msg_block_hash: hash.clone(),
+                msg_ip: self.advertised_addr.to_string(),
+            };
+            sender.send_to_peer(addr, peer_client, PeerMessage::BlockHash(message));
         }
 
         Ok(())
@@ -478,18 +1253,147 @@ impl NodeService {
         amount: u64,
         contract_path: Option<&str>,
     ) -> Result<(), NodeServiceError> {
+        if amount == 0 {
+            return Err(NodeServiceError::ZeroAmount);
+        }
+        // Select unreserved outputs until the amount is covered, so concurrent sends never spend the same output
+        let now = Instant::now();
+        let mut reserved = Vec::new();
+        let mut selected_amount = 0;
+        let selection = self
+            .wallet
+            .prepare_inputs_with(self.chain.outputs(), |owned_output| {
+                if selected_amount >= amount {
+                    return false;
+                }
+                let Ok(key_image) = self.wallet.key_image(owned_output) else {
+                    return false;
+                };
+                if !self.reserve_output(&key_image, now) {
+                    return false;
+                }
+                selected_amount += owned_output.decrypted_amount;
+                reserved.push(key_image);
+                true
+            })
+            .await;
+        let built = match selection {
+            Ok((inputs, total_input_amount)) => {
+                self.build_transaction(
+                    recipient_address,
+                    amount,
+                    contract_path,
+                    inputs,
+                    total_input_amount,
+                )
+                .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        let added = match built {
+            Ok(transaction) => match self.mempool.try_add(transaction.clone()) {
+                AddOutcome::Added => Ok(transaction),
+                outcome => Err(NodeServiceError::MempoolRejected(format!("{:?}", outcome))),
+            },
+            Err(e) => Err(e),
+        };
+        // Once the transaction is in the mempool its outputs stay reserved until a block spends them
+        let transaction = match added {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                self.release_outputs(&reserved);
+                return Err(e);
+            }
+        };
+
+        info!(self.log, "\nCreated transaction, trying to broadcast");
+
+        // A transaction no peer heard of is withdrawn, so its outputs can be spent by the next send
+        if let Err(e) = self.broadcast_tx_hash(&transaction).await {
+            self.mempool.remove(&transaction);
+            self.release_outputs(&reserved);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    // Reserves the output (by its key image) for a transaction being built, false while another one holds it
+    fn reserve_output(&self, key_image: &[u8], now: Instant) -> bool {
+        match self.output_reservations.entry(key_image.to_vec()) {
+            dashmap::mapref::entry::Entry::Occupied(mut entry) => {
+                if now.duration_since(*entry.get()) < self.output_reservation_timeout {
+                    return false;
+                }
+                entry.insert(now);
+                true
+            }
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+
+    fn release_outputs(&self, key_images: &[Vec<u8>]) {
+        for key_image in key_images {
+            self.output_reservations.remove(key_image);
+        }
+    }
+
+    // Outputs spent by the block no longer need holding back from other sends
+    fn release_spent_outputs(&self, block: &Block) {
+        for input in block.msg_transactions.iter().flat_map(|tx| &tx.msg_inputs) {
+            self.output_reservations.remove(&input.msg_key_image);
+        }
+    }
+
+    async fn build_transaction(
+        &self,
+        recipient_address: &str,
+        amount: u64,
+        contract_path: Option<&str>,
+        inputs: Vec<UnsignedInput>,
+        total_input_amount: u64,
+    ) -> Result<Transaction, NodeServiceError> {
         let wallet = &self.wallet;
-        let (inputs, total_input_amount) = wallet.prepare_inputs().await?;
+        if inputs.is_empty() {
+            return Err(NodeServiceError::NoSpendableOutputs);
+        }
         if total_input_amount < amount {
             return Err(NodeServiceError::InsufficientBalance);
         }
+        // Key images never repeat on chain, so they make a unique nonce for deterministic output keys
+        let mut key_images = Vec::new();
+        for input in &inputs {
+            key_images.extend(wallet.input_key_image(input)?);
+        }
+        let tx_nonce = hash!(key_images);
+        let output_r = |output_index| self.output_r(&tx_nonce, output_index);
         let mut outputs = Vec::new();
         if total_input_amount > amount {
             let change = total_input_amount - amount;
-            let change = wallet.prepare_change_output(change, 2)?;
+            let change = wallet.prepare_change_output_with_key(
+                change,
+                CHANGE_OUTPUT_INDEX,
+                &output_r(CHANGE_OUTPUT_INDEX),
+            )?;
+            wallet.verify_change_spendable(&change, CHANGE_OUTPUT_INDEX)?;
+            // Recorded now, when the block paying it is scanned the change looks like any received output
+            self.chain
+                .outputs()
+                .mark_change(&change.msg_stealth_address)
+                .await
+                .map_err(ChainOpsError::from)?;
             outputs.push(change);
         }
-        let output = wallet.prepare_output(recipient_address, 1, amount)?;
+        let output = wallet.prepare_output_with_key(
+            recipient_address,
+            RECIPIENT_OUTPUT_INDEX,
+            amount,
+            None,
+            &output_r(RECIPIENT_OUTPUT_INDEX),
+        )?;
         outputs.push(output);
 
         let contract_code = match contract_path {
@@ -499,19 +1403,21 @@ impl NodeService {
             }
             None => None,
         };
+        let contract_hash = contract_code
+            .as_ref()
+            .map_or(vec![], |contract| hash_contract_code(&contract.msg_code));
 
         let transaction = Transaction {
-            msg_inputs: inputs,
+            msg_inputs: vec![],
             msg_outputs: outputs,
             msg_contract: contract_code,
+            msg_fee: 0,
+            msg_minted: 0,
+            msg_version: TRANSACTION_VERSION,
+            msg_contract_hash: contract_hash,
         };
 
-        self.mempool.add(transaction.clone());
-        info!(self.log, "\nCreated transaction, trying to broadcast");
-
-        self.broadcast_tx_hash(&transaction).await?;
-
-        Ok(())
+        Ok(wallet.sign_transaction(&inputs, transaction)?)
     }
 
     pub async fn broadcast_tx_hash(
@@ -519,6 +1425,7 @@ impl NodeService {
         transaction: &Transaction,
     ) -> Result<(), NodeServiceError> {
         let hash = hash_transaction(transaction);
+        self.drop_dead_peers();
         info!(
             self.log,
             "\nBroadcasting transaction hash {:?}",
@@ -532,54 +1439,133 @@ impl NodeService {
         if peers_data.is_empty() {
             return Err(NodeServiceError::NoRecipient);
         }
+        if let Some(interval) = self.tx_batch_interval {
+            self.queue_tx_hash(hash, interval);
+            return Ok(());
+        }
 
+        let sender = self.peer_sender();
         for (addr, peer_client) in peers_data {
-            let hash_clone = hash.clone();
-            let ip = Arc::clone(&self.ip);
-            let log = Arc::clone(&self.log);
-            tokio::spawn(async move {
-                let mut peer_client_lock = peer_client.write().await;
-                let message = PushTxRequest {
-                    msg_transaction_hash: hash_clone,
-                    msg_ip: ip.to_string(),
-                };
-                if let Err(e) = peer_client_lock.handle_tx_push(message).await {
-                    error!(log, "\nBroadcast error: {:?}", e);
-                } else {
-                    info!(log, "\nBroadcasted hash to: {:?}", addr);
-                }
-            });
+            let message = PushTxRequest {
+                msg_transaction_hash: hash.clone(),
+                msg_ip: self.advertised_addr.to_string(),
+            };
+            sender.send_to_peer(addr, peer_client, PeerMessage::TransactionHash(message));
         }
 
         Ok(())
     }
 
+    // Adds the hash to the pending inventory, the first hash of a batch schedules sending it once the interval
+    // has passed to the peers connected by then, split into batches of at most MAX_INV_BATCH_HASHES
+    fn queue_tx_hash(&self, hash: Vec<u8>, interval: Duration) {
+        let mut pending = self.tx_batch.lock().unwrap();
+        if !pending.insert(hash) || pending.len() > 1 {
+            return;
+        }
+        let batch = Arc::clone(&self.tx_batch);
+        let peers = Arc::clone(&self.peers);
+        let ip = Arc::clone(&self.advertised_addr);
+        let sender = self.peer_sender();
+        tokio::spawn(async move {
+            tokio::time::sleep(interval).await;
+            let hashes = std::mem::take(&mut *batch.lock().unwrap())
+                .into_iter()
+                .collect::<Vec<_>>();
+            let peers_data = peers
+                .iter()
+                .map(|entry| (entry.key().clone(), Arc::clone(entry.value())))
+                .collect::<Vec<_>>();
+            for chunk in hashes.chunks(MAX_INV_BATCH_HASHES) {
+                for (addr, peer_client) in &peers_data {
+                    let message = InvBatch {
+                        msg_transaction_hashes: chunk.to_vec(),
+                        msg_ip: ip.to_string(),
+                    };
+                    sender.send_to_peer(
+                        addr.clone(),
+                        Arc::clone(peer_client),
+                        PeerMessage::Inventory(message),
+                    );
+                }
+            }
+        });
+    }
+
     pub async fn pull_transaction_from(
         &self,
         sender_ip: &str,
         transaction_hash: Vec<u8>,
     ) -> Result<(), NodeServiceError> {
-        if let Some(client_arc_mutex) = self.peers.get(sender_ip) {
+        if let Some(client_arc) = self.get_peer_by_ip(sender_ip) {
             info!(self.log, "\nPulling new transaction from {:?}", sender_ip);
-            let client_arc = client_arc_mutex.clone();
             let mut client = client_arc.write().await;
-            let ip = &self.ip;
+            let ip = &self.advertised_addr;
             let message = PullTxRequest {
                 msg_transaction_hash: transaction_hash,
                 msg_ip: ip.to_string(),
             };
-            let response = client.handle_tx_pull(message).await?;
-            let transaction = response.into_inner();
-            validate_transaction(&transaction).await?;
+            let transaction = client.handle_tx_pull(message).await?;
+            match self.handle_transaction(&transaction).await {
+                Ok(true) => self.broadcast_tx_hash(&transaction).await?,
+                Ok(false) => (),
+                Err(e) => {
+                    if is_malformed_transaction(&e) {
+                        if let Some(address) =
+                            self.peer_ips.get(sender_ip).map(|a| a.value().clone())
+                        {
+                            self.record_protocol_violation(&address);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Validates the incoming transaction and adds it to the mempool, returns false if it was ignored
+    pub async fn handle_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<bool, NodeServiceError> {
+        if self
+            .chain
+            .is_transaction_confirmed(hash_transaction(transaction))
+            .await?
+        {
             info!(
                 self.log,
-                "\nRecieved transaction was successfully validated"
+                "\nRecieved transaction is already included in the chain"
             );
-            self.mempool.add(transaction.clone());
-            self.broadcast_tx_hash(&transaction).await?;
+            return Ok(false);
         }
+        self.chain.validate_transaction(transaction).await?;
+        info!(
+            self.log,
+            "\nRecieved transaction was successfully validated"
+        );
 
-        Ok(())
+        match self.mempool.try_add(transaction.clone()) {
+            AddOutcome::Added => Ok(true),
+            AddOutcome::Duplicate => Ok(false),
+            AddOutcome::Full => {
+                warn!(self.log, "\nMempool is full, dropped received transaction");
+                Ok(false)
+            }
+            AddOutcome::ImageConflict => {
+                info!(
+                    self.log,
+                    "\nReceived transaction spends a key image of a pending transaction"
+                );
+                Ok(false)
+            }
+            AddOutcome::TooManyOutputs => {
+                info!(self.log, "\nReceived transaction has too many outputs");
+                Ok(false)
+            }
+        }
     }
 
     pub async fn pull_block_from(
@@ -587,18 +1573,22 @@ impl NodeService {
         sender_ip: &str,
         block_hash: Vec<u8>,
     ) -> Result<(), NodeServiceError> {
-        if let Some(client_arc_mutex) = self.peers.get(sender_ip) {
+        if let Some(client_arc) = self.get_peer_by_ip(sender_ip) {
             info!(self.log, "\nPulling new block from {:?}", sender_ip);
-            let ip = &self.ip;
-            let client_arc = client_arc_mutex.clone();
+            let ip = &self.advertised_addr;
             let mut client = client_arc.write().await;
             let message = PullBlockRequest {
                 msg_block_hash: block_hash.clone(),
                 msg_ip: ip.to_string(),
             };
-            let response = client.handle_block_pull(message).await?;
-            let block = response.into_inner();
-            self.process_block(&self.wallet, block, &self.ip).await?;
+            let block = client.handle_block_pull(message).await?;
+            // Only the announced block may be relayed under its hash, and only once it's validated and committed,
+            // any error on the way returns before the broadcast
+            if hash_block(&block)? != block_hash {
+                return Err(NodeServiceError::PulledBlockMismatch);
+            }
+            self.process_block(&self.wallet, block, &self.advertised_addr)
+                .await?;
             self.broadcast_block_hash(block_hash).await?;
         }
 
@@ -610,33 +1600,61 @@ impl NodeService {
         wallet: &Wallet,
         block_batch: BlockBatch,
     ) -> Result<(), NodeServiceError> {
+        // Blocks are applied one at a time and the first failing one aborts the rest of the batch,
+        // later blocks build on it and can't be valid either
         for block in block_batch.msg_blocks {
-            for transaction in &block.msg_transactions {
-                wallet.process_transaction(transaction).await?;
+            let index = block.msg_header.as_ref().map(|header| header.msg_index);
+            if let Err(e) = self.apply_synced_block(wallet, &block).await {
+                error!(
+                    self.log,
+                    "\nAborting synchronisation batch at block {:?}: {}", index, e
+                );
+                return Err(e);
             }
-            add_block(wallet, block).await?;
             info!(self.log, "\nNew block added");
         }
 
         Ok(())
     }
 
+    // Applies a single block of a synchronisation batch, the chain only commits it once it's fully validated
+    async fn apply_synced_block(
+        &self,
+        wallet: &Wallet,
+        block: &Block,
+    ) -> Result<(), NodeServiceError> {
+        // Genesis has index 1 and no predecessor, so an empty chain takes it without the previous hash check
+        let is_genesis = block
+            .msg_header
+            .as_ref()
+            .is_some_and(|header| header.msg_index == 1);
+        if is_genesis && self.chain.blocks().is_empty().await? {
+            check_genesis_block(block)?;
+            self.chain.add_genesis_block(wallet, block.clone()).await?;
+        } else {
+            self.chain.add_block(wallet, block.clone()).await?;
+        }
+        self.mempool.prune_block(block);
+        self.release_spent_outputs(block);
+        Ok(())
+    }
+
     pub async fn process_block(
         &self,
         wallet: &Wallet,
         block: Block,
         sender_ip: &str,
     ) -> Result<(), NodeServiceError> {
-        let local_index = max_index().await.unwrap();
+        let local_index = self.chain.max_index().await?;
         info!(self.log, "\nProcessing block");
         if let Some(header) = &block.msg_header {
             if header.msg_index < local_index {
                 Err(NodeServiceError::BlockIndexTooLow)
             } else if header.msg_index == local_index + 1 {
-                for transaction in &block.msg_transactions {
-                    wallet.process_transaction(transaction).await?;
-                }
-                add_block(wallet, block).await?;
+                // add_block validates the whole block before the wallet's outputs are touched
+                self.chain.add_block(wallet, block.clone()).await?;
+                self.mempool.prune_block(&block);
+                self.release_spent_outputs(&block);
                 info!(self.log, "\nNew block added");
                 Ok(())
             } else {
@@ -659,67 +1677,204 @@ impl NodeService {
         wallet: &Wallet,
         ip: String,
     ) -> Result<(), NodeServiceError> {
-        if !self.peers.contains_key(&ip) {
+        let address = match self.peer_ips.get(&ip).map(|entry| entry.value().clone()) {
+            Some(address) => address,
+            None => {
+                info!(
+                    self.log,
+                    "\nProvided ip was not found in peer list ({:?}), sending dial request", ip
+                );
+                let (client, version) = self.dial_remote_node(&ip).await.map_err(|e| {
+                    error!(self.log, "\nFailed to dial remote node: {:?}", e);
+                    NodeServiceError::ConnectionFailed
+                })?;
+                let address = bs58::encode(&version.msg_address).into_string();
+                match self
+                    .add_peer(client, version, PeerDirection::Outbound, &ip)
+                    .await
+                {
+                    Ok(_) => {
+                        info!(self.log, "\nDial success, new peer added: {}", ip);
+                    }
+                    Err(e) => {
+                        error!(self.log, "Failed to add peer: {:?}", e);
+                    }
+                }
+                address
+            }
+        };
+        self.synchronize(wallet, &address).await?;
+
+        Ok(())
+    }
+
+    pub async fn synchronize_with_client(
+        &self,
+        wallet: &Wallet,
+        client: &mut dyn PeerTransport,
+    ) -> Result<(), NodeServiceError> {
+        let token = self.new_sync_token();
+        self.synchronize_with_client_cancellable(wallet, client, &token, SYNC_STALL_TIMEOUT)
+            .await
+    }
+
+    // Synchronizes with the client unless the token gets cancelled or the client stalls
+    pub async fn synchronize_with_client_cancellable(
+        &self,
+        wallet: &Wallet,
+        client: &mut dyn PeerTransport,
+        token: &CancellationToken,
+        stall_timeout: Duration,
+    ) -> Result<(), NodeServiceError> {
+        // The client caps every batch, so keep pulling until it has nothing newer
+        loop {
+            let msg_local_index = self.chain.max_index().await?;
             info!(
                 self.log,
-                "\nProvided ip was not found in peer list ({:?}), sending dial request", ip
+                "\nSending request with current index {:?}", msg_local_index
             );
-            match self.dial_remote_node(&ip).await {
-                Ok((client, version)) => {
-                    match self.add_peer(client.clone(), version).await {
-                        Ok(_) => {
-                            info!(self.log, "\nNew peer added");
-                        }
-                        Err(e) => {
-                            error!(self.log, "Failed to add peer: {:?}", e);
-                        }
-                    }
-                    info!(self.log, "\nDial success, new peer added: {}", ip);
-                    let client_arc = Arc::new(Mutex::new(client));
-                    let mut client_lock = client_arc.lock().await;
-                    self.synchronize_with_client(wallet, &mut client_lock)
-                        .await?;
-                }
-                Err(e) => {
-                    error!(self.log, "\nFailed to dial remote node: {:?}", e);
-                    return Err(NodeServiceError::ConnectionFailed);
+            let request = LocalState { msg_local_index };
+            let response = tokio::select! {
+                _ = token.cancelled() => return Err(NodeServiceError::SyncCancelled),
+                response = tokio::time::timeout(stall_timeout, client.push_state(request)) => {
+                    response.map_err(|_| NodeServiceError::SyncStalled)??
                 }
+            };
+            let block_batch = response;
+            if block_batch.msg_blocks.is_empty() {
+                break;
             }
-        } else {
-            let client = self
-                .peers
-                .get(&ip)
-                .ok_or(NodeServiceError::PeerNotFound)?
-                .clone();
+            self.process_synchronisation(wallet, block_batch).await?;
+            info!(self.log, "\nPulled and processed blocks from client");
+            // A batch that leaves the index where it was would be asked for again and again
+            if self.chain.max_index().await? <= msg_local_index {
+                return Err(NodeServiceError::SyncNoProgress);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tries to synchronize with the given peers in order, abandoning the ones that stall
+    pub async fn synchronize_with_peers(
+        &self,
+        wallet: &Wallet,
+        addresses: &[String],
+        stall_timeout: Duration,
+    ) -> Result<String, NodeServiceError> {
+        for address in addresses {
+            let client = match self.peers.get(address) {
+                Some(client) => Arc::clone(client.value()),
+                None => continue,
+            };
+            let token = self.new_sync_token();
+            let target = self.reported_index(address);
+            self.sync_target
+                .store(target, std::sync::atomic::Ordering::SeqCst);
             let mut client_lock = client.write().await;
-            self.synchronize_with_client(wallet, &mut client_lock)
-                .await?;
+            let result = self
+                .synchronize_with_client_cancellable(
+                    wallet,
+                    client_lock.as_mut(),
+                    &token,
+                    stall_timeout,
+                )
+                .await;
             drop(client_lock);
+            // A newer synchronisation may have taken over the target already
+            let _ = self.sync_target.compare_exchange(
+                target,
+                0,
+                std::sync::atomic::Ordering::SeqCst,
+                std::sync::atomic::Ordering::SeqCst,
+            );
+            match result {
+                Ok(_) => return Ok(address.clone()),
+                // Cancelled in favour of a better peer, which runs a synchronisation of its own
+                Err(NodeServiceError::SyncCancelled) => {
+                    return Err(NodeServiceError::SyncCancelled)
+                }
+                Err(e) => {
+                    error!(
+                        self.log,
+                        "\nSynchronisation with {} abandoned: {:?}", address, e
+                    );
+                }
+            }
         }
 
-        Ok(())
+        Err(NodeServiceError::SyncFailed)
     }
 
-    pub async fn synchronize_with_client(
+    // Synchronizes with the preferred peer, falling back to the other connected peers that are ahead of us,
+    // the highest first
+    pub async fn synchronize(
         &self,
         wallet: &Wallet,
-        client: &mut NodeClient<Channel>,
-    ) -> Result<(), NodeServiceError> {
-        let msg_local_index = max_index().await.unwrap();
-        info!(
-            self.log,
-            "\nSending request with current index {:?}", msg_local_index
-        );
-        let request = Request::new(LocalState { msg_local_index });
-        let response = client.push_state(request).await?;
-        let block_batch = response.into_inner();
-        self.process_synchronisation(wallet, block_batch).await?;
-        info!(self.log, "\nPulled and processed blocks from client");
+        preferred: &str,
+    ) -> Result<String, NodeServiceError> {
+        let local_index = self.chain.max_index().await?;
+        let mut others: Vec<(String, u32)> = self
+            .peer_versions
+            .iter()
+            .filter(|entry| {
+                entry.key() != preferred && entry.value().0.msg_local_index > local_index
+            })
+            .map(|entry| (entry.key().clone(), entry.value().0.msg_local_index))
+            .collect();
+        others.sort_by_key(|(_, index)| std::cmp::Reverse(*index));
+        let addresses: Vec<String> = std::iter::once(preferred.to_string())
+            .chain(others.into_iter().map(|(address, _)| address))
+            .collect();
+        self.synchronize_with_peers(wallet, &addresses, SYNC_STALL_TIMEOUT)
+            .await
+    }
 
+    // Synchronizes with a newly connected peer that's ahead of us, unless the synchronisation already running
+    // reaches at least as far; one that doesn't is cancelled in favour of the new peer
+    pub async fn sync_if_ahead(
+        &self,
+        wallet: &Wallet,
+        address: &str,
+        remote_index: u32,
+    ) -> Result<(), NodeServiceError> {
+        if remote_index <= self.chain.max_index().await? {
+            return Ok(());
+        }
+        let target = self.sync_target.load(std::sync::atomic::Ordering::SeqCst);
+        if target != 0 {
+            if remote_index <= target {
+                return Ok(());
+            }
+            info!(
+                self.log,
+                "\nPeer {} is ahead of the current synchronisation, switching to it", address
+            );
+            self.cancel_sync();
+        }
+        self.synchronize(wallet, address).await?;
         Ok(())
     }
 
+    fn reported_index(&self, address: &str) -> u32 {
+        self.peer_versions
+            .get(address)
+            .map_or(0, |entry| entry.value().0.msg_local_index)
+    }
+
+    // Cancels the synchronisation in progress (e.g. when a better peer appears)
+    pub fn cancel_sync(&self) {
+        self.sync_token.lock().unwrap().cancel();
+    }
+
+    fn new_sync_token(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        *self.sync_token.lock().unwrap() = token.clone();
+        token
+    }
+
     pub async fn broadcast_peer_list(&self) -> Result<(), NodeServiceError> {
+        self.drop_dead_peers();
         info!(self.log, "\nBroadcasting peer list");
         let my_addr = bs58::encode(&self.wallet.address).into_string();
         let mut peers_addrs: Vec<String> = self.get_addr_list();
@@ -732,21 +1887,12 @@ impl NodeService {
             .iter()
             .map(|entry| (entry.key().clone(), Arc::clone(entry.value())))
             .collect();
+        let sender = self.peer_sender();
         for (addr, peer_client) in peers_data {
-            let msg_clone = msg.clone();
-            let log = Arc::clone(&self.log);
-            let my_addr_clone = my_addr.clone();
-            tokio::spawn(async move {
-                let mut peer_client_lock = peer_client.write().await;
-                let req = Request::new(msg_clone);
-                if addr != my_addr_clone {
-                    if let Err(e) = peer_client_lock.handle_peer_list(req).await {
-                        error!(log, "\nFailed to broadcast peer list to {}: {:?}", addr, e);
-                    } else {
-                        info!(log, "\nBroadcasted peer list to {}", addr);
-                    }
-                }
-            });
+            if addr == my_addr {
+                continue;
+            }
+            sender.send_to_peer(addr, peer_client, PeerMessage::PeerList(msg.clone()));
         }
 
         Ok(())
@@ -754,38 +1900,32 @@ impl NodeService {
 
     // CLI commands
     pub async fn make_genesis_block(&self) -> Result<(), NodeServiceError> {
-        if max_index().await? != 0 {
+        let validator = self
+            .validator
+            .as_ref()
+            .ok_or(NodeServiceError::NotValidator)?;
+        if self.chain.max_index().await? != 0 {
             return Err(NodeServiceError::ChainIsNotEmpty);
         }
-        let transactions = vec![self.make_genesis_transaction(100000).await?];
-        let transaction_data: Vec<Vec<u8>> = transactions
-            .iter()
-            .map(|transaction| {
-                let mut bytes = Vec::new();
-                transaction.encode(&mut bytes).unwrap();
-                bytes
-            })
-            .collect();
-        let merkle_tree = MerkleTree::from_list(&transaction_data);
-        let merkle_root = merkle_tree.get_hash();
+        let transactions = vec![self.make_genesis_transaction(GENESIS_PREMINE).await?];
+        let merkle_root = transactions_root(&transactions);
         let header = Header {
             msg_version: 1,
             msg_index: 1,
             msg_previous_hash: vec![],
             msg_root_hash: merkle_root,
-            msg_timestamp: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("\nTime went backwards")
-                .as_secs(),
+            msg_timestamp: self.chain.unix_now(),
             msg_nonce: 0,
         };
-        let mut block = Block {
+        let block = Block {
             msg_header: Some(header.clone()),
             msg_transactions: transactions,
         };
-        let nonce = mine(block.clone())?;
-        block.msg_header.as_mut().unwrap().msg_nonce = nonce;
-        add_genesis_block(&self.wallet, block.clone()).await?;
+        let block = mine_block(block, POW_DIFFICULTY, self.mining_threads).await?;
+        self.chain
+            .add_genesis_block(&self.wallet, block.clone())
+            .await?;
+        validator.record_produced_block(1);
         let bs58_hash = bs58::encode(hash_block(&block)?).into_string();
         info!(
             self.log,
@@ -795,22 +1935,28 @@ impl NodeService {
         Ok(())
     }
 
+    // Transaction private key of an output: derived from the wallet in deterministic mode, random otherwise
+    fn output_r(&self, tx_nonce: &[u8], output_index: u32) -> Scalar {
+        if self.deterministic_output_keys {
+            self.wallet.deterministic_r(tx_nonce, output_index)
+        } else {
+            Scalar::random(&mut rand::thread_rng())
+        }
+    }
+
     pub async fn make_genesis_transaction(
         &self,
         amount: u64,
     ) -> Result<Transaction, NodeServiceError> {
         let output_index: u32 = 1;
-        let mut rng = rand::thread_rng();
-        let r = Scalar::random(&mut rng);
-        let output_key = (&r * &constants::RISTRETTO_BASEPOINT_TABLE).compress();
-        let view_key_point = &self.wallet.public_view_key.decompress().unwrap();
-        let q = r * view_key_point;
-        let q_bytes = q.compress().to_bytes();
-        let hash = hash!(q_bytes, output_index.to_le_bytes());
-        let hash_in_scalar = Scalar::from_bytes_mod_order(hash.into());
-        let hs_times_g = &constants::RISTRETTO_BASEPOINT_TABLE * &hash_in_scalar;
-        let spend_key_point = &self.wallet.public_spend_key.decompress().unwrap();
-        let stealth = (hs_times_g + spend_key_point).compress();
+        let r = self.output_r(b"genesis", output_index);
+        let (stealth, output_key) = compute_stealth(
+            &r,
+            &self.wallet.public_spend_key,
+            &self.wallet.public_view_key,
+            output_index,
+        )?;
+        let q_bytes = shared_secret(&r, &self.wallet.public_view_key)?;
         let encrypted_amount = self.wallet.encrypt_amount(&q_bytes, output_index, amount)?;
         let output = TransactionOutput {
             msg_stealth_address: stealth.to_bytes().to_vec(),
@@ -819,19 +1965,70 @@ impl NodeService {
             msg_commitment: vec![],
             msg_amount: encrypted_amount.to_vec(),
             msg_index: output_index,
+            msg_memo: vec![],
         };
         let contract = Contract::default();
+        let contract_hash = hash_contract_code(&contract.msg_code);
         let transaction = Transaction {
             msg_inputs: vec![],
             msg_outputs: vec![output],
             msg_contract: Some(contract),
+            msg_fee: 0,
+            msg_minted: amount,
+            msg_version: TRANSACTION_VERSION,
+            msg_contract_hash: contract_hash,
         };
 
         Ok(transaction)
     }
 
     pub async fn get_balance(&self) -> u64 {
-        get_balance().await
+        self.chain.get_balance().await
+    }
+
+    // Repopulates the OutputDB and the key images from the local BlockDB without any network I/O
+    pub async fn rebuild_outputs_from_local_blocks(
+        &self,
+        wallet: &Wallet,
+    ) -> Result<u32, NodeServiceError> {
+        let scanned = self.chain.rebuild_outputs(wallet).await?;
+        info!(self.log, "\nRebuilt outputs from {} local blocks", scanned);
+        Ok(scanned)
+    }
+
+    // A wallet restored from its secret key owns outputs in blocks that are already stored, rescan them
+    // so the balance is right before any new block arrives
+    pub async fn restore_wallet_balance(&self) -> Result<u64, NodeServiceError> {
+        self.rebuild_outputs_from_local_blocks(&self.wallet).await?;
+        Ok(self.get_balance().await)
+    }
+
+    // Watch-only scan of the local blocks from from_index to to_index, nothing is written to the OutputDB
+    pub async fn scan_with_view_key(
+        &self,
+        keys: &ViewOnlyWallet,
+        from_index: u32,
+        to_index: u32,
+    ) -> Result<Vec<ScannedOutput>, NodeServiceError> {
+        Ok(self
+            .chain
+            .scan_blocks_with_view_key(keys, from_index, to_index)
+            .await?)
+    }
+
+    // Balance without immature coinbase outputs and outputs already spent by mempool transactions
+    pub async fn available_balance(&self) -> Result<u64, NodeServiceError> {
+        let reserved_images: HashSet<Vec<u8>> = self
+            .mempool
+            .get_transactions()
+            .iter()
+            .flat_map(|transaction| transaction.msg_inputs.iter())
+            .map(|input| input.msg_key_image.clone())
+            .collect();
+        Ok(self
+            .chain
+            .get_available_balance(&self.wallet, &reserved_images)
+            .await?)
     }
 
     pub async fn connect_to(&self, ip: String) -> Result<(), NodeServiceError> {
@@ -839,9 +2036,17 @@ impl NodeService {
 
         match self.dial_remote_node(&ip).await {
             Ok((c, v)) => {
-                match self.add_peer(c, v).await {
+                let address = bs58::encode(&v.msg_address).into_string();
+                let remote_index = v.msg_local_index;
+                match self.add_peer(c, v, PeerDirection::Outbound, &ip).await {
                     Ok(_) => {
                         info!(self.log, "\nNew peer added");
+                        if let Err(e) = self
+                            .sync_if_ahead(&self.wallet, &address, remote_index)
+                            .await
+                        {
+                            error!(self.log, "\nSynchronisation failed: {:?}", e);
+                        }
                     }
                     Err(e) => {
                         error!(self.log, "Failed to add peer: {:?}", e);
@@ -864,28 +2069,324 @@ impl NodeService {
     }
 
     pub async fn get_last_index(&self) -> Result<u32, NodeServiceError> {
-        let height = max_index().await.unwrap();
+        let height = self.chain.max_index().await?;
 
         Ok(height)
     }
+
+    // Suggested fee per weight unit for inclusion within target_blocks blocks, from the rates the last confirmed
+    // blocks paid and the transactions pending now
+    pub async fn estimate_fee(&self, target_blocks: u64) -> Result<u64, NodeServiceError> {
+        let history = self
+            .chain
+            .recent_fee_rates(FEE_HISTORY_BLOCKS as u32)
+            .await?;
+        Ok(self.mempool.estimate_fee(target_blocks, &history))
+    }
+
+    // Hashes (not full transactions, to bound the size) and total weight of the mempool
+    pub fn mempool_summary(&self) -> MempoolSummary {
+        let transactions = self.mempool.get_transactions();
+        let mut msg_tx_hashes: Vec<Vec<u8>> = transactions.iter().map(hash_transaction).collect();
+        msg_tx_hashes.sort();
+        MempoolSummary {
+            msg_count: transactions.len() as u64,
+            msg_tx_hashes,
+            msg_total_weight: transactions.iter().map(transaction_weight).sum(),
+        }
+    }
+
+    // Flips the chain_stalled flag (warning once) when the tip is older than the threshold
+    pub fn report_tip_age(&self, tip_age: u64, threshold: Duration) -> bool {
+        let stalled = tip_age > threshold.as_secs();
+        let was_stalled = self
+            .chain_stalled
+            .swap(stalled, std::sync::atomic::Ordering::Relaxed);
+        if stalled && !was_stalled {
+            warn!(
+                self.log,
+                "\nNo new block for {} seconds, the chain may be stalled", tip_age
+            );
+        } else if !stalled && was_stalled {
+            info!(self.log, "\nChain is progressing again");
+        }
+        stalled
+    }
+
+    pub async fn check_chain_stall(&self, threshold: Duration) -> Result<bool, NodeServiceError> {
+        let tip_age = self.chain.seconds_since_tip().await?;
+        Ok(self.report_tip_age(tip_age, threshold))
+    }
+
+    // Resolves once the local chain reaches the target height, woken by the chain on every new tip
+    pub async fn wait_for_height(
+        &self,
+        target: u64,
+        timeout: Duration,
+    ) -> Result<(), NodeServiceError> {
+        let mut tip = self.chain.subscribe_tip();
+        let reached =
+            tokio::time::timeout(timeout, tip.wait_for(|index| u64::from(*index) >= target)).await;
+        match reached {
+            Ok(Ok(_)) => Ok(()),
+            // The chain outlives self, so its sender is never dropped while waiting
+            _ => Err(NodeServiceError::HeightNotReached(target)),
+        }
+    }
+
+    // Periodically checks the age of the tip in the background
+    pub fn spawn_stall_watchdog(
+        self: &Arc<Self>,
+        threshold: Duration,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let ns = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                // An empty chain has no tip to measure
+                let _ = ns.check_chain_stall(threshold).await;
+            }
+        })
+    }
+
+    // Periodically drops the stored ips of peers not seen within ip_retention
+    pub fn spawn_ip_pruner(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let ns = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match IP_STORER.prune_older_than(ns.ip_retention).await {
+                    Ok(0) => {}
+                    Ok(pruned) => info!(ns.log, "\nPruned {} stale peer ips", pruned),
+                    Err(e) => error!(ns.log, "\nFailed to prune peer ips: {:?}", e),
+                }
+            }
+        })
+    }
+
+    // Periodically aborts the pulls stuck on unresponsive peers
+    pub fn spawn_pull_pruner(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let ns = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let pruned = ns.prune_stale_pulls();
+                if pruned > 0 {
+                    warn!(ns.log, "\nAborted {} stale pulls", pruned);
+                }
+            }
+        })
+    }
+
+    // Runs the compaction of every Sled database every compaction_interval
+    pub fn spawn_compactor(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let ns = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(ns.compaction_interval);
+            // The first tick completes at once, the databases were just compacted at startup
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = ns.compact_all().await {
+                    error!(ns.log, "\nCompaction failed: {}", e);
+                }
+            }
+        })
+    }
+
+    // Compacts every Sled database on a blocking thread, collecting the failures of all of them, and returns the
+    // reclaimed bytes. Each database is unavailable while it's rewritten
+    pub async fn compact_all(&self) -> Result<u64, NodeServiceError> {
+        let chain = Arc::clone(&self.chain);
+        let results = tokio::task::spawn_blocking(move || {
+            [
+                (
+                    "block db",
+                    chain.blocks().compact().map_err(|e| e.to_string()),
+                ),
+                (
+                    "output db",
+                    chain.outputs().compact().map_err(|e| e.to_string()),
+                ),
+                (
+                    "image db",
+                    chain.images().compact().map_err(|e| e.to_string()),
+                ),
+                ("ip db", IP_STORER.compact().map_err(|e| e.to_string())),
+                ("meta db", chain.meta().compact().map_err(|e| e.to_string())),
+            ]
+        })
+        .await?;
+        let mut failures = Vec::new();
+        let mut reclaimed = 0;
+        for (name, result) in results {
+            match result {
+                Ok(bytes) => reclaimed += bytes,
+                Err(e) => failures.push(format!("{}: {}", name, e)),
+            }
+        }
+        if !failures.is_empty() {
+            return Err(NodeServiceError::CompactionError(failures.join(", ")));
+        }
+        info!(
+            self.log,
+            "\nDatabases compacted, reclaimed {} bytes", reclaimed
+        );
+
+        Ok(reclaimed)
+    }
+
+    // Flushes every Sled database to disk, collecting the failures of all of them
+    pub async fn flush_all(&self) -> Result<(), NodeServiceError> {
+        let mut failures = Vec::new();
+        if let Err(e) = self.chain.blocks().flush().await {
+            failures.push(format!("block db: {}", e));
+        }
+        if let Err(e) = self.chain.outputs().flush().await {
+            failures.push(format!("output db: {}", e));
+        }
+        if let Err(e) = self.chain.images().flush().await {
+            failures.push(format!("image db: {}", e));
+        }
+        if let Err(e) = IP_STORER.flush().await {
+            failures.push(format!("ip db: {}", e));
+        }
+        if let Err(e) = self.chain.meta().flush().await {
+            failures.push(format!("meta db: {}", e));
+        }
+        if !failures.is_empty() {
+            return Err(NodeServiceError::FlushError(failures.join(", ")));
+        }
+        info!(self.log, "\nDatabases flushed");
+
+        Ok(())
+    }
+}
+
+// Settings of a node process that aren't asked for at startup, read from the environment like the data directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeConfig {
+    pub compaction_interval: Duration,
+    pub admin_addr: Option<String>,
+    pub keepalive: KeepaliveConfig,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            compaction_interval: COMPACTION_INTERVAL,
+            admin_addr: None,
+            keepalive: KeepaliveConfig::default(),
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn from_env() -> Result<NodeConfig, ServerConfigError> {
+        NodeConfig::from_vars(|name| std::env::var(name).ok())
+    }
+
+    // Settings whose variable isn't set keep their default
+    pub fn from_vars(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<NodeConfig, ServerConfigError> {
+        let mut config = NodeConfig::default();
+        if let Some(interval) = parse_secs(&lookup, COMPACTION_INTERVAL_VAR)? {
+            config.compaction_interval = interval;
+        }
+        if let Some(interval) = parse_secs(&lookup, HTTP2_KEEPALIVE_INTERVAL_VAR)? {
+            config.keepalive.http2_interval = interval;
+        }
+        if let Some(timeout) = parse_secs(&lookup, HTTP2_KEEPALIVE_TIMEOUT_VAR)? {
+            config.keepalive.http2_timeout = timeout;
+        }
+        if let Some(tcp) = parse_secs(&lookup, TCP_KEEPALIVE_VAR)? {
+            config.keepalive.tcp = Some(tcp);
+        }
+        if let Some(admin_addr) = lookup(ADMIN_ADDR_VAR) {
+            let admin_addr = admin_addr.trim().to_string();
+            if admin_addr.parse::<SocketAddr>().is_err() {
+                return Err(ServerConfigError::InvalidSetting {
+                    name: ADMIN_ADDR_VAR.to_string(),
+                    value: admin_addr,
+                });
+            }
+            config.admin_addr = Some(admin_addr);
+        }
+        Ok(config)
+    }
+}
+
+fn parse_secs(
+    lookup: &impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<Duration>, ServerConfigError> {
+    let Some(value) = lookup(name) else {
+        return Ok(None);
+    };
+    match value.trim().parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(Some(Duration::from_secs(secs))),
+        _ => Err(ServerConfigError::InvalidSetting {
+            name: name.to_string(),
+            value,
+        }),
+    }
+}
+
+impl NodeService {
+    pub fn with_config(mut self, config: &NodeConfig) -> NodeService {
+        self.compaction_interval = config.compaction_interval;
+        self.admin_addr = config.admin_addr.clone();
+        self.keepalive = config.keepalive;
+        self
+    }
 }
 
-pub async fn new(secret_key: String, ip: String) -> Result<ArcNodeService, NodeServiceError> {
-    let ns = NodeService::new(secret_key, ip).await?;
+pub async fn new(
+    chain: Arc<Chain>,
+    secret_key: String,
+    bind_addr: String,
+    advertised_addr: String,
+    is_validator: bool,
+    config: &NodeConfig,
+) -> Result<ArcNodeService, NodeServiceError> {
+    let ns = NodeService::new(chain, secret_key, bind_addr, advertised_addr, is_validator)
+        .await?
+        .with_config(config);
     Ok(ArcNodeService { ns: Arc::new(ns) })
 }
 
 pub async fn start(arc_ns: &Arc<NodeService>) -> Result<(), NodeServiceError> {
     let ip = arc_ns
-        .ip
+        .bind_addr
         .parse()
         .map_err(NodeServiceError::AddrParseError)?;
     info!(arc_ns.log, "\nNodeServer starting listening on {}", ip);
+    arc_ns.spawn_stall_watchdog(CHAIN_STALL_THRESHOLD, CHAIN_STALL_CHECK_INTERVAL);
+    arc_ns.spawn_ip_pruner(IP_PRUNE_INTERVAL);
+    arc_ns.spawn_pull_pruner(PULL_PRUNE_INTERVAL);
+    arc_ns.spawn_compactor();
+    if let Some(admin_addr) = &arc_ns.admin_addr {
+        let admin_ip = admin_addr
+            .parse()
+            .map_err(NodeServiceError::AddrParseError)?;
+        let admin_ns = Arc::clone(arc_ns);
+        tokio::spawn(async move {
+            if let Err(e) = setup_admin_server(&admin_ns, admin_ip).await {
+                error!(admin_ns.log, "\nAdmin server stopped: {}", e);
+            }
+        });
+    }
     setup_server(arc_ns, ip).await?;
 
     Ok(())
 }
 
+// Peers speak plain HTTP/2 gRPC, HTTP/1 is only accepted when accept_http1 is set for a grpc-web gateway
 pub async fn setup_server(
     arc_ns: &Arc<NodeService>,
     cfg_ip: SocketAddr,
@@ -894,13 +2395,31 @@ pub async fn setup_server(
         ns: Arc::clone(arc_ns),
     };
     Server::builder()
-        .accept_http1(true)
+        .accept_http1(arc_ns.accept_http1)
         .add_service(NodeServer::new(ans))
         .serve(cfg_ip)
         .await
         .map_err(NodeServiceError::TonicTransportError)
 }
 
+// Serves the admin calls, which take secret keys, so only on a loopback address
+pub async fn setup_admin_server(
+    arc_ns: &Arc<NodeService>,
+    admin_ip: SocketAddr,
+) -> Result<(), NodeServiceError> {
+    if !admin_ip.ip().is_loopback() {
+        return Err(NodeServiceError::AdminNotLoopback(admin_ip));
+    }
+    let admin = AdminService {
+        ns: Arc::clone(arc_ns),
+    };
+    Server::builder()
+        .add_service(AdminServer::new(admin))
+        .serve(admin_ip)
+        .await
+        .map_err(NodeServiceError::TonicTransportError)
+}
+
 pub async fn bootstrap_network(
     ans: &ArcNodeService,
     ips: Vec<String>,
@@ -911,9 +2430,17 @@ pub async fn bootstrap_network(
         let task = tokio::spawn(async move {
             match ns_arc.dial_remote_node(&ip).await {
                 Ok((c, v)) => {
-                    match ns_arc.add_peer(c, v).await {
+                    let address = bs58::encode(&v.msg_address).into_string();
+                    let remote_index = v.msg_local_index;
+                    match ns_arc.add_peer(c, v, PeerDirection::Outbound, &ip).await {
                         Ok(_) => {
                             info!(ns_arc.log, "\nNew peer added");
+                            if let Err(e) = ns_arc
+                                .sync_if_ahead(&ns_arc.wallet, &address, remote_index)
+                                .await
+                            {
+                                error!(ns_arc.log, "\nSynchronisation failed: {:?}", e);
+                            }
                         }
                         Err(e) => {
                             error!(ns_arc.log, "Failed to add peer: {:?}", e);
@@ -935,11 +2462,277 @@ pub async fn bootstrap_network(
     Ok(())
 }
 
-pub async fn make_node_client(ip: &str) -> Result<NodeClient<Channel>, NodeServiceError> {
-    let uri = format!("http://{}", ip)
-        .parse()
-        .map_err(NodeServiceError::UriParseError)?;
-    let channel = Channel::builder(uri)
+// Keepalive of peer connections, so idle ones dropped by intermediaries are noticed before a broadcast
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    pub http2_interval: Duration,
+    pub http2_timeout: Duration,
+    pub tcp: Option<Duration>,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            http2_interval: HTTP2_KEEPALIVE_INTERVAL,
+            http2_timeout: HTTP2_KEEPALIVE_TIMEOUT,
+            tcp: Some(TCP_KEEPALIVE),
+        }
+    }
+}
+
+// Channel builder settings touched by the keepalive config
+pub trait KeepaliveBuilder: Sized {
+    fn http2_keep_alive_interval(self, interval: Duration) -> Self;
+    fn keep_alive_timeout(self, timeout: Duration) -> Self;
+    fn keep_alive_while_idle(self, enabled: bool) -> Self;
+    fn tcp_keepalive(self, keepalive: Option<Duration>) -> Self;
+}
+
+impl KeepaliveBuilder for Endpoint {
+    fn http2_keep_alive_interval(self, interval: Duration) -> Self {
+        Endpoint::http2_keep_alive_interval(self, interval)
+    }
+
+    fn keep_alive_timeout(self, timeout: Duration) -> Self {
+        Endpoint::keep_alive_timeout(self, timeout)
+    }
+
+    fn keep_alive_while_idle(self, enabled: bool) -> Self {
+        Endpoint::keep_alive_while_idle(self, enabled)
+    }
+
+    fn tcp_keepalive(self, keepalive: Option<Duration>) -> Self {
+        Endpoint::tcp_keepalive(self, keepalive)
+    }
+}
+
+pub fn apply_keepalive<B: KeepaliveBuilder>(builder: B, config: &KeepaliveConfig) -> B {
+    builder
+        .http2_keep_alive_interval(config.http2_interval)
+        .keep_alive_timeout(config.http2_timeout)
+        .keep_alive_while_idle(true)
+        .tcp_keepalive(config.tcp)
+}
+
+// Validation errors no honest peer causes: input data that can't even be checked, as opposed to a
+// transaction that's merely invalid or stale
+pub fn is_malformed_transaction(e: &NodeServiceError) -> bool {
+    matches!(
+        e,
+        NodeServiceError::ChainOpsError(
+            ChainOpsError::InvalidRingMember { .. }
+                | ChainOpsError::CryptoOpsError(
+                    CryptoOpsError::RingSizeMismatch { .. }
+                        | CryptoOpsError::DecompressionFailed
+                        | CryptoOpsError::InvalidBLSAGLength
+                )
+        )
+    )
+}
+
+// /16 of an IPv4 or /32 of an IPv6 peer address, loopback and unparsable addresses (local nets) belong to none
+pub fn ip_subnet(ip: &str) -> Option<String> {
+    let ip = match ip.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip(),
+        Err(_) => ip.parse::<std::net::IpAddr>().ok()?,
+    };
+    if ip.is_loopback() {
+        return None;
+    }
+    match ip {
+        std::net::IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            Some(format!("{}.{}.0.0/16", octets[0], octets[1]))
+        }
+        std::net::IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            Some(format!("{:x}:{:x}::/32", segments[0], segments[1]))
+        }
+    }
+}
+
+// Mines the block to the difficulty on the blocking pool, the parallel search would otherwise stall the runtime's
+// workers. A header whose nonces run out gets its timestamp rolled
+pub async fn mine_block(
+    block: Block,
+    difficulty: usize,
+    threads: usize,
+) -> Result<Block, MiningError> {
+    tokio::task::spawn_blocking(move || {
+        mine_rolling(&POW_HASHER, block, difficulty, threads, NONCE_SPACE)
+    })
+    .await?
+}
+
+// Reconstructs the node's wallet with the view key derivation it was started with the first time, so its address
+// and outputs don't change across restarts, and with its stored birthday. Data written before the format was
+// recorded is legacy if the wallet finds its stored outputs only with the legacy view key
+pub async fn restore_node_wallet(
+    chain: &Chain,
+    secret_spend_key: Scalar,
+) -> Result<Wallet, NodeServiceError> {
+    let wallet = Wallet::reconstruct(secret_spend_key)?;
+    let key = wallet_format_key(wallet.public_spend_key.as_bytes());
+    let format = match chain.meta().get(&key).await.map_err(ChainOpsError::from)? {
+        Some(format) => format,
+        None => {
+            let legacy = Wallet::reconstruct_legacy(secret_spend_key)?;
+            let mut format = WALLET_FORMAT;
+            let stored = chain.outputs().get().await.map_err(ChainOpsError::from)?;
+            for owned_output in stored {
+                let output = &owned_output.output;
+                let owned_by_legacy = legacy.check_property(
+                    CompressedRistretto::from_slice(&output.output_key),
+                    output.index,
+                    CompressedRistretto::from_slice(&output.stealth),
+                );
+                if owned_by_legacy.unwrap_or(false) {
+                    format = LEGACY_WALLET_FORMAT;
+                    break;
+                }
+            }
+            chain
+                .meta()
+                .put(&key, format)
+                .await
+                .map_err(ChainOpsError::from)?;
+            format
+        }
+    };
+    let wallet = if format == LEGACY_WALLET_FORMAT {
+        Wallet::reconstruct_legacy(secret_spend_key)?
+    } else {
+        wallet
+    };
+    chain.load_birthday(&wallet).await?;
+    Ok(wallet)
+}
+
+// Message a node signs with its identity key to prove it owns the address it advertises. It names the challenger
+// and the endpoint the challenger dialed, so an answer relayed from another node's session doesn't verify
+pub fn identity_message(nonce: &[u8], challenger: &[u8], endpoint: &str) -> Vec<u8> {
+    [
+        IDENTITY_CHALLENGE_DOMAIN,
+        nonce,
+        challenger,
+        endpoint.as_bytes(),
+    ]
+    .concat()
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Message a connecting node signs in its handshake, binding the server's nonce to the advertised ip.
+// Its own domain keeps the challenge RPC from being used to sign handshakes on another node's behalf
+pub fn handshake_message(nonce: &[u8], ip: &str) -> Vec<u8> {
+    [HANDSHAKE_DOMAIN, nonce, ip.as_bytes()].concat()
+}
+
+// Fetches a nonce from the server and signs the version over it with the wallet's spend key
+pub async fn sign_handshake(
+    client: &mut NodeClient<Channel>,
+    wallet: &Wallet,
+    mut version: Version,
+) -> Result<Version, NodeServiceError> {
+    let nonce = client
+        .handshake_nonce(NonceRequest {})
+        .await?
+        .into_inner()
+        .msg_nonce;
+    let signature = wallet.sign(&handshake_message(&nonce, &version.msg_ip))?;
+    version.msg_nonce = nonce;
+    version.msg_signature = signature.to_vec();
+    Ok(version)
+}
+
+// Challenges the peer dialed at the endpoint to sign a fresh nonce with an identity key certified by the spend key
+// of the address it advertised, so one entity can't present identities whose keys it doesn't hold
+pub async fn verify_peer_identity(
+    client: &mut NodeClient<Channel>,
+    address: &[u8],
+    challenger: &[u8],
+    endpoint: &str,
+) -> Result<(), NodeServiceError> {
+    if address.len() != 64 {
+        return Err(NodeServiceError::PeerIdentityUnverified);
+    }
+    let nonce: [u8; 32] = rand::random();
+    let response = client
+        .challenge(ChallengeRequest {
+            msg_nonce: nonce.to_vec(),
+            msg_challenger: challenger.to_vec(),
+        })
+        .await
+        .map_err(|_| NodeServiceError::PeerIdentityUnverified)?
+        .into_inner();
+    if response.msg_identity_key.len() != 32 {
+        return Err(NodeServiceError::PeerIdentityUnverified);
+    }
+    let public_spend_key = CompressedRistretto::from_slice(&address[..32]);
+    let identity_key = CompressedRistretto::from_slice(&response.msg_identity_key);
+    let message = identity_message(&nonce, challenger, endpoint);
+    match (
+        Signature::from_vec(&response.msg_certificate),
+        Signature::from_vec(&response.msg_signature),
+    ) {
+        (Some(certificate), Some(signature))
+            if verify_identity_key(&public_spend_key, &identity_key, &certificate)
+                && verify(&identity_key, &message, &signature) =>
+        {
+            Ok(())
+        }
+        _ => Err(NodeServiceError::PeerIdentityUnverified),
+    }
+}
+
+// Checks that a peer address has the host:port shape before it's turned into a URI,
+// the host being a name, an IPv4 address or a bracketed IPv6 address
+pub fn check_peer_addr(ip: &str) -> Result<(), &'static str> {
+    let (host, port) = ip.rsplit_once(':').ok_or("missing port")?;
+    match port.parse::<u16>() {
+        Ok(0) | Err(_) => return Err("port must be a number between 1 and 65535"),
+        Ok(_) => {}
+    }
+    if host.is_empty() {
+        return Err("missing host");
+    }
+    if let Some(v6) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return match v6.parse::<std::net::Ipv6Addr>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err("invalid IPv6 host"),
+        };
+    }
+    if !host
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    {
+        return Err("host may only contain letters, digits, '-' and '.'");
+    }
+    Ok(())
+}
+
+pub async fn make_node_client(ip: &str) -> Result<NodeClient<Channel>, NodeServiceError> {
+    make_node_client_with(ip, &KeepaliveConfig::default()).await
+}
+
+pub async fn make_node_client_with(
+    ip: &str,
+    keepalive: &KeepaliveConfig,
+) -> Result<NodeClient<Channel>, NodeServiceError> {
+    let invalid = |reason: String| NodeServiceError::UriParseError {
+        input: ip.to_string(),
+        reason,
+    };
+    check_peer_addr(ip).map_err(|reason| invalid(reason.to_string()))?;
+    let uri = format!("http://{}", ip)
+        .parse::<tonic::transport::Uri>()
+        .map_err(|e| invalid(e.to_string()))?;
+    let channel = apply_keepalive(Channel::builder(uri), keepalive)
         .connect()
         .await
         .map_err(NodeServiceError::TonicTransportError)?;
@@ -948,14 +2741,1489 @@ pub async fn make_node_client(ip: &str) -> Result<NodeClient<Channel>, NodeServi
     Ok(node_client)
 }
 
+pub async fn make_admin_client(ip: &str) -> Result<AdminClient<Channel>, NodeServiceError> {
+    let uri = format!("http://{}", ip)
+        .parse::<tonic::transport::Uri>()
+        .map_err(|e| NodeServiceError::UriParseError {
+            input: ip.to_string(),
+            reason: e.to_string(),
+        })?;
+    let channel = Channel::builder(uri)
+        .connect()
+        .await
+        .map_err(NodeServiceError::TonicTransportError)?;
+    Ok(AdminClient::new(channel))
+}
+
 pub fn string_to_vec(string: &str) -> Vec<u8> {
     bs58::decode(string).into_vec().unwrap()
 }
 
 pub async fn shutdown(
+    arc_ns: &Arc<NodeService>,
     shutdown_tx: tokio::sync::oneshot::Sender<()>,
 ) -> Result<(), NodeServiceError> {
+    arc_ns.flush_all().await?;
     shutdown_tx
         .send(())
         .map_err(|_| NodeServiceError::ShutdownError)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tonic::transport::server::Connected;
+    use vec_chain::fixtures::{init_test_data_dir, mined_chain, spending_transaction, temp_chain};
+
+    // Every test node gets a chain of its own, the IPDB all nodes share lives in a temp dir
+    async fn test_chain() -> Arc<Chain> {
+        init_test_data_dir();
+        temp_chain().await
+    }
+
+    async fn make_node() -> NodeService {
+        let wallet = Wallet::generate().unwrap();
+        let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+        NodeService::new(
+            test_chain().await,
+            secret_key,
+            "127.0.0.1:8080".to_string(),
+            "127.0.0.1:8080".to_string(),
+            false,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_confirmed_transaction_is_not_readded() {
+        let node = make_node().await;
+        let transaction = spending_transaction(b"confirmed transaction");
+        let hash = hash_transaction(&transaction);
+        node.chain
+            .blocks()
+            .put_tx_index(hash.clone(), b"block hash".to_vec())
+            .await
+            .unwrap();
+
+        assert!(!node.handle_transaction(&transaction).await.unwrap());
+        assert!(!node.mempool.has(&transaction));
+
+        let ans = ArcNodeService { ns: Arc::new(node) };
+        let request = Request::new(PushTxRequest {
+            msg_transaction_hash: hash,
+            msg_ip: "127.0.0.1:8081".to_string(),
+        });
+        assert!(ans.handle_tx_push(request).await.is_ok());
+        assert!(!ans.ns.mempool.has(&transaction));
+    }
+
+    #[tokio::test]
+    async fn test_unconfirmed_transaction_is_added() {
+        let node = make_node().await;
+        let transaction = spending_transaction(b"unconfirmed transaction");
+
+        assert!(node.handle_transaction(&transaction).await.unwrap());
+        assert!(node.mempool.has(&transaction));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_advertises_public_address() {
+        let wallet = Wallet::generate().unwrap();
+        let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+        let node = NodeService::new(
+            test_chain().await,
+            secret_key,
+            "0.0.0.0:8080".to_string(),
+            "203.0.113.7:8080".to_string(),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(node.bind_addr.as_str(), "0.0.0.0:8080");
+        assert_eq!(node.get_version().await.msg_ip, "203.0.113.7:8080");
+
+        let ans = ArcNodeService { ns: Arc::new(node) };
+        let remote = make_node().await;
+        let version = signed_version(&ans.ns, &remote.wallet, remote.get_version().await).await;
+        let reply = ans
+            .handshake(Request::new(version))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(reply.msg_ip, "203.0.113.7:8080");
+    }
+
+    // Signs the version over a nonce issued by the receiving node, as sign_handshake does over RPC
+    async fn signed_version(ns: &NodeService, wallet: &Wallet, mut version: Version) -> Version {
+        version.msg_nonce = ns.issue_handshake_nonce();
+        version.msg_signature = wallet
+            .sign(&handshake_message(&version.msg_nonce, &version.msg_ip))
+            .unwrap()
+            .to_vec();
+        version
+    }
+
+    #[tokio::test]
+    async fn test_handshake_nonce_expires() {
+        let mut node = make_node().await;
+        node.handshake_nonce_ttl = Duration::from_millis(50);
+        let wallet = Wallet::generate().unwrap();
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: wallet.address.to_vec(),
+            msg_ip: "127.0.0.1:1".to_string(),
+            ..Default::default()
+        };
+
+        let expired = signed_version(&node, &wallet, version.clone()).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(
+            node.verify_handshake(&expired).await,
+            Err(NodeServiceError::InvalidHandshakeNonce)
+        ));
+        let fresh = signed_version(&node, &wallet, version.clone()).await;
+
+        // A fresh nonce signed by a key other than the advertised one is rejected
+        let mut forged = fresh.clone();
+        forged.msg_signature = Wallet::generate()
+            .unwrap()
+            .sign(&handshake_message(&fresh.msg_nonce, &fresh.msg_ip))
+            .unwrap()
+            .to_vec();
+        assert!(matches!(
+            node.verify_handshake(&forged).await,
+            Err(NodeServiceError::InvalidHandshakeSignature)
+        ));
+
+        let fresh = signed_version(&node, &wallet, version.clone()).await;
+        node.verify_handshake(&fresh).await.unwrap();
+        assert_eq!(node.used_handshake_nonces.len(), 1);
+        // Replaying it is rejected, and once expired it's forgotten when the next handshake verifies
+        assert!(matches!(
+            node.verify_handshake(&fresh).await,
+            Err(NodeServiceError::InvalidHandshakeNonce)
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let next = signed_version(&node, &wallet, version).await;
+        node.verify_handshake(&next).await.unwrap();
+        assert_eq!(node.used_handshake_nonces.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_nonce_is_stateless_and_unforgeable() {
+        let node = make_node().await;
+        for _ in 0..10_000 {
+            node.issue_handshake_nonce();
+        }
+        assert!(node.used_handshake_nonces.is_empty());
+
+        let wallet = Wallet::generate().unwrap();
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: wallet.address.to_vec(),
+            msg_ip: "127.0.0.1:1".to_string(),
+            ..Default::default()
+        };
+        // A nonce from another node, or one whose issue time was moved forward, doesn't verify
+        let other = make_node().await;
+        let foreign = signed_version(&other, &wallet, version.clone()).await;
+        assert!(matches!(
+            node.verify_handshake(&foreign).await,
+            Err(NodeServiceError::InvalidHandshakeNonce)
+        ));
+        let mut nonce = node.issue_handshake_nonce();
+        nonce[NONCE_TIME_LEN - 1] ^= 1;
+        let mut tampered = version;
+        tampered.msg_signature = wallet
+            .sign(&handshake_message(&nonce, &tampered.msg_ip))
+            .unwrap()
+            .to_vec();
+        tampered.msg_nonce = nonce;
+        assert!(matches!(
+            node.verify_handshake(&tampered).await,
+            Err(NodeServiceError::InvalidHandshakeNonce)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_block_does_not_change_outputs() {
+        let node = make_node().await;
+        let output = node.wallet.prepare_change_output(500, 1).unwrap();
+        let stealth = output.msg_stealth_address.clone();
+        let transaction = Transaction {
+            msg_outputs: vec![output],
+            ..Default::default()
+        };
+        let local_index = node.chain.max_index().await.unwrap();
+        let block = Block {
+            msg_header: Some(Header {
+                msg_index: local_index + 1,
+                msg_previous_hash: b"invalid previous hash".to_vec(),
+                ..Default::default()
+            }),
+            msg_transactions: vec![transaction],
+        };
+
+        let result = node
+            .process_block(&node.wallet, block, "127.0.0.1:8081")
+            .await;
+        assert!(result.is_err());
+        let outputs = node.chain.outputs().get().await.unwrap();
+        assert!(!outputs.iter().any(|o| o.output.stealth == stealth));
+    }
+
+    #[tokio::test]
+    async fn test_flush_all() {
+        let node = make_node().await;
+        assert!(node.flush_all().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_compact_all() {
+        let node = make_node().await;
+        assert!(node.compact_all().await.is_ok());
+        assert!(node.flush_all().await.is_ok());
+    }
+
+    #[test]
+    fn test_node_config_from_vars() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                pairs
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(
+            NodeConfig::from_vars(vars(&[])).unwrap(),
+            NodeConfig::default()
+        );
+        let config = NodeConfig::from_vars(vars(&[
+            (COMPACTION_INTERVAL_VAR, "3600"),
+            (ADMIN_ADDR_VAR, "127.0.0.1:9090"),
+        ]))
+        .unwrap();
+        assert_eq!(config.compaction_interval, Duration::from_secs(3600));
+        assert_eq!(config.admin_addr.as_deref(), Some("127.0.0.1:9090"));
+        assert_eq!(config.keepalive, KeepaliveConfig::default());
+        let config = NodeConfig::from_vars(vars(&[
+            (HTTP2_KEEPALIVE_INTERVAL_VAR, "5"),
+            (HTTP2_KEEPALIVE_TIMEOUT_VAR, "2"),
+            (TCP_KEEPALIVE_VAR, "20"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.keepalive,
+            KeepaliveConfig {
+                http2_interval: Duration::from_secs(5),
+                http2_timeout: Duration::from_secs(2),
+                tcp: Some(Duration::from_secs(20)),
+            }
+        );
+        for invalid in [
+            &[(COMPACTION_INTERVAL_VAR, "0")],
+            &[(COMPACTION_INTERVAL_VAR, "soon")],
+            &[(ADMIN_ADDR_VAR, "localhost")],
+            &[(HTTP2_KEEPALIVE_TIMEOUT_VAR, "0")],
+        ] {
+            assert!(matches!(
+                NodeConfig::from_vars(vars(invalid)),
+                Err(ServerConfigError::InvalidSetting { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_config_applies_settings() {
+        let config = NodeConfig {
+            compaction_interval: Duration::from_secs(60),
+            admin_addr: Some("127.0.0.1:9090".to_string()),
+            keepalive: KeepaliveConfig {
+                http2_interval: Duration::from_secs(5),
+                http2_timeout: Duration::from_secs(2),
+                tcp: None,
+            },
+        };
+        let node = make_node().await.with_config(&config);
+        assert_eq!(node.compaction_interval, config.compaction_interval);
+        assert_eq!(node.admin_addr, config.admin_addr);
+        // Peers are dialed with the configured keepalive
+        assert_eq!(node.keepalive, config.keepalive);
+    }
+
+    async fn spawn_test_server<N: Node>(node: N) -> NodeClient<Channel> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        tokio::spawn(
+            Server::builder()
+                .add_service(NodeServer::new(node))
+                .serve(addr),
+        );
+        loop {
+            if let Ok(client) = make_node_client(&addr.to_string()).await {
+                return client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    // Node listening on a free port through setup_server, as start does
+    async fn spawn_http2_only_node() -> Arc<NodeService> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let wallet = Wallet::generate().unwrap();
+        let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+        let node = NodeService::new(
+            test_chain().await,
+            secret_key,
+            addr.to_string(),
+            addr.to_string(),
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(!node.accept_http1);
+        let node = Arc::new(node);
+        let server = Arc::clone(&node);
+        tokio::spawn(async move { setup_server(&server, addr).await });
+        node
+    }
+
+    #[tokio::test]
+    async fn test_http2_only_nodes_handshake() {
+        let dialer = spawn_http2_only_node().await;
+        let listener = spawn_http2_only_node().await;
+
+        let (_, version) = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(dialed) = dialer.dial_remote_node(&listener.bind_addr).await {
+                    return dialed;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(version.msg_address, listener.wallet.address.to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_sync_falls_back_to_another_peer() {
+        let node = make_node().await;
+        let slow = MockTransport {
+            delay: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let fast = MockTransport::default();
+        node.peers
+            .insert("slow".to_string(), Arc::new(RwLock::new(Box::new(slow))));
+        node.peers
+            .insert("fast".to_string(), Arc::new(RwLock::new(Box::new(fast))));
+
+        let started = std::time::Instant::now();
+        let synced_with = node
+            .synchronize_with_peers(
+                &node.wallet,
+                &["slow".to_string(), "fast".to_string()],
+                Duration::from_millis(200),
+            )
+            .await
+            .unwrap();
+        assert_eq!(synced_with, "fast");
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+
+    fn insert_mock_peer(node: &NodeService, address: &str, transport: MockTransport, index: u32) {
+        node.peers.insert(
+            address.to_string(),
+            Arc::new(RwLock::new(Box::new(transport))),
+        );
+        let version = Version {
+            msg_local_index: index,
+            ..Default::default()
+        };
+        node.peer_versions
+            .insert(address.to_string(), (version, unix_millis()));
+    }
+
+    #[tokio::test]
+    async fn test_synchronize_falls_back_from_failing_peer() {
+        let node = make_node().await;
+        let failing = MockTransport {
+            state_error: true,
+            ..Default::default()
+        };
+        insert_mock_peer(&node, "failing", failing, 5);
+        insert_mock_peer(&node, "behind", MockTransport::default(), 0);
+        insert_mock_peer(&node, "ahead", MockTransport::default(), 3);
+
+        let synced_with = node.synchronize(&node.wallet, "failing").await.unwrap();
+        assert_eq!(synced_with, "ahead");
+        assert_eq!(
+            node.sync_target.load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_better_peer_cancels_running_sync() {
+        let node = Arc::new(make_node().await);
+        let slow = MockTransport {
+            delay: Duration::from_secs(30),
+            ..Default::default()
+        };
+        insert_mock_peer(&node, "slow", slow, 3);
+        let running = {
+            let node = Arc::clone(&node);
+            tokio::spawn(async move { node.sync_if_ahead(&node.wallet, "slow", 3).await })
+        };
+        while node.sync_target.load(std::sync::atomic::Ordering::SeqCst) != 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // A peer no further ahead leaves the running synchronisation alone
+        node.sync_if_ahead(&node.wallet, "slow", 3).await.unwrap();
+        assert!(!running.is_finished());
+
+        insert_mock_peer(&node, "better", MockTransport::default(), 5);
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            node.sync_if_ahead(&node.wallet, "better", 5),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(matches!(
+            tokio::time::timeout(Duration::from_secs(5), running)
+                .await
+                .unwrap()
+                .unwrap(),
+            Err(NodeServiceError::SyncCancelled)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_sync() {
+        let node = Arc::new(make_node().await);
+        let mut slow = MockTransport {
+            delay: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let token = node.new_sync_token();
+        let canceller = Arc::clone(&node);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel_sync();
+        });
+        let result = node
+            .synchronize_with_client_cancellable(
+                &node.wallet,
+                &mut slow,
+                &token,
+                Duration::from_secs(60),
+            )
+            .await;
+        assert!(matches!(result, Err(NodeServiceError::SyncCancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_stall_watchdog_fires_on_stale_tip() {
+        let node = make_node().await;
+        let now = node.chain.unix_now();
+        let stale_tip = Block {
+            msg_header: Some(Header {
+                msg_timestamp: now - 3600,
+                ..Default::default()
+            }),
+            msg_transactions: vec![],
+        };
+        let threshold = Duration::from_secs(600);
+
+        let tip_age = seconds_since_block(&stale_tip, now).unwrap();
+        assert!(node.report_tip_age(tip_age, threshold));
+        assert!(node
+            .chain_stalled
+            .load(std::sync::atomic::Ordering::Relaxed));
+
+        assert!(!node.report_tip_age(5, threshold));
+        assert!(!node
+            .chain_stalled
+            .load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    async fn add_test_peer(node: &NodeService, direction: PeerDirection) -> bool {
+        add_test_peer_at(node, direction, "127.0.0.1:1")
+            .await
+            .is_ok()
+    }
+
+    async fn add_test_peer_at(
+        node: &NodeService,
+        direction: PeerDirection,
+        ip: &str,
+    ) -> Result<(), NodeServiceError> {
+        add_test_peer_from(node, direction, ip, ip).await
+    }
+
+    // Peer reporting msg_ip about itself while its connection comes from connection_addr
+    async fn add_test_peer_from(
+        node: &NodeService,
+        direction: PeerDirection,
+        msg_ip: &str,
+        connection_addr: &str,
+    ) -> Result<(), NodeServiceError> {
+        let client = NodeClient::new(Channel::from_static("http://127.0.0.1:1").connect_lazy());
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: Wallet::generate().unwrap().address.to_vec(),
+            msg_ip: msg_ip.to_string(),
+            msg_local_index: 0,
+            ..Default::default()
+        };
+        node.add_peer(client, version, direction, connection_addr)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_outbound_slots_reserved() {
+        let node = make_node().await;
+        let inbound_slots = MAX_PEERS - node.reserved_outbound_slots;
+        for _ in 0..inbound_slots {
+            assert!(add_test_peer(&node, PeerDirection::Inbound).await);
+        }
+        assert!(!node.has_peer_slot(PeerDirection::Inbound));
+        assert!(!add_test_peer(&node, PeerDirection::Inbound).await);
+        assert_eq!(node.count_peers(PeerDirection::Inbound), inbound_slots);
+
+        for _ in 0..node.reserved_outbound_slots {
+            assert!(node.has_peer_slot(PeerDirection::Outbound));
+            assert!(add_test_peer(&node, PeerDirection::Outbound).await);
+        }
+        assert_eq!(node.peers.len(), MAX_PEERS);
+
+        assert!(add_test_peer(&node, PeerDirection::Outbound).await);
+        assert_eq!(node.peers.len(), MAX_PEERS);
+        assert_eq!(node.count_peers(PeerDirection::Inbound), inbound_slots - 1);
+        assert_eq!(
+            node.count_peers(PeerDirection::Outbound),
+            node.reserved_outbound_slots + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peers_per_subnet_are_capped() {
+        let node = make_node().await;
+        let crowded = "198.51.0.0/16";
+        for i in 0..node.max_peers_per_subnet {
+            let ip = format!("198.51.{}.1:8080", i);
+            add_test_peer_at(&node, PeerDirection::Inbound, &ip)
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            add_test_peer_at(&node, PeerDirection::Inbound, "198.51.200.1:8080").await,
+            Err(NodeServiceError::SubnetLimitReached(subnet)) if subnet == crowded
+        ));
+        assert!(matches!(
+            add_test_peer_at(&node, PeerDirection::Outbound, "198.51.201.1:8080").await,
+            Err(NodeServiceError::SubnetLimitReached(_))
+        ));
+        assert_eq!(node.count_subnet_peers(crowded), node.max_peers_per_subnet);
+        // Claiming an address elsewhere doesn't help, the subnet is the one the connection comes from
+        assert!(matches!(
+            add_test_peer_from(
+                &node,
+                PeerDirection::Inbound,
+                "203.0.113.1:8080",
+                "198.51.202.1:8080"
+            )
+            .await,
+            Err(NodeServiceError::SubnetLimitReached(subnet)) if subnet == crowded
+        ));
+
+        // Every other subnet still gets its slots, and eviction thins out the crowded one first
+        let mut subnet = 0;
+        while node.peers.len() < MAX_PEERS {
+            subnet += 1;
+            let direction = if node.has_peer_slot(PeerDirection::Inbound) {
+                PeerDirection::Inbound
+            } else {
+                PeerDirection::Outbound
+            };
+            let ip = format!("203.{}.0.1:8080", subnet);
+            add_test_peer_at(&node, direction, &ip).await.unwrap();
+        }
+        add_test_peer_at(&node, PeerDirection::Outbound, "192.0.2.1:8080")
+            .await
+            .unwrap();
+        assert_eq!(node.peers.len(), MAX_PEERS);
+        assert_eq!(
+            node.count_subnet_peers(crowded),
+            node.max_peers_per_subnet - 1
+        );
+        assert_eq!(ip_subnet("127.0.0.1:1"), None);
+        assert_eq!(
+            ip_subnet("[2001:db8::1]:8080").as_deref(),
+            Some("2001:db8::/32")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_mempool_summary() {
+        let ns = Arc::new(make_node().await);
+        let transactions = vec![
+            spending_transaction(b"mempool summary 1"),
+            spending_transaction(b"mempool summary 2"),
+        ];
+        for transaction in &transactions {
+            assert!(ns.mempool.add(transaction.clone()));
+        }
+        let mut client = spawn_test_server(ArcNodeService {
+            ns: Arc::clone(&ns),
+        })
+        .await;
+
+        let summary = client
+            .get_mempool(Request::new(MempoolRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(summary.msg_count, 2);
+        let mut expected_hashes: Vec<Vec<u8>> = transactions.iter().map(hash_transaction).collect();
+        expected_hashes.sort();
+        assert_eq!(summary.msg_tx_hashes, expected_hashes);
+        assert_eq!(
+            summary.msg_total_weight,
+            transactions.iter().map(transaction_weight).sum::<u64>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_handshakes_during_block_application() {
+        let ns = Arc::new(make_node().await);
+        let blocks = mined_chain(&ns.wallet, 6, ns.chain.unix_now());
+        let client = spawn_test_server(ArcNodeService {
+            ns: Arc::clone(&ns),
+        })
+        .await;
+
+        // Applied the way blocks from peers are: the genesis as sync does, the rest as pushed blocks are
+        let applier = {
+            let ns = Arc::clone(&ns);
+            tokio::spawn(async move {
+                let mut blocks = blocks.into_iter();
+                let genesis = blocks.next().unwrap();
+                ns.apply_synced_block(&ns.wallet, &genesis).await.unwrap();
+                for block in blocks {
+                    ns.process_block(&ns.wallet, block, "127.0.0.1:1")
+                        .await
+                        .unwrap();
+                }
+            })
+        };
+        let handshakes: Vec<_> = (0..50)
+            .map(|_| {
+                let mut client = client.clone();
+                let ns = Arc::clone(&ns);
+                let wallet = Wallet::generate().unwrap();
+                let version = Version {
+                    msg_version: VERSION as u32,
+                    msg_address: wallet.address.to_vec(),
+                    msg_ip: "127.0.0.1:1".to_string(),
+                    msg_local_index: 0,
+                    ..Default::default()
+                };
+                tokio::spawn(async move {
+                    let version = signed_version(&ns, &wallet, version).await;
+                    client.handshake(Request::new(version)).await
+                })
+            })
+            .collect();
+
+        let results = tokio::time::timeout(Duration::from_secs(10), async {
+            applier.await.unwrap();
+            futures::future::join_all(handshakes).await
+        })
+        .await
+        .expect("handshakes timed out");
+        for result in results {
+            let version = result.unwrap().unwrap().into_inner();
+            assert!(version.msg_local_index <= 6);
+        }
+        assert_eq!(ns.chain.cached_tip_index(), 6);
+        assert_eq!(ns.chain.validated_up_to().await.unwrap(), Some(6));
+    }
+
+    #[tokio::test]
+    async fn test_validator_role() {
+        let wallet = Wallet::generate().unwrap();
+        let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+        let validator = NodeService::new(
+            test_chain().await,
+            secret_key,
+            "127.0.0.1:8080".to_string(),
+            "127.0.0.1:8080".to_string(),
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(validator.is_validator());
+        assert_eq!(validator.validator.as_ref().unwrap().produced_blocks(), 0);
+
+        let plain = make_node().await;
+        assert!(!plain.is_validator());
+        assert!(plain.validator.is_none());
+        assert!(matches!(
+            plain.make_block().await,
+            Err(NodeServiceError::NotValidator)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_make_block_removes_only_mined_transactions() {
+        let wallet = Wallet::generate().unwrap();
+        let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+        let node = NodeService::new(
+            test_chain().await,
+            secret_key,
+            "127.0.0.1:8080".to_string(),
+            "127.0.0.1:8080".to_string(),
+            true,
+        )
+        .await
+        .unwrap();
+        node.make_genesis_block().await.unwrap();
+        assert_eq!(node.chain.max_index().await.unwrap(), 1);
+
+        let mut invalid = spending_transaction(b"unminable transaction");
+        invalid.msg_inputs.push(TransactionInput {
+            msg_blsag: vec![0; 8],
+            ..Default::default()
+        });
+        assert!(node.mempool.add(invalid.clone()));
+        assert!(node.make_block().await.is_err());
+        assert!(node.mempool.has(&invalid));
+        assert!(node.mempool.remove(&invalid));
+
+        let valid = spending_transaction(b"mined transaction");
+        assert!(node.mempool.add(valid.clone()));
+        node.make_block().await.unwrap();
+        assert_eq!(node.chain.max_index().await.unwrap(), 2);
+        assert_eq!(node.chain.validated_up_to().await.unwrap(), Some(2));
+        assert!(!node.mempool.has(&valid));
+    }
+
+    #[tokio::test]
+    async fn test_confirmed_block_releases_reserved_outputs() {
+        let node = make_node().await;
+        let now = Instant::now();
+        let key_image = vec![7; 32];
+        assert!(node.reserve_output(&key_image, now));
+        assert!(!node.reserve_output(&key_image, now));
+
+        let block = Block {
+            msg_header: None,
+            msg_transactions: vec![Transaction {
+                msg_inputs: vec![TransactionInput {
+                    msg_key_image: key_image.clone(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        node.release_spent_outputs(&block);
+        assert!(node.reserve_output(&key_image, now));
+    }
+
+    #[tokio::test]
+    async fn test_make_transaction_rejects_zero_amount() {
+        let node = make_node().await;
+        let recipient = bs58::encode(node.wallet.address).into_string();
+        let result = node.make_transaction(&recipient, 0, None).await;
+        assert!(matches!(result, Err(NodeServiceError::ZeroAmount)));
+        assert_eq!(node.mempool_summary().msg_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pull_concurrency_is_bounded() {
+        const LIMIT: usize = 3;
+        let mut node = make_node().await;
+        node.pull_permits = Arc::new(Semaphore::new(LIMIT));
+        let ans = ArcNodeService { ns: Arc::new(node) };
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let mut peer_ips = Vec::new();
+        for port in 0..(LIMIT * 3) {
+            let ip = format!("127.0.0.1:{}", 9000 + port);
+            let transport = MockTransport {
+                delay: Duration::from_millis(200),
+                pulls_in_flight: Arc::clone(&in_flight),
+                max_pulls_in_flight: Arc::clone(&max_in_flight),
+                ..Default::default()
+            };
+            let version = Version {
+                msg_version: VERSION as u32,
+                msg_address: Wallet::generate().unwrap().address.to_vec(),
+                msg_ip: ip.clone(),
+                msg_local_index: 0,
+                ..Default::default()
+            };
+            ans.ns
+                .add_peer(transport, version, PeerDirection::Outbound, &ip)
+                .await
+                .unwrap();
+            peer_ips.push(ip);
+        }
+
+        for (i, ip) in peer_ips.iter().enumerate() {
+            let request = PushTxRequest {
+                msg_transaction_hash: hash_transaction(&spending_transaction(
+                    format!("announcement {}", i).as_bytes(),
+                )),
+                msg_ip: ip.clone(),
+            };
+            ans.handle_tx_push(Request::new(request)).await.unwrap();
+        }
+        assert_eq!(ans.ns.pull_permits.available_permits(), 0);
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while ans.ns.pull_permits.available_permits() < LIMIT {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let max_in_flight = max_in_flight.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(max_in_flight > 0);
+        assert!(max_in_flight <= LIMIT);
+    }
+
+    #[tokio::test]
+    async fn test_stale_pull_is_aborted() {
+        let mut node = make_node().await;
+        node.pull_timeout = Duration::from_millis(100);
+        let ans = ArcNodeService { ns: Arc::new(node) };
+        let ip = "127.0.0.1:9000".to_string();
+        let transport = MockTransport {
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: Wallet::generate().unwrap().address.to_vec(),
+            msg_ip: ip.clone(),
+            msg_local_index: 0,
+            ..Default::default()
+        };
+        ans.ns
+            .add_peer(transport, version, PeerDirection::Outbound, &ip)
+            .await
+            .unwrap();
+        let permits = ans.ns.pull_permits.available_permits();
+
+        let hash = hash_transaction(&spending_transaction(b"never answered"));
+        let request = PushTxRequest {
+            msg_transaction_hash: hash.clone(),
+            msg_ip: ip,
+        };
+        ans.handle_tx_push(Request::new(request)).await.unwrap();
+        let in_flight = ans.ns.in_flight_pulls();
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(in_flight[0].0, hash);
+        assert_eq!(ans.ns.pull_permits.available_permits(), permits - 1);
+        assert_eq!(ans.ns.prune_stale_pulls(), 0);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(ans.ns.prune_stale_pulls(), 1);
+        assert!(ans.ns.in_flight_pulls().is_empty());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while ans.ns.pull_permits.available_permits() < permits {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[derive(Default)]
+    struct RecordingBuilder {
+        interval: Option<Duration>,
+        timeout: Option<Duration>,
+        while_idle: bool,
+        tcp: Option<Option<Duration>>,
+    }
+
+    impl KeepaliveBuilder for RecordingBuilder {
+        fn http2_keep_alive_interval(mut self, interval: Duration) -> Self {
+            self.interval = Some(interval);
+            self
+        }
+
+        fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = Some(timeout);
+            self
+        }
+
+        fn keep_alive_while_idle(mut self, enabled: bool) -> Self {
+            self.while_idle = enabled;
+            self
+        }
+
+        fn tcp_keepalive(mut self, keepalive: Option<Duration>) -> Self {
+            self.tcp = Some(keepalive);
+            self
+        }
+    }
+
+    #[test]
+    fn test_keepalive_applied_to_channel_builder() {
+        let config = KeepaliveConfig {
+            http2_interval: Duration::from_secs(5),
+            http2_timeout: Duration::from_secs(2),
+            tcp: Some(Duration::from_secs(15)),
+        };
+        let recorded = apply_keepalive(RecordingBuilder::default(), &config);
+        assert_eq!(recorded.interval, Some(Duration::from_secs(5)));
+        assert_eq!(recorded.timeout, Some(Duration::from_secs(2)));
+        assert!(recorded.while_idle);
+        assert_eq!(recorded.tcp, Some(Some(Duration::from_secs(15))));
+
+        let defaults = apply_keepalive(RecordingBuilder::default(), &KeepaliveConfig::default());
+        assert_eq!(defaults.interval, Some(HTTP2_KEEPALIVE_INTERVAL));
+        assert_eq!(defaults.tcp, Some(Some(TCP_KEEPALIVE)));
+    }
+
+    // Message the node handed to a transport
+    #[derive(Debug, PartialEq)]
+    enum Sent {
+        BlockPush(PushBlockRequest),
+        TxPush(PushTxRequest),
+        PeerList(PeerList),
+        InvBatch(InvBatch),
+    }
+
+    // Transport recording every message instead of sending it, all mock peers share one log. Tests set only
+    // the fields they need
+    #[derive(Default)]
+    struct MockTransport {
+        peer: String,
+        sent: Arc<std::sync::Mutex<Vec<(String, Sent)>>>,
+        // Block served to pulls
+        pulled: Option<Block>,
+        // Transaction served to pulls
+        pulled_tx: Option<Transaction>,
+        // State requests fail right away
+        state_error: bool,
+        // How long state requests and transaction pulls take, a state request then gets an empty batch
+        // and a pull not found
+        delay: Duration,
+        // Transaction pulls running right now and the most that ran at once
+        pulls_in_flight: Arc<AtomicUsize>,
+        max_pulls_in_flight: Arc<AtomicUsize>,
+    }
+
+    impl MockTransport {
+        fn record(&self, message: Sent) -> Confirmed {
+            self.sent.lock().unwrap().push((self.peer.clone(), message));
+            Confirmed {}
+        }
+    }
+
+    #[tonic::async_trait]
+    impl PeerTransport for MockTransport {
+        async fn push_state(&mut self, _: LocalState) -> Result<BlockBatch, Status> {
+            if self.state_error {
+                return Err(Status::unavailable("push_state"));
+            }
+            tokio::time::sleep(self.delay).await;
+            Ok(BlockBatch { msg_blocks: vec![] })
+        }
+
+        async fn handle_peer_list(&mut self, request: PeerList) -> Result<Confirmed, Status> {
+            Ok(self.record(Sent::PeerList(request)))
+        }
+
+        async fn handle_block_push(
+            &mut self,
+            request: PushBlockRequest,
+        ) -> Result<Confirmed, Status> {
+            Ok(self.record(Sent::BlockPush(request)))
+        }
+
+        async fn handle_block_pull(&mut self, _: PullBlockRequest) -> Result<Block, Status> {
+            self.pulled
+                .clone()
+                .ok_or_else(|| Status::not_found("Block not found"))
+        }
+
+        async fn handle_tx_push(&mut self, request: PushTxRequest) -> Result<Confirmed, Status> {
+            Ok(self.record(Sent::TxPush(request)))
+        }
+
+        async fn handle_tx_pull(&mut self, _: PullTxRequest) -> Result<Transaction, Status> {
+            let in_flight = self
+                .pulls_in_flight
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+                + 1;
+            self.max_pulls_in_flight
+                .fetch_max(in_flight, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.pulls_in_flight
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            self.pulled_tx
+                .clone()
+                .ok_or_else(|| Status::not_found("handle_tx_pull"))
+        }
+
+        async fn handle_inv_batch(&mut self, request: InvBatch) -> Result<Confirmed, Status> {
+            Ok(self.record(Sent::InvBatch(request)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_through_mock_transport() {
+        let node = make_node().await;
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut peers = Vec::new();
+        for subnet in 0..2 {
+            let address = Wallet::generate().unwrap().address;
+            let bs58_address = bs58::encode(address).into_string();
+            let transport = MockTransport {
+                peer: bs58_address.clone(),
+                sent: Arc::clone(&sent),
+                ..Default::default()
+            };
+            let version = Version {
+                msg_version: VERSION as u32,
+                msg_address: address.to_vec(),
+                msg_ip: format!("10.0.{}.1:8080", subnet),
+                msg_local_index: 0,
+                ..Default::default()
+            };
+            node.add_peer(
+                transport,
+                version,
+                PeerDirection::Outbound,
+                &format!("10.0.{}.1:8080", subnet),
+            )
+            .await
+            .unwrap();
+            peers.push(bs58_address);
+        }
+        peers.sort();
+        // Broadcasts send from spawned tasks, so wait until every peer got the message
+        let drain = |expected: Sent| {
+            let sent = Arc::clone(&sent);
+            async move {
+                while sent.lock().unwrap().len() < 2 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                let mut messages = std::mem::take(&mut *sent.lock().unwrap());
+                messages.sort_by(|a, b| a.0.cmp(&b.0));
+                assert!(messages.iter().all(|(_, message)| *message == expected));
+                messages
+                    .into_iter()
+                    .map(|(peer, _)| peer)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        node.broadcast_block_hash(vec![7; 32]).await.unwrap();
+        let expected = Sent::BlockPush(PushBlockRequest {
+            msg_block_hash: vec![7; 32],
+            msg_ip: node.advertised_addr.to_string(),
+        });
+        assert_eq!(drain(expected).await, peers);
+
+        let transaction = spending_transaction(b"broadcast through transport");
+        node.broadcast_tx_hash(&transaction).await.unwrap();
+        let expected = Sent::TxPush(PushTxRequest {
+            msg_transaction_hash: hash_transaction(&transaction),
+            msg_ip: node.advertised_addr.to_string(),
+        });
+        assert_eq!(drain(expected).await, peers);
+
+        node.broadcast_peer_list().await.unwrap();
+        let mut peer_list = node.get_addr_list();
+        peer_list.push(bs58::encode(&node.wallet.address).into_string());
+        let expected = Sent::PeerList(PeerList {
+            msg_peers_ips: peer_list,
+        });
+        assert_eq!(drain(expected).await, peers);
+    }
+
+    // Connects an outbound mock peer recording into sent, each on its own subnet
+    async fn add_recording_peer(
+        node: &NodeService,
+        subnet: u8,
+        sent: &Arc<std::sync::Mutex<Vec<(String, Sent)>>>,
+    ) {
+        let address = Wallet::generate().unwrap().address;
+        let transport = MockTransport {
+            peer: bs58::encode(address).into_string(),
+            sent: Arc::clone(sent),
+            ..Default::default()
+        };
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: address.to_vec(),
+            msg_ip: format!("10.0.{}.1:8080", subnet),
+            ..Default::default()
+        };
+        node.add_peer(
+            transport,
+            version,
+            PeerDirection::Outbound,
+            &format!("10.0.{}.1:8080", subnet),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batched_tx_broadcast() {
+        let mut node = make_node().await;
+        node.tx_batch_interval = Some(Duration::from_millis(100));
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        add_recording_peer(&node, 0, &sent).await;
+
+        let transactions: Vec<Transaction> = (0..3)
+            .map(|i| spending_transaction(format!("batched {}", i).as_bytes()))
+            .collect();
+        node.broadcast_tx_hash(&transactions[0]).await.unwrap();
+        // A peer connecting after the batch was scheduled still gets it
+        add_recording_peer(&node, 1, &sent).await;
+        for transaction in &transactions[1..] {
+            node.broadcast_tx_hash(transaction).await.unwrap();
+        }
+        // Announcing a hash twice within the window doesn't repeat it
+        node.broadcast_tx_hash(&transactions[0]).await.unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let mut expected = transactions
+            .iter()
+            .map(hash_transaction)
+            .collect::<Vec<_>>();
+        expected.sort();
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        for (_, message) in sent.iter() {
+            let Sent::InvBatch(batch) = message else {
+                panic!("expected an inventory batch, got {:?}", message);
+            };
+            let mut hashes = batch.msg_transaction_hashes.clone();
+            hashes.sort();
+            assert_eq!(hashes, expected);
+            assert_eq!(batch.msg_ip, node.advertised_addr.as_str());
+        }
+        assert_ne!(sent[0].0, sent[1].0);
+    }
+
+    #[tokio::test]
+    async fn test_large_inventory_is_split_into_capped_batches() {
+        let node = make_node().await;
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        add_recording_peer(&node, 0, &sent).await;
+
+        let count = 2 * MAX_INV_BATCH_HASHES + 1;
+        for i in 0..count {
+            let hash = hash!((i as u64).to_le_bytes()).to_vec();
+            node.queue_tx_hash(hash, Duration::from_millis(50));
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        let mut announced = HashSet::new();
+        for (_, message) in sent.iter() {
+            let Sent::InvBatch(batch) = message else {
+                panic!("expected an inventory batch, got {:?}", message);
+            };
+            assert!(batch.msg_transaction_hashes.len() <= MAX_INV_BATCH_HASHES);
+            announced.extend(batch.msg_transaction_hashes.iter().cloned());
+        }
+        assert_eq!(announced.len(), count);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_inventory_is_rejected() {
+        let node = make_node().await;
+        let ans = ArcNodeService { ns: Arc::new(node) };
+        let request = Request::new(InvBatch {
+            msg_transaction_hashes: vec![vec![7; HASH_LEN]; MAX_INV_BATCH_HASHES + 1],
+            msg_ip: "127.0.0.1:8081".to_string(),
+        });
+        let status = ans.handle_inv_batch(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(ans.ns.pulls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_pulled_block_is_not_rebroadcast() {
+        let node = make_node().await;
+        // Next index with an unknown predecessor, it can't pass validation
+        let invalid = Block {
+            msg_header: Some(Header {
+                msg_index: node.chain.max_index().await.unwrap() + 1,
+                msg_previous_hash: vec![9; 32],
+                msg_timestamp: node.chain.unix_now(),
+                ..Default::default()
+            }),
+            msg_transactions: vec![],
+        };
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let address = Wallet::generate().unwrap().address;
+        let transport = MockTransport {
+            peer: bs58::encode(address).into_string(),
+            sent: Arc::clone(&sent),
+            pulled: Some(invalid.clone()),
+            ..Default::default()
+        };
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: address.to_vec(),
+            msg_ip: "10.0.0.1:8080".to_string(),
+            ..Default::default()
+        };
+        node.add_peer(transport, version, PeerDirection::Outbound, "10.0.0.1:8080")
+            .await
+            .unwrap();
+
+        let invalid_hash = hash_block(&invalid).unwrap();
+        assert!(node
+            .pull_block_from("10.0.0.1:8080", invalid_hash.clone())
+            .await
+            .is_err());
+        // A peer answering with some other block than the announced one is refused as well
+        assert!(matches!(
+            node.pull_block_from("10.0.0.1:8080", vec![7; 32]).await,
+            Err(NodeServiceError::PulledBlockMismatch)
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sent.lock().unwrap().is_empty());
+        assert!(node
+            .chain
+            .blocks()
+            .get(invalid_hash)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_pulled_transaction_is_a_violation() {
+        let node = make_node().await;
+        let mut malformed = spending_transaction(b"malformed ring");
+        malformed.msg_inputs[0].msg_ring[0].truncate(31);
+        let address = Wallet::generate().unwrap().address;
+        let bs58_address = bs58::encode(address).into_string();
+        let transport = MockTransport {
+            peer: bs58_address.clone(),
+            sent: Arc::new(std::sync::Mutex::new(Vec::new())),
+            pulled_tx: Some(malformed.clone()),
+            ..Default::default()
+        };
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: address.to_vec(),
+            msg_ip: "10.0.0.1:8080".to_string(),
+            ..Default::default()
+        };
+        node.add_peer(transport, version, PeerDirection::Outbound, "10.0.0.1:8080")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            node.pull_transaction_from("10.0.0.1:8080", hash_transaction(&malformed))
+                .await,
+            Err(NodeServiceError::ChainOpsError(
+                ChainOpsError::InvalidRingMember { len: 31 }
+            ))
+        ));
+        assert_eq!(*node.protocol_violations.get(&bs58_address).unwrap(), 1);
+        assert!(!node.mempool.has(&malformed));
+    }
+
+    #[tokio::test]
+    async fn test_peer_dropped_after_protocol_violations() {
+        let node = make_node().await;
+        let address = Wallet::generate().unwrap().address;
+        let bs58_address = bs58::encode(address).into_string();
+        let transport = MockTransport {
+            peer: bs58_address.clone(),
+            sent: Arc::new(std::sync::Mutex::new(Vec::new())),
+            ..Default::default()
+        };
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: address.to_vec(),
+            msg_ip: "127.0.0.3:8080".to_string(),
+            msg_local_index: 0,
+            ..Default::default()
+        };
+        node.add_peer(transport, version, PeerDirection::Inbound, "127.0.0.3:8080")
+            .await
+            .unwrap();
+        let ans = ArcNodeService { ns: Arc::new(node) };
+
+        // A well-formed push isn't counted
+        let request = PushTxRequest {
+            msg_transaction_hash: vec![3; 32],
+            msg_ip: "127.0.0.3:8080".to_string(),
+        };
+        ans.handle_tx_push(request_from(request, "127.0.0.3").await)
+            .await
+            .unwrap();
+        // Hashes of the wrong length, as an older message format would carry them
+        for violation in 1..=MAX_PROTOCOL_VIOLATIONS {
+            assert!(ans.ns.get_addr_list().contains(&bs58_address));
+            let request = PushTxRequest {
+                msg_transaction_hash: vec![3; 20],
+                msg_ip: "127.0.0.3:8080".to_string(),
+            };
+            let status = ans
+                .handle_tx_push(request_from(request, "127.0.0.3").await)
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            if violation < MAX_PROTOCOL_VIOLATIONS {
+                assert_eq!(
+                    *ans.ns.protocol_violations.get(&bs58_address).unwrap(),
+                    violation
+                );
+            }
+        }
+        assert!(!ans.ns.get_addr_list().contains(&bs58_address));
+        assert!(ans.ns.get_peer_by_ip("127.0.0.3:8080").is_none());
+        assert!(ans.ns.protocol_violations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_sender_ip_does_not_evict_the_named_peer() {
+        let node = make_node().await;
+        let address = Wallet::generate().unwrap().address;
+        let bs58_address = bs58::encode(address).into_string();
+        let transport = MockTransport {
+            peer: bs58_address.clone(),
+            sent: Arc::new(std::sync::Mutex::new(Vec::new())),
+            ..Default::default()
+        };
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: address.to_vec(),
+            msg_ip: "127.0.0.3:8080".to_string(),
+            msg_local_index: 0,
+            ..Default::default()
+        };
+        node.add_peer(transport, version, PeerDirection::Inbound, "127.0.0.3:8080")
+            .await
+            .unwrap();
+        let ans = ArcNodeService { ns: Arc::new(node) };
+
+        // Another host sends malformed messages claiming to be the peer, or without any connection info
+        for _ in 0..2 * MAX_PROTOCOL_VIOLATIONS {
+            let request = || PullBlockRequest {
+                msg_block_hash: vec![3; 20],
+                msg_ip: "127.0.0.3:8080".to_string(),
+            };
+            assert!(ans
+                .handle_block_pull(request_from(request(), "127.0.0.2").await)
+                .await
+                .is_err());
+            assert!(ans
+                .handle_block_pull(Request::new(request()))
+                .await
+                .is_err());
+        }
+        assert!(ans.ns.get_addr_list().contains(&bs58_address));
+        assert!(ans.ns.protocol_violations.is_empty());
+    }
+
+    // Wraps the message as if it arrived over a TCP connection from the given loopback ip
+    async fn request_from<T>(message: T, ip: &str) -> Request<T> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind(format!("{}:0", ip).parse().unwrap()).unwrap();
+        let (connected, accepted) = tokio::join!(
+            socket.connect(listener.local_addr().unwrap()),
+            listener.accept()
+        );
+        let _client = connected.unwrap();
+        let (stream, _) = accepted.unwrap();
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(stream.connect_info());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_malformed_peer_addresses() {
+        for addr in [
+            "",
+            "127.0.0.1",
+            "127.0.0.1:",
+            ":8080",
+            "127.0.0.1:port",
+            "127.0.0.1:0",
+            "127.0.0.1:70000",
+            "bad host:8080",
+            "[::1:8080",
+            "[not v6]:8080",
+        ] {
+            match make_node_client(addr).await {
+                Err(NodeServiceError::UriParseError { input, .. }) => assert_eq!(input, addr),
+                other => panic!("{:?} wasn't rejected up front: {:?}", addr, other.err()),
+            }
+        }
+        for addr in ["127.0.0.1:8080", "[::1]:8080", "node-1.example.org:8080"] {
+            assert_eq!(check_peer_addr(addr), Ok(()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_peer() {
+        let node = make_node().await;
+        let address = Wallet::generate().unwrap().address;
+        let bs58_address = bs58::encode(address).into_string();
+        let client = NodeClient::new(Channel::from_static("http://127.0.0.1:1").connect_lazy());
+        let version = Version {
+            msg_version: VERSION as u32,
+            msg_address: address.to_vec(),
+            msg_ip: "127.0.0.1:1".to_string(),
+            msg_local_index: 0,
+            ..Default::default()
+        };
+        node.add_peer(client, version, PeerDirection::Outbound, "127.0.0.1:1")
+            .await
+            .unwrap();
+        assert!(node.get_addr_list().contains(&bs58_address));
+
+        assert!(node.remove_peer(&bs58_address, true).await.unwrap());
+        assert!(!node.get_addr_list().contains(&bs58_address));
+        assert!(node.get_peer_by_ip("127.0.0.1:1").is_none());
+        assert!(node.peer_info().is_empty());
+        assert_eq!(IP_STORER.get_by_address(&address).await.unwrap(), None);
+        assert!(!node.remove_peer(&bs58_address, false).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_mine_block_on_configured_threads() {
+        for threads in [1, 3] {
+            let block = Block {
+                msg_header: Some(Header {
+                    msg_index: 2,
+                    ..Default::default()
+                }),
+                msg_transactions: vec![],
+            };
+            let mined = mine_block(block, 2, threads).await.unwrap();
+            assert!(verify_block_pow(&mined, 2));
+        }
+    }
+}
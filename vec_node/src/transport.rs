@@ -0,0 +1,59 @@
+use tonic::{transport::Channel, Status};
+use vec_proto::messages::node_client::NodeClient;
+use vec_proto::messages::*;
+
+// RPCs the node sends to a connected peer, so broadcasts, pulls and sync can run without real sockets
+#[tonic::async_trait]
+pub trait PeerTransport: Send + Sync {
+    async fn push_state(&mut self, request: LocalState) -> Result<BlockBatch, Status>;
+    async fn handle_peer_list(&mut self, request: PeerList) -> Result<Confirmed, Status>;
+    async fn handle_block_push(&mut self, request: PushBlockRequest) -> Result<Confirmed, Status>;
+    async fn handle_block_pull(&mut self, request: PullBlockRequest) -> Result<Block, Status>;
+    async fn handle_tx_push(&mut self, request: PushTxRequest) -> Result<Confirmed, Status>;
+    async fn handle_tx_pull(&mut self, request: PullTxRequest) -> Result<Transaction, Status>;
+    async fn handle_inv_batch(&mut self, request: InvBatch) -> Result<Confirmed, Status>;
+}
+
+// Production transport: the tonic client of the peer's gRPC server
+#[tonic::async_trait]
+impl PeerTransport for NodeClient<Channel> {
+    async fn push_state(&mut self, request: LocalState) -> Result<BlockBatch, Status> {
+        Ok(NodeClient::push_state(self, request).await?.into_inner())
+    }
+
+    async fn handle_peer_list(&mut self, request: PeerList) -> Result<Confirmed, Status> {
+        Ok(NodeClient::handle_peer_list(self, request)
+            .await?
+            .into_inner())
+    }
+
+    async fn handle_block_push(&mut self, request: PushBlockRequest) -> Result<Confirmed, Status> {
+        Ok(NodeClient::handle_block_push(self, request)
+            .await?
+            .into_inner())
+    }
+
+    async fn handle_block_pull(&mut self, request: PullBlockRequest) -> Result<Block, Status> {
+        Ok(NodeClient::handle_block_pull(self, request)
+            .await?
+            .into_inner())
+    }
+
+    async fn handle_tx_push(&mut self, request: PushTxRequest) -> Result<Confirmed, Status> {
+        Ok(NodeClient::handle_tx_push(self, request)
+            .await?
+            .into_inner())
+    }
+
+    async fn handle_tx_pull(&mut self, request: PullTxRequest) -> Result<Transaction, Status> {
+        Ok(NodeClient::handle_tx_pull(self, request)
+            .await?
+            .into_inner())
+    }
+
+    async fn handle_inv_batch(&mut self, request: InvBatch) -> Result<Confirmed, Status> {
+        Ok(NodeClient::handle_inv_batch(self, request)
+            .await?
+            .into_inner())
+    }
+}
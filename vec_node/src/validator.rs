@@ -0,0 +1,29 @@
+use slog::{info, Logger};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Block producing component, only present on nodes started as validators
+pub struct ValidatorService {
+    pub log: Arc<Logger>,
+    produced_blocks: AtomicU64,
+}
+
+impl ValidatorService {
+    pub fn new(log: Arc<Logger>) -> Self {
+        info!(log, "\nValidatorService created");
+        ValidatorService {
+            log,
+            produced_blocks: AtomicU64::new(0),
+        }
+    }
+
+    // Records a block mined by this validator
+    pub fn record_produced_block(&self, index: u32) {
+        self.produced_blocks.fetch_add(1, Ordering::Relaxed);
+        info!(self.log, "\nValidator produced block {}", index);
+    }
+
+    pub fn produced_blocks(&self) -> u64 {
+        self.produced_blocks.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,86 @@
+mod test_net;
+
+use std::sync::{Arc, Mutex};
+use test_net::{data_dir, make_chain, spawn_node_with_role, spawn_service, wait_until, MockPeer};
+use tonic::{Request, Response, Status};
+use vec_crypto::crypto::Wallet;
+use vec_node::node::{make_node_client, PeerDirection};
+use vec_proto::messages::*;
+use vec_storage::block_db::BlockStorer;
+use vec_utils::utils::hash_block;
+
+// Peer with its own in-memory chain: like a real node it pulls every announced block from the sender.
+// Nodes of one test binary share their Sled databases, so a second real node would see the block without any RPC
+struct RecordingNode {
+    blocks: Arc<Mutex<Vec<Block>>>,
+}
+
+#[tonic::async_trait]
+impl MockPeer for RecordingNode {
+    async fn handle_block_push(
+        &self,
+        request: Request<PushBlockRequest>,
+    ) -> Result<Response<Confirmed>, Status> {
+        let push_request = request.into_inner();
+        let mut client = make_node_client(&push_request.msg_ip)
+            .await
+            .map_err(|e| Status::unavailable(format!("{:?}", e)))?;
+        let block = client
+            .handle_block_pull(PullBlockRequest {
+                msg_block_hash: push_request.msg_block_hash,
+                msg_ip: String::new(),
+            })
+            .await?
+            .into_inner();
+        self.blocks.lock().unwrap().push(block);
+        Ok(Response::new(Confirmed {}))
+    }
+}
+
+#[tokio::test]
+async fn test_mined_block_reaches_peer() {
+    let validator = spawn_node_with_role(true).await;
+    let genesis = make_chain(1).remove(0);
+    validator
+        .ns
+        .chain
+        .add_genesis_block(&validator.ns.wallet, genesis)
+        .await
+        .unwrap();
+
+    let blocks = Arc::new(Mutex::new(Vec::new()));
+    let (peer_addr, _shutdown) = spawn_service(RecordingNode {
+        blocks: Arc::clone(&blocks),
+    })
+    .await;
+    let version = Version {
+        msg_version: 1,
+        msg_address: Wallet::generate().unwrap().address.to_vec(),
+        msg_ip: peer_addr.clone(),
+        msg_local_index: 0,
+        ..Default::default()
+    };
+    let client = make_node_client(&peer_addr).await.unwrap();
+    validator
+        .ns
+        .add_peer(client, version, PeerDirection::Outbound, &peer_addr)
+        .await
+        .unwrap();
+
+    validator.ns.make_block().await.unwrap();
+    wait_until(|| !blocks.lock().unwrap().is_empty()).await;
+
+    let received = blocks.lock().unwrap()[0].clone();
+    assert_eq!(received.msg_header.as_ref().unwrap().msg_index, 2);
+    let local = validator
+        .ns
+        .chain
+        .blocks()
+        .get(hash_block(&received).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(local, Some(received));
+
+    validator.shutdown().await;
+    let _ = std::fs::remove_dir_all(data_dir());
+}
@@ -0,0 +1,104 @@
+mod test_net;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use test_net::{init_data_dir, shared_chain, spawn_service, wait_until, MockPeer};
+use tokio::sync::oneshot;
+use tonic::{Request, Response, Status};
+use vec_crypto::crypto::Wallet;
+use vec_node::node::{make_node_client, NodeService, PeerDirection, MAX_BROADCAST_FAILURES};
+use vec_proto::messages::*;
+
+// Peer that counts block pushes, or never answers them at all
+struct PushNode {
+    hang: bool,
+    pushes: Arc<AtomicUsize>,
+}
+
+#[tonic::async_trait]
+impl MockPeer for PushNode {
+    async fn handle_block_push(
+        &self,
+        _: Request<PushBlockRequest>,
+    ) -> Result<Response<Confirmed>, Status> {
+        self.pushes.fetch_add(1, Ordering::SeqCst);
+        if self.hang {
+            std::future::pending::<()>().await;
+        }
+        Ok(Response::new(Confirmed {}))
+    }
+}
+
+async fn add_mock_peer(
+    node: &NodeService,
+    hang: bool,
+    pushes: &Arc<AtomicUsize>,
+) -> (String, oneshot::Sender<()>) {
+    let (addr, shutdown) = spawn_service(PushNode {
+        hang,
+        pushes: Arc::clone(pushes),
+    })
+    .await;
+    let address = Wallet::generate().unwrap().address;
+    let client = make_node_client(&addr).await.unwrap();
+    let version = Version {
+        msg_version: 1,
+        msg_address: address.to_vec(),
+        msg_ip: addr.clone(),
+        msg_local_index: 0,
+        ..Default::default()
+    };
+    node.add_peer(client, version, PeerDirection::Outbound, &addr)
+        .await
+        .unwrap();
+    (bs58::encode(address).into_string(), shutdown)
+}
+
+#[tokio::test]
+async fn test_hung_peer_does_not_block_broadcast() {
+    init_data_dir();
+    let wallet = Wallet::generate().unwrap();
+    let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+    let mut node = NodeService::new(
+        shared_chain(),
+        secret_key,
+        "127.0.0.1:0".to_string(),
+        "127.0.0.1:0".to_string(),
+        false,
+    )
+    .await
+    .unwrap();
+    node.peer_send_timeout = Duration::from_millis(200);
+
+    let healthy_pushes = Arc::new(AtomicUsize::new(0));
+    let hung_pushes = Arc::new(AtomicUsize::new(0));
+    let (healthy, _healthy_shutdown) = add_mock_peer(&node, false, &healthy_pushes).await;
+    let (hung, _hung_shutdown) = add_mock_peer(&node, true, &hung_pushes).await;
+
+    // Every broadcast reaches the healthy peer while the hung one never answers
+    for round in 1..=MAX_BROADCAST_FAILURES as usize {
+        node.broadcast_block_hash(vec![round as u8; 32])
+            .await
+            .unwrap();
+        wait_until(|| healthy_pushes.load(Ordering::SeqCst) == round).await;
+        wait_until(|| {
+            node.broadcast_failures
+                .get(&hung)
+                .is_some_and(|failures| *failures as usize == round)
+        })
+        .await;
+    }
+    assert_eq!(
+        hung_pushes.load(Ordering::SeqCst),
+        MAX_BROADCAST_FAILURES as usize
+    );
+
+    // The next broadcast drops the hung peer and keeps sending to the healthy one
+    node.broadcast_block_hash(vec![0; 32]).await.unwrap();
+    assert!(!node.peers.contains_key(&hung));
+    assert!(node.peers.contains_key(&healthy));
+    wait_until(|| healthy_pushes.load(Ordering::SeqCst) == MAX_BROADCAST_FAILURES as usize + 1)
+        .await;
+    assert!(!node.broadcast_failures.contains_key(&healthy));
+}
@@ -0,0 +1,46 @@
+mod test_net;
+
+use test_net::{spawn_nodes, teardown};
+use vec_proto::messages::{Block, Header};
+use vec_storage::output_db::{OutputKind, OutputStorer};
+
+#[tokio::test]
+async fn test_change_is_recorded_when_the_transaction_is_built() {
+    let nodes = spawn_nodes(2).await;
+    let ns = &nodes[0].ns;
+    let funding = ns.wallet.prepare_change_output(50, 1).unwrap();
+    for owned_output in ns.wallet.scan_outputs(&[&funding]).unwrap() {
+        ns.chain.outputs().put(&owned_output).await.unwrap();
+    }
+    // Paying itself, the wallet owns both the payment and the change
+    let recipient = bs58::encode(ns.wallet.address).into_string();
+    ns.make_transaction(&recipient, 20, None).await.unwrap();
+    let transaction = ns.mempool.get_transactions().remove(0);
+
+    let index = ns.chain.max_index().await.unwrap() + 1;
+    let block = Block {
+        msg_header: Some(Header {
+            msg_index: index,
+            ..Default::default()
+        }),
+        msg_transactions: vec![transaction],
+    };
+    ns.chain.add_genesis_block(&ns.wallet, block).await.unwrap();
+
+    let mut kinds: Vec<(u64, OutputKind)> = ns
+        .wallet
+        .list_owned_outputs(ns.chain.outputs())
+        .await
+        .unwrap()
+        .iter()
+        .filter(|owned| owned.height == index)
+        .map(|owned| (owned.decrypted_amount, owned.kind))
+        .collect();
+    kinds.sort_by_key(|(amount, _)| *amount);
+    assert_eq!(
+        kinds,
+        vec![(20, OutputKind::Received), (30, OutputKind::Change)]
+    );
+
+    teardown(nodes).await;
+}
@@ -0,0 +1,46 @@
+mod test_net;
+
+use std::collections::HashSet;
+use test_net::{spawn_nodes, teardown};
+use vec_storage::output_db::OutputStorer;
+
+#[tokio::test]
+async fn test_concurrent_sends_select_disjoint_outputs() {
+    let nodes = spawn_nodes(2).await;
+    let ns = &nodes[0].ns;
+    let funding = [
+        ns.wallet.prepare_change_output(5, 1).unwrap(),
+        ns.wallet.prepare_change_output(5, 2).unwrap(),
+    ];
+    for owned_output in ns.wallet.scan_outputs(&[&funding[0], &funding[1]]).unwrap() {
+        ns.chain.outputs().put(&owned_output).await.unwrap();
+    }
+    let recipient = bs58::encode(ns.wallet.address).into_string();
+
+    let (first, second) = tokio::join!(
+        ns.make_transaction(&recipient, 3, None),
+        ns.make_transaction(&recipient, 3, None)
+    );
+    first.unwrap();
+    second.unwrap();
+
+    let transactions = ns.mempool.get_transactions();
+    assert_eq!(transactions.len(), 2);
+    let images: Vec<HashSet<Vec<u8>>> = transactions
+        .iter()
+        .map(|transaction| {
+            transaction
+                .msg_inputs
+                .iter()
+                .map(|input| input.msg_key_image.clone())
+                .collect()
+        })
+        .collect();
+    assert_eq!(images[0].len(), 1);
+    assert!(images[0].is_disjoint(&images[1]));
+
+    // Both outputs are reserved until the transactions land, so a third send has nothing to spend
+    assert!(ns.make_transaction(&recipient, 3, None).await.is_err());
+
+    teardown(nodes).await;
+}
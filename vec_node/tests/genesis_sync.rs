@@ -0,0 +1,36 @@
+mod test_net;
+
+use std::sync::Arc;
+use test_net::{data_dir, init_data_dir, make_chain, shared_chain};
+use vec_crypto::crypto::Wallet;
+use vec_node::node::NodeService;
+use vec_proto::messages::BlockBatch;
+
+#[tokio::test]
+async fn test_empty_node_syncs_from_genesis() {
+    init_data_dir();
+    let wallet = Wallet::generate().unwrap();
+    let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+    let node = Arc::new(
+        NodeService::new(
+            shared_chain(),
+            secret_key,
+            "127.0.0.1:0".to_string(),
+            "127.0.0.1:0".to_string(),
+            false,
+        )
+        .await
+        .unwrap(),
+    );
+    assert_eq!(node.chain.max_index().await.unwrap(), 0);
+
+    let batch = BlockBatch {
+        msg_blocks: make_chain(3),
+    };
+    node.process_synchronisation(&node.wallet, batch)
+        .await
+        .unwrap();
+    assert_eq!(node.chain.max_index().await.unwrap(), 3);
+
+    let _ = std::fs::remove_dir_all(data_dir());
+}
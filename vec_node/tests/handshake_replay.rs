@@ -0,0 +1,29 @@
+mod test_net;
+
+use test_net::{spawn_nodes, teardown};
+use vec_node::node::{make_node_client, sign_handshake};
+
+#[tokio::test]
+async fn test_replayed_handshake_is_rejected() {
+    let nodes = spawn_nodes(2).await;
+    let mut client = make_node_client(&nodes[0].addr).await.unwrap();
+    let version = sign_handshake(
+        &mut client,
+        &nodes[1].ns.wallet,
+        nodes[1].ns.get_version().await,
+    )
+    .await
+    .unwrap();
+
+    client.handshake(version.clone()).await.unwrap();
+
+    // The nonce was used by the first handshake, so a captured copy is refused
+    assert!(nodes[0]
+        .ns
+        .used_handshake_nonces
+        .contains_key(&version.msg_nonce));
+    let status = client.handshake(version).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    teardown(nodes).await;
+}
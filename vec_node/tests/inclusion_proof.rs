@@ -0,0 +1,69 @@
+mod test_net;
+
+use prost::Message;
+use test_net::{make_chain, spawn_node_with_role, teardown};
+use vec_errors::errors::ChainOpsError;
+use vec_merkle::merkle::MerkleTree;
+use vec_proto::messages::Transaction;
+
+#[tokio::test]
+async fn test_verify_inclusion_proof() {
+    let node = spawn_node_with_role(false).await;
+    let mut block = make_chain(1).remove(0);
+    block.msg_transactions = (1..=3)
+        .map(|fee| Transaction {
+            msg_fee: fee,
+            ..Default::default()
+        })
+        .collect();
+    let transaction_data: Vec<Vec<u8>> = block
+        .msg_transactions
+        .iter()
+        .map(|transaction| transaction.encode_to_vec())
+        .collect();
+    let tree = MerkleTree::from_list(&transaction_data);
+    block.msg_header.as_mut().unwrap().msg_root_hash = tree.get_hash();
+    let transaction = block.msg_transactions[1].clone();
+    node.ns
+        .chain
+        .add_genesis_block(&node.ns.wallet, block)
+        .await
+        .unwrap();
+
+    let proof = tree.get_proof(&transaction_data[1]).unwrap();
+    assert!(node
+        .ns
+        .chain
+        .verify_inclusion(&transaction, 1, &proof)
+        .await
+        .unwrap());
+
+    // A proof for a transaction that was never in the block, and a tampered sibling hash
+    let forged = Transaction {
+        msg_fee: 99,
+        ..Default::default()
+    };
+    assert!(!node
+        .ns
+        .chain
+        .verify_inclusion(&forged, 1, &proof)
+        .await
+        .unwrap());
+    let mut tampered = proof.clone();
+    tampered[0].0[0] ^= 1;
+    assert!(!node
+        .ns
+        .chain
+        .verify_inclusion(&transaction, 1, &tampered)
+        .await
+        .unwrap());
+    assert!(matches!(
+        node.ns
+            .chain
+            .verify_inclusion(&transaction, 2, &proof)
+            .await,
+        Err(ChainOpsError::BlockNotFound)
+    ));
+
+    teardown(vec![node]).await;
+}
@@ -0,0 +1,52 @@
+mod test_net;
+
+use test_net::{data_dir, init_data_dir, shared_chain};
+use vec_crypto::crypto::Wallet;
+use vec_node::node::NodeService;
+use vec_storage::output_db::OutputStorer;
+
+async fn start(secret_key: &str) -> NodeService {
+    NodeService::new(
+        shared_chain(),
+        secret_key.to_string(),
+        "127.0.0.1:0".to_string(),
+        "127.0.0.1:0".to_string(),
+        false,
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_legacy_wallet_keeps_its_outputs_across_restarts() {
+    init_data_dir();
+    let secret_spend_key = Wallet::generate().unwrap().secret_spend_key;
+    let legacy = Wallet::reconstruct_legacy(secret_spend_key).unwrap();
+    let secret_key = bs58::encode(legacy.secret_spend_key_to_vec()).into_string();
+
+    // Outputs a node stored for the wallet before the view key derivation changed
+    let received = legacy.prepare_change_output(7, 1).unwrap();
+    for owned_output in legacy.scan_outputs(&[&received]).unwrap() {
+        shared_chain().outputs().put(&owned_output).await.unwrap();
+    }
+
+    for _restart in 0..2 {
+        let ns = start(&secret_key).await;
+        assert!(ns.wallet.is_legacy());
+        assert_eq!(ns.wallet.address, legacy.address);
+        assert_eq!(ns.get_balance().await, 7);
+        let owned = ns.wallet.scan_outputs(&[&received]).unwrap();
+        assert_eq!(owned.len(), 1);
+    }
+
+    // A wallet without stored outputs starts with the current derivation and keeps it
+    let fresh = Wallet::generate().unwrap();
+    let fresh_key = bs58::encode(fresh.secret_spend_key_to_vec()).into_string();
+    for _restart in 0..2 {
+        let ns = start(&fresh_key).await;
+        assert!(!ns.wallet.is_legacy());
+        assert_eq!(ns.wallet.address, fresh.address);
+    }
+
+    let _ = std::fs::remove_dir_all(data_dir());
+}
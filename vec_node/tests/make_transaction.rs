@@ -0,0 +1,16 @@
+mod test_net;
+
+use test_net::{spawn_nodes, teardown};
+use vec_errors::errors::NodeServiceError;
+
+#[tokio::test]
+async fn test_make_transaction_rejects_empty_wallet() {
+    let nodes = spawn_nodes(1).await;
+    let recipient = bs58::encode(nodes[0].ns.wallet.address).into_string();
+
+    let result = nodes[0].ns.make_transaction(&recipient, 10, None).await;
+    assert!(matches!(result, Err(NodeServiceError::NoSpendableOutputs)));
+    assert_eq!(nodes[0].ns.mempool_summary().msg_count, 0);
+
+    teardown(nodes).await;
+}
@@ -0,0 +1,37 @@
+mod test_net;
+
+use std::sync::Arc;
+use test_net::{
+    data_dir, make_chain, spawn_node_with_chain, spending_transaction, teardown, wait_until,
+};
+use vec_chain::chain::Chain;
+
+#[tokio::test]
+async fn test_propagated_block_prunes_peer_mempool() {
+    let miner = spawn_node_with_chain(true, None).await;
+    // The receiving node keeps its own chain, so it has to pull and apply the mined block
+    let chain = Chain::open(data_dir().join("receiver").to_str().unwrap())
+        .await
+        .unwrap();
+    let receiver = spawn_node_with_chain(false, Some(Arc::new(chain))).await;
+    let genesis = make_chain(1).remove(0);
+    for node in [&miner, &receiver] {
+        node.ns
+            .chain
+            .add_genesis_block(&node.ns.wallet, genesis.clone())
+            .await
+            .unwrap();
+    }
+    receiver.ns.connect_to(miner.addr.clone()).await.unwrap();
+    wait_until(|| miner.ns.peers.len() == 1 && receiver.ns.peers.len() == 1).await;
+
+    let transaction = spending_transaction(b"mined transaction");
+    assert!(miner.ns.mempool.add(transaction.clone()));
+    assert!(receiver.ns.mempool.add(transaction.clone()));
+
+    miner.ns.make_block().await.unwrap();
+    wait_until(|| receiver.ns.chain.cached_tip_index() == 2).await;
+    assert!(!receiver.ns.mempool.has(&transaction));
+
+    teardown(vec![miner, receiver]).await;
+}
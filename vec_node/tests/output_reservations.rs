@@ -0,0 +1,25 @@
+mod test_net;
+
+use test_net::{spawn_nodes, teardown};
+use vec_errors::errors::NodeServiceError;
+use vec_storage::output_db::OutputStorer;
+
+#[tokio::test]
+async fn test_failed_send_releases_its_outputs() {
+    let nodes = spawn_nodes(1).await;
+    let ns = &nodes[0].ns;
+    let funding = ns.wallet.prepare_change_output(5, 1).unwrap();
+    let owned_output = ns.wallet.scan_outputs(&[&funding]).unwrap().remove(0);
+    ns.chain.outputs().put(&owned_output).await.unwrap();
+    let recipient = bs58::encode(ns.wallet.address).into_string();
+
+    // Without peers the transaction can't be sent, it is withdrawn and the output is free for the next attempt
+    for _ in 0..2 {
+        let result = ns.make_transaction(&recipient, 3, None).await;
+        assert!(matches!(result, Err(NodeServiceError::NoRecipient)));
+        assert_eq!(ns.mempool_summary().msg_count, 0);
+    }
+    assert!(ns.output_reservations.is_empty());
+
+    teardown(nodes).await;
+}
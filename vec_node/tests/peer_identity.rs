@@ -0,0 +1,161 @@
+mod test_net;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use test_net::{spawn_nodes, spawn_service, teardown, MockPeer};
+use tonic::{Request, Response, Status};
+use vec_crypto::crypto::Wallet;
+use vec_errors::errors::NodeServiceError;
+use vec_node::node::{handshake_message, identity_message, make_node_client, verify_peer_identity};
+use vec_proto::messages::*;
+
+// Peer advertising the address of a wallet it doesn't hold. It answers challenges with its own keys,
+// or relays them to the node whose address it claims when it has one to relay to
+struct ImpostorNode {
+    claimed: Vec<u8>,
+    own: Wallet,
+    relay_to: Option<String>,
+    ip: Arc<std::sync::Mutex<String>>,
+    challenges: Arc<AtomicUsize>,
+}
+
+#[tonic::async_trait]
+impl MockPeer for ImpostorNode {
+    async fn handshake(&self, _: Request<Version>) -> Result<Response<Version>, Status> {
+        Ok(Response::new(Version {
+            msg_version: 1,
+            msg_address: self.claimed.clone(),
+            msg_ip: self.ip.lock().unwrap().clone(),
+            msg_local_index: 0,
+            ..Default::default()
+        }))
+    }
+
+    async fn challenge(
+        &self,
+        request: Request<ChallengeRequest>,
+    ) -> Result<Response<ChallengeResponse>, Status> {
+        self.challenges.fetch_add(1, Ordering::SeqCst);
+        let challenge = request.into_inner();
+        if let Some(relay_to) = &self.relay_to {
+            let mut client = make_node_client(relay_to).await.unwrap();
+            return client.challenge(challenge).await;
+        }
+        let ip = self.ip.lock().unwrap().clone();
+        let signature = self.own.sign_identity(&identity_message(
+            &challenge.msg_nonce,
+            &challenge.msg_challenger,
+            &ip,
+        ));
+        Ok(Response::new(ChallengeResponse {
+            msg_signature: signature.to_vec(),
+            msg_identity_key: self.own.identity_key().to_bytes().to_vec(),
+            msg_certificate: self.own.identity_certificate().unwrap().to_vec(),
+        }))
+    }
+
+    async fn handshake_nonce(
+        &self,
+        _: Request<NonceRequest>,
+    ) -> Result<Response<NonceResponse>, Status> {
+        Ok(Response::new(NonceResponse {
+            msg_nonce: rand::random::<[u8; 32]>().to_vec(),
+        }))
+    }
+}
+
+#[tokio::test]
+async fn test_peer_with_unowned_address_is_rejected() {
+    let nodes = spawn_nodes(1).await;
+    let ns = &nodes[0].ns;
+    let ip = Arc::new(std::sync::Mutex::new(String::new()));
+    let challenges = Arc::new(AtomicUsize::new(0));
+    let (impostor_addr, _shutdown) = spawn_service(ImpostorNode {
+        claimed: Wallet::generate().unwrap().address.to_vec(),
+        own: Wallet::generate().unwrap(),
+        relay_to: None,
+        ip: Arc::clone(&ip),
+        challenges: Arc::clone(&challenges),
+    })
+    .await;
+    *ip.lock().unwrap() = impostor_addr.clone();
+
+    // Outbound: the node dials the impostor and checks its reply
+    let result = ns.dial_remote_node(&impostor_addr).await;
+    assert!(matches!(
+        result,
+        Err(NodeServiceError::PeerIdentityUnverified)
+    ));
+
+    // Inbound: the impostor can only sign the node's handshake nonce with its own key,
+    // so it is turned away before any callback challenge
+    let mut client = make_node_client(&nodes[0].addr).await.unwrap();
+    let nonce = client
+        .handshake_nonce(NonceRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .msg_nonce;
+    let signature = Wallet::generate()
+        .unwrap()
+        .sign(&handshake_message(&nonce, &impostor_addr))
+        .unwrap();
+    let status = client
+        .handshake(Version {
+            msg_version: 1,
+            msg_address: Wallet::generate().unwrap().address.to_vec(),
+            msg_ip: impostor_addr,
+            msg_local_index: 0,
+            msg_nonce: nonce,
+            msg_signature: signature.to_vec(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(challenges.load(Ordering::SeqCst), 1);
+    assert!(ns.peers.is_empty());
+
+    teardown(nodes).await;
+}
+
+#[tokio::test]
+async fn test_relayed_challenge_answer_is_rejected() {
+    let nodes = spawn_nodes(2).await;
+    let ns = &nodes[0].ns;
+    let ip = Arc::new(std::sync::Mutex::new(String::new()));
+    let challenges = Arc::new(AtomicUsize::new(0));
+    let (impostor_addr, _shutdown) = spawn_service(ImpostorNode {
+        claimed: nodes[1].ns.wallet.address.to_vec(),
+        own: Wallet::generate().unwrap(),
+        relay_to: Some(nodes[1].addr.clone()),
+        ip: Arc::clone(&ip),
+        challenges: Arc::clone(&challenges),
+    })
+    .await;
+    *ip.lock().unwrap() = impostor_addr.clone();
+    let peers = ns.peers.len();
+
+    // The honest node signs for the endpoint it was dialed at, not the impostor's
+    let result = ns.dial_remote_node(&impostor_addr).await;
+    assert!(matches!(
+        result,
+        Err(NodeServiceError::PeerIdentityUnverified)
+    ));
+    assert_eq!(challenges.load(Ordering::SeqCst), 1);
+    assert_eq!(ns.peers.len(), peers);
+
+    // Dialed directly, the same node proves its address
+    let mut client = make_node_client(&nodes[1].addr).await.unwrap();
+    verify_peer_identity(
+        &mut client,
+        &nodes[1].ns.wallet.address,
+        &ns.wallet.address,
+        &nodes[1].addr,
+    )
+    .await
+    .unwrap();
+
+    teardown(nodes).await;
+}
@@ -0,0 +1,25 @@
+mod test_net;
+
+use test_net::{spawn_nodes, teardown};
+
+#[tokio::test]
+async fn test_peer_info_reports_connected_peers() {
+    let nodes = spawn_nodes(2).await;
+
+    for (node, other) in [(&nodes[0], &nodes[1]), (&nodes[1], &nodes[0])] {
+        let expected = other.ns.get_version().await;
+        let infos = node.ns.peer_info();
+        assert_eq!(infos.len(), 1);
+        let info = &infos[0];
+        assert_eq!(
+            info.address,
+            bs58::encode(&other.ns.wallet.address).into_string()
+        );
+        assert_eq!(info.ip, other.addr);
+        assert_eq!(info.version, expected.msg_version);
+        assert_eq!(info.height, expected.msg_local_index);
+        assert!(info.last_seen > 0);
+    }
+
+    teardown(nodes).await;
+}
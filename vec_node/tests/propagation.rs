@@ -0,0 +1,49 @@
+mod test_net;
+
+use test_net::{spawn_nodes, spending_transaction, teardown, wait_until};
+use vec_node::node::make_node_client;
+use vec_proto::messages::{InvBatch, Transaction};
+use vec_utils::utils::hash_transaction;
+
+#[tokio::test]
+async fn test_transaction_propagates_to_all_nodes() {
+    let nodes = spawn_nodes(4).await;
+    let transaction = spending_transaction(b"propagated transaction");
+
+    assert!(nodes[0].ns.mempool.add(transaction.clone()));
+    nodes[0].ns.broadcast_tx_hash(&transaction).await.unwrap();
+    wait_until(|| nodes.iter().all(|node| node.ns.mempool.has(&transaction))).await;
+
+    teardown(nodes).await;
+}
+
+#[tokio::test]
+async fn test_inventory_batch_pulls_unknown_transactions() {
+    let nodes = spawn_nodes(2).await;
+    let transactions: Vec<Transaction> = (0..3)
+        .map(|i| spending_transaction(format!("inventory {}", i).as_bytes()))
+        .collect();
+    for transaction in &transactions {
+        assert!(nodes[0].ns.mempool.add(transaction.clone()));
+    }
+    // The receiver already knows one of them, only the other two are pulled
+    assert!(nodes[1].ns.mempool.add(transactions[0].clone()));
+
+    let mut client = make_node_client(&nodes[1].addr).await.unwrap();
+    client
+        .handle_inv_batch(InvBatch {
+            msg_transaction_hashes: transactions.iter().map(hash_transaction).collect(),
+            msg_ip: nodes[0].addr.clone(),
+        })
+        .await
+        .unwrap();
+    wait_until(|| {
+        transactions
+            .iter()
+            .all(|transaction| nodes[1].ns.mempool.has(transaction))
+    })
+    .await;
+    assert_eq!(nodes[1].ns.mempool.len(), 3);
+
+    teardown(nodes).await;
+}
@@ -0,0 +1,37 @@
+mod test_net;
+
+use test_net::{spawn_node_with_role, teardown};
+use vec_storage::output_db::OutputStorer;
+
+#[tokio::test]
+async fn test_rebuild_outputs_restores_balance() {
+    let node = spawn_node_with_role(true).await;
+    node.ns.make_genesis_block().await.unwrap();
+    let balance = node.ns.get_balance().await;
+    assert!(balance > 0);
+
+    // Lose the owned outputs and leave a bogus one behind
+    let mut outputs = node.ns.chain.outputs().get().await.unwrap();
+    for owned_output in &outputs {
+        node.ns
+            .chain
+            .outputs()
+            .remove(&owned_output.output.stealth)
+            .await
+            .unwrap();
+    }
+    outputs[0].output.stealth = vec![0; 32];
+    outputs[0].decrypted_amount = 7;
+    node.ns.chain.outputs().put(&outputs[0]).await.unwrap();
+    assert_eq!(node.ns.get_balance().await, 7);
+
+    let scanned = node
+        .ns
+        .rebuild_outputs_from_local_blocks(&node.ns.wallet)
+        .await
+        .unwrap();
+    assert_eq!(scanned, 1);
+    assert_eq!(node.ns.get_balance().await, balance);
+
+    teardown(vec![node]).await;
+}
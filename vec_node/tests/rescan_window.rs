@@ -0,0 +1,47 @@
+mod test_net;
+
+use test_net::{make_chain, spawn_node_with_role, teardown};
+use vec_crypto::crypto::Wallet;
+use vec_utils::utils::hash_block;
+
+#[tokio::test]
+async fn test_rescan_skips_blocks_before_creation_height() {
+    let node = spawn_node_with_role(false).await;
+    let wallet = Wallet::generate().unwrap().with_scan_from_index(2);
+    let address = bs58::encode(wallet.address).into_string();
+
+    // Owned outputs at heights 1 (before the wallet existed) and 3
+    let mut blocks = make_chain(3);
+    blocks[0].msg_transactions[0].msg_outputs = vec![node
+        .ns
+        .wallet
+        .prepare_output(&address, 1, 40, None)
+        .unwrap()];
+    blocks[2].msg_transactions[0].msg_outputs =
+        vec![node.ns.wallet.prepare_output(&address, 1, 2, None).unwrap()];
+    for index in 1..blocks.len() {
+        let previous_hash = hash_block(&blocks[index - 1]).unwrap();
+        blocks[index].msg_header.as_mut().unwrap().msg_previous_hash = previous_hash;
+    }
+    for block in blocks {
+        node.ns
+            .chain
+            .add_genesis_block(&node.ns.wallet, block)
+            .await
+            .unwrap();
+    }
+
+    let scanned = node
+        .ns
+        .rebuild_outputs_from_local_blocks(&wallet)
+        .await
+        .unwrap();
+    assert_eq!(scanned, 2);
+    assert_eq!(node.ns.get_balance().await, 2);
+
+    // The creation height survives serialization
+    let restored = Wallet::from_vec(&wallet.to_vec()).unwrap();
+    assert_eq!(restored.scan_from_index, 2);
+
+    teardown(vec![node]).await;
+}
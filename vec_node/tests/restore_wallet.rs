@@ -0,0 +1,39 @@
+mod test_net;
+
+use test_net::{init_data_dir, make_chain, shared_chain};
+use vec_crypto::crypto::Wallet;
+use vec_node::node::NodeService;
+use vec_utils::utils::hash_block;
+
+#[tokio::test]
+async fn test_restored_wallet_balance_is_rescanned() {
+    init_data_dir();
+    let owner = Wallet::generate().unwrap();
+    let address = bs58::encode(owner.address).into_string();
+    let ns = NodeService::new(
+        shared_chain(),
+        bs58::encode(owner.secret_spend_key_to_vec()).into_string(),
+        "127.0.0.1:0".to_string(),
+        "127.0.0.1:0".to_string(),
+        false,
+    )
+    .await
+    .unwrap();
+
+    // The owner's outputs were stored before the wallet was restored on this node
+    let mut blocks = make_chain(2);
+    blocks[0].msg_transactions[0].msg_outputs =
+        vec![owner.prepare_output(&address, 1, 40, None).unwrap()];
+    blocks[1].msg_transactions[0].msg_outputs =
+        vec![owner.prepare_output(&address, 1, 2, None).unwrap()];
+    let previous_hash = hash_block(&blocks[0]).unwrap();
+    blocks[1].msg_header.as_mut().unwrap().msg_previous_hash = previous_hash;
+    let stranger = Wallet::generate().unwrap();
+    for block in blocks {
+        ns.chain.add_genesis_block(&stranger, block).await.unwrap();
+    }
+    assert_eq!(ns.get_balance().await, 0);
+
+    assert_eq!(ns.restore_wallet_balance().await.unwrap(), 42);
+    assert_eq!(ns.get_balance().await, 42);
+}
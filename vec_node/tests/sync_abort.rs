@@ -0,0 +1,49 @@
+mod test_net;
+
+use std::sync::Arc;
+use test_net::{data_dir, init_data_dir, make_chain, shared_chain};
+use vec_crypto::crypto::Wallet;
+use vec_node::node::NodeService;
+use vec_proto::messages::{BlockBatch, Transaction};
+use vec_utils::utils::{hash_block, hash_transaction};
+
+#[tokio::test]
+async fn test_invalid_block_aborts_rest_of_sync_batch() {
+    init_data_dir();
+    let wallet = Wallet::generate().unwrap();
+    let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+    let node = Arc::new(
+        NodeService::new(
+            shared_chain(),
+            secret_key,
+            "127.0.0.1:0".to_string(),
+            "127.0.0.1:0".to_string(),
+            false,
+        )
+        .await
+        .unwrap(),
+    );
+
+    // Block 3 pays the node but carries a transaction without outputs, the blocks after it are relinked
+    // so they would extend it
+    let mut blocks = make_chain(5);
+    blocks[2].msg_transactions[0].msg_outputs =
+        vec![node.wallet.prepare_change_output(1, 1).unwrap()];
+    blocks[2].msg_transactions.push(Transaction::default());
+    for i in 3..blocks.len() {
+        let previous_hash = hash_block(&blocks[i - 1]).unwrap();
+        blocks[i].msg_header.as_mut().unwrap().msg_previous_hash = previous_hash;
+    }
+    let later_tx = hash_transaction(&blocks[3].msg_transactions[0]);
+
+    let batch = BlockBatch { msg_blocks: blocks };
+    assert!(node
+        .process_synchronisation(&node.wallet, batch)
+        .await
+        .is_err());
+    assert_eq!(node.chain.max_index().await.unwrap(), 2);
+    assert_eq!(node.chain.get_balance().await, 0);
+    assert!(!node.chain.is_transaction_confirmed(later_tx).await.unwrap());
+
+    let _ = std::fs::remove_dir_all(data_dir());
+}
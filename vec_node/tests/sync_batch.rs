@@ -0,0 +1,89 @@
+mod test_net;
+
+use std::sync::{Arc, Mutex};
+use test_net::{data_dir, init_data_dir, make_chain, shared_chain, spawn_service, MockPeer};
+use tonic::{Request, Response, Status};
+use vec_crypto::crypto::Wallet;
+use vec_node::node::{make_node_client, ArcNodeService, NodeService};
+use vec_proto::messages::node_server::Node;
+use vec_proto::messages::*;
+
+const CHAIN_LENGTH: u32 = 10;
+const BATCH_MAX: u32 = 4;
+
+// Peer that is far ahead and serves its chain in batches of at most BATCH_MAX blocks
+struct AheadNode {
+    blocks: Vec<Block>,
+    batch_sizes: Arc<Mutex<Vec<usize>>>,
+}
+
+#[tonic::async_trait]
+impl MockPeer for AheadNode {
+    async fn push_state(
+        &self,
+        request: Request<LocalState>,
+    ) -> Result<Response<BlockBatch>, Status> {
+        let from = request.into_inner().msg_local_index as usize;
+        let msg_blocks: Vec<Block> = self
+            .blocks
+            .iter()
+            .skip(from)
+            .take(BATCH_MAX as usize)
+            .cloned()
+            .collect();
+        self.batch_sizes.lock().unwrap().push(msg_blocks.len());
+        Ok(Response::new(BlockBatch { msg_blocks }))
+    }
+}
+
+#[tokio::test]
+async fn test_sync_in_capped_batches() {
+    init_data_dir();
+    let wallet = Wallet::generate().unwrap();
+    let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+    let mut node = NodeService::new(
+        shared_chain(),
+        secret_key,
+        "127.0.0.1:0".to_string(),
+        "127.0.0.1:0".to_string(),
+        false,
+    )
+    .await
+    .unwrap();
+    node.sync_batch_max = BATCH_MAX;
+    let node = Arc::new(node);
+
+    let batch_sizes = Arc::new(Mutex::new(Vec::new()));
+    let (addr, _shutdown) = spawn_service(AheadNode {
+        blocks: make_chain(CHAIN_LENGTH),
+        batch_sizes: Arc::clone(&batch_sizes),
+    })
+    .await;
+    let mut client = make_node_client(&addr).await.unwrap();
+    node.synchronize_with_client(&node.wallet, &mut client)
+        .await
+        .unwrap();
+    assert_eq!(node.chain.max_index().await.unwrap(), CHAIN_LENGTH);
+    assert_eq!(*batch_sizes.lock().unwrap(), vec![4, 4, 2, 0]);
+
+    // The synced node now serves the chain itself under the same cap
+    let service = ArcNodeService {
+        ns: Arc::clone(&node),
+    };
+    for (msg_local_index, expected) in [(0, BATCH_MAX), (8, 2), (CHAIN_LENGTH, 0)] {
+        let batch = service
+            .push_state(Request::new(LocalState { msg_local_index }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(batch.msg_blocks.len() as u32, expected);
+        if let Some(first) = batch.msg_blocks.first() {
+            assert_eq!(
+                first.msg_header.as_ref().unwrap().msg_index,
+                msg_local_index + 1
+            );
+        }
+    }
+
+    let _ = std::fs::remove_dir_all(data_dir());
+}
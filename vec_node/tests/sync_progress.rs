@@ -0,0 +1,82 @@
+mod test_net;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use test_net::{data_dir, init_data_dir, make_chain, shared_chain, spawn_service, MockPeer};
+use tonic::{Request, Response, Status};
+use vec_crypto::crypto::Wallet;
+use vec_errors::errors::NodeServiceError;
+use vec_node::node::{make_node_client, NodeService};
+use vec_proto::messages::*;
+
+// Peer serving the blocks after the requested index, while a concurrent rollback takes the node's last block
+// back out every time, so each batch only restores what was just lost
+struct RewindingPeer {
+    node: Arc<NodeService>,
+    blocks: Vec<Block>,
+    batches: Arc<AtomicUsize>,
+}
+
+#[tonic::async_trait]
+impl MockPeer for RewindingPeer {
+    async fn push_state(
+        &self,
+        request: Request<LocalState>,
+    ) -> Result<Response<BlockBatch>, Status> {
+        self.batches.fetch_add(1, Ordering::SeqCst);
+        let mut from = request.into_inner().msg_local_index;
+        if from > 0 {
+            from -= 1;
+            self.node
+                .chain
+                .rollback_to(&self.node.wallet, from)
+                .await
+                .unwrap();
+        }
+        let msg_blocks = self
+            .blocks
+            .iter()
+            .skip(from as usize)
+            .take(1)
+            .cloned()
+            .collect();
+        Ok(Response::new(BlockBatch { msg_blocks }))
+    }
+}
+
+#[tokio::test]
+async fn test_sync_stops_when_the_index_does_not_advance() {
+    init_data_dir();
+    let wallet = Wallet::generate().unwrap();
+    let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+    let node = Arc::new(
+        NodeService::new(
+            shared_chain(),
+            secret_key,
+            "127.0.0.1:0".to_string(),
+            "127.0.0.1:0".to_string(),
+            false,
+        )
+        .await
+        .unwrap(),
+    );
+
+    let batches = Arc::new(AtomicUsize::new(0));
+    let (addr, _shutdown) = spawn_service(RewindingPeer {
+        node: Arc::clone(&node),
+        blocks: make_chain(2),
+        batches: Arc::clone(&batches),
+    })
+    .await;
+    let mut client = make_node_client(&addr).await.unwrap();
+    // The first batch takes the node to index 1, the second one only puts block 1 back
+    assert!(matches!(
+        node.synchronize_with_client(&node.wallet, &mut client)
+            .await,
+        Err(NodeServiceError::SyncNoProgress)
+    ));
+    assert_eq!(batches.load(Ordering::SeqCst), 2);
+    assert_eq!(node.chain.max_index().await.unwrap(), 1);
+
+    let _ = std::fs::remove_dir_all(data_dir());
+}
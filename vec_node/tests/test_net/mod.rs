@@ -0,0 +1,393 @@
+#![allow(dead_code, unused_imports)]
+
+use std::path::PathBuf;
+use std::sync::{Arc, Once, OnceLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use vec_chain::chain::{transactions_root, Chain};
+use vec_chain::consensus::{BLOCK_REWARD, GENESIS_PREMINE, POW_DIFFICULTY, POW_HASHER};
+pub use vec_chain::fixtures::spending_transaction;
+use vec_crypto::crypto::Wallet;
+use vec_node::node::{make_node_client, ArcNodeService, NodeService};
+use vec_proto::messages::node_server::{Node, NodeServer};
+use vec_proto::messages::*;
+use vec_storage::lazy_traits::{BLOCK_STORER, IMAGE_STORER, META_STORER, OUTPUT_STORER};
+use vec_utils::utils::{hash_block, hash_contract_code, mine_rolling, NONCE_SPACE};
+
+static DATA_DIR: Once = Once::new();
+static SHARED_CHAIN: OnceLock<Arc<Chain>> = OnceLock::new();
+
+// Sled databases are process-wide, so every node spawned by this test binary shares one temp dir
+pub fn data_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("vector-test-net-{}", std::process::id()))
+}
+
+pub struct NodeHandle {
+    pub ns: Arc<NodeService>,
+    pub addr: String,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    server: Option<JoinHandle<()>>,
+}
+
+impl NodeHandle {
+    // Stops the server and waits for it to release its port
+    pub async fn shutdown(mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        if let Some(server) = self.server.take() {
+            let _ = server.await;
+        }
+    }
+}
+
+impl Drop for NodeHandle {
+    fn drop(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+    }
+}
+
+// Points the Sled databases of this test binary at a fresh temp dir, must run before they are first opened
+pub fn init_data_dir() {
+    DATA_DIR.call_once(|| std::env::set_var("VECTOR_DATA_DIR", data_dir()));
+}
+
+// Nodes of this test binary on the process-wide databases share one chain over them, so they agree on its
+// cached tip
+pub fn shared_chain() -> Arc<Chain> {
+    init_data_dir();
+    let chain = SHARED_CHAIN.get_or_init(|| {
+        Arc::new(Chain::new(
+            Arc::clone(&BLOCK_STORER),
+            Arc::clone(&IMAGE_STORER),
+            Arc::clone(&OUTPUT_STORER),
+            Arc::clone(&META_STORER),
+        ))
+    });
+    Arc::clone(chain)
+}
+
+// Starts n nodes on ephemeral loopback ports and connects every pair of them
+pub async fn spawn_nodes(n: usize) -> Vec<NodeHandle> {
+    init_data_dir();
+    let mut nodes = Vec::with_capacity(n);
+    for _ in 0..n {
+        nodes.push(spawn_node().await);
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        for peer in &nodes[..i] {
+            node.ns.connect_to(peer.addr.clone()).await.unwrap();
+        }
+    }
+    wait_until(|| nodes.iter().all(|node| node.ns.peers.len() == n - 1)).await;
+    nodes
+}
+
+// Builds a linked chain of mined blocks with indexes 1..=length, one second apart and ending now
+pub fn make_chain(length: u32) -> Vec<Block> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut blocks: Vec<Block> = Vec::new();
+    for index in 1..=length {
+        let msg_previous_hash = match blocks.last() {
+            Some(previous) => hash_block(previous).unwrap(),
+            None => vec![],
+        };
+        // The genesis block mints the premine, like the one a validator makes, later ones the block reward
+        let minted = if index == 1 {
+            GENESIS_PREMINE
+        } else {
+            BLOCK_REWARD
+        };
+        let coinbase = Wallet::generate()
+            .unwrap()
+            .prepare_change_output(minted, 1)
+            .unwrap();
+        let transaction = Transaction {
+            msg_outputs: vec![coinbase],
+            msg_contract: Some(Contract {
+                msg_code: index.to_le_bytes().to_vec(),
+            }),
+            msg_minted: minted,
+            msg_contract_hash: hash_contract_code(&index.to_le_bytes()),
+            ..Default::default()
+        };
+        let block = Block {
+            msg_header: Some(Header {
+                msg_version: 1,
+                msg_index: index,
+                msg_previous_hash,
+                msg_root_hash: transactions_root(std::slice::from_ref(&transaction)),
+                msg_timestamp: now - u64::from(length - index),
+                ..Default::default()
+            }),
+            msg_transactions: vec![transaction],
+        };
+        blocks.push(mine_rolling(&POW_HASHER, block, POW_DIFFICULTY, 0, NONCE_SPACE).unwrap());
+    }
+    blocks
+}
+
+async fn spawn_node() -> NodeHandle {
+    spawn_node_with_role(false).await
+}
+
+// Starts a single unconnected node, optionally running the validator role
+pub async fn spawn_node_with_role(is_validator: bool) -> NodeHandle {
+    spawn_node_with_chain(is_validator, None).await
+}
+
+// Like spawn_node_with_role, but the node can get a chain of its own instead of the process-wide one,
+// so it has to receive blocks from its peers like a separate process would
+pub async fn spawn_node_with_chain(is_validator: bool, chain: Option<Arc<Chain>>) -> NodeHandle {
+    init_data_dir();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    let wallet = Wallet::generate().unwrap();
+    let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+    let ns = NodeService::new(
+        chain.unwrap_or_else(shared_chain),
+        secret_key,
+        addr.to_string(),
+        addr.to_string(),
+        is_validator,
+    )
+    .await
+    .unwrap();
+    let ns = Arc::new(ns);
+    let (shutdown_tx, server) = serve(
+        addr,
+        ArcNodeService {
+            ns: Arc::clone(&ns),
+        },
+    )
+    .await;
+
+    NodeHandle {
+        ns,
+        addr: addr.to_string(),
+        shutdown_tx: Some(shutdown_tx),
+        server: Some(server),
+    }
+}
+
+// Serves the mock peer on an ephemeral loopback port
+pub async fn spawn_service<P: MockPeer>(peer: P) -> (String, oneshot::Sender<()>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    let (shutdown_tx, _) = serve(addr, MockNode(peer)).await;
+    (addr.to_string(), shutdown_tx)
+}
+
+// Peer a test serves in place of a real node: every RPC it doesn't override answers unimplemented
+#[tonic::async_trait]
+pub trait MockPeer: Send + Sync + 'static {
+    async fn handshake(&self, _: Request<Version>) -> Result<Response<Version>, Status> {
+        Err(Status::unimplemented("handshake"))
+    }
+
+    async fn push_state(&self, _: Request<LocalState>) -> Result<Response<BlockBatch>, Status> {
+        Err(Status::unimplemented("push_state"))
+    }
+
+    async fn handle_peer_list(&self, _: Request<PeerList>) -> Result<Response<Confirmed>, Status> {
+        Err(Status::unimplemented("handle_peer_list"))
+    }
+
+    async fn handle_block_push(
+        &self,
+        _: Request<PushBlockRequest>,
+    ) -> Result<Response<Confirmed>, Status> {
+        Err(Status::unimplemented("handle_block_push"))
+    }
+
+    async fn handle_block_pull(
+        &self,
+        _: Request<PullBlockRequest>,
+    ) -> Result<Response<Block>, Status> {
+        Err(Status::unimplemented("handle_block_pull"))
+    }
+
+    async fn handle_tx_push(
+        &self,
+        _: Request<PushTxRequest>,
+    ) -> Result<Response<Confirmed>, Status> {
+        Err(Status::unimplemented("handle_tx_push"))
+    }
+
+    async fn handle_tx_pull(
+        &self,
+        _: Request<PullTxRequest>,
+    ) -> Result<Response<Transaction>, Status> {
+        Err(Status::unimplemented("handle_tx_pull"))
+    }
+
+    async fn handle_inv_batch(&self, _: Request<InvBatch>) -> Result<Response<Confirmed>, Status> {
+        Err(Status::unimplemented("handle_inv_batch"))
+    }
+
+    async fn get_mempool(
+        &self,
+        _: Request<MempoolRequest>,
+    ) -> Result<Response<MempoolSummary>, Status> {
+        Err(Status::unimplemented("get_mempool"))
+    }
+
+    async fn challenge(
+        &self,
+        _: Request<ChallengeRequest>,
+    ) -> Result<Response<ChallengeResponse>, Status> {
+        Err(Status::unimplemented("challenge"))
+    }
+
+    async fn handshake_nonce(
+        &self,
+        _: Request<NonceRequest>,
+    ) -> Result<Response<NonceResponse>, Status> {
+        Err(Status::unimplemented("handshake_nonce"))
+    }
+
+    async fn is_image_spent(
+        &self,
+        _: Request<ImageSpentRequest>,
+    ) -> Result<Response<ImageSpentResponse>, Status> {
+        Err(Status::unimplemented("is_image_spent"))
+    }
+}
+
+// Serves a MockPeer as a Node, whose generated trait has no default methods
+pub struct MockNode<P>(pub P);
+
+#[tonic::async_trait]
+impl<P: MockPeer> Node for MockNode<P> {
+    async fn handshake(&self, request: Request<Version>) -> Result<Response<Version>, Status> {
+        self.0.handshake(request).await
+    }
+
+    async fn push_state(
+        &self,
+        request: Request<LocalState>,
+    ) -> Result<Response<BlockBatch>, Status> {
+        self.0.push_state(request).await
+    }
+
+    async fn handle_peer_list(
+        &self,
+        request: Request<PeerList>,
+    ) -> Result<Response<Confirmed>, Status> {
+        self.0.handle_peer_list(request).await
+    }
+
+    async fn handle_block_push(
+        &self,
+        request: Request<PushBlockRequest>,
+    ) -> Result<Response<Confirmed>, Status> {
+        self.0.handle_block_push(request).await
+    }
+
+    async fn handle_block_pull(
+        &self,
+        request: Request<PullBlockRequest>,
+    ) -> Result<Response<Block>, Status> {
+        self.0.handle_block_pull(request).await
+    }
+
+    async fn handle_tx_push(
+        &self,
+        request: Request<PushTxRequest>,
+    ) -> Result<Response<Confirmed>, Status> {
+        self.0.handle_tx_push(request).await
+    }
+
+    async fn handle_tx_pull(
+        &self,
+        request: Request<PullTxRequest>,
+    ) -> Result<Response<Transaction>, Status> {
+        self.0.handle_tx_pull(request).await
+    }
+
+    async fn handle_inv_batch(
+        &self,
+        request: Request<InvBatch>,
+    ) -> Result<Response<Confirmed>, Status> {
+        self.0.handle_inv_batch(request).await
+    }
+
+    async fn get_mempool(
+        &self,
+        request: Request<MempoolRequest>,
+    ) -> Result<Response<MempoolSummary>, Status> {
+        self.0.get_mempool(request).await
+    }
+
+    async fn challenge(
+        &self,
+        request: Request<ChallengeRequest>,
+    ) -> Result<Response<ChallengeResponse>, Status> {
+        self.0.challenge(request).await
+    }
+
+    async fn handshake_nonce(
+        &self,
+        request: Request<NonceRequest>,
+    ) -> Result<Response<NonceResponse>, Status> {
+        self.0.handshake_nonce(request).await
+    }
+
+    async fn is_image_spent(
+        &self,
+        request: Request<ImageSpentRequest>,
+    ) -> Result<Response<ImageSpentResponse>, Status> {
+        self.0.is_image_spent(request).await
+    }
+}
+
+async fn serve<N: Node>(
+    addr: std::net::SocketAddr,
+    node: N,
+) -> (oneshot::Sender<()>, JoinHandle<()>) {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let service = NodeServer::new(node);
+    let server = tokio::spawn(async move {
+        Server::builder()
+            .add_service(service)
+            .serve_with_shutdown(addr, async {
+                let _ = shutdown_rx.await;
+            })
+            .await
+            .unwrap();
+    });
+    while make_node_client(&addr.to_string()).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    (shutdown_tx, server)
+}
+
+// Shuts every node down and removes the temp databases
+pub async fn teardown(nodes: Vec<NodeHandle>) {
+    for node in nodes {
+        node.ns.flush_all().await.unwrap();
+        node.shutdown().await;
+    }
+    let _ = std::fs::remove_dir_all(data_dir());
+}
+
+// Polls the condition until it holds, panicking after 10 seconds
+pub async fn wait_until(condition: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("condition not met within 10 seconds");
+}
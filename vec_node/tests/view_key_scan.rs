@@ -0,0 +1,165 @@
+mod test_net;
+
+use std::sync::Arc;
+use std::time::Duration;
+use test_net::{make_chain, shared_chain, spawn_node_with_role, teardown};
+use vec_crypto::crypto::Wallet;
+use vec_errors::errors::NodeServiceError;
+use vec_node::node::{
+    make_admin_client, new, setup_admin_server, start, NodeConfig, ADMIN_ADDR_VAR,
+    MAX_VIEW_KEY_SCAN_RANGE,
+};
+use vec_proto::messages::{TransactionOutput, ViewKeyScanRequest};
+
+#[tokio::test]
+async fn test_scan_with_view_key_finds_recipient_outputs() {
+    let node = spawn_node_with_role(false).await;
+    let sender = Wallet::generate().unwrap();
+    let recipient = Wallet::generate().unwrap();
+    let recipient_address = bs58::encode(recipient.address).into_string();
+    let other_address = bs58::encode(Wallet::generate().unwrap().address).into_string();
+
+    let mut block = make_chain(1).remove(0);
+    block.msg_transactions[0].msg_outputs = vec![
+        sender
+            .prepare_output(&recipient_address, 1, 42, None)
+            .unwrap(),
+        sender.prepare_output(&other_address, 2, 7, None).unwrap(),
+        // Undecodable output key, skipped rather than failing the scan
+        TransactionOutput {
+            msg_output_key: vec![0xff; 32],
+            msg_stealth_address: vec![0xff; 32],
+            msg_amount: vec![0; 8],
+            msg_index: 4,
+            ..Default::default()
+        },
+        sender
+            .prepare_output(&recipient_address, 3, 5, None)
+            .unwrap(),
+    ];
+    node.ns
+        .chain
+        .add_genesis_block(&node.ns.wallet, block)
+        .await
+        .unwrap();
+
+    // The peer service doesn't take view keys, the admin service only listens on loopback
+    let public = "0.0.0.0:0".parse().unwrap();
+    assert!(matches!(
+        setup_admin_server(&node.ns, public).await,
+        Err(NodeServiceError::AdminNotLoopback(_))
+    ));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let admin_addr = listener.local_addr().unwrap();
+    drop(listener);
+    let admin_ns = Arc::clone(&node.ns);
+    tokio::spawn(async move { setup_admin_server(&admin_ns, admin_addr).await });
+    let mut client = loop {
+        match make_admin_client(&admin_addr.to_string()).await {
+            Ok(client) => break client,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+
+    let request = ViewKeyScanRequest {
+        msg_public_spend_key: recipient.public_spend_key_to_vec(),
+        msg_secret_view_key: recipient.secret_view_key_to_vec(),
+        msg_from_index: 0,
+        msg_to_index: 10,
+    };
+    let outputs = client
+        .scan_with_view_key(request.clone())
+        .await
+        .unwrap()
+        .into_inner()
+        .msg_outputs;
+    let found: Vec<(u32, u32, u64)> = outputs
+        .iter()
+        .map(|output| {
+            (
+                output.msg_block_index,
+                output.msg_output_index,
+                output.msg_amount,
+            )
+        })
+        .collect();
+    assert_eq!(found, vec![(1, 1, 42), (1, 3, 5)]);
+
+    // Blocks below the starting index are skipped
+    let later = client
+        .scan_with_view_key(ViewKeyScanRequest {
+            msg_from_index: 2,
+            ..request.clone()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(later.msg_outputs.is_empty());
+    assert_eq!(node.ns.get_balance().await, 0);
+
+    // A single call can't scan more than the capped range
+    let status = client
+        .scan_with_view_key(ViewKeyScanRequest {
+            msg_from_index: 1,
+            msg_to_index: MAX_VIEW_KEY_SCAN_RANGE + 1,
+            ..request
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    teardown(vec![node]).await;
+}
+
+fn free_addr() -> std::net::SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn test_admin_server_starts_from_config() {
+    let admin_addr = free_addr().to_string();
+    let bind_addr = free_addr().to_string();
+    let config =
+        NodeConfig::from_vars(|name| (name == ADMIN_ADDR_VAR).then(|| admin_addr.clone())).unwrap();
+    let secret_key =
+        bs58::encode(Wallet::generate().unwrap().secret_spend_key_to_vec()).into_string();
+    let ans = new(
+        shared_chain(),
+        secret_key,
+        bind_addr.clone(),
+        bind_addr,
+        false,
+        &config,
+    )
+    .await
+    .unwrap();
+    assert_eq!(ans.ns.admin_addr, Some(admin_addr.clone()));
+    let ns = Arc::clone(&ans.ns);
+    let node = tokio::spawn(async move { start(&ns).await });
+
+    let mut client = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            match make_admin_client(&admin_addr).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .unwrap();
+    let recipient = Wallet::generate().unwrap();
+    let result = client
+        .scan_with_view_key(ViewKeyScanRequest {
+            msg_public_spend_key: recipient.public_spend_key_to_vec(),
+            msg_secret_view_key: recipient.secret_view_key_to_vec(),
+            msg_from_index: 0,
+            msg_to_index: 10,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(result.msg_outputs.is_empty());
+
+    node.abort();
+}
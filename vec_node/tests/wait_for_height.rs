@@ -0,0 +1,36 @@
+mod test_net;
+
+use std::sync::Arc;
+use std::time::Duration;
+use test_net::{spawn_node_with_role, teardown};
+use vec_errors::errors::NodeServiceError;
+
+#[tokio::test]
+async fn test_wait_for_height_resolves_on_new_blocks() {
+    let node = spawn_node_with_role(true).await;
+    node.ns.make_genesis_block().await.unwrap();
+
+    let ns = Arc::clone(&node.ns);
+    let waiter = tokio::spawn(async move {
+        ns.wait_for_height(3, Duration::from_secs(30)).await?;
+        Ok::<u32, NodeServiceError>(ns.chain.cached_tip_index())
+    });
+    node.ns.make_block().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!waiter.is_finished());
+
+    node.ns.make_block().await.unwrap();
+    assert_eq!(waiter.await.unwrap().unwrap(), 3);
+
+    // Heights already reached resolve at once, unreachable ones time out
+    node.ns
+        .wait_for_height(2, Duration::from_millis(1))
+        .await
+        .unwrap();
+    assert!(matches!(
+        node.ns.wait_for_height(4, Duration::from_millis(100)).await,
+        Err(NodeServiceError::HeightNotReached(4))
+    ));
+
+    teardown(vec![node]).await;
+}
@@ -0,0 +1,56 @@
+mod test_net;
+
+use test_net::{data_dir, init_data_dir, shared_chain};
+use vec_chain::consensus::BLOCK_REWARD;
+use vec_crypto::crypto::Wallet;
+use vec_node::node::NodeService;
+use vec_proto::messages::{Block, Header, Transaction};
+
+fn block(index: u32, transactions: Vec<Transaction>) -> Block {
+    Block {
+        msg_header: Some(Header {
+            msg_index: index,
+            ..Default::default()
+        }),
+        msg_transactions: transactions,
+    }
+}
+
+#[tokio::test]
+async fn test_birthday_survives_a_restart() {
+    init_data_dir();
+    let wallet = Wallet::generate().unwrap();
+    let secret_key = bs58::encode(wallet.secret_spend_key_to_vec()).into_string();
+    let chain = shared_chain();
+
+    // The wallet's first output comes in the third block
+    for index in 1..=3 {
+        let transactions = if index == 3 {
+            vec![Transaction {
+                msg_outputs: vec![wallet.prepare_change_output(BLOCK_REWARD, 1).unwrap()],
+                msg_minted: BLOCK_REWARD,
+                ..Default::default()
+            }]
+        } else {
+            vec![]
+        };
+        chain
+            .add_genesis_block(&wallet, block(index, transactions))
+            .await
+            .unwrap();
+    }
+    assert_eq!(wallet.birthday(), Some(3));
+
+    let ns = NodeService::new(
+        shared_chain(),
+        secret_key,
+        "127.0.0.1:0".to_string(),
+        "127.0.0.1:0".to_string(),
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(ns.wallet.birthday(), Some(3));
+
+    let _ = std::fs::remove_dir_all(data_dir());
+}
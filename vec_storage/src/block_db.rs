@@ -1,12 +1,21 @@
+use crate::maintenance::CompactableDb;
 use async_trait::async_trait;
 use prost::Message;
-use sled::{Db, IVec};
+use sled::IVec;
 use vec_errors::errors::*;
 use vec_proto::messages::Block;
 
+// Stealth addresses share the tx_db with the transaction hashes, the prefix keeps their keys apart
+const STEALTH_PREFIX: &[u8] = b"stealth_";
+
+fn stealth_key(stealth_address: &[u8]) -> Vec<u8> {
+    [STEALTH_PREFIX, stealth_address].concat()
+}
+
 pub struct BlockDB {
-    blocks_db: Db,
-    index_db: Db,
+    blocks_db: CompactableDb,
+    index_db: CompactableDb,
+    tx_db: CompactableDb,
 }
 
 #[async_trait]
@@ -17,24 +26,57 @@ pub trait BlockStorer: Send + Sync {
         hash: Vec<u8>,
         block: &Block,
     ) -> Result<(), BlockStorageError>;
+    async fn remove_block(&self, index: u32) -> Result<Option<Block>, BlockStorageError>;
     async fn get(&self, hash: Vec<u8>) -> Result<Option<Block>, BlockStorageError>;
     async fn get_by_index(&self, index: u32) -> Result<Option<Block>, BlockStorageError>;
     async fn get_hash_by_index(&self, index: u32) -> Result<Option<Vec<u8>>, BlockStorageError>;
     async fn get_highest_index(&self) -> Result<Option<u32>, BlockStorageError>;
     async fn is_empty(&self) -> Result<bool, BlockStorageError>;
+    async fn put_tx_index(
+        &self,
+        tx_hash: Vec<u8>,
+        block_hash: Vec<u8>,
+    ) -> Result<(), BlockStorageError>;
+    async fn get_block_hash_by_tx(
+        &self,
+        tx_hash: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, BlockStorageError>;
+    async fn remove_tx_index(&self, tx_hash: Vec<u8>) -> Result<(), BlockStorageError>;
+    async fn put_stealth_index(
+        &self,
+        stealth_address: &[u8],
+        tx_hash: Vec<u8>,
+    ) -> Result<(), BlockStorageError>;
+    async fn remove_stealth_index(&self, stealth_address: &[u8]) -> Result<(), BlockStorageError>;
+    async fn contains_stealth_address(
+        &self,
+        stealth_address: &[u8],
+    ) -> Result<bool, BlockStorageError>;
+    async fn flush(&self) -> Result<(), BlockStorageError>;
 }
 
 impl BlockDB {
-    pub fn new(blocks_db: Db, index_db: Db) -> Self {
+    pub fn new(blocks_db: CompactableDb, index_db: CompactableDb, tx_db: CompactableDb) -> Self {
         BlockDB {
             blocks_db,
             index_db,
+            tx_db,
         }
     }
+
+    // Compacts the databases while they stay in use, returns the reclaimed bytes
+    pub fn compact(&self) -> Result<u64, BlockStorageError> {
+        let mut reclaimed = 0;
+        for db in [&self.blocks_db, &self.index_db, &self.tx_db] {
+            reclaimed += db.compact().map_err(|_| BlockStorageError::WriteError)?;
+        }
+        Ok(reclaimed)
+    }
 }
 
 #[async_trait]
 impl BlockStorer for BlockDB {
+    // Storing the same block again is a no-op, a different block at an occupied index is refused
     async fn put_block(
         &self,
         index: u32,
@@ -46,18 +88,60 @@ impl BlockStorer for BlockDB {
             .encode(&mut block_data)
             .map_err(|_| BlockStorageError::SerializationError)?;
 
-        self.blocks_db
-            .insert(&hash, block_data)
-            .map_err(|_| BlockStorageError::WriteError)?;
-        self.index_db
-            .insert(index.to_be_bytes(), IVec::from(hash))
+        // The index is claimed atomically, of two blocks racing for it only one gets it
+        let claimed = self
+            .index_db
+            .read()
+            .compare_and_swap(
+                index.to_be_bytes(),
+                None::<&[u8]>,
+                Some(IVec::from(hash.clone())),
+            )
             .map_err(|_| BlockStorageError::WriteError)?;
+        if let Err(conflict) = claimed {
+            if conflict.current.as_deref() != Some(hash.as_slice()) {
+                return Err(BlockStorageError::IndexOccupied { index });
+            }
+        }
+        if self.blocks_db.read().insert(&hash, block_data).is_err() {
+            // Frees the index again so it doesn't point to a block that was never stored
+            let _ = self.index_db.read().compare_and_swap(
+                index.to_be_bytes(),
+                Some(hash.as_slice()),
+                None::<IVec>,
+            );
+            return Err(BlockStorageError::WriteError);
+        }
 
         Ok(())
     }
 
+    // Takes the block at the index out of the chain, only for rolling back the blocks after a fork
+    async fn remove_block(&self, index: u32) -> Result<Option<Block>, BlockStorageError> {
+        let hash = match self
+            .index_db
+            .read()
+            .remove(index.to_be_bytes())
+            .map_err(|_| BlockStorageError::WriteError)?
+        {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        match self
+            .blocks_db
+            .read()
+            .remove(hash)
+            .map_err(|_| BlockStorageError::WriteError)?
+        {
+            Some(data) => Ok(Some(
+                Block::decode(&*data).map_err(|_| BlockStorageError::DeserializationError)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
     async fn get(&self, hash: Vec<u8>) -> Result<Option<Block>, BlockStorageError> {
-        match self.blocks_db.get(hash) {
+        match self.blocks_db.read().get(hash) {
             Ok(Some(data)) => {
                 let block =
                     Block::decode(&*data).map_err(|_| BlockStorageError::DeserializationError)?;
@@ -69,7 +153,8 @@ impl BlockStorer for BlockDB {
     }
 
     async fn get_by_index(&self, index: u32) -> Result<Option<Block>, BlockStorageError> {
-        match self.index_db.get(index.to_be_bytes()) {
+        let hash = self.index_db.read().get(index.to_be_bytes());
+        match hash {
             Ok(Some(hash)) => self.get(hash.to_vec()).await,
             Ok(None) => Ok(None),
             Err(_) => Err(BlockStorageError::ReadError),
@@ -77,7 +162,7 @@ impl BlockStorer for BlockDB {
     }
 
     async fn get_hash_by_index(&self, index: u32) -> Result<Option<Vec<u8>>, BlockStorageError> {
-        match self.index_db.get(index.to_be_bytes()) {
+        match self.index_db.read().get(index.to_be_bytes()) {
             Ok(Some(hash)) => Ok(Some(hash.to_vec())),
             Ok(None) => Ok(None),
             Err(_) => Err(BlockStorageError::ReadError),
@@ -87,7 +172,7 @@ impl BlockStorer for BlockDB {
     async fn get_highest_index(&self) -> Result<Option<u32>, BlockStorageError> {
         let mut max_index = None;
 
-        for result in self.index_db.iter() {
+        for result in self.index_db.read().iter() {
             let (key, _) = result.map_err(|_| BlockStorageError::ReadError)?;
             let index = u32::from_be_bytes(
                 <[u8; 4]>::try_from(key.as_ref())
@@ -101,6 +186,194 @@ impl BlockStorer for BlockDB {
     }
 
     async fn is_empty(&self) -> Result<bool, BlockStorageError> {
-        Ok(self.blocks_db.iter().next().is_none())
+        Ok(self.blocks_db.read().iter().next().is_none())
+    }
+
+    async fn put_tx_index(
+        &self,
+        tx_hash: Vec<u8>,
+        block_hash: Vec<u8>,
+    ) -> Result<(), BlockStorageError> {
+        self.tx_db
+            .read()
+            .insert(tx_hash, block_hash)
+            .map_err(|_| BlockStorageError::WriteError)?;
+
+        Ok(())
+    }
+
+    async fn get_block_hash_by_tx(
+        &self,
+        tx_hash: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, BlockStorageError> {
+        match self.tx_db.read().get(tx_hash) {
+            Ok(Some(hash)) => Ok(Some(hash.to_vec())),
+            Ok(None) => Ok(None),
+            Err(_) => Err(BlockStorageError::ReadError),
+        }
+    }
+
+    async fn remove_tx_index(&self, tx_hash: Vec<u8>) -> Result<(), BlockStorageError> {
+        self.tx_db
+            .read()
+            .remove(tx_hash)
+            .map_err(|_| BlockStorageError::WriteError)?;
+
+        Ok(())
+    }
+
+    // Records which transaction created the output at the stealth address, for every output in the chain
+    async fn put_stealth_index(
+        &self,
+        stealth_address: &[u8],
+        tx_hash: Vec<u8>,
+    ) -> Result<(), BlockStorageError> {
+        self.tx_db
+            .read()
+            .insert(stealth_key(stealth_address), tx_hash)
+            .map_err(|_| BlockStorageError::WriteError)?;
+
+        Ok(())
+    }
+
+    async fn remove_stealth_index(&self, stealth_address: &[u8]) -> Result<(), BlockStorageError> {
+        self.tx_db
+            .read()
+            .remove(stealth_key(stealth_address))
+            .map_err(|_| BlockStorageError::WriteError)?;
+
+        Ok(())
+    }
+
+    async fn contains_stealth_address(
+        &self,
+        stealth_address: &[u8],
+    ) -> Result<bool, BlockStorageError> {
+        self.tx_db
+            .read()
+            .contains_key(stealth_key(stealth_address))
+            .map_err(|_| BlockStorageError::ReadError)
+    }
+
+    async fn flush(&self) -> Result<(), BlockStorageError> {
+        for db in [&self.blocks_db, &self.index_db, &self.tx_db] {
+            db.read()
+                .flush()
+                .map_err(|_| BlockStorageError::WriteError)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vec_proto::messages::Header;
+
+    fn open_block_db(path: &std::path::Path) -> BlockDB {
+        BlockDB::new(
+            CompactableDb::open(path.join("blocks_db")).unwrap(),
+            CompactableDb::open(path.join("index_db")).unwrap(),
+            CompactableDb::open(path.join("tx_db")).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_flushed_blocks_are_durable() {
+        let path = std::env::temp_dir().join(format!("vec_flush_test_{}", rand::random::<u64>()));
+        let block = Block {
+            msg_header: Some(Header {
+                msg_index: 1,
+                ..Default::default()
+            }),
+            msg_transactions: vec![],
+        };
+        {
+            let block_db = open_block_db(&path);
+            block_db
+                .put_block(1, b"hash".to_vec(), &block)
+                .await
+                .unwrap();
+            block_db.flush().await.unwrap();
+        }
+        let block_db = open_block_db(&path);
+        assert_eq!(block_db.get_by_index(1).await.unwrap(), Some(block));
+        drop(block_db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_occupied_index_is_not_overwritten() {
+        let path = std::env::temp_dir().join(format!("vec_index_test_{}", rand::random::<u64>()));
+        let block_db = open_block_db(&path);
+        let block = |nonce: u32| Block {
+            msg_header: Some(Header {
+                msg_index: 1,
+                msg_nonce: nonce,
+                ..Default::default()
+            }),
+            msg_transactions: vec![],
+        };
+        block_db
+            .put_block(1, b"first".to_vec(), &block(1))
+            .await
+            .unwrap();
+        // The same block again is accepted
+        block_db
+            .put_block(1, b"first".to_vec(), &block(1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            block_db.put_block(1, b"second".to_vec(), &block(2)).await,
+            Err(BlockStorageError::IndexOccupied { index: 1 })
+        ));
+        assert_eq!(block_db.get_by_index(1).await.unwrap(), Some(block(1)));
+
+        // Switching branches takes the block out first
+        block_db.remove_block(1).await.unwrap();
+        block_db
+            .put_block(1, b"second".to_vec(), &block(2))
+            .await
+            .unwrap();
+        assert_eq!(block_db.get_by_index(1).await.unwrap(), Some(block(2)));
+        drop(block_db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_racing_blocks_claim_index_once() {
+        let path = std::env::temp_dir().join(format!("vec_race_test_{}", rand::random::<u64>()));
+        let block_db = std::sync::Arc::new(open_block_db(&path));
+        let tasks = (0..16u32)
+            .map(|nonce| {
+                let block_db = std::sync::Arc::clone(&block_db);
+                tokio::spawn(async move {
+                    let block = Block {
+                        msg_header: Some(Header {
+                            msg_index: 1,
+                            msg_nonce: nonce,
+                            ..Default::default()
+                        }),
+                        msg_transactions: vec![],
+                    };
+                    block_db
+                        .put_block(1, nonce.to_be_bytes().to_vec(), &block)
+                        .await
+                        .map(|_| nonce)
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut winners = Vec::new();
+        for task in tasks {
+            if let Ok(nonce) = task.await.unwrap() {
+                winners.push(nonce);
+            }
+        }
+
+        assert_eq!(winners.len(), 1);
+        let stored = block_db.get_by_index(1).await.unwrap().unwrap();
+        assert_eq!(stored.msg_header.unwrap().msg_nonce, winners[0]);
+        drop(block_db);
+        std::fs::remove_dir_all(path).unwrap();
     }
 }
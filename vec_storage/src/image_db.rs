@@ -1,28 +1,35 @@
+use crate::maintenance::CompactableDb;
 use async_trait::async_trait;
 use curve25519_dalek_ng::ristretto::CompressedRistretto;
-use sled::Db;
 use vec_errors::errors::*;
 
 pub struct ImageDB {
-    db: Db,
+    db: CompactableDb,
 }
 
 #[async_trait]
 pub trait ImageStorer: Send + Sync {
     async fn put(&self, key_image: Vec<u8>) -> Result<(), UTXOStorageError>;
+    async fn remove(&self, key_image: Vec<u8>) -> Result<(), UTXOStorageError>;
     async fn contains(&self, key_image: Vec<u8>) -> Result<bool, UTXOStorageError>;
+    async fn flush(&self) -> Result<(), UTXOStorageError>;
 }
 
 impl ImageDB {
-    pub fn new(db: Db) -> Self {
+    pub fn new(db: CompactableDb) -> Self {
         ImageDB { db }
     }
+
+    // Compacts the database while it stays in use, returns the reclaimed bytes
+    pub fn compact(&self) -> Result<u64, UTXOStorageError> {
+        self.db.compact().map_err(|_| UTXOStorageError::WriteError)
+    }
 }
 
 #[async_trait]
 impl ImageStorer for ImageDB {
     async fn put(&self, key_image: Vec<u8>) -> Result<(), UTXOStorageError> {
-        let db = self.db.clone();
+        let db = self.db.read();
         let key_image = CompressedRistretto::from_slice(&key_image);
         let key_image_bytes = key_image.as_bytes();
         db.insert(key_image_bytes, &[])
@@ -30,8 +37,18 @@ impl ImageStorer for ImageDB {
         Ok(())
     }
 
+    // Unspends the key image again, when the block spending it is rolled back
+    async fn remove(&self, key_image: Vec<u8>) -> Result<(), UTXOStorageError> {
+        let key_image = CompressedRistretto::from_slice(&key_image);
+        self.db
+            .read()
+            .remove(key_image.as_bytes())
+            .map_err(|_| UTXOStorageError::WriteError)?;
+        Ok(())
+    }
+
     async fn contains(&self, key_image: Vec<u8>) -> Result<bool, UTXOStorageError> {
-        let db = self.db.clone();
+        let db = self.db.read();
         let key_image = CompressedRistretto::from_slice(&key_image);
         let key_image_bytes = key_image.as_bytes();
         match db
@@ -42,4 +59,12 @@ impl ImageStorer for ImageDB {
             None => Ok(false),
         }
     }
+
+    async fn flush(&self) -> Result<(), UTXOStorageError> {
+        self.db
+            .read()
+            .flush()
+            .map_err(|_| UTXOStorageError::WriteError)?;
+        Ok(())
+    }
 }
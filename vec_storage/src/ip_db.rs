@@ -1,32 +1,73 @@
+use crate::maintenance::CompactableDb;
 use async_trait::async_trait;
-use sled::Db;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use vec_errors::errors::*;
 
 #[async_trait]
 pub trait IPStorer: Send + Sync {
     async fn put(&self, address: Vec<u8>, ip: String) -> Result<(), IPStorageError>;
+    async fn put_seen_at(
+        &self,
+        address: Vec<u8>,
+        ip: String,
+        last_seen: u64,
+    ) -> Result<(), IPStorageError>;
     async fn remove(&self, key: &[u8]) -> Result<(), IPStorageError>;
     async fn get(&self) -> Result<Vec<(Vec<u8>, String)>, IPStorageError>;
     async fn get_by_address(&self, address: &[u8]) -> Result<Option<String>, IPStorageError>;
     async fn update(&self, address: &[u8], new_ip: &str) -> Result<(), IPStorageError>;
+    async fn prune_older_than(&self, age: Duration) -> Result<usize, IPStorageError>;
+    async fn flush(&self) -> Result<(), IPStorageError>;
 }
 
 pub struct IPDB {
-    ip_db: Db,
+    ip_db: CompactableDb,
 }
 
 impl IPDB {
-    pub fn new(ip_db: Db) -> Self {
+    pub fn new(ip_db: CompactableDb) -> Self {
         IPDB { ip_db }
     }
+
+    // Compacts the database while it stays in use, returns the reclaimed bytes
+    pub fn compact(&self) -> Result<u64, IPStorageError> {
+        self.ip_db.compact().map_err(|_| IPStorageError::WriteError)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+// Entries are (address, ip, last seen in unix seconds), ones written before the timestamp existed read as seen at 0
+fn decode(value: &[u8]) -> Result<(Vec<u8>, String, u64), IPStorageError> {
+    if let Ok(entry) = bincode::deserialize::<(Vec<u8>, String, u64)>(value) {
+        return Ok(entry);
+    }
+    let (address, ip): (Vec<u8>, String) =
+        bincode::deserialize(value).map_err(|_| IPStorageError::DeserializationError)?;
+    Ok((address, ip, 0))
 }
 
 #[async_trait]
 impl IPStorer for IPDB {
     async fn put(&self, address: Vec<u8>, ip: String) -> Result<(), IPStorageError> {
-        let data = (address.clone(), ip);
+        self.put_seen_at(address, ip, now()).await
+    }
+
+    async fn put_seen_at(
+        &self,
+        address: Vec<u8>,
+        ip: String,
+        last_seen: u64,
+    ) -> Result<(), IPStorageError> {
+        let data = (address.clone(), ip, last_seen);
         let bin = bincode::serialize(&data).map_err(|_| IPStorageError::SerializationError)?;
         self.ip_db
+            .read()
             .insert(&address, bin)
             .map_err(|_| IPStorageError::WriteError)?;
         Ok(())
@@ -34,6 +75,7 @@ impl IPStorer for IPDB {
 
     async fn remove(&self, key: &[u8]) -> Result<(), IPStorageError> {
         self.ip_db
+            .read()
             .remove(key)
             .map_err(|_| IPStorageError::WriteError)?;
         Ok(())
@@ -41,20 +83,18 @@ impl IPStorer for IPDB {
 
     async fn get(&self) -> Result<Vec<(Vec<u8>, String)>, IPStorageError> {
         let mut ips = vec![];
-        for result in self.ip_db.iter() {
+        for result in self.ip_db.read().iter() {
             let (_key, value) = result.map_err(|_| IPStorageError::ReadError)?;
-            let data: (Vec<u8>, String) =
-                bincode::deserialize(&value).map_err(|_| IPStorageError::DeserializationError)?;
-            ips.push(data);
+            let (address, ip, _last_seen) = decode(&value)?;
+            ips.push((address, ip));
         }
         Ok(ips)
     }
 
     async fn get_by_address(&self, address: &[u8]) -> Result<Option<String>, IPStorageError> {
-        match self.ip_db.get(address) {
+        match self.ip_db.read().get(address) {
             Ok(Some(value)) => {
-                let (_stored_address, ip): (Vec<u8>, String) = bincode::deserialize(&value)
-                    .map_err(|_| IPStorageError::DeserializationError)?;
+                let (_stored_address, ip, _last_seen) = decode(&value)?;
                 Ok(Some(ip))
             }
             Ok(None) => Ok(None),
@@ -62,21 +102,102 @@ impl IPStorer for IPDB {
         }
     }
 
+    // Updating an entry also marks its peer as just seen
     async fn update(&self, address: &[u8], new_ip: &str) -> Result<(), IPStorageError> {
-        match self.ip_db.get(address) {
+        let old_value = self.ip_db.read().get(address);
+        match old_value {
             Ok(Some(old_value)) => {
-                let (old_address, _old_ip): (Vec<u8>, String) = bincode::deserialize(&old_value)
-                    .map_err(|_| IPStorageError::DeserializationError)?;
-                let new_data = (old_address, new_ip.to_string());
-                let new_data_bin = bincode::serialize(&new_data)
-                    .map_err(|_| IPStorageError::SerializationError)?;
-                self.ip_db
-                    .insert(address, new_data_bin)
-                    .map_err(|_| IPStorageError::WriteError)?;
-                Ok(())
+                let (old_address, _old_ip, _last_seen) = decode(&old_value)?;
+                self.put_seen_at(old_address, new_ip.to_string(), now())
+                    .await
             }
             Ok(None) => Err(IPStorageError::NotFound),
             Err(_) => Err(IPStorageError::ReadError),
         }
     }
+
+    // Drops the entries not seen within age, returning how many were removed.
+    // Entries without a timestamp get stamped now instead, so they age out like the others
+    async fn prune_older_than(&self, age: Duration) -> Result<usize, IPStorageError> {
+        let cutoff = now().saturating_sub(age.as_secs());
+        let mut pruned = 0;
+        let entries: Vec<_> = self.ip_db.read().iter().collect();
+        for result in entries {
+            let (key, value) = result.map_err(|_| IPStorageError::ReadError)?;
+            let (address, ip, last_seen) = decode(&value)?;
+            if last_seen == 0 {
+                self.put_seen_at(address, ip, now()).await?;
+            } else if last_seen < cutoff {
+                self.remove(&key).await?;
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    async fn flush(&self) -> Result<(), IPStorageError> {
+        self.ip_db
+            .read()
+            .flush()
+            .map_err(|_| IPStorageError::WriteError)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prune_older_than() {
+        let path =
+            std::env::temp_dir().join(format!("vec_ip_prune_test_{}", rand::random::<u64>()));
+        let ip_db = IPDB::new(CompactableDb::open(&path).unwrap());
+        let day = 24 * 60 * 60;
+        ip_db
+            .put_seen_at(
+                b"stale".to_vec(),
+                "10.0.0.1:8080".to_string(),
+                now() - 10 * day,
+            )
+            .await
+            .unwrap();
+        ip_db
+            .put_seen_at(b"recent".to_vec(), "10.0.0.2:8080".to_string(), now() - day)
+            .await
+            .unwrap();
+        ip_db
+            .put(b"fresh".to_vec(), "10.0.0.3:8080".to_string())
+            .await
+            .unwrap();
+        // An entry written before timestamps were stored survives and gets stamped
+        let legacy =
+            bincode::serialize(&(b"legacy".to_vec(), "10.0.0.4:8080".to_string())).unwrap();
+        ip_db.ip_db.read().insert(b"legacy", legacy).unwrap();
+
+        let pruned = ip_db
+            .prune_older_than(Duration::from_secs(7 * day))
+            .await
+            .unwrap();
+        assert_eq!(pruned, 1);
+        assert_eq!(ip_db.get_by_address(b"stale").await.unwrap(), None);
+        let mut remaining: Vec<Vec<u8>> = ip_db
+            .get()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(address, _)| address)
+            .collect();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![b"fresh".to_vec(), b"legacy".to_vec(), b"recent".to_vec()]
+        );
+        let (_, _, last_seen) =
+            decode(&ip_db.ip_db.read().get(b"legacy").unwrap().unwrap()).unwrap();
+        assert!(last_seen > 0);
+
+        drop(ip_db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
 }
@@ -4,24 +4,36 @@ use std::sync::Arc;
 use crate::block_db::*;
 use crate::image_db::*;
 use crate::ip_db::*;
+use crate::maintenance::CompactableDb;
+use crate::meta_db::*;
 use crate::output_db::*;
 
+// Directory of the Sled databases, can be overridden with the VECTOR_DATA_DIR environment variable
+pub fn data_dir() -> String {
+    std::env::var("VECTOR_DATA_DIR").unwrap_or_else(|_| "C:/Vector".to_string())
+}
+
 lazy_static! {
     pub static ref BLOCK_STORER: Arc<BlockDB> = {
-        let block_db = sled::open("C:/Vector/blocks_db").unwrap();
-        let index_db = sled::open("C:/Vector/index_db").unwrap();
-        Arc::new(BlockDB::new(block_db, index_db))
+        let block_db = CompactableDb::open(format!("{}/blocks_db", data_dir())).unwrap();
+        let index_db = CompactableDb::open(format!("{}/index_db", data_dir())).unwrap();
+        let tx_db = CompactableDb::open(format!("{}/tx_db", data_dir())).unwrap();
+        Arc::new(BlockDB::new(block_db, index_db, tx_db))
     };
     pub static ref IMAGE_STORER: Arc<ImageDB> = {
-        let image_db = sled::open("C:/Vector/image_db").unwrap();
+        let image_db = CompactableDb::open(format!("{}/image_db", data_dir())).unwrap();
         Arc::new(ImageDB::new(image_db))
     };
     pub static ref OUTPUT_STORER: Arc<OutputDB> = {
-        let output_db = sled::open("C:/Vector/output_db").unwrap();
+        let output_db = CompactableDb::open(format!("{}/output_db", data_dir())).unwrap();
         Arc::new(OutputDB::new(output_db))
     };
     pub static ref IP_STORER: Arc<IPDB> = {
-        let ip_db = sled::open("C:/Vector/ip_db").unwrap();
+        let ip_db = CompactableDb::open(format!("{}/ip_db", data_dir())).unwrap();
         Arc::new(IPDB::new(ip_db))
     };
+    pub static ref META_STORER: Arc<MetaDB> = {
+        let meta_db = CompactableDb::open(format!("{}/meta_db", data_dir())).unwrap();
+        Arc::new(MetaDB::new(meta_db))
+    };
 }
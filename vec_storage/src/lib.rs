@@ -3,4 +3,6 @@ pub mod contract_db;
 pub mod image_db;
 pub mod ip_db;
 pub mod lazy_traits;
+pub mod maintenance;
+pub mod meta_db;
 pub mod output_db;
@@ -0,0 +1,229 @@
+use crate::lazy_traits::data_dir;
+use sled::Db;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard};
+
+// Every Sled database of the data directory, by directory name
+pub const DB_NAMES: [&str; 7] = [
+    "blocks_db",
+    "index_db",
+    "tx_db",
+    "image_db",
+    "output_db",
+    "ip_db",
+    "meta_db",
+];
+
+// Sled database the storers keep open for the node's lifetime and that can still be compacted. Every access
+// goes through the lock, so compaction can swap in the rewritten copy without losing a write
+pub struct CompactableDb {
+    path: PathBuf,
+    db: RwLock<Db>,
+}
+
+impl CompactableDb {
+    // Opens the database at the path, finishing or undoing a compaction a crash interrupted
+    pub fn open(path: impl AsRef<Path>) -> sled::Result<CompactableDb> {
+        let path = path.as_ref().to_path_buf();
+        recover_interrupted(&path)?;
+        let db = sled::open(&path)?;
+        Ok(CompactableDb {
+            path,
+            db: RwLock::new(db),
+        })
+    }
+
+    // The guard blocks compaction, it must not be held across an await
+    pub fn read(&self) -> RwLockReadGuard<'_, Db> {
+        self.db.read().unwrap()
+    }
+
+    // Sled 0.34 has no manual compaction and its segment cleaner never gives space back to the filesystem, so
+    // the database is exported into a fresh one that replaces it. Every access waits until the swap is done.
+    // Returns how many bytes the files shrank by
+    pub fn compact(&self) -> sled::Result<u64> {
+        let mut db = self.db.write().unwrap();
+        let compacted_path = sibling(&self.path, "compacting");
+        if compacted_path.exists() {
+            fs::remove_dir_all(&compacted_path)?;
+        }
+        let before = db.size_on_disk()?;
+        let compacted = sled::open(&compacted_path)?;
+        compacted.import(db.export());
+        compacted.flush()?;
+        let after = compacted.size_on_disk()?;
+        drop(compacted);
+
+        // Sled only lets go of the files once the last handle is dropped, a temporary database stands in meanwhile
+        let placeholder = sled::Config::new().temporary(true).open()?;
+        drop(std::mem::replace(&mut *db, placeholder));
+        let swapped = swap_in(&self.path, &compacted_path);
+        let recovered = recover_interrupted(&self.path);
+        *db = sled::open(&self.path)?;
+        swapped?;
+        recovered?;
+        Ok(before.saturating_sub(after))
+    }
+}
+
+// Replaces the database directory with the compacted one
+fn swap_in(path: &Path, compacted_path: &Path) -> std::io::Result<()> {
+    let old_path = sibling(path, "old");
+    fs::rename(path, &old_path)?;
+    fs::rename(compacted_path, path)?;
+    fs::remove_dir_all(&old_path)
+}
+
+// A compaction interrupted between the renames left the data only in the old copy, one interrupted after them
+// left a stale old copy behind
+fn recover_interrupted(path: &Path) -> std::io::Result<()> {
+    let old_path = sibling(path, "old");
+    if !old_path.exists() {
+        return Ok(());
+    }
+    if path.exists() {
+        fs::remove_dir_all(&old_path)
+    } else {
+        fs::rename(&old_path, path)
+    }
+}
+
+// Compacts every database of the data directory, has to run before any of the storers opens them.
+// Returns the reclaimed bytes
+pub fn compact_data_dir() -> sled::Result<u64> {
+    let mut reclaimed = 0;
+    for name in DB_NAMES {
+        let path = Path::new(&data_dir()).join(name);
+        if path.exists() || sibling(&path, "old").exists() {
+            reclaimed += compact_db_dir(&path)?;
+        }
+    }
+    Ok(reclaimed)
+}
+
+// Compacts the closed database at the path
+pub fn compact_db_dir(path: &Path) -> sled::Result<u64> {
+    CompactableDb::open(path)?.compact()
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", suffix));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compaction_after_churn_shrinks_and_keeps_data() {
+        let path = std::env::temp_dir().join(format!("vec_compact_test_{}", rand::random::<u64>()));
+        let db = sled::open(&path).unwrap();
+        let tree = db.open_tree(b"tree").unwrap();
+        for round in 0..5u32 {
+            for key in 0..2_000u32 {
+                db.insert(key.to_be_bytes(), vec![round as u8; 256])
+                    .unwrap();
+            }
+            for key in (0..2_000u32).filter(|key| key % 2 == 0) {
+                db.remove(key.to_be_bytes()).unwrap();
+            }
+        }
+        tree.insert(b"key", b"value").unwrap();
+        db.flush().unwrap();
+        let before = db.size_on_disk().unwrap();
+        drop(tree);
+        drop(db);
+
+        let reclaimed = compact_db_dir(&path).unwrap();
+        assert!(reclaimed > 0);
+
+        let db = sled::open(&path).unwrap();
+        assert!(db.size_on_disk().unwrap() < before);
+        assert_eq!(db.len(), 1_000);
+        for key in 0..2_000u32 {
+            let value = db.get(key.to_be_bytes()).unwrap();
+            if key % 2 == 0 {
+                assert_eq!(value, None);
+            } else {
+                assert_eq!(value.unwrap().to_vec(), vec![4u8; 256]);
+            }
+        }
+        let tree = db.open_tree(b"tree").unwrap();
+        assert_eq!(tree.get(b"key").unwrap().unwrap().to_vec(), b"value");
+        drop(tree);
+        drop(db);
+        assert!(!sibling(&path, "compacting").exists());
+        assert!(!sibling(&path, "old").exists());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_while_in_use_keeps_writes() {
+        let path = std::env::temp_dir().join(format!("vec_compact_test_{}", rand::random::<u64>()));
+        let db = CompactableDb::open(&path).unwrap();
+        for round in 0..5u32 {
+            for key in 0..1_000u32 {
+                db.read()
+                    .insert(key.to_be_bytes(), vec![round as u8; 256])
+                    .unwrap();
+            }
+        }
+        db.read()
+            .open_tree(b"tree")
+            .unwrap()
+            .insert(b"key", b"value")
+            .unwrap();
+        db.read().flush().unwrap();
+
+        assert!(db.compact().unwrap() > 0);
+        db.read().insert(b"after", b"compaction").unwrap();
+        assert_eq!(db.read().len(), 1_001);
+        assert_eq!(
+            db.read().get(0u32.to_be_bytes()).unwrap().unwrap().to_vec(),
+            vec![4u8; 256]
+        );
+        assert!(!sibling(&path, "compacting").exists());
+        assert!(!sibling(&path, "old").exists());
+        drop(db);
+
+        // The compacted copy is the one found at the path from now on
+        let db = sled::open(&path).unwrap();
+        assert_eq!(db.get(b"after").unwrap().unwrap().to_vec(), b"compaction");
+        let tree = db.open_tree(b"tree").unwrap();
+        assert_eq!(tree.get(b"key").unwrap().unwrap().to_vec(), b"value");
+        drop(tree);
+        drop(db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_compaction_is_recovered() {
+        let path = std::env::temp_dir().join(format!("vec_compact_test_{}", rand::random::<u64>()));
+        let old_path = sibling(&path, "old");
+        let write = |path: &Path, value: &[u8]| {
+            let db = sled::open(path).unwrap();
+            db.insert(b"key", value).unwrap();
+            db.flush().unwrap();
+        };
+        let read = |path: &Path| {
+            let db = sled::open(path).unwrap();
+            db.get(b"key").unwrap().map(|value| value.to_vec())
+        };
+
+        // Interrupted between the renames, only the old copy holds the data
+        write(&old_path, b"old");
+        compact_db_dir(&path).unwrap();
+        assert_eq!(read(&path), Some(b"old".to_vec()));
+        assert!(!old_path.exists());
+
+        // Interrupted after the renames, the compacted copy is in place and the old one is stale
+        write(&old_path, b"stale");
+        compact_db_dir(&path).unwrap();
+        assert_eq!(read(&path), Some(b"old".to_vec()));
+        assert!(!old_path.exists());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
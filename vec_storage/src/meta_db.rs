@@ -0,0 +1,81 @@
+use crate::maintenance::CompactableDb;
+use async_trait::async_trait;
+use vec_errors::errors::*;
+
+pub const TOTAL_SUPPLY_KEY: &str = "total_supply";
+// Highest block index up to which every stored block is fully validated and applied
+pub const VALIDATED_UP_TO_KEY: &str = "validated_up_to";
+
+// Key of the work summed over the blocks from genesis up to the given index
+pub fn cumulative_work_key(index: u32) -> String {
+    format!("cumulative_work_{}", index)
+}
+
+// Key of the view key derivation the wallet with this public spend key was started with
+pub fn wallet_format_key(public_spend_key: &[u8]) -> String {
+    format!("wallet_format_{}", hex(public_spend_key))
+}
+
+// Key of the height at which the wallet with this public spend key received its first output
+pub fn wallet_birthday_key(public_spend_key: &[u8]) -> String {
+    format!("wallet_birthday_{}", hex(public_spend_key))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub struct MetaDB {
+    meta_db: CompactableDb,
+}
+
+#[async_trait]
+pub trait MetaStorer: Send + Sync {
+    async fn put(&self, key: &str, value: u64) -> Result<(), MetaStorageError>;
+    async fn get(&self, key: &str) -> Result<Option<u64>, MetaStorageError>;
+    async fn flush(&self) -> Result<(), MetaStorageError>;
+}
+
+impl MetaDB {
+    pub fn new(meta_db: CompactableDb) -> Self {
+        MetaDB { meta_db }
+    }
+
+    // Compacts the database while it stays in use, returns the reclaimed bytes
+    pub fn compact(&self) -> Result<u64, MetaStorageError> {
+        self.meta_db
+            .compact()
+            .map_err(|_| MetaStorageError::WriteError)
+    }
+}
+
+#[async_trait]
+impl MetaStorer for MetaDB {
+    async fn put(&self, key: &str, value: u64) -> Result<(), MetaStorageError> {
+        self.meta_db
+            .read()
+            .insert(key, &value.to_be_bytes())
+            .map_err(|_| MetaStorageError::WriteError)?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<u64>, MetaStorageError> {
+        match self.meta_db.read().get(key) {
+            Ok(Some(value)) => {
+                let bytes = <[u8; 8]>::try_from(value.as_ref())
+                    .map_err(|_| MetaStorageError::DeserializationError)?;
+                Ok(Some(u64::from_be_bytes(bytes)))
+            }
+            Ok(None) => Ok(None),
+            Err(_) => Err(MetaStorageError::ReadError),
+        }
+    }
+
+    async fn flush(&self) -> Result<(), MetaStorageError> {
+        self.meta_db
+            .read()
+            .flush()
+            .map_err(|_| MetaStorageError::WriteError)?;
+        Ok(())
+    }
+}
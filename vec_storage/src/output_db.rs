@@ -1,8 +1,11 @@
+use crate::maintenance::CompactableDb;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use sled::Db;
 use vec_errors::errors::*;
 
+// Tree of the stealth addresses of the change outputs the node wallet built, scans can't tell them from payments
+const CHANGE_TREE: &[u8] = b"change_outputs";
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Output {
     pub stealth: Vec<u8>,
@@ -10,57 +13,279 @@ pub struct Output {
     pub amount: Vec<u8>,
     pub commitment: Vec<u8>,
     pub range_proof: Vec<u8>,
+    pub index: u32,
+}
+
+// Whether an owned output pays us or returns our own change
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum OutputKind {
+    Received,
+    Change,
 }
 
+// Output the wallet recognised as its own, with what the scan learned about it. Whether it was spent
+// is not kept here, the key image store answers that
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct OwnedOutput {
     pub output: Output,
     pub decrypted_amount: u64,
+    pub decrypted_memo: Vec<u8>,
+    pub kind: OutputKind,
+    // Index of the block the output was included in
+    pub height: u32,
+    pub coinbase: bool,
+}
+
+impl OwnedOutput {
+    // Encoding of the stored values, shared by the node wallet's tree and the account trees
+    pub fn to_bytes(&self) -> Result<Vec<u8>, OutputStorageError> {
+        bincode::serialize(self).map_err(|_| OutputStorageError::SerializationError)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<OwnedOutput, OutputStorageError> {
+        bincode::deserialize(bytes).map_err(|_| OutputStorageError::DeserializationError)
+    }
 }
 
 #[async_trait]
 pub trait OutputStorer: Send + Sync {
     async fn put(&self, owned_output: &OwnedOutput) -> Result<(), OutputStorageError>;
     async fn remove(&self, key: &[u8]) -> Result<(), OutputStorageError>;
+    async fn contains(&self, key: &[u8]) -> Result<bool, OutputStorageError>;
     async fn get(&self) -> Result<Vec<OwnedOutput>, OutputStorageError>;
+    // Outputs of additional accounts live in a tree per account address, apart from the node wallet's ones
+    async fn put_for(
+        &self,
+        account: &[u8],
+        owned_output: &OwnedOutput,
+    ) -> Result<(), OutputStorageError>;
+    async fn get_for(&self, account: &[u8]) -> Result<Vec<OwnedOutput>, OutputStorageError>;
+    async fn remove_for(&self, account: &[u8], key: &[u8]) -> Result<(), OutputStorageError>;
+    async fn clear_for(&self, account: &[u8]) -> Result<(), OutputStorageError>;
+    // Records the output as change when the wallet builds the transaction paying it
+    async fn mark_change(&self, stealth: &[u8]) -> Result<(), OutputStorageError>;
+    async fn is_change(&self, stealth: &[u8]) -> Result<bool, OutputStorageError>;
+    // Drops the node wallet's outputs and those of every account, the change records can't be rebuilt and stay
+    async fn clear(&self) -> Result<(), OutputStorageError>;
+    async fn flush(&self) -> Result<(), OutputStorageError>;
 }
 
 pub struct OutputDB {
-    owned_db: Db,
+    owned_db: CompactableDb,
 }
 
 impl OutputDB {
-    pub fn new(owned_db: Db) -> Self {
+    pub fn new(owned_db: CompactableDb) -> Self {
         OutputDB { owned_db }
     }
+
+    // Compacts the database while it stays in use, returns the reclaimed bytes
+    pub fn compact(&self) -> Result<u64, OutputStorageError> {
+        self.owned_db
+            .compact()
+            .map_err(|_| OutputStorageError::WriteError)
+    }
 }
 
 #[async_trait]
 impl OutputStorer for OutputDB {
     async fn put(&self, owned_output: &OwnedOutput) -> Result<(), OutputStorageError> {
-        let owned_bin =
-            bincode::serialize(owned_output).map_err(|_| OutputStorageError::SerializationError)?;
         self.owned_db
-            .insert(&owned_output.output.stealth, owned_bin)
+            .read()
+            .insert(&owned_output.output.stealth, owned_output.to_bytes()?)
             .map_err(|_| OutputStorageError::WriteError)?;
         Ok(())
     }
 
     async fn remove(&self, key: &[u8]) -> Result<(), OutputStorageError> {
         self.owned_db
+            .read()
             .remove(key)
             .map_err(|_| OutputStorageError::WriteError)?;
         Ok(())
     }
 
+    async fn contains(&self, key: &[u8]) -> Result<bool, OutputStorageError> {
+        self.owned_db
+            .read()
+            .contains_key(key)
+            .map_err(|_| OutputStorageError::ReadError)
+    }
+
     async fn get(&self) -> Result<Vec<OwnedOutput>, OutputStorageError> {
-        let mut outputs = vec![];
-        for result in self.owned_db.iter() {
-            let (_key, value) = result.map_err(|_| OutputStorageError::ReadError)?;
-            let owned_output: OwnedOutput = bincode::deserialize(&value)
-                .map_err(|_| OutputStorageError::DeserializationError)?;
-            outputs.push(owned_output);
+        read_outputs(&self.owned_db.read())
+    }
+
+    async fn put_for(
+        &self,
+        account: &[u8],
+        owned_output: &OwnedOutput,
+    ) -> Result<(), OutputStorageError> {
+        self.owned_db
+            .read()
+            .open_tree(account)
+            .map_err(|_| OutputStorageError::WriteError)?
+            .insert(&owned_output.output.stealth, owned_output.to_bytes()?)
+            .map_err(|_| OutputStorageError::WriteError)?;
+        Ok(())
+    }
+
+    async fn get_for(&self, account: &[u8]) -> Result<Vec<OwnedOutput>, OutputStorageError> {
+        let tree = self
+            .owned_db
+            .read()
+            .open_tree(account)
+            .map_err(|_| OutputStorageError::ReadError)?;
+        read_outputs(&tree)
+    }
+
+    async fn remove_for(&self, account: &[u8], key: &[u8]) -> Result<(), OutputStorageError> {
+        self.owned_db
+            .read()
+            .open_tree(account)
+            .map_err(|_| OutputStorageError::WriteError)?
+            .remove(key)
+            .map_err(|_| OutputStorageError::WriteError)?;
+        Ok(())
+    }
+
+    async fn clear_for(&self, account: &[u8]) -> Result<(), OutputStorageError> {
+        self.owned_db
+            .read()
+            .open_tree(account)
+            .map_err(|_| OutputStorageError::WriteError)?
+            .clear()
+            .map_err(|_| OutputStorageError::WriteError)?;
+        Ok(())
+    }
+
+    async fn mark_change(&self, stealth: &[u8]) -> Result<(), OutputStorageError> {
+        self.owned_db
+            .read()
+            .open_tree(CHANGE_TREE)
+            .map_err(|_| OutputStorageError::WriteError)?
+            .insert(stealth, &[])
+            .map_err(|_| OutputStorageError::WriteError)?;
+        Ok(())
+    }
+
+    async fn is_change(&self, stealth: &[u8]) -> Result<bool, OutputStorageError> {
+        self.owned_db
+            .read()
+            .open_tree(CHANGE_TREE)
+            .map_err(|_| OutputStorageError::ReadError)?
+            .contains_key(stealth)
+            .map_err(|_| OutputStorageError::ReadError)
+    }
+
+    async fn clear(&self) -> Result<(), OutputStorageError> {
+        let db = self.owned_db.read();
+        db.clear().map_err(|_| OutputStorageError::WriteError)?;
+        let default_tree = db.name();
+        for name in db.tree_names() {
+            if name != default_tree && name != CHANGE_TREE {
+                db.drop_tree(name)
+                    .map_err(|_| OutputStorageError::WriteError)?;
+            }
         }
-        Ok(outputs)
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), OutputStorageError> {
+        self.owned_db
+            .read()
+            .flush()
+            .map_err(|_| OutputStorageError::WriteError)?;
+        Ok(())
+    }
+}
+
+// Decodes every owned output of the tree, a Db derefs to its default tree
+fn read_outputs(tree: &sled::Tree) -> Result<Vec<OwnedOutput>, OutputStorageError> {
+    let mut outputs = vec![];
+    for result in tree.iter() {
+        let (_key, value) = result.map_err(|_| OutputStorageError::ReadError)?;
+        outputs.push(OwnedOutput::from_bytes(&value)?);
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned_output(stealth: u8, height: u32) -> OwnedOutput {
+        OwnedOutput {
+            output: Output {
+                stealth: vec![stealth; 32],
+                output_key: vec![2; 32],
+                amount: vec![3; 8],
+                commitment: vec![4; 32],
+                range_proof: vec![5; 16],
+                index: 2,
+            },
+            decrypted_amount: 42,
+            decrypted_memo: b"invoice 7".to_vec(),
+            kind: OutputKind::Change,
+            height,
+            coinbase: true,
+        }
+    }
+
+    #[test]
+    fn test_owned_output_bytes_round_trip() {
+        let output = owned_output(1, 9);
+        let bytes = output.to_bytes().unwrap();
+        assert_eq!(OwnedOutput::from_bytes(&bytes).unwrap(), output);
+        assert!(matches!(
+            OwnedOutput::from_bytes(&bytes[..bytes.len() / 2]),
+            Err(OutputStorageError::DeserializationError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stored_outputs_keep_every_field() {
+        let path = std::env::temp_dir().join(format!("vec_output_test_{}", rand::random::<u64>()));
+        let db = OutputDB::new(CompactableDb::open(&path).unwrap());
+        let own = owned_output(1, 9);
+        let account = owned_output(6, 11);
+
+        db.put(&own).await.unwrap();
+        db.put_for(b"account", &account).await.unwrap();
+        assert_eq!(db.get().await.unwrap(), vec![own.clone()]);
+        assert_eq!(db.get_for(b"account").await.unwrap(), vec![account]);
+        assert!(db.contains(&own.output.stealth).await.unwrap());
+
+        db.remove(&own.output.stealth).await.unwrap();
+        assert!(db.get().await.unwrap().is_empty());
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn test_clear_drops_account_outputs() {
+        let path = std::env::temp_dir().join(format!("vec_output_test_{}", rand::random::<u64>()));
+        let db = OutputDB::new(CompactableDb::open(&path).unwrap());
+        db.put(&owned_output(1, 9)).await.unwrap();
+        db.put_for(b"first", &owned_output(2, 9)).await.unwrap();
+        db.put_for(b"first", &owned_output(3, 9)).await.unwrap();
+        db.put_for(b"second", &owned_output(4, 9)).await.unwrap();
+
+        db.remove_for(b"first", &[2; 32]).await.unwrap();
+        assert_eq!(
+            db.get_for(b"first").await.unwrap(),
+            vec![owned_output(3, 9)]
+        );
+        db.clear_for(b"second").await.unwrap();
+        assert!(db.get_for(b"second").await.unwrap().is_empty());
+
+        db.mark_change(&[1; 32]).await.unwrap();
+        db.clear().await.unwrap();
+        assert!(db.get().await.unwrap().is_empty());
+        assert!(db.get_for(b"first").await.unwrap().is_empty());
+        assert!(db.is_change(&[1; 32]).await.unwrap());
+        assert!(!db.is_change(&[2; 32]).await.unwrap());
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
     }
 }
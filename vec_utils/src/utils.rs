@@ -1,5 +1,8 @@
 use prost::Message;
 use sha3::{Digest, Keccak256};
+use std::sync::atomic::{AtomicBool, Ordering};
+pub use vec_crypto::crypto::canonical_transaction_bytes;
+use vec_crypto::crypto::put_bytes;
 use vec_errors::errors::*;
 use vec_macros::hash;
 use vec_proto::messages::*;
@@ -7,57 +10,181 @@ use vec_proto::messages::{Block, Header};
 
 pub fn hash_header_by_block(block: &Block) -> Result<Vec<u8>, BlockOpsError> {
     if let Some(header) = block.msg_header.as_ref() {
-        let hash = hash!(
-            header.msg_version.to_be_bytes(),
-            header.msg_index.to_be_bytes(),
-            &header.msg_previous_hash,
-            &header.msg_root_hash,
-            header.msg_timestamp.to_be_bytes(),
-            header.msg_nonce.to_be_bytes()
-        )
-        .to_vec();
-        Ok(hash)
+        hash_header(header)
     } else {
         return Err(BlockOpsError::MissingHeader);
     }
 }
 
 pub fn hash_header(header: &Header) -> Result<Vec<u8>, BlockOpsError> {
-    let hash = hash!(
-        header.msg_version.to_be_bytes(),
-        header.msg_index.to_be_bytes(),
-        &header.msg_previous_hash,
-        &header.msg_root_hash,
-        header.msg_timestamp.to_be_bytes()
-    )
-    .to_vec();
+    let hash = hash!(canonical_header_bytes(header)).to_vec();
     Ok(hash)
 }
 
+// A block is identified by its header's hash, the header commits to the transactions through msg_root_hash,
+// so a light client holding only headers can check their linkage and PoW
 pub fn hash_block(block: &Block) -> Result<Vec<u8>, BlockOpsError> {
+    hash_header_by_block(block)
+}
+
+// Canonical serialization used for hashing, independent of the protobuf wire encoding:
+// fields in a fixed order, integers big-endian, byte strings and lists prefixed with their u32 length
+// and optional messages prefixed with a presence byte
+pub fn canonical_header_bytes(header: &Header) -> Vec<u8> {
     let mut bytes = Vec::new();
-    block.encode(&mut bytes).unwrap();
-    let hash = hash!(&bytes).to_vec();
-    Ok(hash)
+    bytes.extend_from_slice(&header.msg_version.to_be_bytes());
+    bytes.extend_from_slice(&header.msg_index.to_be_bytes());
+    put_bytes(&mut bytes, &header.msg_previous_hash);
+    put_bytes(&mut bytes, &header.msg_root_hash);
+    bytes.extend_from_slice(&header.msg_timestamp.to_be_bytes());
+    bytes.extend_from_slice(&header.msg_nonce.to_be_bytes());
+    bytes
 }
 
-pub fn mine(mut block: Block) -> Result<u32, NodeServiceError> {
-    let difficulty = 4;
-    for nonce in 0..(u32::max_value()) {
-        block.msg_header.as_mut().unwrap().msg_nonce = nonce;
-        let hash = hash_block(&block)?;
-        if check_difficulty(&hash, difficulty) {
-            return Ok(nonce);
+pub const DEFAULT_POW_DIFFICULTY: usize = 4;
+
+// Hash function of the proof of work, swappable for experiments with other functions
+pub trait PowHasher: Sync {
+    fn pow_hash(&self, block: &Block) -> Vec<u8>;
+}
+
+// Default PoW hasher: Keccak256 over the canonical header bytes, i.e. the block hash
+pub struct KeccakPowHasher;
+
+impl PowHasher for KeccakPowHasher {
+    fn pow_hash(&self, block: &Block) -> Vec<u8> {
+        match &block.msg_header {
+            Some(header) => hash!(canonical_header_bytes(header)).to_vec(),
+            None => hash!().to_vec(),
         }
     }
-    Err(NodeServiceError::MineError)
+}
+
+pub fn mine(block: Block) -> Result<u32, MiningError> {
+    mine_with(&KeccakPowHasher, block, DEFAULT_POW_DIFFICULTY)
+}
+
+// Nonces tried for a header before its timestamp is rolled, every u32 nonce
+pub const NONCE_SPACE: u64 = 1 << 32;
+
+// Searches the nonce for which the block's PoW hash meets the difficulty on one thread per CPU
+pub fn mine_with<H: PowHasher>(
+    hasher: &H,
+    block: Block,
+    difficulty: usize,
+) -> Result<u32, MiningError> {
+    mine_parallel(hasher, block, difficulty, 0)
+}
+
+// Searches every nonce of the block's header on the given number of threads, 0 picks the number of CPUs.
+// Blocks the calling thread until done
+pub fn mine_parallel<H: PowHasher>(
+    hasher: &H,
+    block: Block,
+    difficulty: usize,
+    threads: usize,
+) -> Result<u32, MiningError> {
+    search_nonces(hasher, &block, difficulty, threads, NONCE_SPACE)?
+        .ok_or(MiningError::NoValidNonce)
+}
+
+// Mines the block even if no nonce below nonce_space works for its header: the timestamp is then rolled forward
+// a second, which changes the PoW hash of every nonce, and the search starts over. Searches on the given number of
+// threads like mine_parallel. Returns the mined block
+pub fn mine_rolling<H: PowHasher>(
+    hasher: &H,
+    mut block: Block,
+    difficulty: usize,
+    threads: usize,
+    nonce_space: u64,
+) -> Result<Block, MiningError> {
+    loop {
+        let nonce = search_nonces(hasher, &block, difficulty, threads, nonce_space)?;
+        let header = block
+            .msg_header
+            .as_mut()
+            .ok_or(MiningError::MissingHeader)?;
+        match nonce {
+            Some(nonce) => {
+                header.msg_nonce = nonce;
+                return Ok(block);
+            }
+            None => {
+                header.msg_timestamp = header
+                    .msg_timestamp
+                    .checked_add(1)
+                    .ok_or(MiningError::TimestampOverflow)?
+            }
+        }
+    }
+}
+
+// Worker i of n tries the nonces i, i + n, i + 2n, ... below nonce_space, which is capped to the 2^32 nonces a header
+// holds, and every worker stops as soon as one of them finds a valid nonce. None if no nonce meets the difficulty
+fn search_nonces<H: PowHasher>(
+    hasher: &H,
+    block: &Block,
+    difficulty: usize,
+    threads: usize,
+    nonce_space: u64,
+) -> Result<Option<u32>, MiningError> {
+    if block.msg_header.is_none() {
+        return Err(MiningError::MissingHeader);
+    }
+    let threads = match threads {
+        0 => std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+        threads => threads,
+    } as u64;
+    let found = AtomicBool::new(false);
+    Ok(std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|first_nonce| {
+                let mut block = block.clone();
+                let found = &found;
+                scope.spawn(move || {
+                    let mut nonce = first_nonce;
+                    while nonce < nonce_space.min(1 << 32) && !found.load(Ordering::Relaxed) {
+                        block.msg_header.as_mut()?.msg_nonce = nonce as u32;
+                        if check_difficulty(&hasher.pow_hash(&block), difficulty) {
+                            found.store(true, Ordering::Relaxed);
+                            return Some(nonce as u32);
+                        }
+                        nonce += threads;
+                    }
+                    None
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .filter_map(|worker| worker.join().ok().flatten())
+            .min()
+    }))
+}
+
+// Checks that the block's nonce satisfies the difficulty under the given hasher
+pub fn verify_pow<H: PowHasher>(hasher: &H, block: &Block, difficulty: usize) -> bool {
+    check_difficulty(&hasher.pow_hash(block), difficulty)
 }
 
 fn check_difficulty(hash: &[u8], difficulty: usize) -> bool {
+    leading_pow_digits(hash) >= difficulty
+}
+
+// Expected number of hashes mining a block to the difficulty takes, every leading 'd' hex digit takes 16 attempts
+// on average. Fork choice counts this rather than what a block's hash happened to reach
+pub fn difficulty_work(difficulty: usize) -> u64 {
+    1 << (4 * difficulty.min(15))
+}
+
+fn leading_pow_digits(hash: &[u8]) -> usize {
     let hex_hash = hex::encode(hash);
-    let leading_zeros = hex_hash.chars().take_while(|c| *c == 'd').count();
+    hex_hash.chars().take_while(|c| *c == 'd').count()
+}
 
-    leading_zeros >= difficulty
+// Keccak256 of the contract code a transaction commits to in msg_contract_hash
+pub fn hash_contract_code(code: &[u8]) -> Vec<u8> {
+    hash!(code).to_vec()
 }
 
 pub fn hash_transaction(transaction: &Transaction) -> Vec<u8> {
@@ -66,6 +193,100 @@ pub fn hash_transaction(transaction: &Transaction) -> Vec<u8> {
     hash!(&transaction_bytes).to_vec()
 }
 
+// Base58 of the transaction's protobuf encoding, for pasting a transaction around or signing it offline
+pub fn transaction_to_string(transaction: &Transaction) -> String {
+    bs58::encode(transaction.encode_to_vec()).into_string()
+}
+
+// Inverse of transaction_to_string. Only accepts the exact encoding a transaction would produce, so unknown
+// fields or trailing bytes are rejected instead of being silently dropped
+pub fn transaction_from_string(string: &str) -> Result<Transaction, ValidationError> {
+    let bytes = bs58::decode(string.trim())
+        .into_vec()
+        .map_err(|_| ValidationError::InvalidTransactionString)?;
+    if bytes.is_empty() {
+        return Err(ValidationError::InvalidTransactionString);
+    }
+    let transaction = Transaction::decode(bytes.as_slice())
+        .map_err(|_| ValidationError::InvalidTransactionString)?;
+    if transaction.encode_to_vec() != bytes {
+        return Err(ValidationError::InvalidTransactionString);
+    }
+    Ok(transaction)
+}
+
+// Returns the weight of the transaction (the length of its serialized form)
+pub fn transaction_weight(transaction: &Transaction) -> u64 {
+    transaction.encoded_len() as u64
+}
+
+// Returns the fee paid per weight unit, rounded up so that paid fees never produce a zero rate
+pub fn fee_rate(transaction: &Transaction) -> u64 {
+    let weight = transaction_weight(transaction).max(1);
+    transaction.msg_fee.div_ceil(weight)
+}
+
+// One-line summary of the block header followed by one indented line per transaction, for logging
+pub fn describe_block(block: &Block) -> String {
+    let mut description = match block.msg_header.as_ref() {
+        Some(header) => format!(
+            "Block #{} {} (previous {}, timestamp {}, nonce {}), {} transactions",
+            header.msg_index,
+            hash_block(block)
+                .map(|hash| bs58::encode(hash).into_string())
+                .unwrap_or_default(),
+            bs58::encode(&header.msg_previous_hash).into_string(),
+            header.msg_timestamp,
+            header.msg_nonce,
+            block.msg_transactions.len()
+        ),
+        None => format!(
+            "Block without header, {} transactions",
+            block.msg_transactions.len()
+        ),
+    };
+    for transaction in block.msg_transactions.iter() {
+        description.push_str("\n  ");
+        description.push_str(&describe_transaction(transaction));
+    }
+    description
+}
+
+// Structure of the transaction only: amounts stay encrypted and only public data such as key images is shown
+pub fn describe_transaction(transaction: &Transaction) -> String {
+    let rings: Vec<usize> = transaction
+        .msg_inputs
+        .iter()
+        .map(|input| input.msg_ring.len())
+        .collect();
+    let indices: Vec<u32> = transaction
+        .msg_outputs
+        .iter()
+        .map(|output| output.msg_index)
+        .collect();
+    let key_images: Vec<String> = transaction
+        .msg_inputs
+        .iter()
+        .map(|input| bs58::encode(&input.msg_key_image).into_string())
+        .collect();
+    format!(
+        "Transaction {}: {} inputs (ring sizes {:?}, key images {:?}), {} outputs (indices {:?}), fee {}, minted {}, contract {} bytes, weight {}",
+        bs58::encode(hash_transaction(transaction)).into_string(),
+        transaction.msg_inputs.len(),
+        rings,
+        key_images,
+        transaction.msg_outputs.len(),
+        indices,
+        transaction.msg_fee,
+        transaction.msg_minted,
+        transaction
+            .msg_contract
+            .as_ref()
+            .map_or(0, |contract| contract.msg_code.len()),
+        transaction_weight(transaction)
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -89,6 +310,57 @@ mod tests {
         assert_ne!(hash1_sync, hash2_sync);
     }
 
+    #[test]
+    fn test_transaction_string_round_trip() {
+        let mut transaction = create_test_transaction(7);
+        transaction.msg_fee = 150;
+        transaction.msg_contract_hash = hash_contract_code(b"code");
+        let string = transaction_to_string(&transaction);
+        let decoded = transaction_from_string(&string).unwrap();
+        assert_eq!(decoded, transaction);
+        assert_eq!(hash_transaction(&decoded), hash_transaction(&transaction));
+    }
+
+    #[test]
+    fn test_unversioned_transaction_encodes_its_version() {
+        let mut transaction = create_test_transaction(0);
+        let versioned = canonical_transaction_bytes(&transaction);
+        transaction.msg_version = 0;
+        let unversioned = canonical_transaction_bytes(&transaction);
+        assert_eq!(unversioned.len(), versioned.len());
+        let version_at = versioned.len() - 8;
+        assert_eq!(unversioned[version_at..version_at + 4], 0u32.to_be_bytes());
+        assert_eq!(versioned[version_at..version_at + 4], 1u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_contract_hash_is_always_encoded() {
+        let mut transaction = create_test_transaction(0);
+        let without_hash = canonical_transaction_bytes(&transaction);
+        assert!(without_hash.ends_with(&0u32.to_be_bytes()));
+        transaction.msg_contract_hash = hash_contract_code(b"code");
+        let with_hash = canonical_transaction_bytes(&transaction);
+        assert_eq!(with_hash.len(), without_hash.len() + 32);
+        assert_eq!(
+            with_hash[..without_hash.len() - 4],
+            without_hash[..without_hash.len() - 4]
+        );
+    }
+
+    #[test]
+    fn test_transaction_string_rejects_garbage() {
+        // 0, O, I and l are outside the base58 alphabet
+        assert!(transaction_from_string("0OIl").is_err());
+        assert!(transaction_from_string("").is_err());
+        // Valid base58 but not a protobuf message
+        let garbage = bs58::encode([0xff; 16]).into_string();
+        assert!(transaction_from_string(&garbage).is_err());
+        // A valid transaction followed by an unknown field
+        let mut bytes = create_test_transaction(0).encode_to_vec();
+        bytes.extend_from_slice(&[0xf8, 0x07, 0x01]);
+        assert!(transaction_from_string(&bs58::encode(bytes).into_string()).is_err());
+    }
+
     fn create_test_transaction(msg_index: u32) -> Transaction {
         let contract = Contract::default();
         Transaction {
@@ -105,8 +377,13 @@ mod tests {
                 msg_commitment: vec![],
                 msg_amount: vec![],
                 msg_index,
+                msg_memo: vec![],
             }],
             msg_contract: Some(contract),
+            msg_fee: 0,
+            msg_minted: 0,
+            msg_version: 1,
+            msg_contract_hash: vec![],
         }
     }
 
@@ -140,7 +417,195 @@ mod tests {
 
     #[test]
     fn test_hash_block() {
-        let block = make_block();
-        assert!(hash_block(&block).is_ok());
+        let mut block = make_block();
+        assert!(matches!(
+            hash_block(&block),
+            Err(BlockOpsError::MissingHeader)
+        ));
+        let header = Header {
+            msg_index: 2,
+            msg_root_hash: vec![7; 32],
+            ..Default::default()
+        };
+        block.msg_header = Some(header.clone());
+        assert_eq!(hash_block(&block).unwrap(), hash_header(&header).unwrap());
+        // The transactions count through the root hash only
+        block.msg_transactions.push(create_test_transaction(0));
+        assert_eq!(hash_block(&block).unwrap(), hash_header(&header).unwrap());
+    }
+
+    // Header as it would look like after adding an optional field to the proto
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct ExtendedHeader {
+        #[prost(uint32, tag = "1")]
+        msg_version: u32,
+        #[prost(uint32, tag = "2")]
+        msg_index: u32,
+        #[prost(bytes = "vec", tag = "3")]
+        msg_previous_hash: Vec<u8>,
+        #[prost(bytes = "vec", tag = "4")]
+        msg_root_hash: Vec<u8>,
+        #[prost(uint64, tag = "5")]
+        msg_timestamp: u64,
+        #[prost(uint32, tag = "6")]
+        msg_nonce: u32,
+        #[prost(uint64, optional, tag = "7")]
+        msg_extra: Option<u64>,
+    }
+
+    #[test]
+    fn test_canonical_hash_stable_across_proto_versions() {
+        let header = Header {
+            msg_version: 1,
+            msg_index: 7,
+            msg_previous_hash: vec![1; 32],
+            msg_root_hash: vec![2; 32],
+            msg_timestamp: 1_690_000_000,
+            msg_nonce: 42,
+        };
+        let block = Block {
+            msg_header: Some(header.clone()),
+            msg_transactions: vec![create_test_transaction(0)],
+        };
+        let extended = ExtendedHeader {
+            msg_version: header.msg_version,
+            msg_index: header.msg_index,
+            msg_previous_hash: header.msg_previous_hash.clone(),
+            msg_root_hash: header.msg_root_hash.clone(),
+            msg_timestamp: header.msg_timestamp,
+            msg_nonce: header.msg_nonce,
+            msg_extra: Some(0),
+        };
+        let extended_bytes = extended.encode_to_vec();
+        assert_ne!(extended_bytes, header.encode_to_vec());
+
+        let decoded = Header::decode(extended_bytes.as_slice()).unwrap();
+        assert_eq!(
+            hash_header(&decoded).unwrap(),
+            hash_header(&header).unwrap()
+        );
+        let decoded_block = Block {
+            msg_header: Some(decoded),
+            msg_transactions: block.msg_transactions.clone(),
+        };
+        assert_eq!(
+            hash_block(&decoded_block).unwrap(),
+            hash_block(&block).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_canonical_bytes_are_length_prefixed() {
+        let a = Header {
+            msg_previous_hash: vec![1, 2],
+            msg_root_hash: vec![3],
+            ..Default::default()
+        };
+        let b = Header {
+            msg_previous_hash: vec![1],
+            msg_root_hash: vec![2, 3],
+            ..Default::default()
+        };
+        assert_ne!(hash_header(&a).unwrap(), hash_header(&b).unwrap());
+    }
+
+    // Test-only PoW hash: the Keccak hash of the reversed canonical header bytes
+    struct ReversedPowHasher;
+
+    impl PowHasher for ReversedPowHasher {
+        fn pow_hash(&self, block: &Block) -> Vec<u8> {
+            let mut bytes = canonical_header_bytes(block.msg_header.as_ref().unwrap());
+            bytes.reverse();
+            hash!(bytes).to_vec()
+        }
+    }
+
+    #[test]
+    fn test_parallel_mining_finds_valid_nonce() {
+        let mut block = Block {
+            msg_header: Some(Header {
+                msg_index: 3,
+                ..Default::default()
+            }),
+            msg_transactions: vec![create_test_transaction(0)],
+        };
+        for threads in [1, 4] {
+            let nonce = mine_parallel(&KeccakPowHasher, block.clone(), 2, threads).unwrap();
+            block.msg_header.as_mut().unwrap().msg_nonce = nonce;
+            assert!(check_difficulty(&KeccakPowHasher.pow_hash(&block), 2));
+        }
+        assert!(matches!(
+            mine_parallel(&KeccakPowHasher, Block::default(), 2, 2),
+            Err(MiningError::MissingHeader)
+        ));
+    }
+
+    #[test]
+    fn test_exhausted_nonce_space_rolls_timestamp() {
+        let block = Block {
+            msg_header: Some(Header {
+                msg_index: 3,
+                msg_timestamp: 1_700_000_000,
+                ..Default::default()
+            }),
+            msg_transactions: vec![create_test_transaction(0)],
+        };
+        // A single nonce per header meets difficulty 3 once in 4096 headers, so the header has to be rolled
+        let mined = mine_rolling(&KeccakPowHasher, block, 3, 0, 1).unwrap();
+        let header = mined.msg_header.as_ref().unwrap();
+        assert_eq!(header.msg_nonce, 0);
+        assert!(header.msg_timestamp > 1_700_000_000);
+        assert!(verify_pow(&KeccakPowHasher, &mined, 3));
+    }
+
+    #[test]
+    fn test_mining_and_verification_agree_per_hasher() {
+        let mut block = Block {
+            msg_header: Some(Header {
+                msg_index: 1,
+                ..Default::default()
+            }),
+            msg_transactions: vec![create_test_transaction(0)],
+        };
+        let nonce = mine_with(&ReversedPowHasher, block.clone(), 2).unwrap();
+        block.msg_header.as_mut().unwrap().msg_nonce = nonce;
+        assert!(verify_pow(&ReversedPowHasher, &block, 2));
+
+        let nonce = mine_with(&KeccakPowHasher, block.clone(), 2).unwrap();
+        block.msg_header.as_mut().unwrap().msg_nonce = nonce;
+        assert!(verify_pow(&KeccakPowHasher, &block, 2));
+        assert_eq!(
+            KeccakPowHasher.pow_hash(&block),
+            hash_block(&block).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_describe_block() {
+        let mut transaction = create_test_transaction(3);
+        transaction.msg_outputs.push(TransactionOutput {
+            msg_index: 4,
+            msg_amount: vec![0xab; 8],
+            ..Default::default()
+        });
+        transaction.msg_minted = 50;
+        let block = Block {
+            msg_header: Some(Header {
+                msg_index: 7,
+                ..Default::default()
+            }),
+            msg_transactions: vec![transaction.clone(), create_test_transaction(1)],
+        };
+
+        let description = describe_block(&block);
+        let lines: Vec<&str> = description.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("Block #7 "));
+        assert!(lines[0].ends_with(", 2 transactions"));
+        assert!(lines[1].contains("1 inputs (ring sizes [1]"));
+        assert!(lines[1].contains("2 outputs (indices [3, 4])"));
+        assert!(lines[1].contains("minted 50"));
+        assert!(lines[2].contains("1 outputs (indices [1])"));
+        assert_eq!(lines[1].trim(), describe_transaction(&transaction));
     }
 }
@@ -6,6 +6,7 @@ use curve25519_dalek_ng::{
 use merlin::Transcript;
 use rand::seq::SliceRandom;
 use sha3::{Digest, Keccak256};
+use vec_crypto::crypto::derive_view_key;
 use vec_errors::errors::{CryptoOpsError, SchemeError};
 use vec_macros::hash;
 use vec_proto::messages::TransactionOutput;
@@ -65,8 +66,7 @@ impl Wallet {
     pub fn new() -> Result<Wallet, SchemeError> {
         let mut rng = rand::thread_rng();
         let secret_spend_key: Scalar = Scalar::random(&mut rng);
-        let hashed_key = hash!(secret_spend_key.as_bytes());
-        let secret_view_key = Scalar::from_bytes_mod_order(hashed_key.into());
+        let secret_view_key = derive_view_key(&secret_spend_key);
         let public_spend_key = &constants::RISTRETTO_BASEPOINT_TABLE * &secret_spend_key;
         let public_view_key = &constants::RISTRETTO_BASEPOINT_TABLE * &secret_view_key;
         let data = [
@@ -299,6 +299,7 @@ impl Scheme {
             msg_commitment: commitment.to_bytes().to_vec(),
             msg_amount: encrypted_amount.to_vec(),
             msg_index: output_index,
+            msg_memo: vec![],
         })
     }
 }